@@ -23,6 +23,13 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    // List every feature for the shell's config dump, so the list cannot drift from Cargo.toml
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    File::create(out.join("features.rs"))
+        .unwrap()
+        .write_all(feature_list().as_bytes())
+        .unwrap();
+
     // Configure defmt log level based on features
     if env::var("CARGO_FEATURE_DEBUG").is_ok() {
         // Debug build - enable all log levels
@@ -41,3 +48,29 @@ fn main() {
     // Link the defmt linker script
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
 }
+
+/// Build a slice expression of every feature in `Cargo.toml` but `default`, each with whether it
+/// is enabled
+fn feature_list() -> String {
+    let manifest = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.toml");
+    let manifest = std::fs::read_to_string(manifest).unwrap();
+    let mut list = String::from("&[\n");
+    let mut in_features = false;
+    for line in manifest.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_features = line == "[features]";
+            continue;
+        }
+        // Feature names start their line, the lines continuing a list start with a quote
+        let Some((name, _)) = line.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        if !in_features || name == "default" || name.starts_with(['#', '"']) {
+            continue;
+        }
+        let variable = format!("CARGO_FEATURE_{}", name.to_uppercase().replace('-', "_"));
+        list += &format!("    (\"{}\", {}),\n", name, env::var_os(variable).is_some());
+    }
+    list + "]\n"
+}
@@ -4,7 +4,9 @@ MEMORY
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
   /* Application starts at ACTIVE region in Bank 1 */
   FLASH                             : ORIGIN = 0x08000000, LENGTH = 1024K  /* Bank 1 */
-  DFU                               : ORIGIN = 0x08100000, LENGTH = 1024K  /* Bank 2 */
+  DFU                               : ORIGIN = 0x08100000, LENGTH = 896K   /* Bank 2 */
+  /* Last sector of bank 2 is kept out of the image for settings, see src/peripherals/flash_config.rs */
+  CONFIG                            : ORIGIN = 0x081E0000, LENGTH = 128K
   RAM                         (rwx) : ORIGIN = 0x24000000, LENGTH = 512K  /* 512 KiB of AXI ram */
 
   /* DTCM is directly linked to the CPU and very fast but can't be used with DMA */
@@ -19,3 +21,13 @@ MEMORY
   /* Backup SRAM is backed up by VBAT and can survive power cycles */
   BACKUP_SRAM               (rwx) : ORIGIN = 0x38800000, LENGTH = 4K    /* 4 KiB of Backup SRAM */
 }
+
+SECTIONS
+{
+  /* Backup SRAM keeps its contents across resets, so it must never be zeroed or initialised at boot */
+  .backup_sram (NOLOAD) : ALIGN(4)
+  {
+    *(.backup_sram .backup_sram.*);
+    . = ALIGN(4);
+  } > BACKUP_SRAM
+} INSERT AFTER .bss;
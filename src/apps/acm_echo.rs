@@ -10,18 +10,44 @@
 //! - Motor control command processing
 //! - System status and diagnostics reporting
 //! - Configuration and calibration interfaces
+//!
+//! # Zero-length packets
+//!
+//! A USB bulk transfer ends with a packet shorter than the endpoint's max packet size. A packet of
+//! exactly [`MAX_PACKET_SIZE`] bytes therefore leaves the host waiting for more data, so every
+//! full-size echo is followed by a zero-length packet (ZLP) that ends the transfer. Host read
+//! loops see the full packet followed by an empty read, and can use the empty read as the
+//! boundary. Empty packets from the host, e.g. the ZLP ending a full-size write, carry no data and
+//! are not echoed.
+//!
+//! # Framed mode
+//!
+//! A host that opens the port at [`FRAMED_ECHO_BAUD`] baud has its messages echoed through
+//! [`FramedAcm`] instead: every message received with [`FramedAcm::receive_message`] is sent back
+//! whole with [`FramedAcm::send_message`], which exercises the COBS framing and fragmentation from
+//! the host. The baud rate is read when the port is opened, and the mode it picks holds until the
+//! USB connection drops.
 
-use crate::peripherals::acm::{AcmConnection, Disconnected};
+use crate::peripherals::acm::{AcmConnection, Disconnected, FramedAcm};
 use crate::peripherals::usb_system::MAX_PACKET_SIZE;
 use defmt::{info, warn};
-use embassy_time::Timer;
+use embassy_time::{Duration, Timer};
 
 /// Maximum size of data that can be processed in a single packet
 const BUFFER_SIZE: usize = MAX_PACKET_SIZE as usize;
 
+/// Baud rate that selects the [framed mode](self#framed-mode)
+pub const FRAMED_ECHO_BAUD: u32 = 1_000_000;
+
+/// Largest message echoed in framed mode, longer ones are dropped by [`FramedAcm::receive_message`]
+const MESSAGE_SIZE: usize = 4096;
+
 /// Delay between reconnection attempts when the connection is lost
 const RECONNECT_DELAY_MS: u64 = 100;
 
+/// Time to wait for a host before reporting that the app is running without one
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Echo application that demonstrates USB CDC ACM packet-based communication.
 ///
 /// This application provides a low-latency echo server that processes individual
@@ -46,7 +72,7 @@ const RECONNECT_DELAY_MS: u64 = 100;
 /// # Example Usage
 ///
 /// ```rust,ignore
-/// let mut echo_app = AcmEcho::new(acm_connection);
+/// let echo_app = AcmEcho::new(acm_connection);
 /// echo_app.run().await; // Runs forever
 /// ```
 pub struct AcmEcho<'d> {
@@ -74,24 +100,45 @@ impl<'d> AcmEcho<'d> {
     /// reconnections gracefully.
     ///
     /// The application will:
-    /// 1. Wait for a USB host to connect
-    /// 2. Echo all received data back to the host
+    /// 1. Wait for a USB host to connect and open the port (assert DTR)
+    /// 2. Echo all received data back to the host, packet by packet or message by message
+    ///    depending on the baud rate
     /// 3. Handle disconnections by waiting for reconnection
     /// 4. Repeat indefinitely
     ///
     /// # Note
     ///
     /// This function never returns under normal operation.
-    pub async fn run(&mut self) -> ! {
+    pub async fn run(mut self) -> ! {
         info!("Echo application started");
 
         loop {
-            // Wait for a host to connect
-            self.acm.wait_connection().await;
-            info!("Echo app: Host connected, starting echo loop");
+            // Wait for a host to connect, then for a program on the host to open the port. There
+            // is nothing to do offline, so keep waiting after noting the host is missing.
+            if !self.acm.wait_connection_timeout(CONNECTION_TIMEOUT).await {
+                info!(
+                    "Echo app: No host after {} s, waiting for one",
+                    CONNECTION_TIMEOUT.as_secs()
+                );
+                self.acm.wait_connection().await;
+            }
+            if !self.acm.dtr() {
+                info!("Echo app: Host enumerated the port, waiting for it to be opened");
+                self.acm.wait_dtr().await;
+            }
 
             // Run the echo loop until disconnection
-            match self.echo_loop().await {
+            let result = if self.acm.line_coding().data_rate() == FRAMED_ECHO_BAUD {
+                info!("Echo app: Host connected, starting framed echo loop");
+                let mut framed = FramedAcm::new(self.acm);
+                let result = Self::framed_echo_loop(&mut framed).await;
+                self.acm = framed.into_inner();
+                result
+            } else {
+                info!("Echo app: Host connected, starting echo loop");
+                self.echo_loop().await
+            };
+            match result {
                 Err(Disconnected) => {
                     warn!("Echo loop: Connection lost, will reconnect...");
 
@@ -109,7 +156,8 @@ impl<'d> AcmEcho<'d> {
     /// Internal echo loop that handles data transfer.
     ///
     /// This function continuously reads data from the ACM connection and
-    /// echoes it back to the host until a disconnection occurs.
+    /// echoes it back to the host until a disconnection occurs. Empty packets are skipped and
+    /// full-size packets are followed by a ZLP, see the [module docs](self).
     ///
     /// # Returns
     ///
@@ -123,6 +171,11 @@ impl<'d> AcmEcho<'d> {
             let bytes_received = self.acm.receive_packet(&mut buffer).await?;
             let data = &buffer[..bytes_received];
 
+            // A ZLP only ends the host's transfer, and echoing it would end ours early
+            if data.is_empty() {
+                continue;
+            }
+
             // Log the received packet for debugging
             let utilization_percent = (bytes_received * 100) / BUFFER_SIZE;
             info!(
@@ -147,11 +200,38 @@ impl<'d> AcmEcho<'d> {
                 }
             }
 
-            // Echo the packet back to the host
+            // Echo the packet back to the host, ending a full-size packet's transfer with a ZLP
             self.acm.send_packet(data).await?;
+            if bytes_received == MAX_PACKET_SIZE as usize {
+                self.acm.send_packet(&[]).await?;
+            }
             info!("Echoed {} bytes packet back to host", bytes_received);
         }
     }
+
+    /// Internal echo loop for the [framed mode](self#framed-mode).
+    ///
+    /// Sends every complete message received back to the host as a message of its own.
+    ///
+    /// # Arguments
+    ///
+    /// * `framed` - The framed connection, created for this connection to the host
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - Should never happen under normal operation
+    /// * `Err(Disconnected)` - When the host disconnects
+    async fn framed_echo_loop(framed: &mut FramedAcm<'d>) -> Result<(), Disconnected> {
+        let mut buffer = [0u8; MESSAGE_SIZE];
+
+        loop {
+            let len = framed.receive_message(&mut buffer).await?;
+            info!("Received {} bytes message", len);
+
+            framed.send_message(&buffer[..len]).await?;
+            info!("Echoed {} bytes message back to host", len);
+        }
+    }
 }
 
 /// USB system task with integrated echo functionality
@@ -168,7 +248,7 @@ impl<'d> AcmEcho<'d> {
 #[embassy_executor::task]
 pub async fn task(acm: AcmConnection<'static>) -> ! {
     // Run both the USB device and echo app concurrently
-    let mut echo_app = AcmEcho::new(acm);
+    let echo_app = AcmEcho::new(acm);
 
     // Run the echo application indefinitely
     echo_app.run().await;
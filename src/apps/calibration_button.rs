@@ -0,0 +1,59 @@
+//! Gyroscope calibration from the user button.
+//!
+//! Holding the user button for [`LONG_PRESS`] measures the gyroscope bias while the robot is
+//! still, the same calibration as the shell's `cal gyro` command, so the bias can be refreshed
+//! without a host attached. A shorter press does nothing but log how to start the calibration.
+//!
+//! The result is logged, and a board that moved during the capture keeps its previous bias.
+
+use defmt::{info, warn};
+use embassy_time::Duration;
+
+use crate::drivers::button::{Button, ButtonPeripherals, PressKind};
+use crate::drivers::imu::{self, ImuError, ImuRequest, ImuResponse};
+
+/// Hold time that starts a calibration
+pub const LONG_PRESS: Duration = Duration::from_secs(2);
+
+/// Samples averaged into the gyroscope bias, one second at 1000Hz
+const CALIBRATION_SAMPLES: u16 = 1000;
+
+/// Time allowed for the IMU task to answer once all the samples have been captured
+const RESPONSE_MARGIN: Duration = Duration::from_millis(100);
+
+/// Embassy task calibrating the gyroscope when the user button is held
+///
+/// # Parameters
+/// - `peripherals`: The user button, from `claim_button!`.
+///
+/// # Behavior
+/// - Waits for presses of the button, a press held for [`LONG_PRESS`] starts a calibration.
+/// - Presses during a calibration are ignored until it has finished.
+#[embassy_executor::task]
+pub async fn task(peripherals: ButtonPeripherals<'static>) -> ! {
+    let mut button = Button::new(peripherals);
+
+    loop {
+        if button.wait_for_long_press(LONG_PRESS).await == PressKind::Short {
+            info!(
+                "Hold the button for {} ms to calibrate the gyroscope",
+                LONG_PRESS.as_millis()
+            );
+            continue;
+        }
+
+        info!("Calibrating the gyroscope, keep the robot still");
+        // The calibration answers once all its samples have been captured
+        let timeout = Duration::from_millis(u64::from(CALIBRATION_SAMPLES)) + RESPONSE_MARGIN;
+        match imu::request(ImuRequest::CalibrateGyro(CALIBRATION_SAMPLES), timeout).await {
+            Some(ImuResponse::GyroBias(bias)) => {
+                info!("Gyroscope bias [{}, {}, {}] rad/s", bias[0], bias[1], bias[2]);
+            }
+            Some(ImuResponse::Error(ImuError::CalibrationFailed)) => {
+                warn!("The robot moved during the gyroscope calibration, bias unchanged");
+            }
+            Some(ImuResponse::Error(e)) => warn!("Gyroscope calibration failed: {:?}", e),
+            _ => warn!("The IMU task did not answer the calibration request"),
+        }
+    }
+}
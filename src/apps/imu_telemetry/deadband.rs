@@ -0,0 +1,190 @@
+//! Deadband deciding which IMU samples are worth streaming
+//!
+//! A sample is sent when any field moved further than its deadband from the sample last sent, when
+//! its status bits differ from that sample's, or when it saw an FSYNC pulse. Otherwise it is only
+//! sent as a keyframe, once [`Deadband::keyframe_period_us`] has passed since the last sample sent,
+//! so the host keeps hearing from a robot standing still.
+//!
+//! Samples are compared with the last sample sent rather than the last one seen, so a slow drift
+//! is sent once it adds up to the deadband.
+//!
+//! This module only uses `core`, so its tests run on the host without the rest of the firmware:
+//!
+//! ```text
+//! rustc --edition 2021 --test src/apps/imu_telemetry/deadband.rs -o target/deadband && target/deadband
+//! ```
+
+/// Deadband of each field, and the keyframe period
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct Deadband {
+    /// Change of an acceleration axis in m/s² that is sent
+    pub accel: f32,
+    /// Change of an angular velocity axis in rad/s that is sent
+    pub gyro: f32,
+    /// Change of the temperature in °C that is sent
+    pub temperature: f32,
+    /// Longest time in µs between samples sent
+    pub keyframe_period_us: u64,
+}
+
+/// The fields of a sample the deadband looks at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fields {
+    /// Acceleration in m/s² (X, Y, Z)
+    pub accel: [f32; 3],
+    /// Angular velocity in rad/s (X, Y, Z)
+    pub gyro: [f32; 3],
+    /// Temperature in °C
+    pub temperature: f32,
+    /// Status bits
+    pub status: u8,
+    /// The sample saw an FSYNC pulse
+    pub fsync: bool,
+    /// When the sample was measured, in µs
+    pub at_us: u64,
+}
+
+/// Why a sample is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// A field moved past its deadband, the status changed or FSYNC was seen
+    Changed,
+    /// Nothing changed, but the keyframe period has passed
+    Keyframe,
+}
+
+/// Deadband and the last sample it let through
+pub struct DeadbandFilter {
+    deadband: Deadband,
+    /// Last sample sent, `None` until the first sample
+    last: Option<Fields>,
+}
+
+impl DeadbandFilter {
+    /// Create a filter that sends the first sample it sees as a keyframe
+    pub const fn new(deadband: Deadband) -> Self {
+        Self { deadband, last: None }
+    }
+
+    /// Decide whether to send a sample
+    ///
+    /// # Arguments
+    /// * `sample` - The sample, taken as sent if the answer is to send it
+    ///
+    /// # Returns
+    /// Why the sample is sent, or `None` to drop it
+    pub fn check(&mut self, sample: &Fields) -> Option<Reason> {
+        let reason = match &self.last {
+            None => Reason::Keyframe,
+            Some(last) => {
+                let moved = |now: &[f32], then: &[f32], deadband: f32| {
+                    now.iter().zip(then).any(|(now, then)| (now - then).abs() > deadband)
+                };
+                if sample.fsync
+                    || sample.status != last.status
+                    || moved(&sample.accel, &last.accel, self.deadband.accel)
+                    || moved(&sample.gyro, &last.gyro, self.deadband.gyro)
+                    || moved(&[sample.temperature], &[last.temperature], self.deadband.temperature)
+                {
+                    Reason::Changed
+                } else if sample.at_us.saturating_sub(last.at_us) >= self.deadband.keyframe_period_us {
+                    Reason::Keyframe
+                } else {
+                    return None;
+                }
+            }
+        };
+        self.last = Some(*sample);
+        Some(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEADBAND: Deadband = Deadband {
+        accel: 0.1,
+        gyro: 0.01,
+        temperature: 0.5,
+        keyframe_period_us: 100_000,
+    };
+
+    /// A still sample at `at_us`
+    fn still(at_us: u64) -> Fields {
+        Fields {
+            accel: [0.0, 0.0, 9.81],
+            gyro: [0.0; 3],
+            temperature: 30.0,
+            status: 0,
+            fsync: false,
+            at_us,
+        }
+    }
+
+    #[test]
+    fn first_sample_is_a_keyframe() {
+        let mut filter = DeadbandFilter::new(DEADBAND);
+        assert_eq!(filter.check(&still(0)), Some(Reason::Keyframe));
+    }
+
+    #[test]
+    fn each_field_has_its_own_deadband() {
+        let mut filter = DeadbandFilter::new(DEADBAND);
+        filter.check(&still(0));
+
+        // Within every deadband
+        let mut sample = still(1000);
+        sample.accel[0] = 0.09;
+        sample.gyro[2] = -0.009;
+        sample.temperature = 30.4;
+        assert_eq!(filter.check(&sample), None);
+
+        // Past a single field's deadband
+        let mut sample = still(2000);
+        sample.gyro[1] = 0.02;
+        assert_eq!(filter.check(&sample), Some(Reason::Changed));
+        let mut sample = still(3000);
+        sample.temperature = 31.0;
+        assert_eq!(filter.check(&sample), Some(Reason::Changed));
+    }
+
+    #[test]
+    fn drift_is_measured_from_the_last_sample_sent() {
+        let mut filter = DeadbandFilter::new(DEADBAND);
+        filter.check(&still(0));
+
+        let sent = [1, 2, 3, 4, 5].map(|step| {
+            let mut sample = still(step * 1000);
+            sample.accel[1] = 0.04 * step as f32;
+            filter.check(&sample).is_some()
+        });
+        // 0.04 steps only pass 0.1 on the third step, then count again from there
+        assert_eq!(sent, [false, false, true, false, false]);
+    }
+
+    #[test]
+    fn status_and_fsync_are_always_sent() {
+        let mut filter = DeadbandFilter::new(DEADBAND);
+        filter.check(&still(0));
+
+        let mut sample = still(1000);
+        sample.status = 0b100;
+        assert_eq!(filter.check(&sample), Some(Reason::Changed));
+        let mut sample = still(2000);
+        sample.status = 0b100;
+        sample.fsync = true;
+        assert_eq!(filter.check(&sample), Some(Reason::Changed));
+    }
+
+    #[test]
+    fn keyframe_once_the_period_passes() {
+        let mut filter = DeadbandFilter::new(DEADBAND);
+        filter.check(&still(0));
+        assert_eq!(filter.check(&still(99_999)), None);
+        assert_eq!(filter.check(&still(100_000)), Some(Reason::Keyframe));
+        assert_eq!(filter.check(&still(150_000)), None);
+        assert_eq!(filter.check(&still(200_000)), Some(Reason::Keyframe));
+    }
+}
@@ -0,0 +1,168 @@
+//! IMU sample streaming over the telemetry port.
+//!
+//! Every sample the IMU task publishes is encoded into a fixed-size record and pushed into the
+//! telemetry ring, which [`crate::peripherals::telemetry::task`] drains to the host. Pushing never
+//! waits, so a slow host only costs dropped records, never IMU samples.
+//!
+//! On a slow link the samples can be thinned out with a [`Deadband`], set through
+//! [`TELEMETRY_DEADBAND`]: a sample is then only sent once a field moved past its deadband since
+//! the last sample sent, or as a keyframe after a quiet period. Every sample is sent until a
+//! deadband is set.
+//!
+//! Every [`SERVO_STATS_PERIOD`] the communication statistics of each servo on the Dynamixel bus
+//! are streamed as well, one servo record per servo, see
+//! [`ServoStats`](crate::drivers::dynamixel_bus::ServoStats).
+//!
+//! # Record layout
+//!
+//! Each record is [`RECORD_SIZE`] bytes, little endian:
+//!
+//! | Offset | Size | Field                                                                      |
+//! |--------|------|----------------------------------------------------------------------------|
+//! | 0      | 1    | [`RECORD_SYNC`]                                                            |
+//! | 1      | 1    | Flags: bit 0 FSYNC, bit 1 timestamp in host time, bit 2 keyframe           |
+//! | 2      | 1    | Status bits, see `ImuStatus`                                               |
+//! | 3      | 1    | Accelerometer full scale in g                                              |
+//! | 4      | 8    | Timestamp in µs (`i64`), in host time if flagged and device time otherwise |
+//! | 12     | 12   | Acceleration X, Y, Z in m/s² (`f32`)                                       |
+//! | 24     | 12   | Angular velocity X, Y, Z in rad/s (`f32`)                                  |
+//! | 36     | 4    | Temperature in °C (`f32`)                                                  |
+//!
+//! Servo records are also [`RECORD_SIZE`] bytes, little endian, with the counters since boot:
+//!
+//! | Offset | Size | Field                                 |
+//! |--------|------|---------------------------------------|
+//! | 0      | 1    | [`SERVO_RECORD_SYNC`]                 |
+//! | 1      | 1    | Servo ID                              |
+//! | 2      | 2    | Reserved, zero                        |
+//! | 4      | 4    | Successful transactions (`u32`)       |
+//! | 8      | 4    | Timeouts (`u32`)                      |
+//! | 12     | 4    | CRC errors (`u32`)                    |
+//! | 16     | 4    | Corrupted replies (`u32`)             |
+//! | 20     | 4    | Instructions refused (`u32`)          |
+//! | 24     | 4    | Replies with a hardware alert (`u32`) |
+//! | 28     | 12   | Reserved, zero                        |
+//!
+//! A host joining mid-stream finds the first record by looking for [`RECORD_SYNC`] or
+//! [`SERVO_RECORD_SYNC`] at a [`RECORD_SIZE`] stride.
+
+mod deadband;
+
+use crate::drivers::dynamixel_bus::{self, ServoStats};
+use crate::drivers::imu::{ImuChannel, ImuData};
+use crate::peripherals::telemetry::TelemetryWriter;
+pub use deadband::Deadband;
+use deadband::{DeadbandFilter, Fields, Reason};
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Ticker};
+
+/// Size of an encoded sample
+pub const RECORD_SIZE: usize = 40;
+
+/// First byte of every IMU sample record
+pub const RECORD_SYNC: u8 = 0xA5;
+
+/// First byte of every servo record
+pub const SERVO_RECORD_SYNC: u8 = 0xA6;
+
+/// Time between servo statistics updates
+pub const SERVO_STATS_PERIOD: Duration = Duration::from_secs(1);
+
+/// Flag bit set when the sample saw an FSYNC pulse
+const FLAG_FSYNC: u8 = 0b01;
+/// Flag bit set when the timestamp is in host time, see [`crate::time_sync`]
+const FLAG_HOST_TIME: u8 = 0b10;
+/// Flag bit set when the sample was sent as a keyframe rather than because it changed
+const FLAG_KEYFRAME: u8 = 0b100;
+
+/// Deadband to thin the samples out with, `None` to send every sample
+pub static TELEMETRY_DEADBAND: Signal<CriticalSectionRawMutex, Option<Deadband>> = Signal::new();
+
+/// Encode a sample into a record
+///
+/// # Arguments
+/// * `sample` - The sample
+/// * `keyframe` - The sample is sent as a keyframe
+fn encode(sample: &ImuData, keyframe: bool) -> [u8; RECORD_SIZE] {
+    let mut record = [0u8; RECORD_SIZE];
+
+    let (timestamp, host_time) = match sample.host_time_us {
+        Some(host_time_us) => (host_time_us, true),
+        None => (sample.timestamp.as_micros() as i64, false),
+    };
+    record[0] = RECORD_SYNC;
+    record[1] = if sample.fsync { FLAG_FSYNC } else { 0 }
+        | if host_time { FLAG_HOST_TIME } else { 0 }
+        | if keyframe { FLAG_KEYFRAME } else { 0 };
+    record[2] = sample.status.0;
+    record[3] = sample.accel_range.full_scale_g() as u8;
+    record[4..12].copy_from_slice(&timestamp.to_le_bytes());
+
+    let values = sample.accel.iter().chain(&sample.gyro).chain([&sample.temperature]);
+    for (chunk, value) in record[12..].chunks_exact_mut(4).zip(values) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    record
+}
+
+/// Encode the statistics of a servo into a record
+fn encode_servo(id: u8, stats: &ServoStats) -> [u8; RECORD_SIZE] {
+    let mut record = [0u8; RECORD_SIZE];
+    record[0] = SERVO_RECORD_SYNC;
+    record[1] = id;
+
+    let counters = [
+        stats.successes,
+        stats.timeouts,
+        stats.crc_errors,
+        stats.corrupted,
+        stats.servo_errors,
+        stats.hardware_alerts,
+    ];
+    for (chunk, counter) in record[4..].chunks_exact_mut(4).zip(counters) {
+        chunk.copy_from_slice(&counter.to_le_bytes());
+    }
+    record
+}
+
+/// Embassy task streaming the IMU samples and servo statistics into the telemetry ring
+///
+/// # Parameters
+/// - `samples`: Channel the IMU task publishes its samples on
+/// - `writer`: Producer end of the telemetry ring, from [`crate::peripherals::telemetry::split`]
+///
+/// # Behavior
+/// Sends every sample, or only those the [`TELEMETRY_DEADBAND`] lets through once one is set. A
+/// new deadband starts from the next sample, which is sent as a keyframe.
+#[embassy_executor::task]
+pub async fn task(samples: &'static ImuChannel, mut writer: TelemetryWriter) -> ! {
+    let mut servo_ticker = Ticker::every(SERVO_STATS_PERIOD);
+    let mut filter: Option<DeadbandFilter> = None;
+
+    loop {
+        // A full ring drops the record, which the telemetry task counts and reports
+        match select3(samples.receive(), servo_ticker.next(), TELEMETRY_DEADBAND.wait()).await {
+            Either3::First(sample) => {
+                let reason = match &mut filter {
+                    Some(filter) => filter.check(&Fields {
+                        accel: sample.accel,
+                        gyro: sample.gyro,
+                        temperature: sample.temperature,
+                        status: sample.status.0,
+                        fsync: sample.fsync,
+                        at_us: sample.timestamp.as_micros(),
+                    }),
+                    None => Some(Reason::Changed),
+                };
+                if let Some(reason) = reason {
+                    writer.push(&encode(&sample, reason == Reason::Keyframe));
+                }
+            }
+            Either3::Second(()) => dynamixel_bus::for_each_servo_stats(|id, stats| {
+                writer.push(&encode_servo(id, stats));
+            }),
+            Either3::Third(deadband) => filter = deadband.map(DeadbandFilter::new),
+        }
+    }
+}
@@ -12,5 +12,16 @@
 
 /// Simple echo application for testing USB CDC ACM communication
 pub mod acm_echo;
+/// Gyroscope calibration started by holding the user button
+pub mod calibration_button;
 /// CRC demonstration application for Dynamixel protocol
 pub mod crc_test;
+/// IMU sample and servo statistics streaming over the telemetry port
+#[cfg(feature = "telemetry")]
+pub mod imu_telemetry;
+/// Servo bus task, sending the scheduled servo commands and polling the servos
+pub mod servos;
+/// Line-based debug shell over USB CDC ACM
+pub mod shell;
+/// Waypoint interpolation into timed servo goal positions
+pub mod trajectory;
@@ -0,0 +1,224 @@
+//! Servo bus task.
+//!
+//! Owns the [`DynamixelBus`]. At boot it finds the servos on the bus with [`DynamixelBus::scan`].
+//! From then on it sends the scheduled commands as they fall due with
+//! [`servo_schedule::run_until`], and every [`POLL_PERIOD`] in between it polls the next
+//! [`POLL_SERVOS`] servos found with a bulk read, whose replies [`ServoRequests`] matches to the
+//! servos that owe them. Every reply or timeout is counted in the servo's
+//! [`ServoStats`](crate::drivers::dynamixel_bus::ServoStats).
+//!
+//! A poll reads the servos' Hardware Error Status, logging a servo whose status changes, except
+//! every [`SUPPLY_POLL_EVERY`]th poll, which reads their Present Input Voltage instead. The median
+//! of the voltages read is the battery voltage, which the task feeds to its [`BatteryMonitor`], so
+//! a cutoff switches the torque off from this same task.
+//!
+//! A poll holds up the scheduled commands until it has finished, so it only reads a few servos,
+//! keeping the delay within [`MAX_LATENESS`](servo_schedule::MAX_LATENESS).
+//!
+//! The servos found at boot are the ones polled, a servo connected later is only polled after a
+//! reset.
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::battery::BatteryMonitor;
+use crate::drivers::dynamixel_bus::{
+    DynamixelBus, DynamixelBusClaims, HARDWARE_ERROR_STATUS_ADDRESS, MAX_SCAN_IDS, PRESENT_INPUT_VOLTAGE_ADDRESS,
+};
+use crate::drivers::servo_requests::{Expected, ServoRequests};
+use crate::drivers::servo_schedule;
+use crate::liveness::{self, TaskId};
+use crate::peripherals::crc::SharedCrc;
+use crate::protocol::dynamixel::{BulkRead, Instruction, MAX_ID};
+
+/// Time between polls of the servos
+pub const POLL_PERIOD: Duration = Duration::from_millis(20);
+
+/// Most servos read by one poll, the servos take turns
+pub const POLL_SERVOS: usize = 4;
+
+/// Polls between reads of the Present Input Voltage, checking the battery every 200 ms
+pub const SUPPLY_POLL_EVERY: u32 = 10;
+
+/// Longest a scan of every ID takes: the broadcast ping window plus a ping of every ID after it
+const SCAN_BUDGET: Duration = Duration::from_secs(3);
+
+/// Time allowed per servo for its reply to a bulk read, as each servo only starts its reply once
+/// the one before it has finished
+const BULK_REPLY_SLOT: Duration = Duration::from_millis(1);
+
+/// Largest bulk read: the 8 bytes before the parameters, 5 parameter bytes per servo even if a
+/// third of them needed byte stuffing, and the 2 CRC bytes
+const BULK_READ_SIZE: usize = 8 + 5 * POLL_SERVOS + 5 * POLL_SERVOS / 3 + 2;
+
+/// A servo found by the scan
+struct Servo {
+    /// ID of the servo
+    id: u8,
+    /// Hardware Error Status last read, zero while the servo has no fault
+    hardware_error: u8,
+}
+
+/// Read the same registers of some servos with a bulk read
+///
+/// # Arguments
+/// * `bus` - The servo bus
+/// * `crc` - CRC processor used to build the bulk read and check the replies
+/// * `requests` - Replies owed by the servos
+/// * `servos` - Servos to read, at most [`POLL_SERVOS`]
+/// * `address` - Control table address of the first register read
+/// * `length` - Number of bytes read from each servo
+/// * `on_reply` - Called with every servo that replied and the bytes it returned
+async fn bulk_read(
+    bus: &mut DynamixelBus<'_>,
+    crc: &SharedCrc,
+    requests: &mut ServoRequests,
+    servos: &mut [Servo],
+    address: u16,
+    length: u16,
+    mut on_reply: impl FnMut(&mut Servo, &[u8]),
+) {
+    let mut bulk_read = BulkRead::new();
+    let mut expected = Vec::<Expected, POLL_SERVOS>::new();
+    for (slot, servo) in servos.iter().enumerate() {
+        bulk_read
+            .add_servo(servo.id, address, length)
+            .expect("a poll fits a bulk read");
+        expected
+            .push(Expected {
+                id: servo.id,
+                params: usize::from(length),
+                timeout: BULK_REPLY_SLOT * (slot as u32 + 1),
+            })
+            .expect("a poll reads at most POLL_SERVOS servos");
+    }
+
+    let mut packet = [0u8; BULK_READ_SIZE];
+    let len = bulk_read
+        .finalize(&mut packet, &mut *crc.lock().await)
+        .expect("a poll fits its buffer");
+    if let Err(e) = requests
+        .send(bus, &packet[..len], Instruction::BulkRead, &expected)
+        .await
+    {
+        defmt::warn!("Servo poll failed: {:?}", e);
+        return;
+    }
+
+    requests
+        .collect(bus, &mut *crc.lock().await, |completion| {
+            let (Ok(status), Some(servo)) = (
+                completion.result,
+                servos.iter_mut().find(|servo| servo.id == completion.id),
+            ) else {
+                return;
+            };
+            on_reply(servo, status.params());
+        })
+        .await;
+}
+
+/// Read the Hardware Error Status of some servos and log the ones that changed
+async fn poll_errors(bus: &mut DynamixelBus<'_>, crc: &SharedCrc, requests: &mut ServoRequests, servos: &mut [Servo]) {
+    bulk_read(
+        bus,
+        crc,
+        requests,
+        servos,
+        HARDWARE_ERROR_STATUS_ADDRESS,
+        1,
+        |servo, params| {
+            let hardware_error = params[0];
+            if hardware_error != servo.hardware_error {
+                defmt::warn!("Servo {} hardware error status 0x{:02X}", servo.id, hardware_error);
+                servo.hardware_error = hardware_error;
+            }
+        },
+    )
+    .await;
+}
+
+/// Read the Present Input Voltage of some servos
+///
+/// # Returns
+/// The median voltage read in millivolts, or `None` if no servo replied
+async fn poll_supply(
+    bus: &mut DynamixelBus<'_>,
+    crc: &SharedCrc,
+    requests: &mut ServoRequests,
+    servos: &mut [Servo],
+) -> Option<u16> {
+    let mut voltages = Vec::<u16, POLL_SERVOS>::new();
+    bulk_read(
+        bus,
+        crc,
+        requests,
+        servos,
+        PRESENT_INPUT_VOLTAGE_ADDRESS,
+        2,
+        |_, params| {
+            let _ = voltages.push(u16::from_le_bytes([params[0], params[1]]).saturating_mul(100));
+        },
+    )
+    .await;
+
+    // A servo with a poor supply connection reads low, the median leaves it out
+    voltages.sort_unstable();
+    voltages.get(voltages.len() / 2).copied()
+}
+
+/// Embassy task running the servo bus
+///
+/// # Parameters
+/// - `claims`: Peripherals of the Dynamixel bus, from `claim_dynamixel_bus!`.
+/// - `crc`: CRC processor, shared with the other tasks that build servo packets.
+///
+/// # Behavior
+/// - Scans every ID once at boot, then sends the scheduled commands and polls the servos found.
+/// - Checks the battery voltage read from the servos, switching their torque off at cutoff.
+/// - Checks in with [`liveness`] after every poll, so a bus that stops completing polls resets the board.
+#[embassy_executor::task]
+pub async fn task(claims: DynamixelBusClaims<'static>, crc: &'static SharedCrc) -> ! {
+    let mut bus = DynamixelBus::new(claims).expect("Failed to configure the servo bus UART");
+    let mut requests = ServoRequests::new();
+    let mut battery_monitor = BatteryMonitor::new();
+
+    let found = {
+        let _operation = liveness::long_operation(TaskId::Servo, SCAN_BUDGET);
+        bus.scan(0..=MAX_ID, &mut *crc.lock().await).await
+    };
+    let mut servos = Vec::<Servo, MAX_SCAN_IDS>::new();
+    match found {
+        Ok(ids) => {
+            defmt::info!("Servos found: {}", ids.as_slice());
+            for id in ids {
+                let _ = servos.push(Servo { id, hardware_error: 0 });
+            }
+        }
+        Err(e) => defmt::error!("Servo scan failed: {:?}", e),
+    }
+
+    let mut next_poll = Instant::now();
+    let mut next_servo = 0;
+    let mut polls = 0u32;
+    loop {
+        // A poll that overran skips the polls it missed rather than running them back to back
+        next_poll = (next_poll + POLL_PERIOD).max(Instant::now());
+        servo_schedule::run_until(&mut bus, crc, next_poll).await;
+
+        if !servos.is_empty() {
+            let end = (next_servo + POLL_SERVOS).min(servos.len());
+            let polled = &mut servos[next_servo..end];
+            if polls % SUPPLY_POLL_EVERY == 0 {
+                if let Some(voltage_mv) = poll_supply(&mut bus, crc, &mut requests, polled).await {
+                    battery_monitor.update(voltage_mv);
+                }
+            } else {
+                poll_errors(&mut bus, crc, &mut requests, polled).await;
+            }
+            polls = polls.wrapping_add(1);
+            next_servo = if end == servos.len() { 0 } else { end };
+        }
+        liveness::checkin(TaskId::Servo);
+    }
+}
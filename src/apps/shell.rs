@@ -0,0 +1,1052 @@
+//! Minimal line-based debug shell over USB CDC ACM.
+//!
+//! The shell reads newline-terminated ASCII commands from a terminal (e.g. `screen` or `picocom`)
+//! and prints human readable responses. It is meant for interactive bring-up and debugging; the
+//! binary protocol is unaffected and keeps its own interface.
+//!
+//! # Command grammar
+//!
+//! Commands are whitespace separated words terminated by `\r`, `\n` or `\r\n`. Numbers are decimal
+//! or `0x`-prefixed hexadecimal.
+//!
+//! ```text
+//! help              List the available commands
+//! version           Print the firmware version
+//! uptime            Print the time since boot
+//! time              Print the device time and the host-aligned time
+//! time ping         Print the device time in µs, for measuring the host clock offset
+//! time offset <offset_us> <round_trip_us>
+//!                   Set the host clock offset measured with `time ping`
+//! boot              Print the reset count and cumulative uptime
+//! config            Dump the effective configuration as `key=value` lines
+//! log               Print the messages retained in the on-device log buffer
+//! log clear         Discard the retained log messages
+//! reset             Reset the microcontroller
+//! imu status        Print the latest IMU sample, tilt, FIFO level, recoveries, SPI clock and rate
+//! imu reg <addr>    Read a single IMU register
+//! imu read          Read the IMU data registers directly, bypassing the FIFO
+//! imu spi <hz>      Change the IMU SPI clock, within the datasheet limits
+//! imu stats <ms|off>
+//!                   Log IMU statistics every <ms> milliseconds, or stop logging them
+//! imu noise [n]     Measure the sensor noise over n samples (default 10000), keep the board still
+//! imu selftest      Run the IMU built-in self-test, keep the board still
+//! imu <sleep|wake>  Put the IMU to sleep or wake it up again
+//! imu set <setting> <value>...
+//!                   Change an IMU setting: accel_averaging <4|8|16|32>, fifo_mode <overwrite|stop>,
+//!                   fsync <off|temp|gyro_x|gyro_y|gyro_z|accel_x|accel_y|accel_z>,
+//!                   interrupt <ready|packets>, axis_remap <±x|±y|±z> x3 or gyro_tempco <ppm> x3
+//! cal gyro          Measure and apply the gyroscope bias, keep the board still
+//! cal gyro clear    Stop subtracting the gyroscope bias
+//! servo goal <host_us> <id>=<position>...
+//!                   Queue goal positions to be sent at a host time, see `time offset`
+//! traj <linear|cubic> <ms> <id>=<position>...
+//!                   Start a trajectory reaching the positions in <ms> milliseconds
+//! traj add <ms> <id>=<position>...
+//!                   Add a waypoint to the trajectory, with a position for each of its servos
+//! traj stop         Stop the trajectory and hold the servos where they are
+//! battery set <cutoff_mv> <warn_mv> <over_mv> <hysteresis_mv>
+//!                   Change the battery protection thresholds and store them in flash
+//! telemetry deadband <accel> <gyro> <temp> <keyframe_ms>|off
+//!                   Only stream IMU samples that moved past the deadbands, in m/s², rad/s and °C,
+//!                   or a keyframe every <keyframe_ms> (`telemetry` feature only)
+//! usb test <mode>   Enter a USB test mode: j, k, se0 or packet (`usb-compliance` feature only)
+//! ```
+
+use crate::apps::trajectory::{Interpolation, TrajectoryCommand, Waypoint, MAX_TRAJECTORY_SERVOS, TRAJECTORY_COMMANDS};
+use crate::battery::{self, BatteryThresholds, ThresholdsError};
+use crate::drivers::dynamixel_bus::GOAL_POSITION_ADDRESS;
+use crate::drivers::imu::{
+    self, AccelAveraging, Axis, AxisMap, AxisRemap, FifoMode, FsyncLatch, ImuConfig, ImuError, ImuPower, ImuRequest,
+    ImuResponse, InterruptMode, NoiseReport,
+};
+use crate::drivers::servo_schedule::{self, MAX_COMMAND_SIZE};
+use crate::log_ring;
+use crate::peripherals::acm::{AcmConnection, Disconnected};
+use crate::peripherals::crc::SharedCrc;
+use crate::peripherals::persistent;
+use crate::peripherals::spi;
+use crate::peripherals::system::cpu_frequency_hz;
+use crate::peripherals::usb_system::{self, MAX_PACKET_SIZE};
+use crate::protocol::dynamixel::SyncWrite;
+use crate::time_sync;
+use core::fmt::Write;
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+/// Maximum size of a single USB packet sent or received by the shell
+const PACKET_SIZE: usize = MAX_PACKET_SIZE as usize;
+
+/// Longest response to a single command, sent as several packets when needed
+const RESPONSE_SIZE: usize = 3 * PACKET_SIZE;
+
+/// Longest command line accepted, longer lines are discarded
+const LINE_BUFFER_SIZE: usize = 128;
+
+/// Delay between reconnection attempts when the connection is lost
+const RECONNECT_DELAY_MS: u64 = 100;
+
+/// How long to wait for the IMU task to answer a request
+const IMU_RESPONSE_TIMEOUT_MS: u64 = 100;
+
+/// Samples averaged by `cal gyro`, one second at 1000Hz
+const GYRO_CALIBRATION_SAMPLES: u16 = 1000;
+
+/// Samples captured by `imu noise` unless told otherwise, ten seconds at 1000Hz
+const DEFAULT_NOISE_SAMPLES: u16 = 10_000;
+
+/// Time allowed for `imu selftest`, which averages two sets of 200 samples
+const SELF_TEST_TIMEOUT_MS: u64 = 1000;
+
+/// Prompt printed before every command
+const PROMPT: &str = "nusense> ";
+
+/// Every Cargo feature and whether it is enabled, generated from `Cargo.toml` by `build.rs`
+const FEATURES: &[(&str, bool)] = include!(concat!(env!("OUT_DIR"), "/features.rs"));
+
+/// Fixed-size text buffer for composing responses
+///
+/// Output past the end of the buffer is silently truncated.
+struct Response {
+    buffer: [u8; RESPONSE_SIZE],
+    len: usize,
+}
+
+impl Response {
+    const fn new() -> Self {
+        Self {
+            buffer: [0u8; RESPONSE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+impl Write for Response {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let available = self.buffer.len() - self.len;
+        let count = core::cmp::min(available, s.len());
+        self.buffer[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// Interactive debug shell running on a CDC ACM connection.
+///
+/// Characters typed into the terminal are echoed back, and each complete line is parsed and
+/// executed as a command. Backspace removes the last character of the current line.
+///
+/// # Example Usage
+///
+/// ```rust,ignore
+/// let mut shell = Shell::new(acm_connection, crc);
+/// shell.run().await; // Runs forever
+/// ```
+pub struct Shell<'d> {
+    acm: AcmConnection<'d>,
+    /// CRC processor used to build servo instructions
+    crc: &'static SharedCrc,
+    /// IDs of the servos of the last trajectory started with `traj`, which `traj add` gives
+    /// positions for
+    trajectory_servos: Vec<u8, MAX_TRAJECTORY_SERVOS>,
+    /// Characters of the line currently being typed
+    line: [u8; LINE_BUFFER_SIZE],
+    /// Number of valid characters in `line`
+    line_len: usize,
+    /// Set when the current line outgrew the buffer and must be discarded
+    line_overflowed: bool,
+    /// Set when the previous character was `\r`, so a following `\n` is ignored
+    last_was_cr: bool,
+}
+
+impl<'d> Shell<'d> {
+    /// Create a new shell on the specified ACM connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `acm` - The CDC ACM connection to use for communication
+    /// * `crc` - The shared CRC processor, used to build servo instructions
+    pub const fn new(acm: AcmConnection<'d>, crc: &'static SharedCrc) -> Self {
+        Self {
+            acm,
+            crc,
+            trajectory_servos: Vec::new(),
+            line: [0u8; LINE_BUFFER_SIZE],
+            line_len: 0,
+            line_overflowed: false,
+            last_was_cr: false,
+        }
+    }
+
+    /// Run the shell.
+    ///
+    /// Waits for a host to connect, then processes commands until the host disconnects,
+    /// and repeats indefinitely.
+    pub async fn run(&mut self) -> ! {
+        info!("Debug shell started");
+
+        loop {
+            self.acm.wait_connection().await;
+            info!("Shell: Host connected");
+
+            if let Err(Disconnected) = self.session().await {
+                warn!("Shell: Connection lost, will reconnect...");
+                Timer::after_millis(RECONNECT_DELAY_MS).await;
+            }
+        }
+    }
+
+    /// Process input from a connected host until it disconnects.
+    async fn session(&mut self) -> Result<(), Disconnected> {
+        let mut packet = [0u8; PACKET_SIZE];
+        self.clear_line();
+        self.acm.send_packet(PROMPT.as_bytes()).await?;
+
+        loop {
+            let bytes_received = self.acm.receive_packet(&mut packet).await?;
+
+            // Echo the input so the terminal shows what was typed
+            self.acm.send_packet(&packet[..bytes_received]).await?;
+
+            for &byte in &packet[..bytes_received] {
+                self.handle_byte(byte).await?;
+            }
+        }
+    }
+
+    /// Feed a single received character into the line editor.
+    async fn handle_byte(&mut self, byte: u8) -> Result<(), Disconnected> {
+        let was_cr = self.last_was_cr;
+        self.last_was_cr = byte == b'\r';
+
+        match byte {
+            // Treat \r\n as a single line ending
+            b'\n' if was_cr => Ok(()),
+            b'\r' | b'\n' => self.end_line().await,
+            // Backspace and delete both remove the last character
+            0x08 | 0x7F => {
+                self.line_len = self.line_len.saturating_sub(1);
+                Ok(())
+            }
+            _ => {
+                if self.line_len < self.line.len() {
+                    self.line[self.line_len] = byte;
+                    self.line_len += 1;
+                } else {
+                    self.line_overflowed = true;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Execute the completed line and print a fresh prompt.
+    async fn end_line(&mut self) -> Result<(), Disconnected> {
+        let mut response = Response::new();
+        let _ = response.write_str("\r\n");
+
+        if self.line_overflowed {
+            let _ = writeln!(response, "error: line longer than {} characters\r", LINE_BUFFER_SIZE);
+        } else {
+            match core::str::from_utf8(&self.line[..self.line_len]) {
+                // The log is larger than a packet so it is streamed rather than built in `response`
+                Ok(line) if line.trim() == "log" => {
+                    self.send_response(&response).await?;
+                    self.send_log().await?;
+                    response = Response::new();
+                }
+                Ok(line) => execute(line, &mut response, self.crc, &mut self.trajectory_servos).await,
+                Err(_) => {
+                    let _ = response.write_str("error: input is not valid text\r\n");
+                }
+            }
+        }
+
+        let _ = response.write_str(PROMPT);
+        self.clear_line();
+        self.send_response(&response).await
+    }
+
+    /// Send a response to the host, split into as many packets as needed.
+    ///
+    /// A response ending on a full-size packet is followed by a zero-length packet, otherwise the
+    /// host keeps waiting for the rest of the transfer.
+    async fn send_response(&mut self, response: &Response) -> Result<(), Disconnected> {
+        let bytes = response.as_bytes();
+        for packet in bytes.chunks(PACKET_SIZE) {
+            self.acm.send_packet(packet).await?;
+        }
+        if !bytes.is_empty() && bytes.len() % PACKET_SIZE == 0 {
+            self.acm.send_packet(&[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Send the retained log messages to the host, streamed out of the ring one packet at a time.
+    ///
+    /// Like [`Self::send_response`], a log ending on a full-size packet is followed by a
+    /// zero-length packet.
+    async fn send_log(&mut self) -> Result<(), Disconnected> {
+        let mut packet = [0u8; PACKET_SIZE];
+        let (mut position, end) = log_ring::range();
+        let mut last_len = 0;
+        loop {
+            let (next, len) = log_ring::read(position, end, &mut packet);
+            if len == 0 {
+                break;
+            }
+            self.acm.send_packet(&packet[..len]).await?;
+            position = next;
+            last_len = len;
+        }
+        if last_len == PACKET_SIZE {
+            self.acm.send_packet(&[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Discard the line currently being typed.
+    fn clear_line(&mut self) {
+        self.line_len = 0;
+        self.line_overflowed = false;
+    }
+}
+
+/// Parse and execute a single command line, writing the output into `out`.
+///
+/// `trajectory_servos` holds the servos of the trajectory started by `traj`, kept between commands.
+async fn execute(
+    line: &str,
+    out: &mut Response,
+    crc: &SharedCrc,
+    trajectory_servos: &mut Vec<u8, MAX_TRAJECTORY_SERVOS>,
+) {
+    let mut words = line.split_whitespace();
+
+    match (words.next(), words.next(), words.next()) {
+        (None, _, _) => {}
+        (Some("help"), None, _) => {
+            let _ = out.write_str(
+                "help              List the available commands\r\n\
+                 version           Print the firmware version\r\n\
+                 uptime            Print the time since boot\r\n\
+                 time              Print the device time and the host-aligned time\r\n\
+                 time ping         Print the device time in us, for measuring the host clock offset\r\n\
+                 time offset <offset_us> <round_trip_us>\r\n\
+                 \x20                 Set the host clock offset measured with 'time ping'\r\n\
+                 boot              Print the reset count and cumulative uptime\r\n\
+                 config            Dump the effective configuration as key=value lines\r\n\
+                 log               Print the messages retained in the on-device log buffer\r\n\
+                 log clear         Discard the retained log messages\r\n\
+                 reset             Reset the microcontroller\r\n\
+                 imu status        Print the latest IMU sample, tilt, FIFO level, recoveries, SPI clock and rate\r\n\
+                 imu reg <addr>    Read a single IMU register\r\n\
+                 imu read          Read the IMU data registers directly, bypassing the FIFO\r\n\
+                 imu spi <hz>      Change the IMU SPI clock, within the datasheet limits\r\n\
+                 imu stats <ms|off>\r\n\
+                 \x20                 Log IMU statistics every <ms> milliseconds, or stop logging them\r\n\
+                 imu noise [n]     Measure the sensor noise over n samples (default 10000), keep the board still\r\n\
+                 imu selftest      Run the IMU built-in self-test, keep the board still\r\n\
+                 imu <sleep|wake>  Put the IMU to sleep or wake it up again\r\n\
+                 imu set <setting> <value>...\r\n\
+                 \x20                 Change an IMU setting: accel_averaging <4|8|16|32>, fifo_mode <overwrite|stop>,\r\n\
+                 \x20                 fsync <off|temp|gyro_x|gyro_y|gyro_z|accel_x|accel_y|accel_z>,\r\n\
+                 \x20                 interrupt <ready|packets>, axis_remap <+-x|+-y|+-z> x3 or gyro_tempco <ppm> x3\r\n\
+                 cal gyro          Measure and apply the gyroscope bias, keep the board still\r\n\
+                 cal gyro clear    Stop subtracting the gyroscope bias\r\n\
+                 servo goal <host_us> <id>=<position>...\r\n\
+                 \x20                 Queue goal positions to be sent at a host time, see 'time offset'\r\n\
+                 traj <linear|cubic> <ms> <id>=<position>...\r\n\
+                 \x20                 Start a trajectory reaching the positions in <ms> milliseconds\r\n\
+                 traj add <ms> <id>=<position>...\r\n\
+                 \x20                 Add a waypoint to the trajectory, with a position for each of its servos\r\n\
+                 traj stop         Stop the trajectory and hold the servos where they are\r\n\
+                 battery set <cutoff_mv> <warn_mv> <over_mv> <hysteresis_mv>\r\n\
+                 \x20                 Change the battery protection thresholds and store them in flash\r\n",
+            );
+            #[cfg(feature = "telemetry")]
+            let _ = out.write_str(
+                "telemetry deadband <accel> <gyro> <temp> <keyframe_ms>|off\r\n\
+                 \x20                 Only stream IMU samples that moved past the deadbands, in m/s^2, rad/s and C,\r\n\
+                 \x20                 or a keyframe every <keyframe_ms>\r\n",
+            );
+            #[cfg(feature = "usb-compliance")]
+            let _ = out.write_str("usb test <mode>   Enter a USB test mode: j, k, se0 or packet\r\n");
+        }
+        (Some("version"), None, _) => {
+            let _ = writeln!(out, "NUSense firmware v{}\r", env!("CARGO_PKG_VERSION"));
+        }
+        (Some("uptime"), None, _) => {
+            let _ = writeln!(out, "{} ms\r", Instant::now().as_millis());
+        }
+        (Some("time"), None, _) => {
+            let now = Instant::now();
+            let _ = writeln!(out, "device {} us\r", now.as_micros());
+            let _ = match (time_sync::to_host_us(now), time_sync::offset()) {
+                (Some(host_us), Some(offset)) => writeln!(
+                    out,
+                    "host   {} us (+/- {} us, measured {} s ago)\r",
+                    host_us,
+                    offset.round_trip_us / 2,
+                    now.duration_since(offset.measured_at).as_secs()
+                ),
+                _ => out.write_str("host   not synchronized\r\n"),
+            };
+        }
+        (Some("time"), Some("ping"), None) => {
+            let _ = writeln!(out, "{}\r", Instant::now().as_micros());
+        }
+        (Some("time"), Some("offset"), Some(offset)) => {
+            match (offset.parse::<i64>(), words.next().map(str::parse::<u64>), words.next()) {
+                (Ok(offset_us), Some(Ok(round_trip_us)), None) => {
+                    if time_sync::set_offset(offset_us, round_trip_us) {
+                        let _ = out.write_str("ok\r\n");
+                    } else {
+                        let _ = out.write_str("ignored: less accurate than the current offset\r\n");
+                    }
+                }
+                _ => {
+                    let _ = out.write_str("error: usage 'time offset <offset_us> <round_trip_us>'\r\n");
+                }
+            }
+        }
+        (Some("boot"), None, _) => {
+            let stats = persistent::stats();
+            let _ = writeln!(
+                out,
+                "resets {}, cumulative uptime {} s\r",
+                stats.reset_count, stats.total_uptime_secs
+            );
+        }
+        (Some("config"), None, _) => write_config(out).await,
+        (Some("log"), Some("clear"), None) => {
+            log_ring::clear();
+            let _ = out.write_str("ok\r\n");
+        }
+        (Some("reset"), None, _) => {
+            info!("Shell: Reset requested");
+            // Give the USB stack a moment to flush anything queued before resetting
+            Timer::after_millis(10).await;
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        (Some("imu"), Some("status"), None) => match imu_request(ImuRequest::Status).await {
+            Some(ImuResponse::Status {
+                latest,
+                attitude,
+                fifo_fill_percent,
+                stuck_interrupt_recoveries,
+                spi_frequency,
+                sample_rate_hz,
+            }) => {
+                let _ = writeln!(
+                    out,
+                    "accel [{:.3}, {:.3}, {:.3}] m/s^2\r\n\
+                     gyro  [{:.3}, {:.3}, {:.3}] rad/s\r\n\
+                     temp  {:.2} C\r\n\
+                     status 0b{:08b}\r\n\
+                     fifo  {}%\r\n\
+                     stuck INT recoveries {}\r\n\
+                     spi   {} Hz\r",
+                    latest.accel[0],
+                    latest.accel[1],
+                    latest.accel[2],
+                    latest.gyro[0],
+                    latest.gyro[1],
+                    latest.gyro[2],
+                    latest.temperature,
+                    latest.status.0,
+                    fifo_fill_percent,
+                    stuck_interrupt_recoveries,
+                    spi_frequency
+                );
+                let _ = match sample_rate_hz {
+                    Some(rate) => writeln!(out, "rate  {:.1} Hz\r", rate),
+                    None => out.write_str("rate  unknown\r\n"),
+                };
+                let _ = match attitude {
+                    Some((roll, pitch)) => writeln!(out, "tilt  roll {:.3} pitch {:.3} rad\r", roll, pitch),
+                    None => out.write_str("tilt  unknown\r\n"),
+                };
+            }
+            response => write_imu_failure(out, response),
+        },
+        (Some("imu"), Some("read"), None) => match imu_request(ImuRequest::ReadOnce).await {
+            Some(ImuResponse::Sample(data)) => {
+                let _ = writeln!(
+                    out,
+                    "accel [{:.3}, {:.3}, {:.3}] m/s^2\r\n\
+                     gyro  [{:.3}, {:.3}, {:.3}] rad/s\r\n\
+                     temp  {:.2} C\r",
+                    data.accel[0],
+                    data.accel[1],
+                    data.accel[2],
+                    data.gyro[0],
+                    data.gyro[1],
+                    data.gyro[2],
+                    data.temperature
+                );
+            }
+            response => write_imu_failure(out, response),
+        },
+        #[cfg(feature = "usb-compliance")]
+        (Some("usb"), Some("test"), Some(mode)) if words.next().is_none() => {
+            use crate::peripherals::usb_system::{enter_test_mode, UsbTestMode};
+            let mode = match mode {
+                "j" => Some(UsbTestMode::TestJ),
+                "k" => Some(UsbTestMode::TestK),
+                "se0" => Some(UsbTestMode::TestSe0Nak),
+                "packet" => Some(UsbTestMode::TestPacket),
+                _ => None,
+            };
+            match mode {
+                // The shell connection is lost as soon as the port leaves normal operation
+                Some(mode) => enter_test_mode(mode),
+                None => {
+                    let _ = out.write_str("error: test mode must be one of j, k, se0, packet\r\n");
+                }
+            }
+        }
+        (Some("imu"), Some("stats"), Some(interval)) if words.next().is_none() => {
+            let interval = match interval {
+                "off" => Some(None),
+                ms => match ms.parse::<u32>() {
+                    Ok(ms) if ms > 0 => Some(Some(Duration::from_millis(u64::from(ms)))),
+                    _ => None,
+                },
+            };
+            match interval {
+                Some(interval) => match imu_request(ImuRequest::SetStatsInterval(interval)).await {
+                    Some(ImuResponse::StatsInterval(Some(interval))) => {
+                        let _ = writeln!(out, "imu stats every {} ms\r", interval.as_millis());
+                    }
+                    Some(ImuResponse::StatsInterval(None)) => {
+                        let _ = out.write_str("imu stats off\r\n");
+                    }
+                    response => write_imu_failure(out, response),
+                },
+                None => {
+                    let _ = out.write_str("error: interval must be a positive number of ms or 'off'\r\n");
+                }
+            }
+        }
+        (Some("imu"), Some("spi"), Some(frequency)) if words.next().is_none() => match frequency.parse::<u32>() {
+            Ok(frequency) => match imu_request(ImuRequest::SetSpiFrequency(frequency)).await {
+                Some(ImuResponse::SpiFrequency(frequency)) => {
+                    let _ = writeln!(out, "imu spi {} Hz\r", frequency);
+                }
+                Some(ImuResponse::Error(_)) => {
+                    let _ = writeln!(
+                        out,
+                        "error: SPI clock must be between {} and {} Hz\r",
+                        spi::MIN_FREQUENCY.0,
+                        spi::MAX_FREQUENCY.0
+                    );
+                }
+                response => write_imu_failure(out, response),
+            },
+            Err(_) => {
+                let _ = writeln!(out, "error: invalid frequency '{}'\r", frequency);
+            }
+        },
+        (Some("imu"), Some("noise"), samples) if words.next().is_none() => {
+            match samples.map_or(Ok(DEFAULT_NOISE_SAMPLES), str::parse::<u16>) {
+                Ok(samples) if samples > 0 => {
+                    // The capture answers once all its samples have been taken
+                    let timeout = Duration::from_millis(u64::from(samples) + IMU_RESPONSE_TIMEOUT_MS);
+                    match imu::request(ImuRequest::MeasureNoise(samples), timeout).await {
+                        Some(ImuResponse::Noise(report)) => write_noise_report(out, &report),
+                        response => write_imu_failure(out, response),
+                    }
+                }
+                _ => {
+                    let _ = writeln!(out, "error: sample count must be 1 to {}\r", u16::MAX);
+                }
+            }
+        }
+        (Some("cal"), Some("gyro"), None) => {
+            // The calibration answers once all its samples have been captured
+            let timeout = Duration::from_millis(u64::from(GYRO_CALIBRATION_SAMPLES) + IMU_RESPONSE_TIMEOUT_MS);
+            match imu::request(ImuRequest::CalibrateGyro(GYRO_CALIBRATION_SAMPLES), timeout).await {
+                Some(ImuResponse::GyroBias(bias)) => {
+                    let _ = writeln!(
+                        out,
+                        "gyro bias [{:.5}, {:.5}, {:.5}] rad/s\r",
+                        bias[0], bias[1], bias[2]
+                    );
+                }
+                Some(ImuResponse::Error(ImuError::CalibrationFailed)) => {
+                    let _ = out.write_str("error: the board moved, bias unchanged\r\n");
+                }
+                response => write_imu_failure(out, response),
+            }
+        }
+        (Some("cal"), Some("gyro"), Some("clear")) if words.next().is_none() => {
+            match imu_request(ImuRequest::ClearGyroBias).await {
+                Some(ImuResponse::GyroBias(_)) => {
+                    let _ = out.write_str("gyro bias cleared\r\n");
+                }
+                response => write_imu_failure(out, response),
+            }
+        }
+        (Some("imu"), Some("selftest"), None) => {
+            match imu::request(ImuRequest::SelfTest, Duration::from_millis(SELF_TEST_TIMEOUT_MS)).await {
+                Some(ImuResponse::SelfTest(result)) => {
+                    let _ = writeln!(
+                        out,
+                        "self-test {}: accel {:?} gyro {:?}\r",
+                        if result.passed() { "passed" } else { "failed" },
+                        result.accel,
+                        result.gyro
+                    );
+                }
+                response => write_imu_failure(out, response),
+            }
+        }
+        (Some("imu"), Some(power @ ("sleep" | "wake")), None) => {
+            imu::IMU_POWER.signal(if power == "sleep" {
+                ImuPower::Sleep
+            } else {
+                ImuPower::Wake
+            });
+            let _ = out.write_str("ok\r\n");
+        }
+        (Some("imu"), Some("set"), Some(setting)) => {
+            let mut config = match imu_request(ImuRequest::Config).await {
+                Some(ImuResponse::Config { config, .. }) => config,
+                response => {
+                    write_imu_failure(out, response);
+                    return;
+                }
+            };
+            if parse_imu_setting(&mut config, setting, &mut words).is_none() {
+                let _ = writeln!(out, "error: invalid value for IMU setting '{}', try 'help'\r", setting);
+                return;
+            }
+            match imu_request(ImuRequest::SetConfig(config)).await {
+                Some(ImuResponse::Config { .. }) => {
+                    let _ = out.write_str("ok\r\n");
+                }
+                Some(ImuResponse::Error(ImuError::InvalidConfig)) => {
+                    let _ = out.write_str("error: the IMU cannot run with that setting, see 'config'\r\n");
+                }
+                response => write_imu_failure(out, response),
+            }
+        }
+        (Some("imu"), Some("reg"), Some(address)) if words.next().is_none() => match parse_u8(address) {
+            Some(address) => match imu_request(ImuRequest::ReadRegister(address)).await {
+                Some(ImuResponse::Register { address, value }) => {
+                    let _ = writeln!(out, "reg 0x{:02X} = 0x{:02X}\r", address, value);
+                }
+                response => write_imu_failure(out, response),
+            },
+            None => {
+                let _ = writeln!(out, "error: invalid register address '{}'\r", address);
+            }
+        },
+        (Some("servo"), Some("goal"), Some(at)) => {
+            let Ok(at_host_us) = at.parse::<i64>() else {
+                let _ = writeln!(out, "error: invalid host time '{}'\r", at);
+                return;
+            };
+            let mut sync_write = SyncWrite::new(GOAL_POSITION_ADDRESS, 4);
+            for goal in words {
+                let Some((id, position)) = parse_goal(goal) else {
+                    let _ = writeln!(out, "error: invalid goal '{}', expected <id>=<position>\r", goal);
+                    return;
+                };
+                if let Err(e) = sync_write.add_servo(id, &position.to_le_bytes()) {
+                    let _ = writeln!(out, "error: goal '{}' rejected ({:?})\r", goal, e);
+                    return;
+                }
+            }
+            if sync_write.servo_count() == 0 {
+                let _ = out.write_str("error: usage 'servo goal <host_us> <id>=<position>...'\r\n");
+                return;
+            }
+
+            let mut packet = [0u8; MAX_COMMAND_SIZE];
+            let len = sync_write
+                .finalize(&mut packet, &mut *crc.lock().await)
+                .expect("a full sync write fits a scheduled command");
+            let _ = match servo_schedule::schedule_host(at_host_us, &packet[..len]) {
+                Ok(()) => out.write_str("ok\r\n"),
+                Err(e) => writeln!(out, "error: goal positions refused ({:?})\r", e),
+            };
+        }
+        (Some("traj"), Some(kind @ ("linear" | "cubic")), Some(ms)) => {
+            let interpolation = match kind {
+                "linear" => Interpolation::Linear,
+                _ => Interpolation::Cubic,
+            };
+            let Ok(ms) = ms.parse::<u32>() else {
+                let _ = writeln!(out, "error: invalid duration '{}'\r", ms);
+                return;
+            };
+            let mut waypoint = Waypoint {
+                positions: [0; MAX_TRAJECTORY_SERVOS],
+                duration: Duration::from_millis(u64::from(ms)),
+            };
+            let mut servos = Vec::<u8, MAX_TRAJECTORY_SERVOS>::new();
+            for goal in words {
+                let Some((id, position)) = parse_goal(goal) else {
+                    let _ = writeln!(out, "error: invalid goal '{}', expected <id>=<position>\r", goal);
+                    return;
+                };
+                if servos.contains(&id) {
+                    let _ = writeln!(out, "error: servo {} given twice\r", id);
+                    return;
+                }
+                if servos.push(id).is_err() {
+                    let _ = writeln!(out, "error: at most {} servos per trajectory\r", MAX_TRAJECTORY_SERVOS);
+                    return;
+                }
+                waypoint.positions[servos.len() - 1] = position;
+            }
+            if servos.is_empty() {
+                let _ = out.write_str("error: usage 'traj <linear|cubic> <ms> <id>=<position>...'\r\n");
+                return;
+            }
+
+            let mut waypoints = Vec::new();
+            let _ = waypoints.push(waypoint);
+            *trajectory_servos = servos.clone();
+            TRAJECTORY_COMMANDS
+                .send(TrajectoryCommand::Replace {
+                    servos,
+                    interpolation,
+                    waypoints,
+                })
+                .await;
+            let _ = out.write_str("ok\r\n");
+        }
+        (Some("traj"), Some("add"), Some(ms)) => {
+            let Ok(ms) = ms.parse::<u32>() else {
+                let _ = writeln!(out, "error: invalid duration '{}'\r", ms);
+                return;
+            };
+            if trajectory_servos.is_empty() {
+                let _ = out.write_str("error: no trajectory started, see 'traj <linear|cubic>'\r\n");
+                return;
+            }
+            let mut waypoint = Waypoint {
+                positions: [0; MAX_TRAJECTORY_SERVOS],
+                duration: Duration::from_millis(u64::from(ms)),
+            };
+            let mut given = [false; MAX_TRAJECTORY_SERVOS];
+            for goal in words {
+                let Some((id, position)) = parse_goal(goal) else {
+                    let _ = writeln!(out, "error: invalid goal '{}', expected <id>=<position>\r", goal);
+                    return;
+                };
+                let Some(i) = trajectory_servos.iter().position(|servo| *servo == id) else {
+                    let _ = writeln!(out, "error: servo {} is not part of the trajectory\r", id);
+                    return;
+                };
+                if given[i] {
+                    let _ = writeln!(out, "error: servo {} given twice\r", id);
+                    return;
+                }
+                given[i] = true;
+                waypoint.positions[i] = position;
+            }
+            if given[..trajectory_servos.len()].contains(&false) {
+                let _ = writeln!(
+                    out,
+                    "error: a position is needed for each of servos {:?}\r",
+                    trajectory_servos.as_slice()
+                );
+                return;
+            }
+
+            TRAJECTORY_COMMANDS.send(TrajectoryCommand::Append(waypoint)).await;
+            let _ = out.write_str("ok\r\n");
+        }
+        (Some("battery"), Some("set"), Some(cutoff)) => {
+            let millivolts = |text: Option<&str>| text.and_then(|text| text.parse::<u16>().ok());
+            let (Some(cutoff_mv), Some(warn_mv), Some(over_mv), Some(hysteresis_mv), None) = (
+                millivolts(Some(cutoff)),
+                millivolts(words.next()),
+                millivolts(words.next()),
+                millivolts(words.next()),
+                words.next(),
+            ) else {
+                let _ = out.write_str("error: usage 'battery set <cutoff_mv> <warn_mv> <over_mv> <hysteresis_mv>'\r\n");
+                return;
+            };
+            let thresholds = BatteryThresholds {
+                cutoff_mv,
+                warn_mv,
+                over_mv,
+                hysteresis_mv,
+            };
+            let _ = match battery::set_thresholds(thresholds).await {
+                Ok(()) => out.write_str("ok\r\n"),
+                Err(ThresholdsError::OutOfOrder) => {
+                    out.write_str("error: thresholds must rise from cutoff to over by at least the hysteresis\r\n")
+                }
+                Err(ThresholdsError::Flash(e)) => writeln!(out, "error: in effect but not stored ({:?})\r", e),
+            };
+        }
+        #[cfg(feature = "telemetry")]
+        (Some("telemetry"), Some("deadband"), Some("off")) if words.next().is_none() => {
+            crate::apps::imu_telemetry::TELEMETRY_DEADBAND.signal(None);
+            let _ = out.write_str("ok\r\n");
+        }
+        #[cfg(feature = "telemetry")]
+        (Some("telemetry"), Some("deadband"), Some(accel)) => {
+            use crate::apps::imu_telemetry::{Deadband, TELEMETRY_DEADBAND};
+            let value = |text: Option<&str>| text.and_then(|text| text.parse::<f32>().ok()).filter(|v| *v >= 0.0);
+            let (Some(accel), Some(gyro), Some(temperature), Some(keyframe_ms), None) = (
+                value(Some(accel)),
+                value(words.next()),
+                value(words.next()),
+                words.next().and_then(|text| text.parse::<u32>().ok()),
+                words.next(),
+            ) else {
+                let _ = out.write_str("error: usage 'telemetry deadband <accel> <gyro> <temp> <keyframe_ms>|off'\r\n");
+                return;
+            };
+            TELEMETRY_DEADBAND.signal(Some(Deadband {
+                accel,
+                gyro,
+                temperature,
+                keyframe_period_us: u64::from(keyframe_ms) * 1000,
+            }));
+            let _ = out.write_str("ok\r\n");
+        }
+        (Some("traj"), Some("stop"), None) => {
+            TRAJECTORY_COMMANDS.send(TrajectoryCommand::Stop).await;
+            let _ = out.write_str("ok\r\n");
+        }
+        _ => {
+            let _ = writeln!(out, "error: unknown command '{}', try 'help'\r", line.trim());
+        }
+    }
+}
+
+/// Send a request to the IMU task and wait for its response.
+///
+/// Returns `None` if the IMU task is not servicing requests (e.g. while it restarts).
+async fn imu_request(request: ImuRequest) -> Option<ImuResponse> {
+    imu::request(request, Duration::from_millis(IMU_RESPONSE_TIMEOUT_MS)).await
+}
+
+/// Write the effective firmware configuration as `key=value` lines.
+///
+/// Keys are grouped by a dotted prefix (`firmware.`, `features.`, `system.`, `usb.`, `imu.`) so
+/// the host can parse the dump into a nested structure. Nothing is loaded from stored
+/// configuration yet, which `config.stored=false` records.
+async fn write_config(out: &mut Response) {
+    let boot = persistent::stats();
+    let _ = writeln!(out, "firmware.version={}\r", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(out, "firmware.resets={}\r", boot.reset_count);
+    let _ = writeln!(out, "config.stored=false\r");
+
+    for (name, enabled) in FEATURES {
+        let _ = writeln!(out, "features.{}={}\r", name, enabled);
+    }
+
+    let _ = writeln!(out, "system.cpu_hz={}\r", cpu_frequency_hz());
+
+    let thresholds = battery::thresholds();
+    let _ = writeln!(out, "battery.cutoff_mv={}\r", thresholds.cutoff_mv);
+    let _ = writeln!(out, "battery.warn_mv={}\r", thresholds.warn_mv);
+    let _ = writeln!(out, "battery.over_mv={}\r", thresholds.over_mv);
+    let _ = writeln!(out, "battery.hysteresis_mv={}\r", thresholds.hysteresis_mv);
+
+    let descriptors = usb_system::descriptor_config();
+    let _ = writeln!(out, "usb.vid=0x{:04x}\r", descriptors.vid);
+    let _ = writeln!(out, "usb.pid=0x{:04x}\r", descriptors.pid);
+    let _ = writeln!(out, "usb.serial={}\r", descriptors.serial);
+    let _ = writeln!(out, "usb.language_id=0x{:04x}\r", descriptors.language_id);
+    let _ = writeln!(out, "usb.self_powered={}\r", descriptors.self_powered);
+    let _ = writeln!(out, "usb.max_power_ma={}\r", descriptors.max_power_ma);
+    let _ = writeln!(out, "usb.max_packet_size={}\r", MAX_PACKET_SIZE);
+    let _ = writeln!(out, "usb.acm_count={}\r", usb_system::ACM_COUNT);
+
+    match imu_request(ImuRequest::Config).await {
+        Some(ImuResponse::Config {
+            config,
+            spi_frequency,
+            variant,
+            bandwidth_hz: (accel_bandwidth_hz, gyro_bandwidth_hz),
+        }) => {
+            let _ = writeln!(out, "imu.variant={:?}\r", variant);
+            let _ = writeln!(out, "imu.spi_hz={}\r", spi_frequency);
+            let _ = writeln!(out, "imu.accel_bandwidth_hz={}\r", accel_bandwidth_hz);
+            let _ = writeln!(out, "imu.gyro_bandwidth_hz={}\r", gyro_bandwidth_hz);
+            let _ = writeln!(out, "imu.accel_range={:?}\r", config.accel_range);
+            let _ = writeln!(out, "imu.accel_auto_range={}\r", config.accel_auto_range);
+            let _ = writeln!(out, "imu.accel_averaging={:?}\r", config.accel_averaging);
+            let _ = writeln!(out, "imu.gyro_range={:?}\r", config.gyro_range);
+            let _ = writeln!(out, "imu.status_mask=0b{:08b}\r", config.status_mask);
+            let _ = writeln!(out, "imu.fifo_mode={:?}\r", config.fifo_mode);
+            let _ = writeln!(out, "imu.fsync={:?}\r", config.fsync);
+            let _ = writeln!(out, "imu.interrupt_mode={:?}\r", config.interrupt_mode);
+            let _ = writeln!(out, "imu.fifo_contents={:?}\r", config.fifo_contents);
+            let _ = writeln!(out, "imu.axis_remap={:?}\r", config.axis_remap.axes());
+            let _ = writeln!(
+                out,
+                "imu.gyro_temp_coefficients_ppm={:?}\r",
+                config.gyro_temp_coefficients_ppm
+            );
+            let _ = writeln!(out, "imu.startup_discard_samples={}\r", config.startup_discard_samples);
+            let _ = match config.low_pass_cutoff_hz {
+                Some(cutoff) => writeln!(out, "imu.low_pass_cutoff_hz={}\r", cutoff),
+                None => writeln!(out, "imu.low_pass_cutoff_hz=none\r"),
+            };
+            let _ = match config.attitude_alpha {
+                Some(alpha) => writeln!(out, "imu.attitude_alpha={}\r", alpha),
+                None => writeln!(out, "imu.attitude_alpha=none\r"),
+            };
+            let _ = match config.latency_deadline_us {
+                Some(deadline) => writeln!(out, "imu.latency_deadline_us={}\r", deadline),
+                None => writeln!(out, "imu.latency_deadline_us=none\r"),
+            };
+        }
+        response => write_imu_failure(out, response),
+    }
+}
+
+/// Describe why an IMU request did not produce the expected response.
+fn write_imu_failure(out: &mut Response, response: Option<ImuResponse>) {
+    let _ = match response {
+        None => out.write_str("error: IMU not responding\r\n"),
+        Some(ImuResponse::Error(e)) => writeln!(out, "error: IMU request failed ({:?})\r", e),
+        Some(_) => out.write_str("error: unexpected IMU response\r\n"),
+    };
+}
+
+/// Write the per-axis statistics of a noise capture.
+fn write_noise_report(out: &mut Response, report: &NoiseReport) {
+    let _ = writeln!(out, "noise over {} samples\r", report.samples);
+    for (axis, name) in ["x", "y", "z"].iter().enumerate() {
+        let (accel, gyro) = (report.accel[axis], report.gyro[axis]);
+        let _ = writeln!(
+            out,
+            "accel {}: mean {:.4} std {:.5} adev {:.5} m/s^2\r",
+            name, accel.mean, accel.std_dev, accel.allan_dev
+        );
+        let _ = writeln!(
+            out,
+            "gyro {}:  mean {:.5} std {:.6} adev {:.6} rad/s\r",
+            name, gyro.mean, gyro.std_dev, gyro.allan_dev
+        );
+    }
+}
+
+/// Parse a decimal or `0x`-prefixed hexadecimal byte.
+fn parse_u8(text: &str) -> Option<u8> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Change one setting of an IMU configuration, see `imu set`.
+///
+/// # Arguments
+/// * `config` - Configuration the setting is changed in
+/// * `setting` - Name of the setting
+/// * `values` - Words holding the new value, all of which must be used
+///
+/// # Returns
+/// `None` if the setting is unknown or the value is invalid, leaving `config` partly changed
+fn parse_imu_setting<'a>(
+    config: &mut ImuConfig,
+    setting: &str,
+    values: &mut impl Iterator<Item = &'a str>,
+) -> Option<()> {
+    match setting {
+        "accel_averaging" => {
+            config.accel_averaging = match values.next()? {
+                "4" => AccelAveraging::Samples4,
+                "8" => AccelAveraging::Samples8,
+                "16" => AccelAveraging::Samples16,
+                "32" => AccelAveraging::Samples32,
+                _ => return None,
+            }
+        }
+        "fifo_mode" => {
+            config.fifo_mode = match values.next()? {
+                "overwrite" => FifoMode::Overwrite,
+                "stop" => FifoMode::StopWhenFull,
+                _ => return None,
+            }
+        }
+        "fsync" => {
+            config.fsync = match values.next()? {
+                "off" => FsyncLatch::Disabled,
+                "temp" => FsyncLatch::Temperature,
+                "gyro_x" => FsyncLatch::GyroX,
+                "gyro_y" => FsyncLatch::GyroY,
+                "gyro_z" => FsyncLatch::GyroZ,
+                "accel_x" => FsyncLatch::AccelX,
+                "accel_y" => FsyncLatch::AccelY,
+                "accel_z" => FsyncLatch::AccelZ,
+                _ => return None,
+            }
+        }
+        "interrupt" => {
+            config.interrupt_mode = match values.next()? {
+                "ready" => InterruptMode::DataReady,
+                packets => InterruptMode::FifoWatermark(packets.parse().ok()?),
+            }
+        }
+        "axis_remap" => {
+            let axes = [
+                parse_axis(values.next()?)?,
+                parse_axis(values.next()?)?,
+                parse_axis(values.next()?)?,
+            ];
+            config.axis_remap = AxisRemap::new(axes)?;
+        }
+        "gyro_tempco" => {
+            for coefficient in config.gyro_temp_coefficients_ppm.iter_mut() {
+                *coefficient = values.next()?.parse().ok()?;
+            }
+        }
+        _ => return None,
+    }
+    values.next().is_none().then_some(())
+}
+
+/// Parse the sensor axis a body axis is read from, written as `+x`, `-y` and so on.
+fn parse_axis(text: &str) -> Option<AxisMap> {
+    let negate = match text.get(..1)? {
+        "+" => false,
+        "-" => true,
+        _ => return None,
+    };
+    let source = match &text[1..] {
+        "x" => Axis::X,
+        "y" => Axis::Y,
+        "z" => Axis::Z,
+        _ => return None,
+    };
+    Some(AxisMap { source, negate })
+}
+
+/// Parse a servo goal position written as `<id>=<position>`.
+fn parse_goal(text: &str) -> Option<(u8, i32)> {
+    let (id, position) = text.split_once('=')?;
+    Some((parse_u8(id)?, position.parse().ok()?))
+}
+
+/// Embassy task running the debug shell.
+///
+/// # Parameters
+/// - `acm`: The ACM connection to the USB host that the shell reads commands from.
+/// - `crc`: The shared CRC processor, used to build servo instructions.
+///
+/// # Behavior
+/// Runs the shell indefinitely, reconnecting whenever the host disconnects.
+#[embassy_executor::task]
+pub async fn task(acm: AcmConnection<'static>, crc: &'static SharedCrc) -> ! {
+    let mut shell = Shell::new(acm, crc);
+    shell.run().await;
+}
@@ -0,0 +1,121 @@
+//! The arithmetic that fills in the motion of one servo along a segment between two waypoints
+//!
+//! Positions are in servo position units and velocities in position units per µs. This module only
+//! uses `core`, so its tests run on the host without the rest of the firmware:
+//!
+//! ```text
+//! rustc --edition 2021 --test src/apps/trajectory/interpolation.rs -o target/interpolation && target/interpolation
+//! ```
+
+/// How the motion between waypoints is filled in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum Interpolation {
+    /// Constant speed along each segment
+    Linear,
+    /// Catmull-Rom spline through the waypoints
+    Cubic,
+}
+
+impl Interpolation {
+    /// Position and velocity part way along a segment
+    ///
+    /// # Arguments
+    /// * `from` - Position and velocity at the start of the segment
+    /// * `to` - Position and velocity at the end of the segment, a linear segment ignores the
+    ///   velocities
+    /// * `s` - Fraction of the segment covered, from 0 to 1
+    /// * `duration` - Duration of the segment in µs
+    ///
+    /// # Returns
+    /// The position and velocity
+    pub fn at(self, (p0, v0): (f32, f32), (p1, v1): (f32, f32), s: f32, duration: f32) -> (f32, f32) {
+        match self {
+            Interpolation::Linear => (p0 + (p1 - p0) * s, (p1 - p0) / duration),
+            Interpolation::Cubic => {
+                // Cubic Hermite segment, with the tangents scaled to the segment's duration
+                let (m0, m1) = (v0 * duration, v1 * duration);
+                let (s2, s3) = (s * s, s * s * s);
+                let position = (2.0 * s3 - 3.0 * s2 + 1.0) * p0
+                    + (s3 - 2.0 * s2 + s) * m0
+                    + (-2.0 * s3 + 3.0 * s2) * p1
+                    + (s3 - s2) * m1;
+                let velocity = ((6.0 * s2 - 6.0 * s) * p0
+                    + (3.0 * s2 - 4.0 * s + 1.0) * m0
+                    + (-6.0 * s2 + 6.0 * s) * p1
+                    + (3.0 * s2 - 2.0 * s) * m1)
+                    / duration;
+                (position, velocity)
+            }
+        }
+    }
+}
+
+/// Catmull-Rom velocity at a waypoint, from the points either side of it
+///
+/// # Arguments
+/// * `before` - Position at the waypoint before
+/// * `after` - Position at the waypoint after
+/// * `span` - Time in µs from the waypoint before to the one after
+pub fn catmull_rom_velocity(before: f32, after: f32, span: f32) -> f32 {
+    (after - before) / span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assert two values agree to within `tolerance`
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn linear_moves_at_constant_speed() {
+        for s in [0.0, 0.25, 0.5, 1.0] {
+            let (position, velocity) = Interpolation::Linear.at((100.0, 5.0), (300.0, -5.0), s, 1000.0);
+            assert_close(position, 100.0 + 200.0 * s, 1e-3);
+            assert_close(velocity, 0.2, 1e-6);
+        }
+    }
+
+    #[test]
+    fn cubic_meets_the_ends_and_their_velocities() {
+        let (from, to) = ((100.0, 0.1), (300.0, -0.05));
+        let start = Interpolation::Cubic.at(from, to, 0.0, 1000.0);
+        let end = Interpolation::Cubic.at(from, to, 1.0, 1000.0);
+        assert_close(start.0, 100.0, 1e-3);
+        assert_close(start.1, 0.1, 1e-6);
+        assert_close(end.0, 300.0, 1e-3);
+        assert_close(end.1, -0.05, 1e-6);
+    }
+
+    #[test]
+    fn cubic_from_rest_to_rest_is_symmetric() {
+        let (position, velocity) = Interpolation::Cubic.at((0.0, 0.0), (1000.0, 0.0), 0.5, 2000.0);
+        assert_close(position, 500.0, 1e-3);
+        // Peak speed of a rest to rest Hermite segment is 1.5 times the average
+        assert_close(velocity, 1.5 * 1000.0 / 2000.0, 1e-6);
+    }
+
+    #[test]
+    fn cubic_velocity_is_the_derivative_of_position() {
+        let (from, to, duration) = ((-50.0, 0.2), (400.0, 0.3), 1500.0);
+        let step = 1e-3;
+        for s in [0.1, 0.4, 0.8] {
+            let (before, _) = Interpolation::Cubic.at(from, to, s - step, duration);
+            let (after, _) = Interpolation::Cubic.at(from, to, s + step, duration);
+            let (_, velocity) = Interpolation::Cubic.at(from, to, s, duration);
+            assert_close(velocity, (after - before) / (2.0 * step * duration), 1e-3);
+        }
+    }
+
+    #[test]
+    fn catmull_rom_velocity_spans_both_neighbours() {
+        assert_close(catmull_rom_velocity(100.0, 500.0, 2000.0), 0.2, 1e-6);
+        assert_close(catmull_rom_velocity(500.0, 100.0, 2000.0), -0.2, 1e-6);
+    }
+}
@@ -0,0 +1,308 @@
+//! Trajectory executor turning sparse waypoints into timed servo goal positions.
+//!
+//! The host sends a few waypoints, each holding a goal position for every servo of the trajectory
+//! and the time to take to reach it, and [`task`] fills in the motion between them. Every
+//! [`CONTROL_PERIOD`] it works out where the servos should be and queues that as a Goal Position
+//! sync write with [`servo_schedule`], [`LEAD_TIME`] ahead so the write leaves the board on time.
+//! The host therefore does not have to stream goal positions at the control rate, and bursts or
+//! jitter on the USB link do not show in the motion.
+//!
+//! # Interpolation
+//!
+//! - [`Interpolation::Linear`] moves at constant speed along each segment, changing speed at
+//!   every waypoint
+//! - [`Interpolation::Cubic`] follows a Catmull-Rom spline through the waypoints, so the speed
+//!   changes smoothly, coming to rest at the last waypoint
+//!
+//! # Commands
+//!
+//! Commands arrive on [`TRAJECTORY_COMMANDS`]:
+//! - [`TrajectoryCommand::Replace`] drops the waypoints not reached yet and starts the new ones
+//!   from where the servos are commanded to be at that moment, at the speed they already have, so
+//!   a change of plan mid-motion does not jerk the joints. A servo the previous trajectory did not
+//!   move starts at rest at its first waypoint, so a trajectory from rest should start with the
+//!   servos' present positions
+//! - [`TrajectoryCommand::Append`] adds a waypoint to the end. The speed at a waypoint is settled
+//!   when the segment towards it starts, so a waypoint appended after that is only set off to
+//!   once the servos stopped at the one before. Append at least one segment ahead to keep moving
+//! - [`TrajectoryCommand::Stop`] drops every waypoint and holds the servos where they are
+//!   commanded to be
+//!
+//! The debug shell sends these commands with its `traj` commands.
+
+mod interpolation;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Ticker};
+use heapless::{Deque, Vec};
+
+use crate::drivers::dynamixel_bus::GOAL_POSITION_ADDRESS;
+use crate::drivers::servo_schedule::{self, MAX_COMMAND_SIZE};
+use crate::peripherals::crc::SharedCrc;
+use crate::protocol::dynamixel::SyncWrite;
+pub use interpolation::Interpolation;
+
+/// Most servos a trajectory moves
+pub const MAX_TRAJECTORY_SERVOS: usize = 20;
+
+/// Most waypoints waiting to be reached
+pub const MAX_WAYPOINTS: usize = 8;
+
+/// Time between goal positions
+pub const CONTROL_PERIOD: Duration = Duration::from_millis(10);
+
+/// How far ahead of its time each goal position is queued
+pub const LEAD_TIME: Duration = Duration::from_millis(2);
+
+/// Commands waiting for [`task`]
+const COMMAND_QUEUE_DEPTH: usize = 4;
+
+/// Commands to the trajectory executor
+pub static TRAJECTORY_COMMANDS: Channel<CriticalSectionRawMutex, TrajectoryCommand, COMMAND_QUEUE_DEPTH> =
+    Channel::new();
+
+/// A point the servos pass through
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct Waypoint {
+    /// Goal position of each servo of the trajectory, in the order of its servo IDs
+    pub positions: [i32; MAX_TRAJECTORY_SERVOS],
+    /// Time to take to reach the waypoint from the one before
+    pub duration: Duration,
+}
+
+/// Commands to the trajectory executor, see the module documentation
+#[derive(Debug, Clone)]
+pub enum TrajectoryCommand {
+    /// Start a new trajectory in place of the current one
+    Replace {
+        /// IDs of the servos moved, in the order of the waypoint positions
+        servos: Vec<u8, MAX_TRAJECTORY_SERVOS>,
+        /// How the motion between waypoints is filled in
+        interpolation: Interpolation,
+        /// Waypoints in the order they are reached
+        waypoints: Vec<Waypoint, MAX_WAYPOINTS>,
+    },
+    /// Add a waypoint to the end of the current trajectory
+    Append(Waypoint),
+    /// Drop every waypoint and hold the current position
+    Stop,
+}
+
+/// Position and velocity of every servo, velocities in position units per µs
+type State = ([f32; MAX_TRAJECTORY_SERVOS], [f32; MAX_TRAJECTORY_SERVOS]);
+
+/// Interpolation state of the trajectory being followed
+struct Executor {
+    /// IDs of the servos moved
+    servos: Vec<u8, MAX_TRAJECTORY_SERVOS>,
+    /// How the motion between waypoints is filled in
+    interpolation: Interpolation,
+    /// Waypoints not reached yet, the first one ends the current segment
+    waypoints: Deque<Waypoint, MAX_WAYPOINTS>,
+    /// When the current segment started
+    segment_start: Instant,
+    /// Position at the start of the current segment, the position held once every waypoint is reached
+    from: [f32; MAX_TRAJECTORY_SERVOS],
+    /// Velocity at the start of the current segment
+    from_velocity: [f32; MAX_TRAJECTORY_SERVOS],
+    /// Velocity at the end of the current segment
+    to_velocity: [f32; MAX_TRAJECTORY_SERVOS],
+}
+
+impl Executor {
+    /// Create an executor without a trajectory
+    fn new() -> Self {
+        Self {
+            servos: Vec::new(),
+            interpolation: Interpolation::Linear,
+            waypoints: Deque::new(),
+            segment_start: Instant::from_ticks(0),
+            from: [0.0; MAX_TRAJECTORY_SERVOS],
+            from_velocity: [0.0; MAX_TRAJECTORY_SERVOS],
+            to_velocity: [0.0; MAX_TRAJECTORY_SERVOS],
+        }
+    }
+
+    /// Position and velocity at `at`, holding once every waypoint is reached
+    fn state(&self, at: Instant) -> State {
+        let Some(to) = self.waypoints.front() else {
+            return (self.from, [0.0; MAX_TRAJECTORY_SERVOS]);
+        };
+        // A zero duration jumps straight to the waypoint
+        let duration = (to.duration.as_micros() as f32).max(1.0);
+        let s = (at.saturating_duration_since(self.segment_start).as_micros() as f32 / duration).min(1.0);
+
+        let mut positions = [0.0; MAX_TRAJECTORY_SERVOS];
+        let mut velocities = [0.0; MAX_TRAJECTORY_SERVOS];
+        let servos = positions.iter_mut().zip(velocities.iter_mut()).take(self.servos.len());
+        for (i, (position, velocity)) in servos.enumerate() {
+            (*position, *velocity) = self.interpolation.at(
+                (self.from[i], self.from_velocity[i]),
+                (to.positions[i] as f32, self.to_velocity[i]),
+                s,
+                duration,
+            );
+        }
+        (positions, velocities)
+    }
+
+    /// Settle the velocity at the end of the segment that just started
+    ///
+    /// For a cubic trajectory it is the Catmull-Rom tangent through the points either side of the
+    /// waypoint, zero at the last waypoint. A linear trajectory does not use it.
+    fn start_segment(&mut self) {
+        self.to_velocity = [0.0; MAX_TRAJECTORY_SERVOS];
+        if self.interpolation != Interpolation::Cubic {
+            return;
+        }
+        let mut waypoints = self.waypoints.iter();
+        let (Some(to), Some(next)) = (waypoints.next(), waypoints.next()) else {
+            return;
+        };
+        let span = ((to.duration + next.duration).as_micros() as f32).max(1.0);
+        let servos = self.to_velocity.iter_mut().zip(&next.positions).zip(&self.from);
+        for ((velocity, next), from) in servos.take(self.servos.len()) {
+            *velocity = interpolation::catmull_rom_velocity(*from, *next as f32, span);
+        }
+    }
+
+    /// Advance to the segment `at` falls in
+    ///
+    /// # Returns
+    /// The goal positions for `at`, or `None` once the last waypoint was reached and sent
+    fn advance(&mut self, at: Instant) -> Option<[f32; MAX_TRAJECTORY_SERVOS]> {
+        loop {
+            let to = self.waypoints.front()?;
+            if at < self.segment_start + to.duration {
+                return Some(self.state(at).0);
+            }
+
+            // Waypoint reached
+            self.segment_start += to.duration;
+            for (from, position) in self.from.iter_mut().zip(&to.positions) {
+                *from = *position as f32;
+            }
+            self.from_velocity = self.to_velocity;
+            self.waypoints.pop_front();
+            if self.waypoints.is_empty() {
+                // Sent once so the servos end exactly on the last waypoint
+                return Some(self.from);
+            }
+            self.start_segment();
+        }
+    }
+
+    /// Act on a command, taking effect at `at`
+    fn command(&mut self, command: TrajectoryCommand, at: Instant) {
+        // Catch up with the waypoints reached since the last goal position
+        let _ = self.advance(at);
+
+        match command {
+            TrajectoryCommand::Replace {
+                servos,
+                interpolation,
+                waypoints,
+            } => {
+                let (positions, velocities) = self.state(at);
+                let mut from = [0.0; MAX_TRAJECTORY_SERVOS];
+                let mut from_velocity = [0.0; MAX_TRAJECTORY_SERVOS];
+                for (i, id) in servos.iter().enumerate() {
+                    if let Some(previous) = self.servos.iter().position(|servo| servo == id) {
+                        from[i] = positions[previous];
+                        from_velocity[i] = velocities[previous];
+                    } else if let Some(first) = waypoints.first() {
+                        from[i] = first.positions[i] as f32;
+                    }
+                }
+
+                self.servos = servos;
+                self.interpolation = interpolation;
+                self.from = from;
+                self.from_velocity = from_velocity;
+                self.waypoints.clear();
+                for waypoint in waypoints {
+                    let _ = self.waypoints.push_back(waypoint);
+                }
+                self.segment_start = at;
+                self.start_segment();
+            }
+            TrajectoryCommand::Append(waypoint) => {
+                if self.servos.is_empty() {
+                    defmt::warn!("Trajectory waypoint dropped, no trajectory to append it to");
+                    return;
+                }
+                if self.waypoints.is_empty() {
+                    // Setting off from the position held
+                    self.segment_start = at;
+                    self.from_velocity = [0.0; MAX_TRAJECTORY_SERVOS];
+                    let _ = self.waypoints.push_back(waypoint);
+                    self.start_segment();
+                } else if self.waypoints.push_back(waypoint).is_err() {
+                    defmt::warn!("Trajectory waypoint dropped, {} already waiting", MAX_WAYPOINTS);
+                }
+            }
+            TrajectoryCommand::Stop => {
+                self.from = self.state(at).0;
+                self.waypoints.clear();
+            }
+        }
+    }
+}
+
+/// Queue goal positions to be sent at `at`
+async fn send(servos: &[u8], positions: &[f32; MAX_TRAJECTORY_SERVOS], at: Instant, crc: &SharedCrc) {
+    let mut sync_write = SyncWrite::new(GOAL_POSITION_ADDRESS, 4);
+    for (id, position) in servos.iter().zip(positions) {
+        sync_write
+            .add_servo(*id, &(*position as i32).to_le_bytes())
+            .expect("every trajectory servo fits a sync write");
+    }
+
+    let mut packet = [0u8; MAX_COMMAND_SIZE];
+    let len = sync_write
+        .finalize(&mut packet, &mut *crc.lock().await)
+        .expect("a full sync write fits a scheduled command");
+    if let Err(e) = servo_schedule::schedule(at, &packet[..len]) {
+        defmt::warn!("Trajectory goal position dropped: {:?}", e);
+    }
+}
+
+/// Embassy task following the trajectories sent on [`TRAJECTORY_COMMANDS`]
+///
+/// # Parameters
+/// - `crc`: CRC processor used to build the goal position sync writes, shared with the servo task.
+///
+/// # Behavior
+/// Queues a goal position every [`CONTROL_PERIOD`] while a trajectory is followed, which the servo
+/// task sends with [`servo_schedule::run_until`].
+#[embassy_executor::task]
+pub async fn task(crc: &'static SharedCrc) -> ! {
+    let mut executor = Executor::new();
+    let mut ticker = Ticker::every(CONTROL_PERIOD);
+
+    loop {
+        let command = match select(TRAJECTORY_COMMANDS.receive(), ticker.next()).await {
+            Either::First(command) => Some(command),
+            Either::Second(()) => None,
+        };
+
+        let at = Instant::now() + LEAD_TIME;
+        match command {
+            Some(TrajectoryCommand::Stop) => {
+                executor.command(TrajectoryCommand::Stop, at);
+                // Hold where the servos are now, not where the last goal position sent was heading
+                if !executor.servos.is_empty() {
+                    send(&executor.servos, &executor.from, at, crc).await;
+                }
+            }
+            Some(command) => executor.command(command, at),
+            None => {
+                if let Some(positions) = executor.advance(at) {
+                    send(&executor.servos, &positions, at, crc).await;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Battery protection thresholds with hysteresis.
+//!
+//! The battery supplies the servos, and the board has no ADC channel on that rail: the internal
+//! ADC only measures VBAT, the backup cell of the RTC, see [`crate::drivers::internal_adc`]. The
+//! battery voltage is therefore read from the servos themselves. [`crate::apps::servos::task`]
+//! polls their Present Input Voltage and feeds the median of each poll to a [`BatteryMonitor`],
+//! which sorts it into a [`BatteryState`] against the configured [`BatteryThresholds`]:
+//! - [`BatteryState::Over`] at or above the over-voltage threshold
+//! - [`BatteryState::Cutoff`] at or below the cutoff threshold
+//! - [`BatteryState::Low`] at or below the warning threshold
+//! - [`BatteryState::Normal`] otherwise
+//!
+//! With no servo answering there is no reading, and the monitor stays in the state it was in.
+//!
+//! Entering a state only takes the voltage reaching its threshold, but leaving it again takes the
+//! voltage moving back past the threshold by the hysteresis, so a reading hovering at a threshold
+//! does not flap between states. Every change is published on [`BATTERY_EVENTS`].
+//!
+//! Entering [`BatteryState::Cutoff`] also asks for the servo torque to be switched off through
+//! [`TORQUE_OFF`](crate::drivers::dynamixel_bus::TORQUE_OFF), which the servo task acts on before
+//! it sends anything else. Torque stays off when the voltage recovers, it is up to the operator to
+//! enable it again.
+//!
+//! The thresholds are stored in flash with [`set_thresholds`], see
+//! [`crate::peripherals::flash_config`], and [`BatteryThresholds::DEFAULT`] applies until then.
+
+mod state;
+
+use core::cell::Cell;
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+
+use crate::drivers::dynamixel_bus::TORQUE_OFF;
+use crate::peripherals::flash_config;
+use state::next_state;
+pub use state::{BatteryState, BatteryThresholds};
+
+/// Errors from changing the thresholds
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum ThresholdsError {
+    /// The thresholds were out of order, see [`BatteryThresholds::is_valid`]
+    OutOfOrder,
+    /// The thresholds are in effect, but could not be stored in flash
+    Flash(embassy_stm32::flash::Error),
+}
+
+/// A change of [`BatteryState`]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct BatteryEvent {
+    /// The state the battery entered
+    pub state: BatteryState,
+    /// The reading that caused the change, in millivolts
+    pub voltage_mv: u16,
+}
+
+/// Signalled on every change of [`BatteryState`], holding the most recent change
+pub static BATTERY_EVENTS: Signal<CriticalSectionRawMutex, BatteryEvent> = Signal::new();
+
+/// Thresholds in effect, loaded from flash by [`BatteryMonitor::new`]
+static THRESHOLDS: Mutex<CriticalSectionRawMutex, Cell<BatteryThresholds>> =
+    Mutex::new(Cell::new(BatteryThresholds::DEFAULT));
+
+/// Thresholds in effect
+pub fn thresholds() -> BatteryThresholds {
+    THRESHOLDS.lock(Cell::get)
+}
+
+/// Change the thresholds and store them in flash
+///
+/// Takes effect from the next reading, without resetting the current state.
+///
+/// # Arguments
+/// * `thresholds` - The new thresholds
+///
+/// # Returns
+/// Success, [`ThresholdsError::OutOfOrder`] leaving the previous thresholds in effect, or
+/// [`ThresholdsError::Flash`] if the new thresholds will be lost at the next reset
+pub async fn set_thresholds(thresholds: BatteryThresholds) -> Result<(), ThresholdsError> {
+    if !thresholds.is_valid() {
+        return Err(ThresholdsError::OutOfOrder);
+    }
+    THRESHOLDS.lock(|cell| cell.set(thresholds));
+    flash_config::store_battery_thresholds(thresholds)
+        .await
+        .map_err(ThresholdsError::Flash)
+}
+
+/// Battery state machine, fed with the battery voltage read by the servos
+pub struct BatteryMonitor {
+    state: BatteryState,
+}
+
+impl BatteryMonitor {
+    /// Create a monitor, loading the stored thresholds
+    ///
+    /// Stored thresholds that are missing or out of order are replaced with
+    /// [`BatteryThresholds::DEFAULT`].
+    pub fn new() -> Self {
+        let thresholds = flash_config::battery_thresholds()
+            .filter(BatteryThresholds::is_valid)
+            .unwrap_or_default();
+        THRESHOLDS.lock(|cell| cell.set(thresholds));
+        Self {
+            state: BatteryState::Normal,
+        }
+    }
+
+    /// Sort a reading into a state and act on a change
+    ///
+    /// # Arguments
+    /// * `voltage_mv` - Battery voltage in millivolts
+    ///
+    /// # Returns
+    /// The new state if it changed
+    pub fn update(&mut self, voltage_mv: u16) -> Option<BatteryState> {
+        let state = next_state(&thresholds(), self.state, voltage_mv);
+        if state == self.state {
+            return None;
+        }
+
+        match state {
+            BatteryState::Normal => defmt::info!("Battery back to normal at {} mV", voltage_mv),
+            BatteryState::Low => defmt::warn!("Battery low at {} mV", voltage_mv),
+            BatteryState::Cutoff => {
+                defmt::error!("Battery at cutoff at {} mV, switching the servo torque off", voltage_mv);
+                TORQUE_OFF.signal(());
+            }
+            BatteryState::Over => defmt::error!("Battery over-voltage at {} mV", voltage_mv),
+        }
+        self.state = state;
+        BATTERY_EVENTS.signal(BatteryEvent { state, voltage_mv });
+        Some(state)
+    }
+}
+
+impl Default for BatteryMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,151 @@
+//! Battery states and the thresholds that sort a voltage into them
+//!
+//! This module only uses `core`, so its tests run on the host without the rest of the firmware:
+//!
+//! ```text
+//! rustc --edition 2021 --test src/battery/state.rs -o target/battery-state && target/battery-state
+//! ```
+
+/// Voltage thresholds in millivolts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct BatteryThresholds {
+    /// Voltage at or below which the servo torque is switched off
+    pub cutoff_mv: u16,
+    /// Voltage at or below which the battery is reported low
+    pub warn_mv: u16,
+    /// Voltage at or above which the battery is reported over-voltage
+    pub over_mv: u16,
+    /// Distance the voltage must move back past a threshold to leave its state
+    pub hysteresis_mv: u16,
+}
+
+impl BatteryThresholds {
+    /// Thresholds for the 4S lithium polymer battery supplying the servos: cutoff at 3.3V and
+    /// warning at 3.5V per cell, over-voltage above the 4.2V of a full cell, and a hysteresis of
+    /// two steps of the servos' 0.1V input voltage reading
+    pub const DEFAULT: Self = Self {
+        cutoff_mv: 13200,
+        warn_mv: 14000,
+        over_mv: 17000,
+        hysteresis_mv: 200,
+    };
+
+    /// Whether the thresholds are in order with at least the hysteresis between them
+    pub fn is_valid(&self) -> bool {
+        self.hysteresis_mv > 0
+            && u32::from(self.cutoff_mv) + u32::from(self.hysteresis_mv) <= u32::from(self.warn_mv)
+            && u32::from(self.warn_mv) + u32::from(self.hysteresis_mv) <= u32::from(self.over_mv)
+    }
+}
+
+impl Default for BatteryThresholds {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Where the battery voltage stands against the thresholds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum BatteryState {
+    /// Between the warning and over-voltage thresholds
+    Normal,
+    /// At or below the warning threshold
+    Low,
+    /// At or below the cutoff threshold, the servo torque has been switched off
+    Cutoff,
+    /// At or above the over-voltage threshold
+    Over,
+}
+
+/// State a reading puts the battery in, coming from `state`
+///
+/// A state is entered at its threshold and only left once the voltage is back past it by the
+/// hysteresis. Recovering from cutoff passes through low until the warning threshold is cleared
+/// as well.
+pub fn next_state(thresholds: &BatteryThresholds, state: BatteryState, voltage_mv: u16) -> BatteryState {
+    let BatteryThresholds {
+        cutoff_mv,
+        warn_mv,
+        over_mv,
+        hysteresis_mv,
+    } = *thresholds;
+
+    if voltage_mv >= over_mv || (state == BatteryState::Over && voltage_mv.saturating_add(hysteresis_mv) > over_mv) {
+        BatteryState::Over
+    } else if voltage_mv <= cutoff_mv || (state == BatteryState::Cutoff && voltage_mv < cutoff_mv + hysteresis_mv) {
+        BatteryState::Cutoff
+    } else if voltage_mv <= warn_mv
+        || (matches!(state, BatteryState::Low | BatteryState::Cutoff) && voltage_mv < warn_mv + hysteresis_mv)
+    {
+        BatteryState::Low
+    } else {
+        BatteryState::Normal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: BatteryThresholds = BatteryThresholds {
+        cutoff_mv: 2000,
+        warn_mv: 2500,
+        over_mv: 3600,
+        hysteresis_mv: 100,
+    };
+
+    /// Feed five readings in turn from the normal state, returning the state after each
+    fn states(readings: [u16; 5]) -> [BatteryState; 5] {
+        let mut state = BatteryState::Normal;
+        readings.map(|reading| {
+            state = next_state(&THRESHOLDS, state, reading);
+            state
+        })
+    }
+
+    #[test]
+    fn thresholds_enter_their_state() {
+        use BatteryState::*;
+        assert_eq!(next_state(&THRESHOLDS, Normal, 3000), Normal);
+        assert_eq!(next_state(&THRESHOLDS, Normal, 2500), Low);
+        assert_eq!(next_state(&THRESHOLDS, Normal, 2000), Cutoff);
+        assert_eq!(next_state(&THRESHOLDS, Normal, 3600), Over);
+        assert_eq!(next_state(&THRESHOLDS, Normal, 0), Cutoff);
+    }
+
+    #[test]
+    fn leaving_a_state_takes_the_hysteresis() {
+        use BatteryState::*;
+        // Hovering just above the warning threshold stays low until it is cleared by the hysteresis
+        assert_eq!(states([2500, 2510, 2599, 2600, 2599]), [Low, Low, Low, Normal, Normal]);
+        assert_eq!(next_state(&THRESHOLDS, Over, 3550), Over);
+        assert_eq!(next_state(&THRESHOLDS, Over, 3500), Normal);
+    }
+
+    #[test]
+    fn recovering_from_cutoff_passes_through_low() {
+        use BatteryState::*;
+        assert_eq!(
+            states([1900, 2050, 2100, 2550, 2600]),
+            [Cutoff, Cutoff, Low, Low, Normal]
+        );
+    }
+
+    #[test]
+    fn validates_threshold_order() {
+        assert!(THRESHOLDS.is_valid());
+        assert!(BatteryThresholds::DEFAULT.is_valid());
+        assert!(!BatteryThresholds {
+            hysteresis_mv: 0,
+            ..THRESHOLDS
+        }
+        .is_valid());
+        assert!(!BatteryThresholds {
+            warn_mv: 2050,
+            ..THRESHOLDS
+        }
+        .is_valid());
+    }
+}
@@ -0,0 +1,163 @@
+//! Raw Dynamixel bus traffic capture for protocol debugging.
+//!
+//! With the `bus-sniffer` feature [`DynamixelBus`](super::dynamixel_bus::DynamixelBus) hands every
+//! packet it sends and every chunk of bytes it receives to [`capture`], and [`task`] forwards them
+//! to the host on their own ACM port. The bus is the one run by
+//! [`crate::apps::servos::task`], so the captures show its scan, polls and scheduled commands. Capturing only runs while the host has the port open, so the
+//! tap costs nothing otherwise. Captures are queued without waiting, so a slow host only costs
+//! dropped captures, never bus timing; the number dropped is logged as they are sent again.
+//!
+//! # Frame layout
+//!
+//! Each capture is sent as one frame, little endian:
+//!
+//! | Offset | Size | Field                                                                     |
+//! |--------|------|---------------------------------------------------------------------------|
+//! | 0      | 1    | [`FRAME_SYNC`]                                                            |
+//! | 1      | 1    | Flags: bit 0 received (otherwise sent), bit 1 truncated, bit 2 UART error |
+//! | 2      | 2    | Number of bytes that follow (`u16`)                                       |
+//! | 4      | 8    | Device time in µs when the bytes finished on the bus (`u64`)              |
+//! | 12     | n    | The bytes, at most [`MAX_CAPTURE`]                                        |
+//!
+//! A frame that ends on a full USB packet is followed by a zero-length packet, so every frame is
+//! its own transfer. A receive that failed with a UART error carries no bytes, as the driver does
+//! not get to see them.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::Instant;
+
+use crate::peripherals::acm::{AcmConnection, Disconnected};
+use crate::peripherals::usb_system::MAX_PACKET_SIZE;
+
+/// First byte of every frame
+pub const FRAME_SYNC: u8 = 0x5A;
+
+/// Most bytes kept from a single capture, the rest are dropped and the frame flagged truncated
+pub const MAX_CAPTURE: usize = 256;
+
+/// Size of the frame header in front of the bytes
+const HEADER_SIZE: usize = 12;
+
+/// Captures waiting to be sent
+const QUEUE_DEPTH: usize = 8;
+
+/// Flag bit set for bytes received from the servos
+const FLAG_RX: u8 = 0b001;
+/// Flag bit set when the capture held more than [`MAX_CAPTURE`] bytes
+const FLAG_TRUNCATED: u8 = 0b010;
+/// Flag bit set when the receive failed with a UART error
+const FLAG_ERROR: u8 = 0b100;
+
+/// Which way the captured bytes went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum Direction {
+    /// Sent by us
+    Tx,
+    /// Received from the servos
+    Rx,
+}
+
+/// Bytes seen on the bus in one go
+struct Capture {
+    flags: u8,
+    timestamp: Instant,
+    len: usize,
+    bytes: [u8; MAX_CAPTURE],
+}
+
+/// Captures waiting for [`task`]
+static CAPTURES: Channel<CriticalSectionRawMutex, Capture, QUEUE_DEPTH> = Channel::new();
+
+/// Whether the host has the sniffer port open
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+/// Captures dropped because the queue was full
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Queue bytes seen on the bus to be sent to the host, without waiting
+///
+/// Does nothing while the host does not have the sniffer port open.
+///
+/// # Arguments
+/// * `direction` - Whether the bytes were sent or received
+/// * `bytes` - The bytes, of which the first [`MAX_CAPTURE`] are kept
+/// * `error` - Whether the transfer failed with a UART error
+pub fn capture(direction: Direction, bytes: &[u8], error: bool) {
+    if !LISTENING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let len = bytes.len().min(MAX_CAPTURE);
+    let mut capture = Capture {
+        flags: if direction == Direction::Rx { FLAG_RX } else { 0 }
+            | if len < bytes.len() { FLAG_TRUNCATED } else { 0 }
+            | if error { FLAG_ERROR } else { 0 },
+        timestamp: Instant::now(),
+        len,
+        bytes: [0; MAX_CAPTURE],
+    };
+    capture.bytes[..len].copy_from_slice(&bytes[..len]);
+
+    if CAPTURES.try_send(capture).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Encode a capture into a frame
+///
+/// # Returns
+/// The length of the frame
+fn encode(capture: &Capture, frame: &mut [u8; HEADER_SIZE + MAX_CAPTURE]) -> usize {
+    frame[0] = FRAME_SYNC;
+    frame[1] = capture.flags;
+    frame[2..4].copy_from_slice(&(capture.len as u16).to_le_bytes());
+    frame[4..12].copy_from_slice(&capture.timestamp.as_micros().to_le_bytes());
+    frame[HEADER_SIZE..HEADER_SIZE + capture.len].copy_from_slice(&capture.bytes[..capture.len]);
+    HEADER_SIZE + capture.len
+}
+
+/// Send one frame as its own transfer, ending a full last packet with a zero-length packet
+async fn send_frame(acm: &mut AcmConnection<'static>, frame: &[u8]) -> Result<(), Disconnected> {
+    for packet in frame.chunks(MAX_PACKET_SIZE as usize) {
+        acm.send_packet(packet).await?;
+    }
+    if frame.len() % MAX_PACKET_SIZE as usize == 0 {
+        acm.send_packet(&[]).await?;
+    }
+    Ok(())
+}
+
+/// Embassy task sending the captured bus traffic to the host
+///
+/// # Parameters
+/// - `acm`: The ACM port the captures are streamed over
+#[embassy_executor::task]
+pub async fn task(mut acm: AcmConnection<'static>) -> ! {
+    let mut frame = [0u8; HEADER_SIZE + MAX_CAPTURE];
+
+    loop {
+        acm.wait_connection().await;
+        // Start from the traffic after the port was opened
+        CAPTURES.clear();
+        DROPPED.store(0, Ordering::Relaxed);
+        LISTENING.store(true, Ordering::Relaxed);
+
+        loop {
+            let capture = CAPTURES.receive().await;
+
+            let dropped = DROPPED.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                defmt::warn!("{} servo bus captures dropped while the port was busy", dropped);
+            }
+
+            let len = encode(&capture, &mut frame);
+            if send_frame(&mut acm, &frame[..len]).await.is_err() {
+                break;
+            }
+        }
+
+        LISTENING.store(false, Ordering::Relaxed);
+    }
+}
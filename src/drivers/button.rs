@@ -0,0 +1,118 @@
+//! Debounced push button input
+//!
+//! The user button on PC13 pulls the pin to ground when pressed, with the internal pull-up
+//! holding it high otherwise. Contact bounce is filtered by only accepting a level once it has
+//! been stable for the debounce window, with the EXTI line waking the task on every edge so
+//! nothing busy-waits.
+
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{
+    exti::ExtiInput,
+    gpio::Pull,
+    peripherals::{EXTI13, PC13},
+    Peri,
+};
+use embassy_time::{Duration, Timer};
+
+/// Time the level must be stable before a press or release is accepted
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(20);
+
+/// Peripheral collection for the user button
+pub struct ButtonPeripherals<'d> {
+    pub pin: Peri<'d, PC13>,
+    pub line: Peri<'d, EXTI13>,
+}
+
+/// Macro to claim peripherals for Button
+#[macro_export]
+macro_rules! claim_button {
+    ($peripherals:expr) => {{
+        $crate::peripherals::claims::register($crate::peripherals::claims::Resource::Exti13);
+        $crate::drivers::button::ButtonPeripherals {
+            pin: $peripherals.PC13,
+            line: $peripherals.EXTI13,
+        }
+    }};
+}
+
+/// How long the button was held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum PressKind {
+    /// Released before the long press duration
+    Short,
+    /// Still held when the long press duration elapsed
+    Long,
+}
+
+/// Debounced active-low push button
+pub struct Button<'d> {
+    input: ExtiInput<'d>,
+    /// Time the level must be stable before it is accepted
+    debounce: Duration,
+}
+
+impl<'d> Button<'d> {
+    /// Create a button with the [`DEFAULT_DEBOUNCE`] window
+    ///
+    /// # Arguments
+    /// * `peripherals` - ButtonPeripherals struct containing the pin and its EXTI line
+    pub fn new(peripherals: ButtonPeripherals<'d>) -> Self {
+        Self {
+            input: ExtiInput::new(peripherals.pin, peripherals.line, Pull::Up),
+            debounce: DEFAULT_DEBOUNCE,
+        }
+    }
+
+    /// Wait for the next press
+    ///
+    /// A button already held when this is called must be released first, so a single press is
+    /// never reported twice.
+    pub async fn wait_for_press(&mut self) {
+        self.wait_stable(false).await;
+        self.wait_stable(true).await;
+    }
+
+    /// Wait for the next press and classify it by how long it is held
+    ///
+    /// A long press is reported as soon as `duration` has elapsed, without waiting for the
+    /// release, so the user gets feedback while still holding the button.
+    ///
+    /// # Arguments
+    /// * `duration` - Hold time from which a press counts as long
+    ///
+    /// # Returns
+    /// [`PressKind::Long`] if the button was still held after `duration`, otherwise [`PressKind::Short`]
+    pub async fn wait_for_long_press(&mut self, duration: Duration) -> PressKind {
+        self.wait_for_press().await;
+
+        // The debounce window has already passed while confirming the press
+        let remaining = duration.checked_sub(self.debounce).unwrap_or(Duration::from_ticks(0));
+        match select(self.wait_stable(false), Timer::after(remaining)).await {
+            Either::First(()) => PressKind::Short,
+            Either::Second(()) => PressKind::Long,
+        }
+    }
+
+    /// Wait until the button has been pressed (or released) for a full debounce window
+    async fn wait_stable(&mut self, pressed: bool) {
+        let debounce = self.debounce;
+        loop {
+            self.wait_level(pressed).await;
+
+            // Any edge back to the other level within the window was bounce, start over
+            if let Either::First(()) = select(Timer::after(debounce), self.wait_level(!pressed)).await {
+                return;
+            }
+        }
+    }
+
+    /// Wait until the pin is at the pressed (low) or released (high) level, without debouncing
+    async fn wait_level(&mut self, pressed: bool) {
+        if pressed {
+            self.input.wait_for_low().await;
+        } else {
+            self.input.wait_for_high().await;
+        }
+    }
+}
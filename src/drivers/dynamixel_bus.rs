@@ -0,0 +1,560 @@
+//! Dynamixel servo bus over an RS-485 transceiver
+//!
+//! The servos share a single differential pair, so the bus is half-duplex: the transceiver driver
+//! is only enabled while we transmit, and must be released before the first servo starts replying.
+//! This driver wraps USART2 with DMA in both directions and a GPIO driving the transceiver's
+//! direction (DE/RE) input:
+//! - High: transmit, the receiver is disabled
+//! - Low: receive, the line is left to the servos
+//!
+//! A servo waits its Return Delay Time (control table address 9, 2µs per unit, 500µs by default)
+//! before answering, which [`DynamixelBus::read_packet`] adds on top of the caller's timeout.
+//! Packets are delimited by line idle, so one read returns one status packet as long as the
+//! servos do not answer back to back.
+//!
+//! Every transmission waits until the bus has been quiet for the packet gap, [`DEFAULT_PACKET_GAP`],
+//! counted from the end of our last packet or of the last reply, whichever came later. Back-to-back sync writes therefore leave the servos time to finish
+//! parsing, and we never switch the transceiver to transmit while a servo is still releasing the
+//! line after its reply. The wait is a timer, not a busy loop, so other tasks keep running.
+//!
+//! Instructions are sent with [`DynamixelBus::write_packet`] and their replies collected with
+//! [`DynamixelBus::collect_statuses`], through [`super::servo_requests`], which matches each reply
+//! to the request it answers. Its [`BusError`] tells apart what the control loop reacts to
+//! differently: a timeout or a corrupted reply is worth retrying, while a [`BusError::ServoError`]
+//! is the servo refusing the instruction. A hardware alert alone does not fail the instruction, so
+//! it is left on the [`StatusPacket`] for the caller to surface.
+//!
+//! Every reply is also counted per servo ID, see [`ServoStats`], so a servo whose timeouts or CRC
+//! errors keep rising points at a connector or harness before the joint drops out. With the
+//! `telemetry` feature the counts are streamed to the host, see [`crate::apps::imu_telemetry`].
+//!
+//! With the `bus-sniffer` feature every packet sent and every chunk of bytes received is also
+//! copied to [`super::bus_sniffer`], which streams them to the host.
+//!
+//! [`DynamixelBus::scan`] finds the servos on the bus with a single broadcast ping, which every
+//! servo answers in turn in ID order. Should two replies collide anyway, e.g. because two servos
+//! share an ID, or should nothing answer at all, the IDs that were not heard are pinged one at a
+//! time.
+//!
+//! The bus is created at boot and owned by the servo task, see [`crate::apps::servos`].
+
+use core::cell::RefCell;
+use core::ops::RangeInclusive;
+
+use embassy_stm32::{
+    bind_interrupts,
+    gpio::{Level, Output, Speed},
+    mode::Async,
+    peripherals::{self as stm32_peripherals, DMA1_CH2, DMA1_CH3, PD4, PD5, PD6, USART2},
+    usart::{self, Config as UartConfig, Uart},
+    Peri,
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{with_deadline, with_timeout, Duration, Instant, Timer};
+use heapless::{LinearMap, Vec};
+
+#[cfg(feature = "bus-sniffer")]
+use super::bus_sniffer::{self, Direction};
+use crate::peripherals::crc::CrcProcessor;
+use crate::protocol::dynamixel::{
+    self, Instruction, ParseError, StatusPacket, BROADCAST_ID, MAX_ID, MAX_STATUS_PARAMS, PING_STATUS_SIZE,
+};
+
+/// Control table address of Torque Enable on X-series servos
+pub const TORQUE_ENABLE_ADDRESS: u16 = 64;
+
+/// Control table address of Hardware Error Status on X-series servos, 1 byte
+pub const HARDWARE_ERROR_STATUS_ADDRESS: u16 = 70;
+
+/// Control table address of Goal Position on X-series servos, 4 bytes
+pub const GOAL_POSITION_ADDRESS: u16 = 116;
+
+/// Control table address of Present Input Voltage on X-series servos, 2 bytes in units of 0.1V
+pub const PRESENT_INPUT_VOLTAGE_ADDRESS: u16 = 144;
+
+/// Signalled by safety monitors to have the servo torque switched off
+///
+/// [`servo_schedule::run_until`](super::servo_schedule::run_until) waits on it, drops the
+/// scheduled commands and calls [`DynamixelBus::torque_off`], see [`crate::battery`].
+pub static TORQUE_OFF: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Default bus baud rate, matching the rate the servos are provisioned with
+pub const DEFAULT_BAUDRATE: u32 = 1_000_000;
+
+/// Default servo Return Delay Time (control table value 250, 2µs per unit)
+pub const DEFAULT_RETURN_DELAY: Duration = Duration::from_micros(500);
+
+/// Default quiet time on the bus before each transmission, ten byte times at [`DEFAULT_BAUDRATE`]
+pub const DEFAULT_PACKET_GAP: Duration = Duration::from_micros(100);
+
+/// Most servos [`DynamixelBus::scan`] reports
+pub const MAX_SCAN_IDS: usize = 64;
+
+/// Time allowed on top of the transfer time for the replies to a ping
+const PING_MARGIN: Duration = Duration::from_millis(3);
+
+/// Time allowed per ID for the replies to a broadcast ping, as the Robotis Dynamixel SDK does, as
+/// each servo only starts its reply some time after the one before it has finished
+const BROADCAST_PING_SLOT: Duration = Duration::from_millis(3);
+
+/// Size of the largest status packet: the 9 bytes up to the error byte, the most status parameters
+/// even if a third of them needed byte stuffing, the 2 CRC bytes and one spare, as
+/// [`DynamixelBus::read_packet`] treats a full buffer as a cut-off packet
+const STATUS_BUFFER_SIZE: usize = 9 + MAX_STATUS_PARAMS + MAX_STATUS_PARAMS / 3 + 2 + 1;
+
+/// Size of the buffer [`DynamixelBus::collect_statuses`] parses replies from as they arrive, room
+/// for one reply of any size to arrive while the one before it is still incomplete
+const COLLECT_BUFFER_SIZE: usize = 2 * STATUS_BUFFER_SIZE;
+
+/// Size of a ping instruction packet
+const PING_SIZE: usize = 10;
+
+bind_interrupts!(
+    /// Dynamixel bus UART interrupt handlers
+    pub struct DynamixelBusInterrupts {
+        USART2 => usart::InterruptHandler<stm32_peripherals::USART2>;
+    }
+);
+
+/// Peripheral collection for the Dynamixel bus
+pub struct DynamixelBusClaims<'d> {
+    pub usart: Peri<'d, USART2>,
+    pub tx: Peri<'d, PD5>,          // TX
+    pub rx: Peri<'d, PD6>,          // RX
+    pub direction: Peri<'d, PD4>,   // Transceiver DE/RE
+    pub dma_tx: Peri<'d, DMA1_CH2>, // TX DMA
+    pub dma_rx: Peri<'d, DMA1_CH3>, // RX DMA
+}
+
+/// Macro to claim peripherals for DynamixelBus
+#[macro_export]
+macro_rules! claim_dynamixel_bus {
+    ($peripherals:expr) => {{
+        use $crate::peripherals::claims::{register, Resource};
+        register(Resource::Usart2);
+        register(Resource::Dma1Ch2);
+        register(Resource::Dma1Ch3);
+        $crate::drivers::dynamixel_bus::DynamixelBusClaims {
+            usart: $peripherals.USART2,
+            tx: $peripherals.PD5,          // TX
+            rx: $peripherals.PD6,          // RX
+            direction: $peripherals.PD4,   // Transceiver DE/RE
+            dma_tx: $peripherals.DMA1_CH2, // TX DMA
+            dma_rx: $peripherals.DMA1_CH3, // RX DMA
+        }
+    }};
+}
+
+/// Errors that can occur on the Dynamixel bus
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum BusError {
+    /// No complete packet arrived before the timeout
+    Timeout,
+    /// The received packet did not fit in the caller's buffer
+    BufferTooSmall,
+    /// The reply's CRC does not match its contents
+    CrcMismatch,
+    /// The servo replied with a non-zero error number: the full error byte, whose bit 7 is the
+    /// hardware alert and bits 0-6 the error number of the instruction
+    ServoError(u8),
+    /// A byte was received with a missing stop bit, typically two devices driving the bus at once
+    Framing,
+    /// The reply ended before its header or length field said it would
+    ShortPacket,
+    /// The reply is not a status packet from the addressed servo
+    InvalidPacket,
+    /// Any other UART error (noise, parity or overrun)
+    Uart(usart::Error),
+    /// The UART could not be configured for the requested baud rate
+    Config,
+}
+
+impl From<usart::Error> for BusError {
+    fn from(error: usart::Error) -> Self {
+        match error {
+            usart::Error::Framing => BusError::Framing,
+            error => BusError::Uart(error),
+        }
+    }
+}
+
+impl From<ParseError> for BusError {
+    fn from(error: ParseError) -> Self {
+        match error {
+            ParseError::CrcMismatch => BusError::CrcMismatch,
+            ParseError::Incomplete | ParseError::InvalidLength => BusError::ShortPacket,
+            ParseError::InvalidHeader | ParseError::NotStatus | ParseError::TooManyParams => BusError::InvalidPacket,
+        }
+    }
+}
+
+/// Communication health of one servo, counted since boot
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct ServoStats {
+    /// Instructions the servo carried out, with or without a hardware alert
+    pub successes: u32,
+    /// Instructions the servo did not answer in time
+    pub timeouts: u32,
+    /// Replies whose CRC did not match
+    pub crc_errors: u32,
+    /// Replies that were cut short, garbled on the wire or not from the servo addressed
+    pub corrupted: u32,
+    /// Instructions the servo refused with an error number
+    pub servo_errors: u32,
+    /// Replies with the hardware alert bit set
+    pub hardware_alerts: u32,
+}
+
+impl ServoStats {
+    /// Count the outcome of one transaction
+    fn record(&mut self, result: &Result<StatusPacket, BusError>) {
+        match result {
+            Ok(status) => {
+                self.successes += 1;
+                self.hardware_alerts += u32::from(status.hardware_alert());
+            }
+            Err(BusError::Timeout) => self.timeouts += 1,
+            Err(BusError::CrcMismatch) => self.crc_errors += 1,
+            Err(BusError::ServoError(error)) => {
+                self.servo_errors += 1;
+                self.hardware_alerts += u32::from(error & dynamixel::HARDWARE_ALERT != 0);
+            }
+            Err(_) => self.corrupted += 1,
+        }
+    }
+}
+
+/// Statistics of every servo addressed so far, by ID
+///
+/// Holds as many servos as [`DynamixelBus::scan`] reports, further IDs are not counted.
+static SERVO_STATS: Mutex<CriticalSectionRawMutex, RefCell<LinearMap<u8, ServoStats, MAX_SCAN_IDS>>> =
+    Mutex::new(RefCell::new(LinearMap::new()));
+
+/// Count the outcome of an instruction in the statistics of the servo it addressed
+///
+/// Used for the replies received with [`DynamixelBus::collect_statuses`].
+///
+/// # Arguments
+/// * `id` - ID of the servo the instruction addressed
+/// * `result` - The servo's reply, or what went wrong with it
+pub fn record_servo_stats(id: u8, result: &Result<StatusPacket, BusError>) {
+    SERVO_STATS.lock(|stats| {
+        let mut stats = stats.borrow_mut();
+        if let Some(servo) = stats.get_mut(&id) {
+            servo.record(result);
+        } else {
+            let mut servo = ServoStats::default();
+            servo.record(result);
+            // A full map only leaves out servos beyond the most a scan reports
+            let _ = stats.insert(id, servo);
+        }
+    });
+}
+
+/// Visit the statistics of every servo addressed so far
+///
+/// The statistics stay locked while `f` runs, so it must be quick and must not wait.
+///
+/// # Arguments
+/// * `f` - Called with the ID and statistics of each servo, in the order they were first addressed
+#[cfg(feature = "telemetry")]
+pub fn for_each_servo_stats(mut f: impl FnMut(u8, &ServoStats)) {
+    SERVO_STATS.lock(|stats| {
+        for (id, stats) in stats.borrow().iter() {
+            f(*id, stats);
+        }
+    });
+}
+
+/// Half-duplex Dynamixel bus driver
+pub struct DynamixelBus<'d> {
+    /// UART with DMA in both directions
+    uart: Uart<'d, Async>,
+    /// Transceiver direction, high while transmitting
+    direction: Output<'d>,
+    /// Return Delay Time configured on the servos
+    return_delay: Duration,
+    /// Current baud rate, which sets how long replies take
+    baudrate: u32,
+    /// Quiet time on the bus before each transmission
+    packet_gap: Duration,
+    /// When the bus was last released, by the end of our packet or of a reply
+    bus_released: Instant,
+}
+
+impl<'d> DynamixelBus<'d> {
+    /// Create a new Dynamixel bus at [`DEFAULT_BAUDRATE`]
+    ///
+    /// # Arguments
+    /// * `claims` - DynamixelBusClaims struct containing all required peripherals
+    ///
+    /// # Returns
+    /// * Bus driver with the transceiver in receive mode, or an error if the UART rejects the baud rate
+    pub fn new(claims: DynamixelBusClaims<'d>) -> Result<Self, BusError> {
+        let mut config = UartConfig::default();
+        config.baudrate = DEFAULT_BAUDRATE;
+
+        // Start in receive mode so we never drive the bus while a servo is talking
+        let direction = Output::new(claims.direction, Level::Low, Speed::VeryHigh);
+
+        let uart = Uart::new(
+            claims.usart,
+            claims.rx,
+            claims.tx,
+            DynamixelBusInterrupts,
+            claims.dma_tx,
+            claims.dma_rx,
+            config,
+        )
+        .map_err(|_| BusError::Config)?;
+
+        Ok(Self {
+            uart,
+            direction,
+            return_delay: DEFAULT_RETURN_DELAY,
+            baudrate: DEFAULT_BAUDRATE,
+            packet_gap: DEFAULT_PACKET_GAP,
+            bus_released: Instant::now(),
+        })
+    }
+
+    /// Return Delay Time configured on the servos, [`DEFAULT_RETURN_DELAY`]
+    pub fn return_delay(&self) -> Duration {
+        self.return_delay
+    }
+
+    /// Transmit a complete instruction packet
+    ///
+    /// Waits until the bus has been quiet for the packet gap, [`DEFAULT_PACKET_GAP`]. The
+    /// transceiver is then switched to transmit for the duration of the packet and released as
+    /// soon as the last stop bit has left the shift register, so the reply is not clipped.
+    ///
+    /// # Arguments
+    /// * `packet` - Encoded instruction packet, e.g. from [`crate::protocol::dynamixel::build_instruction`]
+    ///
+    /// # Returns
+    /// * Success or UART error
+    pub async fn write_packet(&mut self, packet: &[u8]) -> Result<(), BusError> {
+        Timer::at(self.bus_released + self.packet_gap).await;
+        self.direction.set_high();
+
+        // DMA completion only means the last byte reached the UART, wait for transmission complete
+        // before releasing the bus
+        let result = match self.uart.write(packet).await {
+            Ok(()) => self.uart.blocking_flush(),
+            Err(e) => Err(e),
+        };
+
+        self.direction.set_low();
+        self.bus_released = Instant::now();
+        #[cfg(feature = "bus-sniffer")]
+        bus_sniffer::capture(Direction::Tx, packet, result.is_err());
+
+        result.map_err(BusError::from)
+    }
+
+    /// Receive one status packet
+    ///
+    /// # Arguments
+    /// * `buf` - Buffer to receive the packet into
+    /// * `timeout` - Time allowed for the packet once the Return Delay Time has elapsed
+    ///
+    /// # Returns
+    /// * Number of bytes received, or [`BusError::Timeout`] if the servo did not answer in time
+    /// * [`BusError::BufferTooSmall`] if the packet filled `buf`, as the rest may have been cut off
+    pub async fn read_packet(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, BusError> {
+        let capacity = buf.len();
+        let result = with_timeout(self.return_delay + timeout, self.uart.read_until_idle(buf)).await;
+        // Anything received, even in error, means a servo was driving the bus until now
+        if result.is_ok() {
+            self.bus_released = Instant::now();
+        }
+        #[cfg(feature = "bus-sniffer")]
+        match &result {
+            Ok(Ok(len)) => bus_sniffer::capture(Direction::Rx, &buf[..*len], false),
+            Ok(Err(_)) => bus_sniffer::capture(Direction::Rx, &[], true),
+            Err(_) => {}
+        }
+        match result {
+            Ok(Ok(len)) if len == capacity => Err(BusError::BufferTooSmall),
+            Ok(Ok(len)) => Ok(len),
+            Ok(Err(e)) => Err(BusError::from(e)),
+            Err(_) => Err(BusError::Timeout),
+        }
+    }
+
+    /// Switch the torque off on every servo at once
+    ///
+    /// Writes zero to Torque Enable with a broadcast, which no servo answers, so a servo that
+    /// missed it cannot be told apart. Call it again to be sure, it does no harm.
+    ///
+    /// # Arguments
+    /// * `crc` - CRC processor used to build the instruction
+    ///
+    /// # Returns
+    /// * Success or UART error
+    pub async fn torque_off(&mut self, crc: &mut CrcProcessor<'_>) -> Result<(), BusError> {
+        let [address_low, address_high] = TORQUE_ENABLE_ADDRESS.to_le_bytes();
+        let mut packet = [0u8; PING_SIZE + 3];
+        let len = dynamixel::build_instruction(
+            BROADCAST_ID,
+            Instruction::Write,
+            &[address_low, address_high, 0],
+            crc,
+            &mut packet,
+        )
+        .expect("torque off fits its buffer");
+        self.write_packet(&packet[..len]).await
+    }
+
+    /// Find the servos on the bus
+    ///
+    /// A broadcast ping is sent first, and every servo that answers is recorded. Servos answer in
+    /// ID order, so the replies are waited for as long as every ID up to the end of `range` could
+    /// take to answer. If any bytes of those replies were garbled, or nothing answered at all, the
+    /// IDs in `range` that were not heard are then pinged one by one, each with its own timeout, so
+    /// neither a collision nor servos that ignore broadcasts can hide a servo.
+    ///
+    /// # Arguments
+    /// * `range` - IDs to look for, IDs above [`MAX_ID`] are skipped
+    /// * `crc` - CRC processor used to build the pings and check the replies
+    ///
+    /// # Returns
+    /// * The IDs that answered in ascending order, at most [`MAX_SCAN_IDS`] of them
+    /// * An error if a ping could not be sent
+    pub async fn scan(
+        &mut self,
+        range: RangeInclusive<u8>,
+        crc: &mut CrcProcessor<'_>,
+    ) -> Result<Vec<u8, MAX_SCAN_IDS>, BusError> {
+        let mut found = Vec::new();
+        let range = *range.start()..=(*range.end()).min(MAX_ID);
+
+        let mut ping = [0u8; PING_SIZE];
+        let len = dynamixel::build_instruction(BROADCAST_ID, Instruction::Ping, &[], crc, &mut ping)
+            .expect("ping fits its buffer");
+        self.write_packet(&ping[..len]).await?;
+
+        // Every servo up to the end of the range may answer, one after the other
+        let ids = u32::from(*range.end()) + 1;
+        let window = self.return_delay
+            + self.transfer_time(PING_STATUS_SIZE * ids as usize)
+            + BROADCAST_PING_SLOT * ids
+            + PING_MARGIN;
+        let clean = self
+            .collect_statuses(Instant::now() + window, crc, |status| {
+                if range.contains(&status.id) && !found.contains(&status.id) {
+                    let _ = found.push(status.id);
+                }
+                true
+            })
+            .await;
+
+        // Silence is not proof of an empty bus, the broadcast may have been lost or ignored
+        if !clean || found.is_empty() {
+            let timeout = self.transfer_time(PING_STATUS_SIZE) + PING_MARGIN;
+            for id in range {
+                if found.contains(&id) {
+                    continue;
+                }
+                let len = dynamixel::build_instruction(id, Instruction::Ping, &[], crc, &mut ping)
+                    .expect("ping fits its buffer");
+                self.write_packet(&ping[..len]).await?;
+
+                let mut reply = [0u8; 2 * PING_STATUS_SIZE];
+                let Ok(len) = self.read_packet(&mut reply, timeout).await else {
+                    continue;
+                };
+                if dynamixel::parse_status(&reply[..len], crc).is_ok_and(|status| status.id == id) {
+                    // A full list can only come from a scan over more than MAX_SCAN_IDS IDs
+                    let _ = found.push(id);
+                }
+            }
+        }
+
+        found.sort_unstable();
+        Ok(found)
+    }
+
+    /// Receive status packets until `deadline`, or until `on_status` has heard enough
+    ///
+    /// Used after an instruction several servos answer, e.g. a broadcast ping or a bulk read.
+    /// Replies can arrive back to back, so bytes are collected into a buffer and every complete
+    /// status packet is taken off its front as soon as it has arrived. The packets are handed over
+    /// as received, whatever their ID or error byte.
+    ///
+    /// # Arguments
+    /// * `deadline` - Time to stop listening at
+    /// * `crc` - CRC processor used to check the replies
+    /// * `on_status` - Called with every valid status packet, returns whether to keep listening
+    ///
+    /// # Returns
+    /// `false` if anything other than valid status packets was received
+    pub async fn collect_statuses(
+        &mut self,
+        deadline: Instant,
+        crc: &mut CrcProcessor<'_>,
+        mut on_status: impl FnMut(&StatusPacket) -> bool,
+    ) -> bool {
+        let mut buf = [0u8; COLLECT_BUFFER_SIZE];
+        let mut filled = 0;
+        let mut clean = true;
+        let mut listening = true;
+
+        while listening {
+            // A corrupted length field can claim more than the buffer holds, drop a byte to move on
+            if filled == buf.len() {
+                buf.copy_within(1.., 0);
+                filled -= 1;
+                clean = false;
+            }
+
+            let result = with_deadline(deadline, self.uart.read_until_idle(&mut buf[filled..])).await;
+            if result.is_ok() {
+                self.bus_released = Instant::now();
+            }
+            #[cfg(feature = "bus-sniffer")]
+            match &result {
+                Ok(Ok(len)) => bus_sniffer::capture(Direction::Rx, &buf[filled..filled + *len], false),
+                Ok(Err(_)) => bus_sniffer::capture(Direction::Rx, &[], true),
+                Err(_) => {}
+            }
+            match result {
+                Ok(Ok(len)) => filled += len,
+                // Framing and noise errors are what two servos talking at once looks like
+                Ok(Err(_)) => clean = false,
+                Err(_) => break,
+            }
+
+            let mut start = 0;
+            while listening && start < filled {
+                match dynamixel::parse_status(&buf[start..filled], crc) {
+                    Ok(status) => {
+                        listening = on_status(&status);
+                        start += dynamixel::packet_length(&buf[start..filled]).expect("parsed packets are complete");
+                    }
+                    Err(dynamixel::ParseError::Incomplete) => break,
+                    // Resynchronise on the next header
+                    Err(_) => {
+                        start += 1;
+                        clean = false;
+                    }
+                }
+            }
+            buf.copy_within(start..filled, 0);
+            filled -= start;
+        }
+
+        clean && filled == 0
+    }
+
+    /// Time `bytes` take on the wire at the current baud rate, with one start and one stop bit
+    fn transfer_time(&self, bytes: usize) -> Duration {
+        Duration::from_micros(10 * bytes as u64 * 1_000_000 / u64::from(self.baudrate))
+    }
+}
@@ -4,23 +4,43 @@
 //! It handles:
 //! - Register-level communication
 //! - FIFO buffer management
-//! - Interrupt handling for data ready
+//! - Interrupt handling for data ready, or polling the FIFO where the INT pin is not wired
 //! - DMA transfers for high-speed data acquisition
 //! - 1000Hz data rate configuration
+//!
+//! The ICM-20602 and ICM-20608 found on some boards share the register map closely enough to be
+//! driven the same way. The variant is told apart by WHO_AM_I during initialization, and only
+//! the temperature scaling and the FIFO size differ, see [`ImuVariant`].
 
-use crate::peripherals::spi::ImuSpi;
+use super::filter::{ComplementaryFilter, LowPass};
+use super::scale::{
+    AccelRange, GyroRange, RawImuData, ICM2060X_TEMPERATURE_SCALE, ICM20689_TEMPERATURE_SCALE, PACKET_SIZE,
+    STANDARD_GRAVITY,
+};
+use crate::liveness::{self, TaskId};
+use crate::peripherals::spi::{FrequencyError, ImuSpi};
+use crate::peripherals::system::{cycle_count, cycles_to_micros};
+use crate::supervisor::{run_supervised, Failure, RestartPolicy};
+use crate::time_sync;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_futures::select::{select, Either};
 use embassy_stm32::{
     exti::ExtiInput,
     gpio::Pull,
     peripherals::{EXTI10, PE10},
+    time::Hertz,
     Peri,
 };
-use embassy_time::{Duration, Timer};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex, signal::Signal};
+use embassy_time::{with_timeout, Duration, Instant, Ticker, Timer};
 
 /// Peripheral collection for IMU interface
+///
+/// Without both the interrupt pin and its EXTI line the driver polls the FIFO instead, see
+/// [`Icm20689::run_polled`].
 pub struct ImuPeripherals<'d> {
-    pub interrupt_pin: Peri<'d, PE10>,
-    pub interrupt_line: Peri<'d, EXTI10>,
+    pub interrupt_pin: Option<Peri<'d, PE10>>,
+    pub interrupt_line: Option<Peri<'d, EXTI10>>,
 }
 
 /// Register addresses for the ICM-20689
@@ -29,6 +49,8 @@ pub struct ImuPeripherals<'d> {
 #[repr(u8)]
 #[derive(Copy, Clone)]
 enum Register {
+    SelfTestXGyro = 0x00,
+    SelfTestXAccel = 0x0D,
     SmplrtDiv = 0x19,
     Config = 0x1A,
     GyroConfig = 0x1B,
@@ -37,6 +59,10 @@ enum Register {
     FifoEn = 0x23,
     IntPinCfg = 0x37,
     IntEnable = 0x38,
+    IntStatus = 0x3A,
+    AccelXoutH = 0x3B,
+    FifoWmTh1 = 0x60,
+    FifoWmTh2 = 0x61,
     UserCtrl = 0x6A,
     PwrMgmt1 = 0x6B,
     PwrMgmt2 = 0x6C,
@@ -45,37 +71,340 @@ enum Register {
     WhoAmI = 0x75,
 }
 
+/// Number of packets read from the FIFO at once unless [`Icm20689`] is given another count
+pub const DEFAULT_MAX_PACKETS: usize = 20;
+/// Output data rate the chip is configured for
+const SAMPLE_RATE_HZ: u32 = 1000;
+/// Time between samples at the 1000Hz output data rate
+const SAMPLE_PERIOD: Duration = Duration::from_hz(SAMPLE_RATE_HZ as u64);
+/// [`SAMPLE_PERIOD`] in seconds
+const SAMPLE_PERIOD_SECS: f32 = 1.0 / SAMPLE_RATE_HZ as f32;
+/// Relative deviation of the measured sample rate from [`SAMPLE_RATE_HZ`] that is warned about
+const SAMPLE_RATE_TOLERANCE: f32 = 0.05;
+/// Shortest statistics window the sample rate is checked over, shorter windows are dominated by
+/// samples arriving in batches
+const SAMPLE_RATE_MIN_WINDOW: Duration = Duration::from_secs(1);
+/// Polling interval used by the IMU task when the interrupt pin is not wired
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// Interval between statistics log lines until [`Icm20689::set_stats_interval`] changes it
+const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of registers in the register map, from SELF_TEST_X_GYRO (0x00) to WHO_AM_I (0x75)
+#[cfg(feature = "debug-imu")]
+pub const REGISTER_COUNT: usize = Register::WhoAmI as usize + 1;
+/// Registers per line of [`log_registers`]
+#[cfg(feature = "debug-imu")]
+const REGISTERS_PER_LINE: usize = 16;
+
+/// Failed FIFO reads retried within one batch before the error is passed on and the chip is
+/// reinitialized
+const SPI_RETRY_LIMIT: u32 = 3;
+/// Time the chip select is held high after a failed transfer before the read is retried
+const SPI_RETRY_DELAY: Duration = Duration::from_micros(100);
+
+/// Consecutive interrupts with an empty FIFO and the line still asserted before the
+/// interrupt line is treated as stuck
+const STUCK_INTERRUPT_LIMIT: u32 = 50;
+
+/// Gyroscope start-up time from enable to valid output (datasheet typ. 35ms)
+const GYRO_STARTUP_TIME: Duration = Duration::from_millis(35);
+/// Accelerometer start-up time from enable to valid output (datasheet typ. 20ms)
+const ACCEL_STARTUP_TIME: Duration = Duration::from_millis(20);
+
+/// Largest deviation of the acceleration magnitude from one g, in m/s², accepted while
+/// calibrating the gyroscope bias; anything more means the board is moving
+const CALIBRATION_ACCEL_TOLERANCE: f32 = 0.5;
+
+/// Time a gyroscope calibration may take on top of its samples, for the FIFO reset and latency
+const CALIBRATION_MARGIN: Duration = Duration::from_millis(500);
+
+/// Fraction of the accelerometer full scale that makes auto-ranging switch to a wider range
+const AUTO_RANGE_WIDEN_RATIO: f32 = 0.9;
+/// Fraction of the full scale the readings must stay below before switching to a narrower range
+///
+/// Ranges double in size, so this is 80% of the narrower range, leaving a gap to the widen
+/// threshold that stops the range from thrashing.
+const AUTO_RANGE_NARROW_RATIO: f32 = 0.4;
+/// Consecutive samples below [`AUTO_RANGE_NARROW_RATIO`] before switching to a narrower range
+const AUTO_RANGE_CALM_SAMPLES: u32 = 1000;
+
+/// Temperature in °C at which the nominal gyroscope sensitivity applies
+const GYRO_TEMP_REFERENCE_C: f32 = 25.0;
+
+/// DLPF_CFG bits in CONFIG, selecting the gyroscope (and temperature) filter
+const GYRO_DLPF_CFG: u8 = 0b0000_0001;
+/// A_DLPF_CFG bits in ACCEL_CONFIG2, selecting the accelerometer filter
+const ACCEL_DLPF_CFG: u8 = 0b0000_0001;
+/// FCHOICE_B bits in GYRO_CONFIG, which bypass the gyroscope DLPF when set
+const GYRO_CONFIG_FCHOICE_B: u8 = 0b0000_0011;
+/// Gyroscope 3dB bandwidth in Hz for each DLPF_CFG value, from the CONFIG register description
+const GYRO_DLPF_BANDWIDTH_HZ: [u16; 8] = [250, 176, 92, 41, 20, 10, 5, 3281];
+/// Accelerometer 3dB bandwidth in Hz for each A_DLPF_CFG value, from the ACCEL_CONFIG2 register
+/// description (rounded down)
+const ACCEL_DLPF_BANDWIDTH_HZ: [u16; 8] = [218, 218, 99, 44, 21, 10, 5, 420];
+
+/// Number of readings averaged for each half of the self-test
+const SELF_TEST_SAMPLES: u32 = 200;
+/// Time for the outputs to settle after switching the self-test actuation on or off
+const SELF_TEST_SETTLE_TIME: Duration = Duration::from_millis(20);
+/// XG_ST/YG_ST/ZG_ST and XA_ST/YA_ST/ZA_ST bits in GYRO_CONFIG and ACCEL_CONFIG
+const SELF_TEST_ENABLE: u8 = 0b1110_0000;
+/// DLPF setting (92Hz) required by the self-test procedure for both sensors
+const SELF_TEST_DLPF: u8 = 0b0000_0010;
+/// Gyroscope self-test limits when the factory trim code is set: the response must be at least
+/// this fraction of the factory response
+const SELF_TEST_GYRO_MIN_RATIO: f32 = 0.5;
+/// Gyroscope self-test minimum response in °/s when the factory trim code is zero
+const SELF_TEST_GYRO_MIN_DPS: f32 = 60.0;
+/// Largest gyroscope output in °/s accepted at rest with self-test disabled
+const SELF_TEST_GYRO_MAX_OFFSET_DPS: f32 = 20.0;
+/// Accelerometer self-test limits as a fraction of the factory response
+const SELF_TEST_ACCEL_RATIO: (f32, f32) = (0.5, 1.5);
+/// Accelerometer self-test response limits in g when the factory trim code is zero
+const SELF_TEST_ACCEL_LIMITS_G: (f32, f32) = (0.225, 0.675);
+
+/// INT_ENABLE/INT_STATUS bit for the raw data ready interrupt
+const INT_DATA_RDY: u8 = 0b0000_0001;
+/// INT_ENABLE/INT_STATUS bit for the FIFO overflow interrupt
+const INT_FIFO_OFLOW: u8 = 0b0001_0000;
+
+/// PWR_MGMT_1 bit that puts the chip to sleep, keeping its register configuration
+const PWR_MGMT_1_SLEEP: u8 = 0b0100_0000;
+
+/// USER_CTRL bit that disables the I2C interface (must stay set while in SPI mode)
+const USER_CTRL_I2C_DISABLE: u8 = 0b0001_0000;
+/// USER_CTRL bit that resets the FIFO (self-clearing)
+const USER_CTRL_FIFO_RST: u8 = 0b0000_0100;
+/// USER_CTRL bit that enables the FIFO
+const USER_CTRL_FIFO_EN: u8 = 0b0100_0000;
+
+/// Chip populated on the board, identified by its WHO_AM_I value
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum ImuVariant {
+    /// ICM-20689, the chip the board was designed for
+    #[default]
+    Icm20689,
+    /// ICM-20602
+    Icm20602,
+    /// ICM-20608-G
+    Icm20608,
+}
+
+impl ImuVariant {
+    /// Every variant the driver accepts
+    pub const ALL: [ImuVariant; 3] = [ImuVariant::Icm20689, ImuVariant::Icm20602, ImuVariant::Icm20608];
+
+    /// WHO_AM_I value of the variant
+    pub const fn who_am_i(self) -> u8 {
+        match self {
+            ImuVariant::Icm20689 => 0x98,
+            ImuVariant::Icm20602 => 0x12,
+            ImuVariant::Icm20608 => 0xAF,
+        }
+    }
+
+    /// Identify the chip from its WHO_AM_I value, `None` for an unknown chip
+    pub fn from_who_am_i(chip_id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|variant| variant.who_am_i() == chip_id)
+    }
+
+    /// Size of the FIFO in bytes
+    pub const fn fifo_capacity(self) -> u16 {
+        match self {
+            ImuVariant::Icm20689 => 4096,
+            ImuVariant::Icm20602 => 1008,
+            ImuVariant::Icm20608 => 512,
+        }
+    }
+
+    /// Temperature sensitivity in LSB/°C and the temperature in °C a reading of zero stands for
+    const fn temperature_scale(self) -> (f32, f32) {
+        match self {
+            ImuVariant::Icm20689 => ICM20689_TEMPERATURE_SCALE,
+            ImuVariant::Icm20602 | ImuVariant::Icm20608 => ICM2060X_TEMPERATURE_SCALE,
+        }
+    }
+}
+
+/// Number of accelerometer samples averaged per output (DEC2_CFG bits in ACCEL_CONFIG2)
+///
+/// Averaging only applies while the accelerometer runs in low-power (duty-cycled) mode. In the
+/// normal low-noise mode used for streaming, the accelerometer output goes through the DLPF
+/// selected by A_DLPF_CFG instead and this setting has no effect. More averaging lowers noise at
+/// the cost of a longer active time per sample, which also limits the maximum low-power ODR.
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 #[cfg_attr(feature = "debug", derive(defmt::Format))]
-#[allow(dead_code)]
-pub enum AccelRange {
-    G2 = 0b00 << 3,
-    G4 = 0b01 << 3,
-    G8 = 0b10 << 3,
-    G16 = 0b11 << 3,
+pub enum AccelAveraging {
+    Samples4 = 0b00 << 4,
+    Samples8 = 0b01 << 4,
+    Samples16 = 0b10 << 4,
+    Samples32 = 0b11 << 4,
 }
 
-#[repr(u8)]
+/// Behaviour of the FIFO once it is full (FIFO_MODE bit in CONFIG)
+///
+/// Neither mode keeps everything: a full FIFO always loses data, the modes only choose which end.
 #[derive(Debug, Copy, Clone)]
 #[cfg_attr(feature = "debug", derive(defmt::Format))]
-#[allow(dead_code)]
-pub enum GyroRange {
-    Dps250 = 0b00 << 3,
-    Dps500 = 0b01 << 3,
-    Dps1000 = 0b10 << 3,
-    Dps2000 = 0b11 << 3,
+pub enum FifoMode {
+    /// Overwrite the oldest data, keeping the newest samples
+    ///
+    /// Best for a real-time loop that only cares about the latest state. Packets being
+    /// overwritten while the FIFO is read can leave the stream misaligned, so an overflow should
+    /// be followed by a FIFO reset.
+    Overwrite,
+    /// Stop writing once full, keeping a contiguous window of the oldest samples
+    ///
+    /// Best for capturing an uninterrupted burst of data; everything after the FIFO fills is lost.
+    StopWhenFull,
+}
+
+impl FifoMode {
+    /// Value of the FIFO_MODE bit in the CONFIG register
+    const fn config_bits(self) -> u8 {
+        match self {
+            FifoMode::Overwrite => 0b0000_0000,
+            FifoMode::StopWhenFull => 0b0100_0000,
+        }
+    }
 }
 
 /// Macro to claim peripherals for Icm20689
+///
+/// `claim_imu!(peripherals, polled)` claims nothing, leaving PE10 and EXTI10 free for boards
+/// where the INT pin is not wired.
 #[macro_export]
 macro_rules! claim_imu {
     ($peripherals:expr) => {{
+        $crate::peripherals::claims::register($crate::peripherals::claims::Resource::Exti10);
         drivers::imu::ImuPeripherals {
-            interrupt_pin: $peripherals.PE10,
-            interrupt_line: $peripherals.EXTI10,
+            interrupt_pin: Some($peripherals.PE10),
+            interrupt_line: Some($peripherals.EXTI10),
         }
     }};
+    ($peripherals:expr, polled) => {{
+        drivers::imu::ImuPeripherals {
+            interrupt_pin: None,
+            interrupt_line: None,
+        }
+    }};
+}
+
+/// Output whose least significant bit latches the FSYNC pin (EXT_SYNC_SET bits in CONFIG)
+///
+/// The FSYNC pin must be wired to the external event source (e.g. the camera strobe), or tied to
+/// ground when unused. The pin is sampled active low, as set by FSYNC_INT_LEVEL in INT_PIN_CFG.
+/// When enabled, the chosen output loses its least significant bit of resolution to the flag.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum FsyncLatch {
+    /// FSYNC input disabled
+    Disabled = 0 << 3,
+    /// Latched into TEMP_OUT_L[0]
+    Temperature = 1 << 3,
+    /// Latched into GYRO_XOUT_L[0]
+    GyroX = 2 << 3,
+    /// Latched into GYRO_YOUT_L[0]
+    GyroY = 3 << 3,
+    /// Latched into GYRO_ZOUT_L[0]
+    GyroZ = 4 << 3,
+    /// Latched into ACCEL_XOUT_L[0]
+    AccelX = 5 << 3,
+    /// Latched into ACCEL_YOUT_L[0]
+    AccelY = 6 << 3,
+    /// Latched into ACCEL_ZOUT_L[0]
+    AccelZ = 7 << 3,
+}
+
+impl FsyncLatch {
+    /// Index of the byte carrying the FSYNC flag within a packet of the given layout
+    ///
+    /// # Returns
+    /// The index, or `None` if FSYNC is disabled or the latching output is not in the packet
+    fn packet_index(self, contents: FifoContents) -> Option<usize> {
+        match self {
+            FsyncLatch::Disabled => None,
+            FsyncLatch::Temperature => contents.temperature_offset().map(|offset| offset + 1),
+            FsyncLatch::GyroX => contents.gyro_offset().map(|offset| offset + 1),
+            FsyncLatch::GyroY => contents.gyro_offset().map(|offset| offset + 3),
+            FsyncLatch::GyroZ => contents.gyro_offset().map(|offset| offset + 5),
+            FsyncLatch::AccelX => contents.accel_offset().map(|offset| offset + 1),
+            FsyncLatch::AccelY => contents.accel_offset().map(|offset| offset + 3),
+            FsyncLatch::AccelZ => contents.accel_offset().map(|offset| offset + 5),
+        }
+    }
+}
+
+/// Sensors written to the FIFO (FIFO_EN)
+///
+/// Every FIFO packet holds the enabled sensors in register order: accelerometer (6 bytes),
+/// temperature (2 bytes) and gyroscope (6 bytes). Leaving out the temperature shrinks packets
+/// from 14 to 12 bytes, saving a seventh of the FIFO and SPI bandwidth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct FifoContents {
+    /// Accelerometer X, Y and Z
+    pub accel: bool,
+    /// Gyroscope X, Y and Z
+    pub gyro: bool,
+    /// Die temperature
+    pub temperature: bool,
+}
+
+impl FifoContents {
+    /// All sensors, the layout of the data registers
+    pub const ALL: Self = Self {
+        accel: true,
+        gyro: true,
+        temperature: true,
+    };
+
+    /// Number of bytes in each FIFO packet
+    pub const fn packet_size(self) -> usize {
+        6 * self.accel as usize + 2 * self.temperature as usize + 6 * self.gyro as usize
+    }
+
+    /// FIFO_EN bits selecting these sensors
+    const fn fifo_en_bits(self) -> u8 {
+        // TEMP_FIFO_EN (bit 7), XG/YG/ZG_FIFO_EN (bits 6-4) and ACCEL_FIFO_EN (bit 3)
+        (self.temperature as u8) << 7 | (self.gyro as u8 * 0b111) << 4 | (self.accel as u8) << 3
+    }
+
+    /// Offset of the accelerometer data within a packet, if present
+    const fn accel_offset(self) -> Option<usize> {
+        if self.accel {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// Offset of the temperature within a packet, if present
+    const fn temperature_offset(self) -> Option<usize> {
+        if self.temperature {
+            Some(6 * self.accel as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Offset of the gyroscope data within a packet, if present
+    const fn gyro_offset(self) -> Option<usize> {
+        if self.gyro {
+            Some(6 * self.accel as usize + 2 * self.temperature as usize)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for FifoContents {
+    fn default() -> Self {
+        Self::ALL
+    }
 }
 
 /// Scaled IMU sensor data in physical units
@@ -86,29 +415,591 @@ pub struct ImuData {
     pub accel: [f32; 3],
     /// Angular velocity in rad/s (X, Y, Z)
     pub gyro: [f32; 3],
-    /// Temperature in °C
+    /// Temperature in °C, zero when the temperature is left out of the FIFO
     pub temperature: f32,
+    /// Validity flags describing how far this sample can be trusted
+    pub status: ImuStatus,
+    /// The FSYNC pin was asserted since the previous sample (always false when FSYNC is disabled)
+    pub fsync: bool,
+    /// Accelerometer range the sample was measured with, which changes when auto-ranging
+    pub accel_range: AccelRange,
+    /// Counts the sample was scaled from, in the sensor frame and without any correction
+    pub raw: RawImuData,
+    /// When the sample was measured
+    ///
+    /// Samples published by [`Icm20689::run`] are stamped with the time the interrupt fired (or
+    /// the FIFO was polled) for the newest sample of a batch, and one sample period earlier for
+    /// each sample before it, so the spacing between samples stays exact when they are read in
+    /// bursts. Other reads are stamped when the data was read.
+    pub timestamp: Instant,
+    /// [`timestamp`](Self::timestamp) in host time in µs, `None` until the host has measured the
+    /// clock offset, see [`crate::time_sync`]
+    pub host_time_us: Option<i64>,
+}
+
+impl Default for ImuData {
+    fn default() -> Self {
+        Self {
+            accel: [0.0; 3],
+            gyro: [0.0; 3],
+            temperature: 0.0,
+            status: ImuStatus::default(),
+            fsync: false,
+            accel_range: AccelRange::default(),
+            raw: RawImuData::default(),
+            timestamp: Instant::from_ticks(0),
+            host_time_us: None,
+        }
+    }
+}
+
+impl ImuData {
+    /// Whether every measurement in the sample is a finite number
+    ///
+    /// A NaN or infinity fed into an integrating filter poisons its state permanently, so samples
+    /// failing this check must be rejected rather than passed on.
+    pub fn is_finite(&self) -> bool {
+        self.accel.iter().chain(self.gyro.iter()).all(|value| value.is_finite()) && self.temperature.is_finite()
+    }
+
+    /// Stamp the sample with the time it was measured, converted to host time as well once the
+    /// host has synchronized its clock
+    fn stamp(&mut self, timestamp: Instant) {
+        self.timestamp = timestamp;
+        self.host_time_us = time_sync::to_host_us(timestamp);
+    }
+}
+
+/// Per-sample validity bitfield sent alongside every [`ImuData`] frame
+///
+/// Each bit flags one aspect of sensor health so the host can decide whether to trust each field.
+/// Bits that are not set in [`ImuConfig::status_mask`] are always reported as cleared.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct ImuStatus(pub u8);
+
+impl ImuStatus {
+    /// The sample was read from an initialised IMU
+    pub const IMU_OK: u8 = 1 << 0;
+    /// Calibration offsets have been applied to the sample
+    pub const CALIBRATED: u8 = 1 << 1;
+    /// Accelerometer X axis is at full scale
+    pub const ACCEL_CLIP_X: u8 = 1 << 2;
+    /// Accelerometer Y axis is at full scale
+    pub const ACCEL_CLIP_Y: u8 = 1 << 3;
+    /// Accelerometer Z axis is at full scale
+    pub const ACCEL_CLIP_Z: u8 = 1 << 4;
+    /// Gyroscope X axis is at full scale
+    pub const GYRO_CLIP_X: u8 = 1 << 5;
+    /// Gyroscope Y axis is at full scale
+    pub const GYRO_CLIP_Y: u8 = 1 << 6;
+    /// Gyroscope Z axis is at full scale
+    pub const GYRO_CLIP_Z: u8 = 1 << 7;
+    /// Every accelerometer clipping bit
+    pub const ACCEL_CLIP: u8 = Self::ACCEL_CLIP_X | Self::ACCEL_CLIP_Y | Self::ACCEL_CLIP_Z;
+    /// Every gyroscope clipping bit
+    pub const GYRO_CLIP: u8 = Self::GYRO_CLIP_X | Self::GYRO_CLIP_Y | Self::GYRO_CLIP_Z;
+    /// Every status bit
+    pub const ALL: u8 = 0xFF;
+
+    /// Check whether any accelerometer axis clipped
+    pub const fn accel_clipped(self) -> bool {
+        self.0 & Self::ACCEL_CLIP != 0
+    }
+
+    /// Check whether any gyroscope axis clipped
+    pub const fn gyro_clipped(self) -> bool {
+        self.0 & Self::GYRO_CLIP != 0
+    }
+
+    /// Build the clipping bits for a set of raw axis readings
+    ///
+    /// A reading is clipped when it sits at either end of the signed 16-bit ADC range, which is
+    /// what the ICM-20689 outputs for anything at or beyond the configured full-scale range.
+    /// `x_bit` is the bit for the X axis; Y and Z use the next two bits.
+    fn clipping(raw: &[i16; 3], x_bit: u8) -> u8 {
+        let mut bits = 0;
+        for (axis, &value) in raw.iter().enumerate() {
+            if value == i16::MAX || value == i16::MIN {
+                bits |= x_bit << axis;
+            }
+        }
+        bits
+    }
+}
+
+/// Axis of the sensor frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+/// Sensor axis that one body frame axis is read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct AxisMap {
+    /// Sensor axis lying along the body axis
+    pub source: Axis,
+    /// The sensor axis points the opposite way to the body axis
+    pub negate: bool,
+}
+
+/// Rotation from the sensor frame into the robot body frame
+///
+/// The sensor is mounted in 90° steps relative to the body, so the rotation is a signed
+/// permutation: each body axis is one sensor axis, possibly negated. [`AxisRemap::new`] only
+/// accepts remaps that use every sensor axis once and keep the frame right-handed, so a typo
+/// cannot silently zero or mirror an axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct AxisRemap([AxisMap; 3]);
+
+impl AxisRemap {
+    /// Sensor and body frames are the same
+    pub const IDENTITY: Self = Self([
+        AxisMap {
+            source: Axis::X,
+            negate: false,
+        },
+        AxisMap {
+            source: Axis::Y,
+            negate: false,
+        },
+        AxisMap {
+            source: Axis::Z,
+            negate: false,
+        },
+    ]);
+
+    /// Build a remap from the sensor axis each body axis is read from
+    ///
+    /// # Arguments
+    /// * `axes` - Source of the body X, Y and Z axes
+    ///
+    /// # Returns
+    /// The remap, or `None` if a sensor axis is used twice or the remap mirrors the frame
+    pub const fn new(axes: [AxisMap; 3]) -> Option<Self> {
+        let (x, y, z) = (
+            axes[0].source as usize,
+            axes[1].source as usize,
+            axes[2].source as usize,
+        );
+        if x == y || y == z || x == z {
+            return None;
+        }
+
+        // A rotation has determinant +1, which is the permutation's sign times every negation
+        let mut mirrored = y != (x + 1) % 3; // Only the cyclic permutations are even
+        mirrored ^= axes[0].negate;
+        mirrored ^= axes[1].negate;
+        mirrored ^= axes[2].negate;
+        if mirrored {
+            None
+        } else {
+            Some(Self(axes))
+        }
+    }
+
+    /// Source of the body X, Y and Z axes
+    pub const fn axes(&self) -> [AxisMap; 3] {
+        self.0
+    }
+
+    /// Rotate a sensor frame vector into the body frame
+    fn apply(&self, vector: [f32; 3]) -> [f32; 3] {
+        self.0.map(|axis| {
+            let value = vector[axis.source as usize];
+            if axis.negate {
+                -value
+            } else {
+                value
+            }
+        })
+    }
+
+    /// Move the three per-axis status bits starting at `x_bit` from sensor to body axes
+    fn apply_bits(&self, bits: u8, x_bit: u8) -> u8 {
+        let mut remapped = 0;
+        for (body, axis) in self.0.iter().enumerate() {
+            if bits & (x_bit << axis.source as u8) != 0 {
+                remapped |= x_bit << body;
+            }
+        }
+        remapped
+    }
 }
 
 /// IMU configuration for the ICM-20689
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "debug", derive(defmt::Format))]
 pub struct ImuConfig {
-    /// Accelerometer full-scale range
+    /// Accelerometer full-scale range, the narrowest range used when auto-ranging
     pub accel_range: AccelRange,
+    /// Temporarily widen the accelerometer range while readings approach full scale
+    pub accel_auto_range: bool,
     /// Gyroscope full-scale range
     pub gyro_range: GyroRange,
+    /// Which [`ImuStatus`] bits are reported with each sample
+    pub status_mask: u8,
+    /// What the FIFO does once it is full
+    pub fifo_mode: FifoMode,
+    /// Accelerometer averaging used in low-power mode
+    pub accel_averaging: AccelAveraging,
+    /// Samples discarded after initialization while the sensor output settles
+    pub startup_discard_samples: u16,
+    /// Where the external frame-sync input is latched
+    pub fsync: FsyncLatch,
+    /// Longest allowed time in µs from data-ready to the sample being published, `None` to
+    /// disable the check
+    ///
+    /// The time is counted from the IMU task waking on data-ready, not from the interrupt itself,
+    /// so time the executor takes to run the task after the interrupt is not included.
+    pub latency_deadline_us: Option<u32>,
+    /// Whether the interrupt fires per sample or per batch of FIFO packets
+    pub interrupt_mode: InterruptMode,
+    /// Gyroscope sensitivity drift per axis in ppm/°C relative to [`GYRO_TEMP_REFERENCE_C`],
+    /// zero to disable temperature compensation, which also needs the temperature in the FIFO
+    pub gyro_temp_coefficients_ppm: [f32; 3],
+    /// Sensors included in every FIFO packet
+    pub fifo_contents: FifoContents,
+    /// Rotation applied to the accelerometer and gyroscope to report them in the body frame
+    pub axis_remap: AxisRemap,
+    /// Cutoff in Hz of the low-pass filter run on the accelerometer and gyroscope before samples
+    /// are published, `None` to publish them unfiltered
+    pub low_pass_cutoff_hz: Option<f32>,
+    /// Gyroscope weight of the roll and pitch estimate run on the published samples, from 0 to 1,
+    /// `None` to not estimate them
+    pub attitude_alpha: Option<f32>,
 }
 
 impl Default for ImuConfig {
     fn default() -> Self {
         Self {
             accel_range: AccelRange::G4,
+            accel_auto_range: false,
             gyro_range: GyroRange::Dps500,
+            status_mask: ImuStatus::ALL,
+            fifo_mode: FifoMode::Overwrite,
+            accel_averaging: AccelAveraging::Samples4,
+            startup_discard_samples: 50,
+            fsync: FsyncLatch::Disabled,
+            latency_deadline_us: Some(1000),
+            interrupt_mode: InterruptMode::DataReady,
+            gyro_temp_coefficients_ppm: [0.0; 3],
+            fifo_contents: FifoContents::ALL,
+            axis_remap: AxisRemap::IDENTITY,
+            low_pass_cutoff_hz: None,
+            attitude_alpha: Some(0.98),
         }
     }
 }
 
+impl ImuConfig {
+    /// Check the configuration can be applied
+    ///
+    /// # Arguments
+    /// * `max_packets` - Most packets the driver reads in one batch, which bounds the watermark
+    ///
+    /// # Returns
+    /// Success, or [`ImuError::InvalidConfig`] if the FIFO holds no sensors, the FSYNC flag is
+    /// latched into an output left out of the FIFO, the FIFO watermark is out of range, the
+    /// low-pass cutoff is not between zero and half the sample rate, or the attitude weight is not
+    /// between 0 and 1
+    fn validate(&self, max_packets: usize) -> Result<(), ImuError> {
+        let contents = self.fifo_contents;
+        if contents.packet_size() == 0 {
+            return Err(ImuError::InvalidConfig);
+        }
+        if !matches!(self.fsync, FsyncLatch::Disabled) && self.fsync.packet_index(contents).is_none() {
+            return Err(ImuError::InvalidConfig);
+        }
+        self.interrupt_mode
+            .watermark_bytes(contents.packet_size(), max_packets)?;
+        if self
+            .low_pass_cutoff_hz
+            .is_some_and(|cutoff| !(cutoff > 0.0 && cutoff < SAMPLE_RATE_HZ as f32 / 2.0))
+        {
+            return Err(ImuError::InvalidConfig);
+        }
+        if self.attitude_alpha.is_some() && self.attitude().is_none() {
+            return Err(ImuError::InvalidConfig);
+        }
+        Ok(())
+    }
+
+    /// Low-pass filters for the accelerometer and gyroscope, `None` when filtering is disabled
+    fn low_pass(&self) -> Option<[LowPass; 2]> {
+        self.low_pass_cutoff_hz
+            .map(|cutoff| [LowPass::new(cutoff, SAMPLE_RATE_HZ as f32); 2])
+    }
+
+    /// Roll and pitch estimator, `None` when disabled or the weight is out of range
+    fn attitude(&self) -> Option<ComplementaryFilter> {
+        self.attitude_alpha.and_then(ComplementaryFilter::new)
+    }
+}
+
+/// Noise statistics for a single sensor axis
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct AxisNoise {
+    /// Mean of the captured samples (the static offset)
+    pub mean: f32,
+    /// Standard deviation of the captured samples
+    pub std_dev: f32,
+    /// Allan deviation at a cluster time of one sample period
+    pub allan_dev: f32,
+}
+
+/// Result of a stationary noise capture
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct NoiseReport {
+    /// Number of samples the statistics were computed over
+    pub samples: u32,
+    /// Accelerometer statistics in m/s² (X, Y, Z)
+    pub accel: [AxisNoise; 3],
+    /// Gyroscope statistics in rad/s (X, Y, Z)
+    pub gyro: [AxisNoise; 3],
+}
+
+/// Running noise statistics for a single axis
+///
+/// Mean and variance use Welford's algorithm so the capture can run for any number of samples
+/// without storing them. The Allan variance at one sample period is half the mean squared
+/// difference between consecutive samples.
+#[derive(Clone, Copy, Default)]
+struct NoiseAccumulator {
+    count: u32,
+    mean: f32,
+    m2: f32,
+    last: f32,
+    diff_sq_sum: f32,
+}
+
+impl NoiseAccumulator {
+    fn add(&mut self, value: f32) {
+        if self.count > 0 {
+            let diff = value - self.last;
+            self.diff_sq_sum += diff * diff;
+        }
+        self.last = value;
+        self.count += 1;
+
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    fn finish(&self) -> AxisNoise {
+        if self.count < 2 {
+            return AxisNoise {
+                mean: self.mean,
+                ..AxisNoise::default()
+            };
+        }
+        AxisNoise {
+            mean: self.mean,
+            std_dev: libm::sqrtf(self.m2 / (self.count - 1) as f32),
+            allan_dev: libm::sqrtf(self.diff_sq_sum / (2.0 * (self.count - 1) as f32)),
+        }
+    }
+}
+
+/// Per-axis outcome of [`Icm20689::self_test`]
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct SelfTestResult {
+    /// Accelerometer axes that passed (X, Y, Z)
+    pub accel: [bool; 3],
+    /// Gyroscope axes that passed (X, Y, Z)
+    pub gyro: [bool; 3],
+}
+
+impl SelfTestResult {
+    /// Whether every axis passed
+    pub fn passed(&self) -> bool {
+        self.accel.iter().chain(self.gyro.iter()).all(|&pass| pass)
+    }
+}
+
+/// Factory self-test response in LSB for a trim code, at ±250°/s or ±2g full scale
+///
+/// ST_OTP = 2620 * 1.01^(code - 1), from the InvenSense self-test procedure.
+fn self_test_factory_response(code: u8) -> f32 {
+    2620.0 * libm::powf(1.01, f32::from(code) - 1.0)
+}
+
+/// Number of samples the [`ImuChannel`] buffers before the oldest is dropped
+pub const IMU_CHANNEL_DEPTH: usize = 16;
+
+/// Channel the IMU task publishes every parsed sample into
+pub type ImuChannel = Channel<CriticalSectionRawMutex, ImuData, IMU_CHANNEL_DEPTH>;
+
+/// Set by the IMU task when a sample misses [`ImuConfig::latency_deadline_us`]
+///
+/// A delay in running the task after the interrupt does not set it, see the deadline.
+///
+/// Consumers such as the control loop read and clear it with `swap(false, ..)`.
+pub static IMU_DEADLINE_MISSED: AtomicBool = AtomicBool::new(false);
+
+/// Request sent to the running IMU task from another task (e.g. the debug shell)
+#[derive(Debug, Clone, Copy)]
+pub enum ImuRequest {
+    /// Report the most recent sample and the SPI clock
+    Status,
+    /// Read a single register from the chip
+    ReadRegister(u8),
+    /// Take a one-shot reading from the data registers, bypassing the FIFO
+    ReadOnce,
+    /// Report the configuration the driver is running with
+    Config,
+    /// Apply a new configuration, see [`Icm20689::set_config`]
+    SetConfig(ImuConfig),
+    /// Change the statistics log interval, `None` stops logging them
+    SetStatsInterval(Option<Duration>),
+    /// Change the SPI clock to the given frequency in Hz
+    SetSpiFrequency(u32),
+    /// Measure the gyroscope bias over the given number of samples while the board is still, see
+    /// [`Icm20689::calibrate_gyro_bias`]
+    CalibrateGyro(u16),
+    /// Stop subtracting the gyroscope bias, see [`Icm20689::clear_bias`]
+    ClearGyroBias,
+    /// Measure the sensor noise over the given number of samples while the board is still, see
+    /// [`Icm20689::measure_noise`]
+    MeasureNoise(u16),
+    /// Run the built-in self-test while the board is still, see [`Icm20689::self_test`]
+    SelfTest,
+}
+
+/// Response from the IMU task to an [`ImuRequest`]
+#[derive(Debug, Clone, Copy)]
+pub enum ImuResponse {
+    /// Most recent sample, its roll and pitch estimate in radians, FIFO fill level in percent, stuck
+    /// interrupt recoveries, the SPI clock in Hz and the sample rate in Hz measured over the last
+    /// statistics window
+    Status {
+        latest: ImuData,
+        attitude: Option<(f32, f32)>,
+        fifo_fill_percent: u8,
+        stuck_interrupt_recoveries: u32,
+        spi_frequency: u32,
+        sample_rate_hz: Option<f32>,
+    },
+    /// Register address and the value read from it
+    Register { address: u8, value: u8 },
+    /// Sample read directly from the data registers
+    Sample(ImuData),
+    /// Configuration the driver is running with, the SPI clock in Hz, the chip found and the
+    /// (accelerometer, gyroscope) filter bandwidth in Hz
+    Config {
+        config: ImuConfig,
+        spi_frequency: u32,
+        variant: ImuVariant,
+        bandwidth_hz: (u16, u16),
+    },
+    /// Statistics log interval now in effect
+    StatsInterval(Option<Duration>),
+    /// SPI clock in Hz now in effect
+    SpiFrequency(u32),
+    /// Gyroscope bias in rad/s now subtracted from every sample
+    GyroBias([f32; 3]),
+    /// Noise statistics of a stationary capture
+    Noise(NoiseReport),
+    /// Per-axis outcome of the self-test
+    SelfTest(SelfTestResult),
+    /// The request could not be completed
+    Error(ImuError),
+}
+
+/// Requests for the IMU task, serviced between FIFO reads
+pub static IMU_REQUESTS: Channel<CriticalSectionRawMutex, ImuRequest, 1> = Channel::new();
+/// Responses from the IMU task, one per request
+pub static IMU_RESPONSES: Channel<CriticalSectionRawMutex, ImuResponse, 1> = Channel::new();
+
+/// Held by the task waiting on [`IMU_RESPONSES`], so each response goes to the task that asked
+static REQUESTER: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+/// Send a request to the IMU task and wait for its response
+///
+/// Tasks sending requests at the same time take turns, so none of them takes another's response.
+///
+/// # Arguments
+/// * `request` - The request
+/// * `timeout` - Time allowed for the response, longer for requests that capture samples
+///
+/// # Returns
+/// The response, or `None` if the IMU task is not servicing requests (e.g. while it restarts) or
+/// did not answer within `timeout`
+pub async fn request(request: ImuRequest, timeout: Duration) -> Option<ImuResponse> {
+    let _requester = REQUESTER.lock().await;
+
+    // Discard any reply to an earlier request that timed out
+    while IMU_RESPONSES.try_receive().is_ok() {}
+
+    IMU_REQUESTS.try_send(request).ok()?;
+    with_timeout(timeout, IMU_RESPONSES.receive()).await.ok()
+}
+
+/// What makes the IMU raise its interrupt line
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum InterruptMode {
+    /// Interrupt on every new sample (1000Hz), for the lowest latency
+    DataReady,
+    /// Interrupt once the FIFO holds this many packets, read together as one batch
+    ///
+    /// Cuts wakeups and SPI transactions by the batch size, at the cost of up to that many sample
+    /// periods of extra latency for the oldest sample. Must be between 1 and the most packets read
+    /// in one batch, [`DEFAULT_MAX_PACKETS`] unless the driver is built with another count.
+    FifoWatermark(u16),
+}
+
+impl InterruptMode {
+    /// FIFO_WM_TH value in bytes, zero for data-ready mode, which disables the watermark interrupt
+    ///
+    /// # Arguments
+    /// * `packet_size` - Size of each FIFO packet in bytes, see [`FifoContents::packet_size`]
+    /// * `max_packets` - Most packets the driver reads in one batch
+    ///
+    /// # Returns
+    /// The threshold, or [`ImuError::InvalidConfig`] if the packet count is out of range
+    fn watermark_bytes(self, packet_size: usize, max_packets: usize) -> Result<u16, ImuError> {
+        match self {
+            InterruptMode::DataReady => Ok(0),
+            InterruptMode::FifoWatermark(packets) if (1..=max_packets as u16).contains(&packets) => {
+                Ok(packets * packet_size as u16)
+            }
+            InterruptMode::FifoWatermark(_) => Err(ImuError::InvalidConfig),
+        }
+    }
+
+    /// INT_ENABLE bits for the per-sample interrupt of this mode
+    const fn int_enable_bits(self) -> u8 {
+        match self {
+            InterruptMode::DataReady => INT_DATA_RDY,
+            // The watermark interrupt is enabled by a non-zero FIFO_WM_TH, not by INT_ENABLE
+            InterruptMode::FifoWatermark(_) => 0,
+        }
+    }
+}
+
+/// Power state requested from the running IMU task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum ImuPower {
+    /// Stop sampling and put the sensor to sleep
+    Sleep,
+    /// Wake the sensor and resume sampling
+    Wake,
+}
+
+/// Signal a power manager uses to park and resume the IMU task, see [`Icm20689::run`]
+pub static IMU_POWER: Signal<CriticalSectionRawMutex, ImuPower> = Signal::new();
+
 /// IMU driver errors
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "debug", derive(defmt::Format))]
@@ -117,6 +1008,17 @@ pub enum ImuError {
     SpiError,
     /// Device not found or wrong chip ID
     DeviceNotFound,
+    /// Requested configuration is outside what the hardware supports
+    InvalidConfig,
+    /// The board moved during calibration
+    CalibrationFailed,
+}
+
+impl Failure for ImuError {
+    /// A missing or wrong chip will not appear by retrying quickly, while SPI errors are glitches
+    fn is_permanent(&self) -> bool {
+        matches!(self, ImuError::DeviceNotFound)
+    }
 }
 
 impl From<embassy_stm32::spi::Error> for ImuError {
@@ -125,31 +1027,183 @@ impl From<embassy_stm32::spi::Error> for ImuError {
     }
 }
 
+impl From<FrequencyError> for ImuError {
+    fn from(_: FrequencyError) -> Self {
+        ImuError::InvalidConfig
+    }
+}
+
+/// Scale a packet in the data register layout to physical units
+///
+/// This is the pure conversion behind [`Icm20689::parse_fifo_packet`], which then applies the
+/// configured temperature compensation, gyroscope bias, status mask and FSYNC latch. The
+/// arithmetic itself is in [`RawImuData::scale`], whose tests run on the host.
+///
+/// # Arguments
+/// * `packet` - [accel_x_h, accel_x_l, accel_y_h, accel_y_l, accel_z_h, accel_z_l, temp_h,
+///   temp_l, gyro_x_h, gyro_x_l, gyro_y_h, gyro_y_l, gyro_z_h, gyro_z_l], as in the data
+///   registers and a FIFO packet with all sensors enabled
+/// * `accel_range` - Accelerometer range the packet was measured with
+/// * `gyro_range` - Gyroscope range the packet was measured with
+/// * `variant` - Chip the packet came from, which sets the temperature scaling
+///
+/// # Returns
+/// The sample in m/s², rad/s and °C, with only [`ImuStatus::IMU_OK`] and the clipping flags set
+/// and a zero timestamp
+pub fn scale_packet(
+    packet: &[u8; PACKET_SIZE],
+    accel_range: AccelRange,
+    gyro_range: GyroRange,
+    variant: ImuVariant,
+) -> ImuData {
+    let raw = RawImuData::from_registers(packet);
+    let scaled = raw.scale(accel_range, gyro_range, variant.temperature_scale());
+
+    // Flag any axis sitting at the full-scale limit so the host knows its value is a lower bound
+    let status = ImuStatus::IMU_OK
+        | ImuStatus::clipping(&raw.accel, ImuStatus::ACCEL_CLIP_X)
+        | ImuStatus::clipping(&raw.gyro, ImuStatus::GYRO_CLIP_X);
+
+    ImuData {
+        accel: scaled.accel,
+        gyro: scaled.gyro,
+        temperature: scaled.temperature,
+        status: ImuStatus(status),
+        fsync: false,
+        accel_range,
+        raw,
+        timestamp: Instant::from_ticks(0),
+        host_time_us: None,
+    }
+}
+
+/// Rebuild the data register layout from a packet with the given contents, leaving absent sensors
+/// at zero
+fn register_layout(packet: &[u8], contents: FifoContents) -> [u8; PACKET_SIZE] {
+    let mut registers = [0u8; PACKET_SIZE];
+    if let Some(offset) = contents.accel_offset() {
+        registers[0..6].copy_from_slice(&packet[offset..offset + 6]);
+    }
+    if let Some(offset) = contents.temperature_offset() {
+        registers[6..8].copy_from_slice(&packet[offset..offset + 2]);
+    }
+    if let Some(offset) = contents.gyro_offset() {
+        registers[8..14].copy_from_slice(&packet[offset..offset + 6]);
+    }
+    registers
+}
+
+/// Log a register dump from [`Icm20689::dump_registers`] as a table, one line per 16 registers
+/// labelled with the address of the first
+#[cfg(feature = "debug-imu")]
+pub fn log_registers(registers: &[u8; REGISTER_COUNT]) {
+    for (line, values) in registers.chunks(REGISTERS_PER_LINE).enumerate() {
+        defmt::info!("IMU registers 0x{:02X}: {=[u8]:02X}", line * REGISTERS_PER_LINE, values);
+    }
+}
+
+/// Counters the acquisition loop collects over one statistics window
+#[derive(Default)]
+struct RunStats {
+    /// Samples published
+    sample_count: u32,
+    /// Samples with a clipped accelerometer or gyroscope axis
+    clipped_count: u32,
+    /// Samples dropped for holding a non-finite value
+    rejected_count: u32,
+    /// Highest FIFO fill level in percent after a batch read
+    peak_fifo_fill: u8,
+    /// Longest time from data-ready until the newest sample was published
+    peak_latency_us: u32,
+    /// Batches that missed [`ImuConfig::latency_deadline_us`]
+    deadline_misses: u32,
+}
+
 /// ICM-20689 driver for interfacing with the IMU chip
-pub struct Icm20689<'d> {
+///
+/// `MAX_PACKETS` sets how many FIFO packets one batch read drains, which sizes the buffer the
+/// read loops keep on the stack (14 bytes per packet). A larger count lets a watermark interrupt
+/// or a slow poll drain more per wakeup, a smaller one saves RAM.
+pub struct Icm20689<'d, const MAX_PACKETS: usize = DEFAULT_MAX_PACKETS> {
     /// SPI interface to the chip (includes chip select)
     spi: ImuSpi<'d>,
-    /// Interrupt pin from the chip
-    interrupt: ExtiInput<'d>,
+    /// Chip found by the last initialization
+    variant: ImuVariant,
+    /// Interrupt pin from the chip, `None` when the FIFO is polled
+    interrupt: Option<ExtiInput<'d>>,
     /// Current chip configuration
     config: ImuConfig,
+    /// FIFO byte count seen by the most recent batch read
+    fifo_level: u16,
+    /// Consecutive interrupts that found the FIFO empty with the line still asserted
+    empty_interrupts: u32,
+    /// Number of times the chip was reset to recover a stuck interrupt line
+    stuck_interrupt_recoveries: u32,
+    /// Number of FIFO overflows detected and recovered from
+    fifo_overflows: u32,
+    /// Number of failed FIFO reads recovered by retrying
+    spi_recoveries: u32,
+    /// Accelerometer range currently programmed into the chip
+    accel_range: AccelRange,
+    /// Consecutive samples that stayed below the auto-ranging narrow threshold
+    auto_range_calm_samples: u32,
+    /// Gyroscope bias in rad/s subtracted from every sample, once calibrated
+    gyro_bias: Option<[f32; 3]>,
+    /// Interval between statistics log lines, `None` when statistics are not logged
+    stats_interval: Option<Duration>,
+    /// Sample rate in Hz measured over the last statistics window, `None` before the first one
+    measured_rate_hz: Option<f32>,
+    /// Accelerometer and gyroscope low-pass filters, `None` when samples are published unfiltered
+    low_pass: Option<[LowPass; 2]>,
+    /// Roll and pitch estimate from the published samples, `None` when disabled
+    attitude: Option<ComplementaryFilter>,
 }
 
-impl<'d> Icm20689<'d> {
-    /// Create a new ICM-20689 driver instance
+impl<'d, const MAX_PACKETS: usize> Icm20689<'d, MAX_PACKETS> {
+    /// Longest polling interval whose samples still fit in one batch read, one packet arriving
+    /// every millisecond
+    pub const MAX_POLL_INTERVAL: Duration = Duration::from_millis(MAX_PACKETS as u64);
+
+    /// Create a new ICM-20689 driver instance with the default configuration
     ///
     /// # Arguments
     /// * `spi` - Configured SPI peripheral for communication (includes chip select)
     /// * `imu_peripherals` - IMU peripheral collection for interrupt handling
     pub fn new(spi: ImuSpi<'d>, imu_peripherals: ImuPeripherals<'d>) -> Self {
+        Self::new_with_config(spi, imu_peripherals, ImuConfig::default())
+    }
+
+    /// Create a new ICM-20689 driver instance with the given configuration
+    ///
+    /// The configuration is written to the chip when the driver initializes it.
+    ///
+    /// # Arguments
+    /// * `spi` - Configured SPI peripheral for communication (includes chip select)
+    /// * `imu_peripherals` - IMU peripheral collection for interrupt handling
+    /// * `config` - Ranges, filters and FIFO behaviour to use
+    pub fn new_with_config(spi: ImuSpi<'d>, imu_peripherals: ImuPeripherals<'d>, config: ImuConfig) -> Self {
+        const { assert!(MAX_PACKETS > 0, "a batch read needs room for at least one packet") };
+        let interrupt = imu_peripherals
+            .interrupt_pin
+            .zip(imu_peripherals.interrupt_line)
+            .map(|(pin, line)| ExtiInput::new(pin, line, Pull::None));
         Self {
             spi,
-            interrupt: ExtiInput::new(
-                imu_peripherals.interrupt_pin,
-                imu_peripherals.interrupt_line,
-                Pull::None,
-            ),
-            config: ImuConfig::default(),
+            variant: ImuVariant::default(),
+            interrupt,
+            config,
+            fifo_level: 0,
+            empty_interrupts: 0,
+            stuck_interrupt_recoveries: 0,
+            fifo_overflows: 0,
+            spi_recoveries: 0,
+            accel_range: config.accel_range,
+            auto_range_calm_samples: 0,
+            gyro_bias: None,
+            stats_interval: Some(DEFAULT_STATS_INTERVAL),
+            measured_rate_hz: None,
+            low_pass: config.low_pass(),
+            attitude: config.attitude(),
         }
     }
 
@@ -160,8 +1214,34 @@ impl<'d> Icm20689<'d> {
     /// is asserted, indicating that data is ready for acquisition. It resumes execution once the interrupt
     /// is detected. If called when no interrupt is pending, it will await until the next data-ready event.
     /// This is typically used to synchronize data reads with the IMU's output data rate (e.g., 1000Hz).
+    ///
+    /// Without an interrupt pin this waits one sample period instead.
     pub async fn wait_for_interrupt(&mut self) {
-        self.interrupt.wait_for_low().await;
+        match &mut self.interrupt {
+            Some(interrupt) => interrupt.wait_for_low().await,
+            None => Timer::after(SAMPLE_PERIOD).await,
+        }
+    }
+
+    /// Whether the driver was given the interrupt pin, otherwise it must be run with
+    /// [`run_polled`](Self::run_polled)
+    pub fn has_interrupt(&self) -> bool {
+        self.interrupt.is_some()
+    }
+
+    /// Current SPI clock frequency used to talk to the chip
+    pub fn spi_frequency(&self) -> Hertz {
+        self.spi.frequency()
+    }
+
+    /// Change the SPI clock frequency without reinitializing the chip
+    ///
+    /// Useful for finding the fastest reliable clock on a new board layout. The change takes
+    /// effect from the next register access; the chip configuration and FIFO are untouched.
+    pub fn set_spi_frequency(&mut self, frequency: Hertz) -> Result<(), ImuError> {
+        self.spi.set_frequency(frequency)?;
+        defmt::info!("IMU SPI clock set to {} Hz", frequency.0);
+        Ok(())
     }
 
     /// Read the current FIFO count
@@ -174,75 +1254,198 @@ impl<'d> Icm20689<'d> {
         Ok(u16::from_be_bytes(fifo_count))
     }
 
-    /// Read a batch of sensor data from FIFO
+    /// Read a batch of sensor data from FIFO
+    ///
+    /// Each packet holds the sensors selected by [`ImuConfig::fifo_contents`], 14 bytes when all
+    /// are enabled: 6 bytes accel + 2 bytes temp + 6 bytes gyro
+    ///
+    /// A failed SPI transfer is retried up to [`SPI_RETRY_LIMIT`] times, so an occasional bus
+    /// glitch does not reinitialize the chip. The failed transfer may already have taken part of
+    /// a packet out of the FIFO, so the FIFO is reset before every retry to realign it, dropping
+    /// the samples it held.
+    pub async fn read_fifo_batch(&mut self, buffer: &mut [u8]) -> Result<usize, ImuError> {
+        let mut failures = 0;
+        loop {
+            let realigned = if failures > 0 { self.reset_fifo().await } else { Ok(()) };
+            let result = match realigned {
+                Ok(()) => self.read_fifo_packets(buffer).await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Err(ImuError::SpiError) if failures < SPI_RETRY_LIMIT => {
+                    failures += 1;
+                    self.spi_recoveries += 1;
+                    defmt::warn!("IMU SPI error, retrying FIFO read (attempt {})", failures);
+                    // Deassert the chip select so the chip's SPI interface starts afresh with the
+                    // next transaction, a failed transfer can leave it asserted
+                    self.spi.cs.set_high();
+                    Timer::after(SPI_RETRY_DELAY).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Read the whole packets in the FIFO that fit in `buffer`, see
+    /// [`read_fifo_batch`](Self::read_fifo_batch)
+    async fn read_fifo_packets(&mut self, buffer: &mut [u8]) -> Result<usize, ImuError> {
+        let packet_size = self.packet_size();
+        let fifo_count = self.read_fifo_count().await?;
+        self.fifo_level = fifo_count;
+        // Only read whole packets so the next read still starts on a packet boundary
+        let bytes_to_read = core::cmp::min(buffer.len(), fifo_count as usize) / packet_size * packet_size;
+
+        if bytes_to_read == 0 {
+            return Ok(0);
+        }
+
+        // Read data from FIFO register using burst read
+        self.read_fifo_data(&mut buffer[..bytes_to_read]).await?;
+        Ok(bytes_to_read)
+    }
+
+    /// FIFO fill level seen by the most recent batch read, as a percentage of the capacity of
+    /// the [`ImuVariant`] found
+    ///
+    /// A level that stays high means the read loop is falling behind the sample rate and the
+    /// FIFO is heading for an overflow.
+    pub fn fifo_fill_percent(&self) -> u8 {
+        let capacity = self.variant.fifo_capacity();
+        (u32::from(self.fifo_level.min(capacity)) * 100 / u32::from(capacity)) as u8
+    }
+
+    /// Check INT_STATUS for a FIFO overflow and reset the FIFO if one occurred
+    ///
+    /// Once the FIFO overflows, reads no longer start on a packet boundary and every following
+    /// packet would be misaligned, so everything buffered is discarded. Reading INT_STATUS also
+    /// clears the latched interrupt.
+    ///
+    /// # Returns
+    /// `true` if the FIFO overflowed and was reset
+    async fn check_fifo_overflow(&mut self) -> Result<bool, ImuError> {
+        let int_status = self.spi.read_register(Register::IntStatus as u8).await?;
+        if int_status & INT_FIFO_OFLOW == 0 {
+            return Ok(false);
+        }
+
+        self.fifo_overflows += 1;
+        defmt::warn!("IMU FIFO overflow, resetting FIFO (overflow #{})", self.fifo_overflows);
+        crate::ring_log!("IMU FIFO overflow #{}", self.fifo_overflows);
+        self.reset_fifo().await?;
+        Ok(true)
+    }
+
+    /// Check for an interrupt line that is stuck asserted after a FIFO read
+    ///
+    /// The interrupt is configured to clear on any register read, so after reading FIFO_COUNT
+    /// the line should be released. After an electrical transient the chip can latch the line low,
+    /// which makes [`wait_for_interrupt`](Self::wait_for_interrupt) return immediately and turns
+    /// the read loop into a busy spin over an empty FIFO. When this pattern repeats
+    /// [`STUCK_INTERRUPT_LIMIT`] times in a row the chip is fully reset and INT_STATUS cleared.
+    ///
+    /// # Arguments
+    /// * `bytes_read` - Number of bytes returned by the preceding FIFO batch read
+    async fn check_stuck_interrupt(&mut self, bytes_read: usize) -> Result<(), ImuError> {
+        if bytes_read > 0 || self.interrupt.as_ref().is_none_or(|interrupt| interrupt.is_high()) {
+            self.empty_interrupts = 0;
+            return Ok(());
+        }
+
+        self.empty_interrupts += 1;
+        if self.empty_interrupts < STUCK_INTERRUPT_LIMIT {
+            return Ok(());
+        }
+
+        self.empty_interrupts = 0;
+        self.stuck_interrupt_recoveries += 1;
+        defmt::warn!(
+            "IMU interrupt line stuck asserted with an empty FIFO, resetting chip (recovery #{})",
+            self.stuck_interrupt_recoveries
+        );
+        crate::ring_log!("IMU stuck interrupt recovery #{}", self.stuck_interrupt_recoveries);
+
+        self.initialize().await?;
+        // Reading INT_STATUS clears any interrupt latched during the reset
+        self.spi.read_register(Register::IntStatus as u8).await?;
+        Ok(())
+    }
+
+    /// Read the accelerometer, temperature and gyroscope data registers directly
     ///
-    /// Each packet contains 14 bytes: 6 bytes accel + 2 bytes temp + 6 bytes gyro
-    pub async fn read_fifo_batch(&mut self, buffer: &mut [u8]) -> Result<usize, ImuError> {
-        // Each packet contains 14 bytes: 6 bytes accel + 2 bytes temp + 6 bytes gyro
-        let fifo_count = self.read_fifo_count().await?;
-        let bytes_to_read = core::cmp::min(buffer.len(), fifo_count as usize);
-
-        if bytes_to_read == 0 {
-            return Ok(0);
-        }
+    /// ACCEL_XOUT_H..GYRO_ZOUT_L are read in a single burst, which share the FIFO packet layout,
+    /// and scaled with [`parse_fifo_packet`](Self::parse_fifo_packet). The FIFO is neither read
+    /// nor required to be enabled, so this is usable for diagnostics alongside the FIFO path.
+    pub async fn read_accel_gyro_once(&mut self) -> Result<ImuData, ImuError> {
+        let mut packet = [0u8; PACKET_SIZE];
+        self.spi
+            .read_register_burst(Register::AccelXoutH as u8, &mut packet)
+            .await?;
+        Ok(self.parse_packet(&packet, FifoContents::ALL))
+    }
 
-        // Read data from FIFO register using burst read
-        self.read_fifo_data(&mut buffer[..bytes_to_read]).await?;
-        Ok(bytes_to_read)
+    /// Size of each FIFO packet with the active [`ImuConfig::fifo_contents`]
+    pub fn packet_size(&self) -> usize {
+        self.config.fifo_contents.packet_size()
     }
 
     /// Parse raw FIFO data into scaled sensor readings
     ///
-    /// Each 14-byte packet contains: [accel_x_h, accel_x_l, accel_y_h, accel_y_l,
-    /// accel_z_h, accel_z_l, temp_h, temp_l, gyro_x_h, gyro_x_l, gyro_y_h, gyro_y_l, gyro_z_h, gyro_z_l]
+    /// With all sensors enabled each 14-byte packet contains: [accel_x_h, accel_x_l, accel_y_h,
+    /// accel_y_l, accel_z_h, accel_z_l, temp_h, temp_l, gyro_x_h, gyro_x_l, gyro_y_h, gyro_y_l,
+    /// gyro_z_h, gyro_z_l], and sensors left out of [`ImuConfig::fifo_contents`] are skipped.
     /// Returns scaled data in physical units (m/s² for accelerometer, rad/s for gyroscope, °C for temperature)
-    pub fn parse_fifo_packet(&self, packet: &[u8; 14]) -> ImuData {
-        // Parse raw values from FIFO packet
-        let raw_accel = [
-            i16::from_be_bytes([packet[0], packet[1]]), // X
-            i16::from_be_bytes([packet[2], packet[3]]), // Y
-            i16::from_be_bytes([packet[4], packet[5]]), // Z
-        ];
-        let raw_temperature = i16::from_be_bytes([packet[6], packet[7]]);
-        let raw_gyro = [
-            i16::from_be_bytes([packet[8], packet[9]]),   // X
-            i16::from_be_bytes([packet[10], packet[11]]), // Y
-            i16::from_be_bytes([packet[12], packet[13]]), // Z
-        ];
-
-        // Scale to physical units using datasheet LSB values
-        let accel_lsb_per_g = match self.config.accel_range {
-            AccelRange::G2 => 16384.0, // ±2g range
-            AccelRange::G4 => 8192.0,  // ±4g range
-            AccelRange::G8 => 4096.0,  // ±8g range
-            AccelRange::G16 => 2048.0, // ±16g range
-        };
-        let accel_scale = 9.80665 / accel_lsb_per_g; // Convert to m/s²
+    /// along with the validity flags for the sample. Sensors not in the packet read as zero.
+    /// Accelerometer, gyroscope and clipping flags are rotated by [`ImuConfig::axis_remap`].
+    ///
+    /// # Panics
+    /// Panics if `packet` is shorter than [`packet_size`](Self::packet_size)
+    pub fn parse_fifo_packet(&self, packet: &[u8]) -> ImuData {
+        self.parse_packet(packet, self.config.fifo_contents)
+    }
+
+    /// Parse a packet with the given layout, see [`parse_fifo_packet`](Self::parse_fifo_packet)
+    fn parse_packet(&self, packet: &[u8], contents: FifoContents) -> ImuData {
+        let registers = register_layout(packet, contents);
+        let mut data = scale_packet(&registers, self.accel_range, self.config.gyro_range, self.variant);
 
-        let gyro_lsb_per_dps = match self.config.gyro_range {
-            GyroRange::Dps250 => 131.0, // ±250°/s range
-            GyroRange::Dps500 => 65.5,  // ±500°/s range
-            GyroRange::Dps1000 => 32.8, // ±1000°/s range
-            GyroRange::Dps2000 => 16.4, // ±2000°/s range
+        // The sensitivity drifts linearly with the die temperature, so undo the drift using this
+        // packet's own temperature. Zero coefficients divide by exactly one, leaving the scale as is,
+        // and so does a packet without temperature.
+        let temp_delta = if contents.temperature {
+            data.temperature - GYRO_TEMP_REFERENCE_C
+        } else {
+            data.temperature = 0.0;
+            0.0
         };
-        let gyro_scale = (core::f32::consts::PI / 180.0) / gyro_lsb_per_dps; // Convert to rad/s
 
-        // Temperature scaling (datasheet formula)
-        let temp_c = f32::from(raw_temperature) / 333.87 + 21.0;
+        for (gyro, ppm) in data.gyro.iter_mut().zip(self.config.gyro_temp_coefficients_ppm) {
+            *gyro /= 1.0 + ppm * 1e-6 * temp_delta;
+        }
+
+        // Rotate into the body frame, clipping flags included. The bias is measured from this
+        // output, so it is subtracted in the body frame too.
+        let remap = self.config.axis_remap;
+        data.accel = remap.apply(data.accel);
+        data.gyro = remap.apply(data.gyro);
+        let mut status = (data.status.0 & !(ImuStatus::ACCEL_CLIP | ImuStatus::GYRO_CLIP))
+            | remap.apply_bits(data.status.0, ImuStatus::ACCEL_CLIP_X)
+            | remap.apply_bits(data.status.0, ImuStatus::GYRO_CLIP_X);
 
-        ImuData {
-            accel: [
-                f32::from(raw_accel[0]) * accel_scale,
-                f32::from(raw_accel[1]) * accel_scale,
-                f32::from(raw_accel[2]) * accel_scale,
-            ],
-            gyro: [
-                f32::from(raw_gyro[0]) * gyro_scale,
-                f32::from(raw_gyro[1]) * gyro_scale,
-                f32::from(raw_gyro[2]) * gyro_scale,
-            ],
-            temperature: temp_c,
+        if let Some(bias) = self.gyro_bias {
+            for (gyro, bias) in data.gyro.iter_mut().zip(bias) {
+                *gyro -= bias;
+            }
+            status |= ImuStatus::CALIBRATED;
         }
+        data.status = ImuStatus(status & self.config.status_mask);
+        data.fsync = self
+            .config
+            .fsync
+            .packet_index(contents)
+            .is_some_and(|index| packet[index] & 0b1 != 0);
+        data.stamp(Instant::now());
+        data
     }
 
     /// Read data from FIFO register using DMA
@@ -253,18 +1456,103 @@ impl<'d> Icm20689<'d> {
             .map_err(|_| ImuError::SpiError)
     }
 
+    /// Reset the FIFO and start capturing from an empty buffer
+    ///
+    /// This performs the USER_CTRL sequence recommended by the datasheet:
+    /// 1. Disable the FIFO so no new packets are written mid-reset
+    /// 2. Reset the FIFO, discarding any buffered (possibly misaligned) data
+    /// 3. Re-enable the FIFO
+    ///
+    /// The I2C interface stays disabled throughout. Which sensors are written into the FIFO
+    /// (FIFO_EN) is left untouched.
+    pub async fn reset_fifo(&mut self) -> Result<(), ImuError> {
+        self.spi
+            .write_register(Register::UserCtrl as u8, USER_CTRL_I2C_DISABLE)
+            .await?;
+        self.spi
+            .write_register(Register::UserCtrl as u8, USER_CTRL_FIFO_RST | USER_CTRL_I2C_DISABLE)
+            .await?;
+        Timer::after(Duration::from_millis(1)).await;
+        self.spi
+            .write_register(Register::UserCtrl as u8, USER_CTRL_FIFO_EN | USER_CTRL_I2C_DISABLE)
+            .await?;
+        Ok(())
+    }
+
+    /// Put the sensor to sleep
+    ///
+    /// Sampling stops and the FIFO is disabled, while every configuration register keeps its
+    /// value, so [`wake`](Self::wake) resumes without reinitializing. Current draw drops to the
+    /// datasheet sleep current of a few µA.
+    pub async fn sleep(&mut self) -> Result<(), ImuError> {
+        self.spi
+            .write_register(Register::UserCtrl as u8, USER_CTRL_I2C_DISABLE)
+            .await?;
+        let pwr_mgmt_1 = self.spi.read_register(Register::PwrMgmt1 as u8).await?;
+        self.spi
+            .write_register(Register::PwrMgmt1 as u8, pwr_mgmt_1 | PWR_MGMT_1_SLEEP)
+            .await?;
+        defmt::info!("IMU asleep");
+        Ok(())
+    }
+
+    /// Wake the sensor from [`sleep`](Self::sleep)
+    ///
+    /// Waits for the gyroscope, the slowest sensor, to start up before flushing and re-enabling
+    /// the FIFO, so no stale packet or sample from the start-up transient is read.
+    pub async fn wake(&mut self) -> Result<(), ImuError> {
+        let pwr_mgmt_1 = self.spi.read_register(Register::PwrMgmt1 as u8).await?;
+        self.spi
+            .write_register(Register::PwrMgmt1 as u8, pwr_mgmt_1 & !PWR_MGMT_1_SLEEP)
+            .await?;
+        Timer::after(GYRO_STARTUP_TIME).await;
+
+        self.reset_fifo().await?;
+        self.empty_interrupts = 0;
+        defmt::info!("IMU awake");
+        Ok(())
+    }
+
+    /// Sleep until [`IMU_POWER`] asks for the sensor to wake up again
+    async fn park(&mut self) -> Result<(), ImuError> {
+        self.sleep().await?;
+        liveness::suspend(TaskId::Imu);
+        while IMU_POWER.wait().await != ImuPower::Wake {}
+        liveness::checkin(TaskId::Imu);
+        self.wake().await
+    }
+
+    /// Read the whole register map for diagnostics
+    ///
+    /// Registers 0x00 to 0x73 are read in one burst and WHO_AM_I on its own. FIFO_R_W (0x74) is
+    /// skipped and reads as zero, as reading it would take data out of the FIFO.
+    ///
+    /// # Returns
+    /// The value of every register indexed by its address, or an SPI error
+    #[cfg(feature = "debug-imu")]
+    pub async fn dump_registers(&mut self) -> Result<[u8; REGISTER_COUNT], ImuError> {
+        let mut registers = [0u8; REGISTER_COUNT];
+        self.spi
+            .read_register_burst(0x00, &mut registers[..Register::FifoRw as usize])
+            .await?;
+        registers[Register::WhoAmI as usize] = self.spi.read_register(Register::WhoAmI as u8).await?;
+        Ok(registers)
+    }
+
     /// Initialize the ICM-20689 chip
     ///
     /// This function:
     /// 1. Resets the device
     /// 2. Verifies chip ID
-    /// 3. Configures power management
+    /// 3. Configures power management, enabling the gyroscope before the accelerometer and
+    ///    waiting for both to start up
     /// 4. Sets up accelerometer and gyroscope ranges
-    /// 5. Configures FIFO buffer
+    /// 5. Configures FIFO buffer, discarding the samples taken while the output settles
     /// 6. Enables interrupts
     async fn initialize(&mut self) -> Result<(), ImuError> {
         defmt::info!("Initializing ICM-20689...");
         defmt::debug!("Config: {:?}", self.config);
+        defmt::debug!("SPI clock: {} Hz", self.spi_frequency().0);
 
         // Reset the device
         const DEVICE_RESET: u8 = 0b1000_0000;
@@ -272,19 +1560,19 @@ impl<'d> Icm20689<'d> {
         Timer::after(Duration::from_millis(100)).await;
 
         // Verify chip ID
-        const WHO_AM_I_EXPECTED: u8 = 0x98;
         let chip_id = self.spi.read_register(Register::WhoAmI as u8).await?;
-        if chip_id != WHO_AM_I_EXPECTED {
+        let Some(variant) = ImuVariant::from_who_am_i(chip_id) else {
             defmt::error!(
-                "Wrong chip ID: expected 0x{:02X}, got 0x{:02X}",
-                WHO_AM_I_EXPECTED,
+                "Wrong chip ID: expected one of {=[u8]:02X}, got 0x{:02X}",
+                &ImuVariant::ALL.map(ImuVariant::who_am_i)[..],
                 chip_id
             );
             return Err(ImuError::DeviceNotFound);
-        }
+        };
+        self.variant = variant;
+        defmt::info!("Found {:?} (WHO_AM_I 0x{:02X})", variant, chip_id);
 
         // Disable I2C mode
-        const USER_CTRL_I2C_DISABLE: u8 = 0b0001_0000;
         self.spi
             .write_register(Register::UserCtrl as u8, USER_CTRL_I2C_DISABLE)
             .await?;
@@ -294,63 +1582,575 @@ impl<'d> Icm20689<'d> {
         self.spi.write_register(Register::PwrMgmt1 as u8, CLK_SEL_PLL).await?;
         Timer::after(Duration::from_millis(10)).await;
 
-        // Enable accelerometer and gyroscope and disable all low power modes
+        // Enable the gyroscope first since it takes longest to start, then the accelerometer so
+        // both finish settling together. Low power modes stay disabled.
+        const PWR_MGMT_2_ACCEL_DISABLED: u8 = 0b0011_1000;
+        self.spi
+            .write_register(Register::PwrMgmt2 as u8, PWR_MGMT_2_ACCEL_DISABLED)
+            .await?;
+        Timer::after(GYRO_STARTUP_TIME - ACCEL_STARTUP_TIME).await;
         self.spi.write_register(Register::PwrMgmt2 as u8, 0b0000_0000).await?;
+        Timer::after(ACCEL_STARTUP_TIME).await;
+
+        self.write_sensor_config().await?;
+
+        // Let the output settle with the final configuration, then start from an empty FIFO so
+        // the samples captured meanwhile (one per millisecond at 1000Hz) are discarded
+        Timer::after(Duration::from_millis(u64::from(self.config.startup_discard_samples))).await;
+        self.reset_fifo().await?;
 
-        // Configure DLPF bandwidth
-        const CONFIG_DLPF_BANDWIDTH: u8 = 0b0000_0001;
+        // Configure interrupt pin (active low, push-pull, cleared on any read) and FSYNC (active low)
+        const INT_PIN_CFG_LATCH_CLR_ANY_READ: u8 = 0b1001_1000;
+        self.spi
+            .write_register(Register::IntPinCfg as u8, INT_PIN_CFG_LATCH_CLR_ANY_READ)
+            .await?;
+
+        self.write_interrupt_config().await?;
+
+        defmt::info!("ICM-20689 initialized successfully");
+        Ok(())
+    }
+
+    /// Write the FIFO watermark and interrupt enables for [`ImuConfig::interrupt_mode`]
+    ///
+    /// The FIFO overflow interrupt is always enabled so an overflow is noticed and recovered.
+    async fn write_interrupt_config(&mut self) -> Result<(), ImuError> {
+        let mode = self.config.interrupt_mode;
+        let [threshold_high, threshold_low] = mode.watermark_bytes(self.packet_size(), MAX_PACKETS)?.to_be_bytes();
+        self.spi
+            .write_register_burst(Register::FifoWmTh1 as u8, &[threshold_high, threshold_low])
+            .await?;
         self.spi
-            .write_register(Register::Config as u8, CONFIG_DLPF_BANDWIDTH)
+            .write_register(Register::IntEnable as u8, mode.int_enable_bits() | INT_FIFO_OFLOW)
             .await?;
+        Ok(())
+    }
 
+    /// Write the ranges, filters, frame-sync and FIFO mode from [`ImuConfig`] to the chip
+    ///
+    /// Shared by [`initialize`](Self::initialize) and [`set_config`](Self::set_config). Resets
+    /// auto-ranging to the configured accelerometer range.
+    async fn write_sensor_config(&mut self) -> Result<(), ImuError> {
         // Configure sample rate divider for 1000Hz output
         // Sample Rate = Internal_Sample_Rate / (1 + SMPLRT_DIV)
         // With DLPF enabled, internal rate is 1000Hz, so SMPLRT_DIV = 0
-        self.spi.write_register(Register::SmplrtDiv as u8, 0b0000_0000).await?;
+        const SMPLRT_DIV: u8 = 0b0000_0000;
+        self.accel_range = self.config.accel_range;
+        self.auto_range_calm_samples = 0;
 
-        // Configure accelerometer range
+        // SMPLRT_DIV through ACCEL_CONFIG2 are contiguous, so write them in one burst
+        let mut registers = [0u8; (Register::AccelConfig2 as u8 - Register::SmplrtDiv as u8 + 1) as usize];
+        let at = |reg: Register| (reg as u8 - Register::SmplrtDiv as u8) as usize;
+        registers[at(Register::SmplrtDiv)] = SMPLRT_DIV;
+        // Configure DLPF bandwidth, frame-sync latching and what the FIFO does when full
+        registers[at(Register::Config)] = GYRO_DLPF_CFG | self.config.fsync as u8 | self.config.fifo_mode.config_bits();
+        // FCHOICE_B must be clear or the gyroscope bypasses the DLPF selected in CONFIG
+        registers[at(Register::GyroConfig)] = self.config.gyro_range as u8 & !GYRO_CONFIG_FCHOICE_B;
+        registers[at(Register::AccelConfig)] = self.accel_range as u8;
+        // ACCEL_FCHOICE_B is left clear so the accelerometer uses the DLPF selected here
+        registers[at(Register::AccelConfig2)] = ACCEL_DLPF_CFG | self.config.accel_averaging as u8;
         self.spi
-            .write_register(Register::AccelConfig as u8, self.config.accel_range as u8)
+            .write_register_burst(Register::SmplrtDiv as u8, &registers)
             .await?;
-        const ACC_CONFIG2_DLPF_BANDWIDTH: u8 = 0b0000_0001;
+
+        // Select the sensors in each FIFO packet
         self.spi
-            .write_register(Register::AccelConfig2 as u8, ACC_CONFIG2_DLPF_BANDWIDTH)
+            .write_register(Register::FifoEn as u8, self.config.fifo_contents.fifo_en_bits())
             .await?;
 
-        // Configure gyroscope range
+        Ok(())
+    }
+
+    /// Filter cutoffs the sensor is configured with
+    ///
+    /// Both filters are always enabled (FCHOICE_B and ACCEL_FCHOICE_B clear), so the cutoffs follow
+    /// from the DLPF settings written by [`initialize`](Self::initialize) and
+    /// [`set_config`](Self::set_config).
+    ///
+    /// # Returns
+    /// The (accelerometer, gyroscope) 3dB bandwidth in Hz
+    pub fn effective_bandwidth(&self) -> (u16, u16) {
+        (
+            ACCEL_DLPF_BANDWIDTH_HZ[ACCEL_DLPF_CFG as usize],
+            GYRO_DLPF_BANDWIDTH_HZ[GYRO_DLPF_CFG as usize],
+        )
+    }
+
+    /// Apply a new configuration while the driver is running
+    ///
+    /// Rewrites the range, filter, frame-sync, FIFO mode and FIFO contents registers and then flushes the FIFO,
+    /// as any packets already captured were scaled for the old ranges and would be
+    /// misinterpreted, before switching to the new interrupt mode. The SPI clock and power state
+    /// are untouched, so this is fast enough to use between FIFO reads. A configuration that fails
+    /// validation (see [`ImuConfig`]) is rejected before anything is written.
+    pub async fn set_config(&mut self, config: ImuConfig) -> Result<(), ImuError> {
+        config.validate(MAX_PACKETS)?;
+        self.config = config;
+        self.reset_filters();
+        self.write_sensor_config().await?;
+        self.reset_fifo().await?;
+        self.write_interrupt_config().await?;
+        defmt::info!("IMU config updated: {:?}", self.config);
+        Ok(())
+    }
+
+    /// Switch the accelerometer range without a full reinitialization
+    ///
+    /// The FIFO is reset afterwards because any samples still buffered were measured with the old
+    /// range and would be scaled incorrectly.
+    async fn set_accel_range(&mut self, range: AccelRange) -> Result<(), ImuError> {
         self.spi
-            .write_register(Register::GyroConfig as u8, self.config.gyro_range as u8)
+            .write_register(Register::AccelConfig as u8, range as u8)
             .await?;
+        self.accel_range = range;
+        self.reset_fifo().await
+    }
 
-        // Reset FIFO
-        const USER_FIFO_RST: u8 = 0b0000_0100 | USER_CTRL_I2C_DISABLE; // Reset FIFO disable I2C mode
-        self.spi.write_register(Register::UserCtrl as u8, USER_FIFO_RST).await?;
-        Timer::after(Duration::from_millis(1)).await;
+    /// Widen or narrow the accelerometer range based on the latest batch of samples
+    ///
+    /// Only active with [`ImuConfig::accel_auto_range`]. The range is widened as soon as a reading
+    /// reaches [`AUTO_RANGE_WIDEN_RATIO`] of full scale, and narrowed again (never below
+    /// [`ImuConfig::accel_range`]) once [`AUTO_RANGE_CALM_SAMPLES`] consecutive samples have stayed
+    /// below [`AUTO_RANGE_NARROW_RATIO`].
+    ///
+    /// # Arguments
+    /// * `peak_ratio` - Largest absolute reading of the batch as a fraction of full scale
+    /// * `samples` - Number of samples in the batch
+    async fn auto_range(&mut self, peak_ratio: f32, samples: u32) -> Result<(), ImuError> {
+        if !self.config.accel_auto_range || samples == 0 {
+            return Ok(());
+        }
+
+        if peak_ratio >= AUTO_RANGE_WIDEN_RATIO {
+            self.auto_range_calm_samples = 0;
+            if let Some(wider) = self.accel_range.wider() {
+                defmt::debug!("IMU accel near full scale, widening range to {:?}", wider);
+                self.set_accel_range(wider).await?;
+            }
+        } else if peak_ratio < AUTO_RANGE_NARROW_RATIO && self.accel_range > self.config.accel_range {
+            self.auto_range_calm_samples += samples;
+            if self.auto_range_calm_samples >= AUTO_RANGE_CALM_SAMPLES {
+                self.auto_range_calm_samples = 0;
+                if let Some(narrower) = self.accel_range.narrower() {
+                    defmt::debug!("IMU accel calm, narrowing range to {:?}", narrower);
+                    self.set_accel_range(narrower).await?;
+                }
+            }
+        } else {
+            self.auto_range_calm_samples = 0;
+        }
+        Ok(())
+    }
+
+    /// Measure per-axis sensor noise while the board is kept still
+    ///
+    /// Captures `samples` readings from the FIFO and reports the mean, standard deviation and
+    /// one-sample Allan deviation of every accelerometer and gyroscope axis. For white noise the
+    /// Allan deviation matches the standard deviation; a larger standard deviation points at drift
+    /// or motion during the capture. Dividing the standard deviation by the square root of the
+    /// filter bandwidth gives the noise density to compare against the datasheet.
+    ///
+    /// The chip must already be initialized and the board must not move during the capture.
+    pub async fn measure_noise(&mut self, samples: u32) -> Result<NoiseReport, ImuError> {
+        let mut accel = [NoiseAccumulator::default(); 3];
+        let mut gyro = [NoiseAccumulator::default(); 3];
+        let mut captured = 0u32;
+        let mut fifo_buffer = [[0u8; PACKET_SIZE]; MAX_PACKETS];
+        let fifo_buffer = fifo_buffer.as_flattened_mut();
+
+        while captured < samples {
+            self.wait_for_interrupt().await;
+            let bytes_read = self.read_fifo_batch(fifo_buffer).await?;
+            // A long capture outlasts the liveness timeout
+            liveness::checkin(TaskId::Imu);
+
+            for packet in fifo_buffer[..bytes_read].chunks_exact(self.packet_size()) {
+                if captured == samples {
+                    break;
+                }
+                let data = self.parse_fifo_packet(packet);
+                if !data.is_finite() {
+                    continue;
+                }
+                for (accumulator, value) in accel.iter_mut().zip(data.accel) {
+                    accumulator.add(value);
+                }
+                for (accumulator, value) in gyro.iter_mut().zip(data.gyro) {
+                    accumulator.add(value);
+                }
+                captured += 1;
+            }
+        }
+
+        Ok(NoiseReport {
+            samples: captured,
+            accel: accel.map(|a| a.finish()),
+            gyro: gyro.map(|g| g.finish()),
+        })
+    }
+
+    /// Measure the gyroscope bias and subtract it from all following samples
+    ///
+    /// Averages `samples` gyroscope readings from the FIFO while the board is kept still. The
+    /// resulting bias is stored and subtracted in [`parse_fifo_packet`](Self::parse_fifo_packet)
+    /// so streamed data is zero-mean at rest, and samples are flagged with
+    /// [`ImuStatus::CALIBRATED`].
+    ///
+    /// # Returns
+    /// The per-axis bias in rad/s, or [`ImuError::CalibrationFailed`] if the acceleration
+    /// magnitude of any sample deviated from one g by more than [`CALIBRATION_ACCEL_TOLERANCE`],
+    /// which means the board moved. The previous bias is kept when calibration fails, and
+    /// [`ImuError::InvalidConfig`] is returned if the FIFO lacks the accelerometer or gyroscope.
+    pub async fn calibrate_gyro_bias(&mut self, samples: usize) -> Result<[f32; 3], ImuError> {
+        let contents = self.config.fifo_contents;
+        if samples == 0 || !contents.accel || !contents.gyro {
+            return Err(ImuError::InvalidConfig);
+        }
+
+        // The capture takes as long as its samples, which can outlast the liveness timeout
+        let budget = Duration::from_millis(samples as u64 * 1000 / u64::from(SAMPLE_RATE_HZ)) + CALIBRATION_MARGIN;
+        let _operation = liveness::long_operation(TaskId::Imu, budget);
+
+        // Measure against the raw output and start from fresh samples
+        let previous_bias = self.gyro_bias.take();
+        let result = self.average_gyro(samples).await;
+        self.gyro_bias = match result {
+            Ok(bias) => Some(bias),
+            Err(_) => previous_bias,
+        };
+
+        match result {
+            Ok(bias) => defmt::info!("IMU gyro bias calibrated: {} rad/s", bias),
+            Err(e) => defmt::warn!("IMU gyro bias calibration failed: {:?}", e),
+        }
+        result
+    }
+
+    /// Set how often the acquisition loop logs its statistics
+    ///
+    /// While disabled the loop does not read the clock for statistics at all. Takes effect on the
+    /// next batch, and a newly enabled interval starts with fresh counters.
+    ///
+    /// # Arguments
+    /// * `interval` - Time between statistics log lines, or `None` to stop logging them
+    pub fn set_stats_interval(&mut self, interval: Option<Duration>) {
+        self.stats_interval = interval;
+    }
+
+    /// Average `samples` gyroscope readings, failing if the board moves
+    async fn average_gyro(&mut self, samples: usize) -> Result<[f32; 3], ImuError> {
+        self.reset_fifo().await?;
+
+        let mut sum = [0.0f32; 3];
+        let mut captured = 0usize;
+        let mut fifo_buffer = [[0u8; PACKET_SIZE]; MAX_PACKETS];
+        let fifo_buffer = fifo_buffer.as_flattened_mut();
+
+        while captured < samples {
+            self.wait_for_interrupt().await;
+            let bytes_read = self.read_fifo_batch(fifo_buffer).await?;
+
+            for packet in fifo_buffer[..bytes_read].chunks_exact(self.packet_size()) {
+                if captured == samples {
+                    break;
+                }
+                let data = self.parse_fifo_packet(packet);
+                if !data.is_finite() {
+                    continue;
+                }
+
+                let [x, y, z] = data.accel;
+                let magnitude = libm::sqrtf(x * x + y * y + z * z);
+                if libm::fabsf(magnitude - STANDARD_GRAVITY) > CALIBRATION_ACCEL_TOLERANCE {
+                    return Err(ImuError::CalibrationFailed);
+                }
+
+                for (total, value) in sum.iter_mut().zip(data.gyro) {
+                    *total += value;
+                }
+                captured += 1;
+            }
+        }
+
+        Ok(sum.map(|total| total / captured as f32))
+    }
+
+    /// Stop subtracting a gyroscope bias, returning to the raw sensor output
+    pub fn clear_bias(&mut self) {
+        self.gyro_bias = None;
+    }
+
+    /// Run the built-in self-test and compare the response against the factory trim values
+    ///
+    /// The sensors are switched to ±250°/s and ±2g with a 92Hz DLPF, as the procedure requires,
+    /// and averaged once with the self-test actuation off and once with it on. The difference is
+    /// the self-test response, which is checked per axis against the factory response stored in
+    /// SELF_TEST_X/Y/Z_GYRO and SELF_TEST_X/Y/Z_ACCEL:
+    /// - Gyroscope: at least half the factory response, and the output at rest within ±20°/s
+    /// - Accelerometer: between 0.5 and 1.5 times the factory response
+    ///
+    /// Axes whose trim code is zero are checked against the absolute limits from the datasheet
+    /// instead. The board must be kept still while the test runs. The configured ranges and
+    /// filters are restored and the FIFO flushed afterwards, whatever the outcome.
+    ///
+    /// # Returns
+    /// Pass/fail for each axis, or an error if the SPI bus failed
+    pub async fn self_test(&mut self) -> Result<SelfTestResult, ImuError> {
+        let result = self.run_self_test().await;
+
+        // Restore the configured ranges and discard samples captured with the test settings
+        self.write_sensor_config().await?;
+        self.reset_fifo().await?;
+
+        match &result {
+            Ok(outcome) => defmt::info!("IMU self-test: {:?}", outcome),
+            Err(e) => defmt::warn!("IMU self-test failed to run: {:?}", e),
+        }
+        result
+    }
 
-        // Enable FIFO for TEMP + GYRO + ACCEL (bits 7-3 set)
-        const FIFO_TEMP_GYRO_ACCEL: u8 = 0b1111_1000;
+    /// Measure the self-test response and grade it, leaving the test settings applied
+    async fn run_self_test(&mut self) -> Result<SelfTestResult, ImuError> {
+        // ±250°/s and ±2g full scale, where the factory response is defined
+        const GYRO_LSB_PER_DPS: f32 = 131.0;
+        const ACCEL_LSB_PER_G: f32 = 16384.0;
+
+        self.spi.write_register(Register::Config as u8, SELF_TEST_DLPF).await?;
         self.spi
-            .write_register(Register::FifoEn as u8, FIFO_TEMP_GYRO_ACCEL)
+            .write_register_burst(
+                Register::GyroConfig as u8,
+                &[GyroRange::Dps250 as u8, AccelRange::G2 as u8, SELF_TEST_DLPF],
+            )
             .await?;
+        Timer::after(SELF_TEST_SETTLE_TIME).await;
+        let (accel_normal, gyro_normal) = self.average_raw(SELF_TEST_SAMPLES).await?;
 
-        // Enable FIFO
-        const USER_FIFO_EN: u8 = 0b0100_0000 | USER_CTRL_I2C_DISABLE; // Enable FIFO disable I2C mode
-        self.spi.write_register(Register::UserCtrl as u8, USER_FIFO_EN).await?;
-
-        // Configure interrupt pin (active low, push-pull, cleared on any read)
-        const INT_PIN_CFG_LATCH_CLR_ANY_READ: u8 = 0b1001_1000;
         self.spi
-            .write_register(Register::IntPinCfg as u8, INT_PIN_CFG_LATCH_CLR_ANY_READ)
+            .write_register_burst(
+                Register::GyroConfig as u8,
+                &[
+                    GyroRange::Dps250 as u8 | SELF_TEST_ENABLE,
+                    AccelRange::G2 as u8 | SELF_TEST_ENABLE,
+                ],
+            )
             .await?;
+        Timer::after(SELF_TEST_SETTLE_TIME).await;
+        let (accel_test, gyro_test) = self.average_raw(SELF_TEST_SAMPLES).await?;
 
-        // Enable data ready interrupt (bit 0) instead of FIFO overflow
-        const INT_ENABLE_DATA_RDY: u8 = 0b0000_0001;
+        let mut gyro_codes = [0u8; 3];
         self.spi
-            .write_register(Register::IntEnable as u8, INT_ENABLE_DATA_RDY)
+            .read_register_burst(Register::SelfTestXGyro as u8, &mut gyro_codes)
+            .await?;
+        let mut accel_codes = [0u8; 3];
+        self.spi
+            .read_register_burst(Register::SelfTestXAccel as u8, &mut accel_codes)
             .await?;
 
-        defmt::info!("ICM-20689 initialized successfully");
-        Ok(())
+        let mut result = SelfTestResult::default();
+        for axis in 0..3 {
+            let response = libm::fabsf(gyro_test[axis] - gyro_normal[axis]);
+            let response_ok = match gyro_codes[axis] {
+                0 => response >= SELF_TEST_GYRO_MIN_DPS * GYRO_LSB_PER_DPS,
+                code => response / self_test_factory_response(code) > SELF_TEST_GYRO_MIN_RATIO,
+            };
+            let offset_ok = libm::fabsf(gyro_normal[axis]) <= SELF_TEST_GYRO_MAX_OFFSET_DPS * GYRO_LSB_PER_DPS;
+            result.gyro[axis] = response_ok && offset_ok;
+
+            let response = libm::fabsf(accel_test[axis] - accel_normal[axis]);
+            let (min, max) = match accel_codes[axis] {
+                0 => (
+                    SELF_TEST_ACCEL_LIMITS_G.0 * ACCEL_LSB_PER_G,
+                    SELF_TEST_ACCEL_LIMITS_G.1 * ACCEL_LSB_PER_G,
+                ),
+                code => {
+                    let factory = self_test_factory_response(code);
+                    (SELF_TEST_ACCEL_RATIO.0 * factory, SELF_TEST_ACCEL_RATIO.1 * factory)
+                }
+            };
+            result.accel[axis] = (min..=max).contains(&response);
+        }
+
+        Ok(result)
+    }
+
+    /// Average `samples` raw accelerometer and gyroscope readings from the data registers
+    ///
+    /// # Returns
+    /// Mean (accel, gyro) output in LSB for each axis
+    async fn average_raw(&mut self, samples: u32) -> Result<([f32; 3], [f32; 3]), ImuError> {
+        let mut accel = [0.0f32; 3];
+        let mut gyro = [0.0f32; 3];
+        let mut packet = [0u8; PACKET_SIZE];
+
+        for _ in 0..samples {
+            self.spi
+                .read_register_burst(Register::AccelXoutH as u8, &mut packet)
+                .await?;
+            for axis in 0..3 {
+                accel[axis] += f32::from(i16::from_be_bytes([packet[2 * axis], packet[2 * axis + 1]]));
+                gyro[axis] += f32::from(i16::from_be_bytes([packet[8 + 2 * axis], packet[9 + 2 * axis]]));
+            }
+            // Data registers update at the 1kHz sample rate
+            Timer::after_millis(1).await;
+        }
+
+        Ok((
+            accel.map(|sum| sum / samples as f32),
+            gyro.map(|sum| sum / samples as f32),
+        ))
+    }
+
+    /// Service a pending [`ImuRequest`], if there is one
+    ///
+    /// Called between FIFO reads so requests never interleave with a burst transfer.
+    /// Note that reading the FIFO data register through a request consumes FIFO data.
+    ///
+    /// # Returns
+    /// `true` if the request took samples out of the FIFO itself, leaving a gap in the stream
+    async fn handle_request(&mut self, latest: &ImuData) -> bool {
+        let Ok(request) = IMU_REQUESTS.try_receive() else {
+            return false;
+        };
+        let interrupts_stream = matches!(
+            request,
+            ImuRequest::SetConfig(_)
+                | ImuRequest::CalibrateGyro(_)
+                | ImuRequest::MeasureNoise(_)
+                | ImuRequest::SelfTest
+        );
+
+        let response = match request {
+            ImuRequest::Status => ImuResponse::Status {
+                latest: *latest,
+                attitude: self.attitude.and_then(|attitude| attitude.angles()),
+                fifo_fill_percent: self.fifo_fill_percent(),
+                stuck_interrupt_recoveries: self.stuck_interrupt_recoveries,
+                spi_frequency: self.spi_frequency().0,
+                sample_rate_hz: self.measured_rate_hz,
+            },
+            ImuRequest::ReadRegister(address) => match self.spi.read_register(address).await {
+                Ok(value) => ImuResponse::Register { address, value },
+                Err(e) => ImuResponse::Error(e.into()),
+            },
+            ImuRequest::Config => self.config_response(),
+            ImuRequest::SetConfig(config) => match self.set_config(config).await {
+                Ok(()) => self.config_response(),
+                Err(e) => ImuResponse::Error(e),
+            },
+            ImuRequest::SetStatsInterval(interval) => {
+                self.set_stats_interval(interval);
+                ImuResponse::StatsInterval(interval)
+            }
+            ImuRequest::SetSpiFrequency(frequency) => match self.set_spi_frequency(Hertz(frequency)) {
+                Ok(()) => ImuResponse::SpiFrequency(self.spi_frequency().0),
+                Err(e) => ImuResponse::Error(e),
+            },
+            ImuRequest::ReadOnce => match self.read_accel_gyro_once().await {
+                Ok(data) => ImuResponse::Sample(data),
+                Err(e) => ImuResponse::Error(e),
+            },
+            ImuRequest::CalibrateGyro(samples) => match self.calibrate_gyro_bias(usize::from(samples)).await {
+                Ok(bias) => ImuResponse::GyroBias(bias),
+                Err(e) => ImuResponse::Error(e),
+            },
+            ImuRequest::ClearGyroBias => {
+                self.clear_bias();
+                ImuResponse::GyroBias([0.0; 3])
+            }
+            ImuRequest::MeasureNoise(samples) => match self.measure_noise(u32::from(samples)).await {
+                Ok(report) => ImuResponse::Noise(report),
+                Err(e) => ImuResponse::Error(e),
+            },
+            ImuRequest::SelfTest => match self.self_test().await {
+                Ok(result) => ImuResponse::SelfTest(result),
+                Err(e) => ImuResponse::Error(e),
+            },
+        };
+
+        // The requester may have timed out and gone away, so never block on the reply
+        let _ = IMU_RESPONSES.try_send(response);
+        interrupts_stream
+    }
+
+    /// Answer to [`ImuRequest::Config`], describing the configuration now in effect
+    fn config_response(&self) -> ImuResponse {
+        ImuResponse::Config {
+            config: self.config,
+            spi_frequency: self.spi_frequency().0,
+            variant: self.variant,
+            bandwidth_hz: self.effective_bandwidth(),
+        }
+    }
+
+    /// Run a sample through the low-pass filters, if they are enabled
+    ///
+    /// Only the accelerometer and gyroscope are filtered, the rest of the sample passes through.
+    ///
+    /// # Returns
+    /// The filtered sample, or `None` if a filter produced a non-finite value and was reset
+    fn low_pass_filter(&mut self, mut data: ImuData) -> Option<ImuData> {
+        if let Some([accel, gyro]) = &mut self.low_pass {
+            data.accel = accel.update(data.accel)?;
+            data.gyro = gyro.update(data.gyro)?;
+        }
+        Some(data)
+    }
+
+    /// Start the low-pass filters and the roll and pitch estimate afresh with the configuration
+    ///
+    /// Used after a gap in the samples, which the filters would otherwise smooth across.
+    fn reset_filters(&mut self) {
+        self.low_pass = self.config.low_pass();
+        self.attitude = self.config.attitude();
+    }
+
+    /// Measure the sample rate over one statistics window and warn if it is off target
+    ///
+    /// A wrong rate points at a misconfigured sample rate divider or DLPF, which nothing else
+    /// would notice.
+    ///
+    /// # Arguments
+    /// * `stats` - Counters of the window
+    /// * `window` - Length of the window
+    fn check_sample_rate(&mut self, stats: &RunStats, window: Duration) {
+        // Rejected samples were still produced by the chip
+        let sample_count = stats.sample_count + stats.rejected_count;
+        let rate_hz = sample_count as f32 * 1_000_000.0 / window.as_micros() as f32;
+        self.measured_rate_hz = Some(rate_hz);
+
+        let deviation = (rate_hz - SAMPLE_RATE_HZ as f32) / SAMPLE_RATE_HZ as f32;
+        if window >= SAMPLE_RATE_MIN_WINDOW && libm::fabsf(deviation) > SAMPLE_RATE_TOLERANCE {
+            defmt::warn!(
+                "IMU sample rate {} Hz is {}% off the configured {} Hz",
+                rate_hz,
+                deviation * 100.0,
+                SAMPLE_RATE_HZ
+            );
+        }
+    }
+
+    /// Log the statistics of one window of the acquisition loop
+    fn log_stats(&self, stats: &RunStats, latest: &ImuData) {
+        defmt::info!(
+            "IMU Stats: {} samples/sec | Accel (m/s²): [{}, {}, {}] | Gyro (rad/s): [{}, {}, {}] | Temp: {} °C | Status: 0b{:08b} | Clipped: {} | Rejected: {} | FIFO peak: {}% | FIFO overflows: {} | SPI recoveries: {} | Stuck INT recoveries: {} | Latency peak: {} µs | Deadline misses: {}",
+            self.measured_rate_hz.unwrap_or(0.0),
+            latest.accel[0],
+            latest.accel[1],
+            latest.accel[2],
+            latest.gyro[0],
+            latest.gyro[1],
+            latest.gyro[2],
+            latest.temperature,
+            latest.status.0,
+            stats.clipped_count,
+            stats.rejected_count,
+            stats.peak_fifo_fill,
+            self.fifo_overflows,
+            self.spi_recoveries,
+            self.stuck_interrupt_recoveries,
+            stats.peak_latency_us,
+            stats.deadline_misses
+        );
     }
 
     /// Main IMU task that handles interrupt-driven FIFO reading
@@ -359,8 +2159,45 @@ impl<'d> Icm20689<'d> {
     /// 1. Initializes the IMU chip
     /// 2. Waits for interrupts from the IMU (indicating new data in FIFO)
     /// 3. Reads FIFO data using DMA
-    /// 4. Logs statistics every second (data rate and latest readings)
-    pub async fn run(&mut self) -> Result<(), ImuError> {
+    /// 4. Publishes every parsed sample into `samples`
+    /// 5. Logs statistics every [`set_stats_interval`](Self::set_stats_interval), by default every
+    ///    second (data rate and latest readings)
+    /// 6. Services requests from other tasks through [`IMU_REQUESTS`]
+    /// 7. Puts the sensor to sleep and wakes it again when asked through [`IMU_POWER`]
+    ///
+    /// Publishing never blocks the acquisition loop: when `samples` is full the oldest sample is
+    /// dropped to make room for the newest.
+    pub async fn run(&mut self, samples: &ImuChannel) -> Result<(), ImuError> {
+        self.acquire(samples, None).await
+    }
+
+    /// IMU task loop that polls the FIFO instead of waiting for interrupts
+    ///
+    /// Behaves like [`run`](Self::run), except that the FIFO is read every `interval` and
+    /// whatever samples it holds are processed, so the INT pin does not need to be wired. Samples
+    /// are published in bursts of up to one interval's worth.
+    ///
+    /// # Arguments
+    /// * `samples` - Channel every parsed sample is published into
+    /// * `interval` - Time between FIFO reads, at most [`MAX_POLL_INTERVAL`](Self::MAX_POLL_INTERVAL)
+    ///   so every read drains the FIFO
+    ///
+    /// # Returns
+    /// Only on error, [`ImuError::InvalidConfig`] if `interval` is zero or above
+    /// [`MAX_POLL_INTERVAL`](Self::MAX_POLL_INTERVAL)
+    pub async fn run_polled(&mut self, samples: &ImuChannel, interval: Duration) -> Result<(), ImuError> {
+        if interval == Duration::from_ticks(0) || interval > Self::MAX_POLL_INTERVAL {
+            return Err(ImuError::InvalidConfig);
+        }
+        self.acquire(samples, Some(interval)).await
+    }
+
+    /// Acquisition loop shared by [`run`](Self::run) and [`run_polled`](Self::run_polled)
+    ///
+    /// # Arguments
+    /// * `samples` - Channel every parsed sample is published into
+    /// * `poll_interval` - Time between FIFO reads, or `None` to wait for the interrupt
+    async fn acquire(&mut self, samples: &ImuChannel, poll_interval: Option<Duration>) -> Result<(), ImuError> {
         defmt::info!("Starting IMU task - initializing ICM-20689...");
 
         // Initialize the IMU chip first
@@ -371,114 +2208,227 @@ impl<'d> Icm20689<'d> {
 
         defmt::info!("IMU initialized successfully, starting 1000Hz data acquisition...");
 
-        let mut sample_count = 0u32;
-        let mut last_log_time = embassy_time::Instant::now();
-        let mut latest_accel = [0.0f32; 3];
-        let mut latest_gyro = [0.0f32; 3];
-        let mut latest_temp = 0.0f32;
+        let mut stats = RunStats::default();
+        // Start of the current statistics window, `None` while statistics are disabled
+        let mut window_start = None;
+        let mut latest = ImuData::default();
 
-        /// Number of bytes in a FIFO packet (6 accel + 2 temp + 6 gyro)
-        const PACKET_SIZE: usize = 14;
-        /// Maximum number of packets to read from FIFO at once
-        const MAX_PACKETS: usize = 20;
-        // Buffer sized for up to 20 packets to handle FIFO bursts
-        let mut fifo_buffer = [0u8; PACKET_SIZE * MAX_PACKETS];
+        // Buffer sized for up to MAX_PACKETS packets to handle FIFO bursts
+        let mut fifo_buffer = [[0u8; PACKET_SIZE]; MAX_PACKETS];
+        let fifo_buffer = fifo_buffer.as_flattened_mut();
+        let mut poll_ticker = poll_interval.map(Ticker::every);
 
         loop {
-            // Wait for interrupt indicating new data
-            self.wait_for_interrupt().await;
-
-            // Read available FIFO data
-            match self.read_fifo_batch(&mut fifo_buffer).await {
-                Ok(bytes_read) => {
-                    // Process complete packets from FIFO data
-                    let packet_count = bytes_read / PACKET_SIZE;
-                    for i in 0..packet_count {
-                        let packet_start = i * PACKET_SIZE;
-                        // Ensure we have a complete packet before processing
-                        if packet_start + PACKET_SIZE <= bytes_read {
-                            let packet = &fifo_buffer[packet_start..packet_start + PACKET_SIZE];
-                            match packet.try_into() {
-                                Ok(arr) => {
-                                    let scaled = self.parse_fifo_packet(arr);
-                                    latest_accel = scaled.accel;
-                                    latest_gyro = scaled.gyro;
-                                    latest_temp = scaled.temperature;
-                                    sample_count += 1;
-                                }
-                                Err(e) => {
-                                    defmt::warn!(
-                                        "IMU FIFO packet slice-to-array conversion failed: expected {} bytes, got {} bytes Error: {:?}",
-                                        PACKET_SIZE,
-                                        packet.len(),
-                                        e
-                                    );
-                                    continue;
-                                }
-                            }
-                        }
-                    }
+            // Wait for interrupt (or the next poll) indicating new data, or for the power manager to
+            // park the sensor
+            let data_available = async {
+                match poll_ticker.as_mut() {
+                    Some(ticker) => ticker.next().await,
+                    None => self.wait_for_interrupt().await,
+                }
+            };
+            match select(data_available, IMU_POWER.wait()).await {
+                Either::First(()) => liveness::checkin(TaskId::Imu),
+                Either::Second(ImuPower::Sleep) => {
+                    self.park().await?;
+                    // Time spent parked would read as a missing sample rate, and the filters would
+                    // smooth across the gap
+                    window_start = None;
+                    self.reset_filters();
+                    continue;
                 }
+                Either::Second(ImuPower::Wake) => continue,
+            }
+            let data_ready = cycle_count();
+            let data_ready_at = Instant::now();
+
+            if self.check_fifo_overflow().await? {
+                continue;
+            }
+
+            // Read available FIFO data. Failed reads have already been retried, so an error left
+            // now needs a full reinitialization by the supervisor
+            let bytes_read = match self.read_fifo_batch(fifo_buffer).await {
+                Ok(bytes_read) => bytes_read,
                 Err(e) => {
-                    defmt::warn!("IMU FIFO read error: {:?}", e);
+                    defmt::warn!(
+                        "IMU FIFO read failed {} times, reinitializing: {:?}",
+                        SPI_RETRY_LIMIT + 1,
+                        e
+                    );
+                    return Err(e);
+                }
+            };
+
+            if poll_interval.is_none() {
+                self.check_stuck_interrupt(bytes_read).await?;
+            }
+
+            // Process complete packets from FIFO data, sized by the active FIFO contents
+            let packet_size = self.packet_size();
+            let packet_count = bytes_read / packet_size;
+            let mut batch_peak_accel = 0.0f32;
+            for (index, packet) in fifo_buffer[..bytes_read].chunks_exact(packet_size).enumerate() {
+                let mut scaled = self.parse_fifo_packet(packet);
+                // The newest packet was sampled when the data became ready, each earlier
+                // one a sample period before the next
+                let age = SAMPLE_PERIOD * (packet_count - 1 - index) as u32;
+                scaled.stamp(data_ready_at.checked_sub(age).unwrap_or(Instant::from_ticks(0)));
+                if !scaled.is_finite() {
+                    stats.rejected_count += 1;
+                    defmt::warn!("IMU sample rejected, non-finite value: {:?}", scaled);
+                    continue;
+                }
+                if scaled.status.accel_clipped() || scaled.status.gyro_clipped() {
+                    stats.clipped_count += 1;
+                }
+                for value in scaled.accel {
+                    batch_peak_accel = batch_peak_accel.max(libm::fabsf(value));
+                }
+                let Some(scaled) = self.low_pass_filter(scaled) else {
+                    stats.rejected_count += 1;
+                    continue;
+                };
+                if let Some(attitude) = &mut self.attitude {
+                    // Samples are evenly spaced, including those read in one batch
+                    attitude.update(&scaled, SAMPLE_PERIOD_SECS);
+                }
+                if samples.try_send(scaled).is_err() {
+                    // Consumer fell behind, keep the newest data
+                    let _ = samples.try_receive();
+                    let _ = samples.try_send(scaled);
+                }
+                latest = scaled;
+                stats.sample_count += 1;
+            }
+
+            // Time from the task waking on data-ready until the newest sample is published, taken
+            // before the range switch below, whose register writes are not part of publishing it
+            let latency_us = cycles_to_micros(cycle_count().wrapping_sub(data_ready));
+            stats.peak_latency_us = stats.peak_latency_us.max(latency_us);
+            if self
+                .config
+                .latency_deadline_us
+                .is_some_and(|deadline| latency_us > deadline)
+            {
+                stats.deadline_misses += 1;
+                IMU_DEADLINE_MISSED.store(true, Ordering::Relaxed);
+            }
+
+            // Range changes only take effect between batches so every sample of a batch
+            // is scaled with the range it was measured with
+            let full_scale = self.accel_range.full_scale_g() * STANDARD_GRAVITY;
+            self.auto_range(batch_peak_accel / full_scale, packet_count as u32)
+                .await?;
+
+            stats.peak_fifo_fill = stats.peak_fifo_fill.max(self.fifo_fill_percent());
+
+            // Log statistics to monitor data rate and values, without reading the clock while
+            // logging is disabled. The sample rate is only checked while statistics are logged.
+            match (self.stats_interval, window_start) {
+                (None, _) => {
+                    // Nothing reports the counters, so keep them from growing until enabled
+                    stats = RunStats::default();
+                    window_start = None;
+                }
+                (Some(_), None) => {
+                    // The counters cover an unknown time, start a fresh window after this batch
+                    stats = RunStats::default();
+                    window_start = Some(embassy_time::Instant::now());
+                }
+                (Some(interval), Some(start)) => {
+                    let now = embassy_time::Instant::now();
+                    let window = now.duration_since(start);
+                    if window >= interval {
+                        self.check_sample_rate(&stats, window);
+                        self.log_stats(&stats, &latest);
+                        stats = RunStats::default();
+                        window_start = Some(now);
+                    }
                 }
             }
 
-            // Log statistics every second to monitor data rate and values
-            let now = embassy_time::Instant::now();
-            if now.duration_since(last_log_time).as_millis() >= 1000 {
-                defmt::info!(
-                    "IMU Stats: {} samples/sec | Accel (m/s²): [{}, {}, {}] | Gyro (rad/s): [{}, {}, {}] | Temp: {} °C",
-                    sample_count,
-                    latest_accel[0],
-                    latest_accel[1],
-                    latest_accel[2],
-                    latest_gyro[0],
-                    latest_gyro[1],
-                    latest_gyro[2],
-                    latest_temp
-                );
-
-                sample_count = 0;
-                last_log_time = now;
+            if self.handle_request(&latest).await {
+                // Like time spent parked, the samples the request consumed would read as a
+                // missing sample rate and the filters would smooth across the gap
+                window_start = None;
+                self.reset_filters();
             }
         }
     }
 }
 
+/// Restart policy for the IMU driver
+///
+/// Transient errors cost 50ms of data at first, backing off to 5s if they keep recurring. Ten
+/// seconds of clean running resets the backoff.
+const RESTART_POLICY: RestartPolicy = RestartPolicy::exponential(
+    Duration::from_millis(50),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+);
+
 /// Embassy task for running the ICM-20689 IMU driver with error recovery.
 ///
 /// This task initializes the IMU driver using the provided SPI and IMU peripherals,
-/// and continuously runs the driver in a loop. If an error occurs during operation,
+/// and runs the driver under supervision. If an error occurs during operation,
 /// the task logs the error and automatically restarts the driver after a delay,
 /// ensuring robust operation in the presence of transient faults.
 ///
 /// # Parameters
 /// - `spi_peripherals`: SPI peripheral claims required for IMU communication.
 /// - `imu_peripherals`: IMU interrupt pin and line peripherals.
+/// - `samples`: Channel every parsed sample is published into.
 ///
 /// # Behavior
-/// - Runs the IMU driver in an infinite loop.
-/// - On error, logs the error and restarts the driver after a 5-second delay.
+/// - Runs the IMU driver under [`run_supervised`] with [`RESTART_POLICY`].
+/// - On error, logs the error and restarts the driver with exponential backoff from 50ms to 5s.
+/// - A missing device is logged once and retried every 5 seconds.
 /// - Intended to be spawned as an Embassy task for continuous IMU data acquisition.
 #[embassy_executor::task]
 pub async fn task(
     spi_peripherals: crate::peripherals::spi::SpiClaims<'static>,
     imu_peripherals: ImuPeripherals<'static>,
+    samples: &'static ImuChannel,
 ) -> ! {
     let spi = crate::peripherals::spi::ImuSpi::new(spi_peripherals);
-    let mut imu = Icm20689::new(spi, imu_peripherals);
+    let mut imu: Icm20689 = Icm20689::new(spi, imu_peripherals);
+    // Only dump the registers of a missing chip once, it is retried every few seconds
+    #[cfg(feature = "debug-imu")]
+    let mut registers_dumped = false;
 
-    loop {
-        match imu.run().await {
-            Ok(()) => {
-                // This should never happen as run() is supposed to loop forever
-                defmt::info!("IMU task unexpectedly returned Ok(())");
+    let _ = run_supervised("IMU", RESTART_POLICY, async || {
+        // Only monitored while running, the restart delay can outlast the liveness timeout and a
+        // missing chip must not reset the board
+        liveness::checkin(TaskId::Imu);
+        let result = if imu.has_interrupt() {
+            imu.run(samples).await
+        } else {
+            imu.run_polled(samples, DEFAULT_POLL_INTERVAL).await
+        };
+        #[cfg(feature = "debug-imu")]
+        if matches!(result, Err(ImuError::DeviceNotFound)) && !registers_dumped {
+            registers_dumped = true;
+            match imu.dump_registers().await {
+                Ok(registers) => log_registers(&registers),
+                Err(e) => defmt::warn!("IMU register dump failed: {:?}", e),
             }
-            Err(e) => {
-                defmt::info!("IMU error: {:?}, restarting in 5 seconds...", e);
-                embassy_time::Timer::after(embassy_time::Duration::from_secs(5)).await;
+        }
+
+        // Permanent failures are reported once by the supervisor
+        if let Err(e) = &result {
+            liveness::suspend(TaskId::Imu);
+            if !e.is_permanent() {
+                defmt::info!("IMU error: {:?}", e);
             }
         }
+        result
+    })
+    .await;
+
+    // The IMU policy retries forever, so this is only reached if the policy gains a limit
+    defmt::error!("IMU task stopped");
+    loop {
+        core::future::pending::<()>().await;
     }
 }
@@ -0,0 +1,206 @@
+//! Allocation-free filters for smoothing IMU output.
+//!
+//! Every filter works on `[f32; 3]` vectors, so one instance covers the three axes of either the
+//! accelerometer or the gyroscope, and keeps its history in fixed-size arrays. The IMU task runs a
+//! [`LowPass`] on both sensors when [`ImuConfig::low_pass_cutoff_hz`](super::ImuConfig) is set.
+//!
+//! A NaN or infinity that reaches a filter's history stays there for good, so every filter checks
+//! its output and resets itself, logging it, rather than pass a non-finite value on.
+//!
+//! [`ComplementaryFilter`] builds on the filtered samples to estimate roll and pitch, which the IMU
+//! task does when [`ImuConfig::attitude_alpha`](super::ImuConfig) is set.
+
+use core::f32::consts::{FRAC_PI_2, PI};
+
+use super::driver::ImuData;
+use super::scale::STANDARD_GRAVITY;
+
+/// Relative deviation of the measured acceleration from gravity beyond which the accelerometer is
+/// not trusted to give the direction of gravity
+const GRAVITY_TOLERANCE: f32 = 0.1;
+
+/// Largest pitch magnitude the estimate is held to, just short of ±π/2 where roll is undefined and
+/// the rate conversion through `tan(pitch)` overflows
+const MAX_PITCH: f32 = FRAC_PI_2 - 0.01;
+
+/// First-order IIR low-pass filter
+///
+/// The discrete form of an RC filter, `y += α·(x - y)` with `α = dt / (RC + dt)`. It starts from
+/// the first sample rather than from zero, so the output does not ramp up from nothing.
+#[derive(Debug, Clone, Copy)]
+pub struct LowPass {
+    /// Fraction of the difference to the input taken on every sample
+    alpha: f32,
+    /// Previous output, `None` until the first sample
+    state: Option<[f32; 3]>,
+}
+
+impl LowPass {
+    /// Create a low-pass filter for a fixed sample rate
+    ///
+    /// # Arguments
+    /// * `cutoff_hz` - Frequency attenuated by 3dB, which should be below half the sample rate
+    /// * `sample_rate_hz` - Rate `update` is called at
+    pub fn new(cutoff_hz: f32, sample_rate_hz: f32) -> Self {
+        let dt = 1.0 / sample_rate_hz;
+        let rc = 1.0 / (2.0 * PI * cutoff_hz);
+        Self {
+            alpha: dt / (rc + dt),
+            state: None,
+        }
+    }
+
+    /// Filter the next sample
+    ///
+    /// # Arguments
+    /// * `input` - The newest sample
+    ///
+    /// # Returns
+    /// The filtered sample, or `None` if it is not finite, in which case the filter starts again
+    /// from the next sample
+    pub fn update(&mut self, input: [f32; 3]) -> Option<[f32; 3]> {
+        let output = match self.state {
+            Some(previous) => core::array::from_fn(|axis| previous[axis] + self.alpha * (input[axis] - previous[axis])),
+            None => input,
+        };
+
+        if !is_finite(&output) {
+            defmt::warn!("Low-pass filter output is not finite, resetting it");
+            self.reset();
+            return None;
+        }
+        self.state = Some(output);
+        Some(output)
+    }
+
+    /// Forget the filter history, so the next sample starts it again
+    pub fn reset(&mut self) {
+        self.state = None;
+    }
+}
+
+/// Roll and pitch estimate fusing the accelerometer and gyroscope
+///
+/// At rest the accelerometer measures the reaction to gravity, which points up the body's
+/// vertical and gives the tilt without drift but with all the noise and vibration of the
+/// accelerometer. Integrating the gyroscope is smooth but drifts. Each update blends the two,
+/// `angle = α·(angle + rate·dt) + (1 - α)·accel_angle`, so the gyroscope sets the short-term
+/// motion and the accelerometer slowly pulls out the drift, with a time constant of about
+/// `dt·α / (1 - α)`.
+///
+/// While the robot is accelerating the measured vector is no longer gravity, so the accelerometer
+/// is ignored whenever its magnitude is off by more than [`GRAVITY_TOLERANCE`] or it clipped,
+/// and the estimate follows the gyroscope alone. Angles are in radians in the body frame, roll
+/// about X and pitch about Y, with Z up when level. Pitch is held within [`MAX_PITCH`] of level,
+/// as roll cannot be told apart from yaw pointing straight up or down.
+#[derive(Debug, Clone, Copy)]
+pub struct ComplementaryFilter {
+    /// Weight of the gyroscope prediction in each update
+    alpha: f32,
+    /// Current roll and pitch, `None` until the first sample
+    angles: Option<(f32, f32)>,
+}
+
+impl ComplementaryFilter {
+    /// Create a filter with the given gyroscope weight
+    ///
+    /// # Arguments
+    /// * `alpha` - Weight of the gyroscope from 0 (accelerometer only) to 1 (gyroscope only),
+    ///   typically around 0.98 at 1000Hz
+    ///
+    /// # Returns
+    /// The filter, or `None` if `alpha` is outside 0 to 1
+    pub fn new(alpha: f32) -> Option<Self> {
+        (0.0..=1.0).contains(&alpha).then_some(Self { alpha, angles: None })
+    }
+
+    /// Current roll and pitch in radians, `None` before the first update
+    pub fn angles(&self) -> Option<(f32, f32)> {
+        self.angles
+    }
+
+    /// Forget the estimate, so the next update starts again from the accelerometer
+    pub fn reset(&mut self) {
+        self.angles = None;
+    }
+
+    /// Fuse the next sample into the estimate
+    ///
+    /// The first sample sets the angles from the accelerometer alone. If that sample cannot be
+    /// trusted the estimate starts level.
+    ///
+    /// A NaN or infinity would stay in the estimate for good, so if one comes out of the update
+    /// the estimate is reset and starts again from the next sample.
+    ///
+    /// # Arguments
+    /// * `data` - Sample in the body frame, with the gyroscope bias already removed
+    /// * `dt` - Time in seconds since the previous sample
+    ///
+    /// # Returns
+    /// Roll and pitch in radians, or `None` if the estimate was reset
+    pub fn update(&mut self, data: &ImuData, dt: f32) -> Option<(f32, f32)> {
+        let measured = Self::accel_angles(data);
+
+        let angles = match self.angles {
+            None => measured.unwrap_or((0.0, 0.0)),
+            Some((roll, pitch)) => {
+                // The gyroscope measures body rates, which only equal the Euler angle rates when
+                // level, so convert them before integrating
+                let [x, y, z] = data.gyro;
+                let (sin_roll, cos_roll) = (libm::sinf(roll), libm::cosf(roll));
+                let roll_rate = x + (y * sin_roll + z * cos_roll) * libm::tanf(pitch);
+                let pitch_rate = y * cos_roll - z * sin_roll;
+                let predicted = (
+                    wrap_angle(roll + roll_rate * dt),
+                    (pitch + pitch_rate * dt).clamp(-MAX_PITCH, MAX_PITCH),
+                );
+
+                match measured {
+                    // Blend through the difference, so a roll either side of ±π is not averaged
+                    // to zero
+                    Some((accel_roll, accel_pitch)) => (
+                        wrap_angle(accel_roll + self.alpha * wrap_angle(predicted.0 - accel_roll)),
+                        accel_pitch + self.alpha * (predicted.1 - accel_pitch),
+                    ),
+                    None => predicted,
+                }
+            }
+        };
+        let angles = (angles.0, angles.1.clamp(-MAX_PITCH, MAX_PITCH));
+
+        if !(angles.0.is_finite() && angles.1.is_finite()) {
+            defmt::warn!("Roll and pitch estimate is not finite, resetting it");
+            self.reset();
+            return None;
+        }
+        self.angles = Some(angles);
+        Some(angles)
+    }
+
+    /// Roll and pitch given by the direction of gravity, `None` if the accelerometer is not
+    /// measuring gravity alone
+    fn accel_angles(data: &ImuData) -> Option<(f32, f32)> {
+        let [x, y, z] = data.accel;
+        let magnitude = libm::sqrtf(x * x + y * y + z * z);
+        if data.status.accel_clipped() || libm::fabsf(magnitude / STANDARD_GRAVITY - 1.0) > GRAVITY_TOLERANCE {
+            return None;
+        }
+        Some((libm::atan2f(y, z), libm::atan2f(-x, libm::sqrtf(y * y + z * z))))
+    }
+}
+
+/// Whether every axis of a vector is a finite number
+fn is_finite(vector: &[f32; 3]) -> bool {
+    vector.iter().all(|value| value.is_finite())
+}
+
+/// Wrap an angle in radians into -π to π
+fn wrap_angle(angle: f32) -> f32 {
+    if angle > PI {
+        angle - 2.0 * PI
+    } else if angle < -PI {
+        angle + 2.0 * PI
+    } else {
+        angle
+    }
+}
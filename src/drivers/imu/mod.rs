@@ -1,2 +1,8 @@
 mod driver;
-pub use driver::{task, ImuPeripherals};
+pub mod filter;
+mod scale;
+pub use driver::{
+    request, task, AccelAveraging, Axis, AxisMap, AxisRemap, FifoMode, FsyncLatch, ImuChannel, ImuConfig, ImuData,
+    ImuError, ImuPeripherals, ImuPower, ImuRequest, ImuResponse, InterruptMode, NoiseReport, IMU_DEADLINE_MISSED,
+    IMU_POWER,
+};
@@ -0,0 +1,267 @@
+//! Conversion of IMU register counts to physical units
+//!
+//! The ranges, datasheet sensitivities and the arithmetic that turns a packet in the data register
+//! layout into m/s², rad/s and °C. This module only uses `core`, so its tests run on the host
+//! without the rest of the firmware:
+//!
+//! ```text
+//! rustc --edition 2021 --test src/drivers/imu/scale.rs -o target/imu-scale && target/imu-scale
+//! ```
+
+/// Number of bytes in a full FIFO packet (6 accel + 2 temp + 6 gyro), which is also the size of
+/// the data register block, see `FifoContents::packet_size` for the active packet size
+pub const PACKET_SIZE: usize = 14;
+
+/// Standard gravity in m/s², one g
+pub const STANDARD_GRAVITY: f32 = 9.80665;
+
+/// Temperature sensitivity in LSB/°C and the temperature in °C a reading of zero stands for, on
+/// the ICM-20689
+pub const ICM20689_TEMPERATURE_SCALE: (f32, f32) = (333.87, 21.0);
+/// Temperature sensitivity in LSB/°C and the temperature in °C a reading of zero stands for, on
+/// the ICM-20602 and ICM-20608
+pub const ICM2060X_TEMPERATURE_SCALE: (f32, f32) = (326.8, 25.0);
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+#[allow(dead_code)]
+pub enum AccelRange {
+    G2 = 0b00 << 3,
+    #[default]
+    G4 = 0b01 << 3,
+    G8 = 0b10 << 3,
+    G16 = 0b11 << 3,
+}
+
+impl AccelRange {
+    /// Full-scale acceleration in g
+    pub const fn full_scale_g(self) -> f32 {
+        match self {
+            AccelRange::G2 => 2.0,
+            AccelRange::G4 => 4.0,
+            AccelRange::G8 => 8.0,
+            AccelRange::G16 => 16.0,
+        }
+    }
+
+    /// Sensitivity in LSB/g, from the datasheet
+    pub const fn sensitivity(self) -> f32 {
+        match self {
+            AccelRange::G2 => 16384.0,
+            AccelRange::G4 => 8192.0,
+            AccelRange::G8 => 4096.0,
+            AccelRange::G16 => 2048.0,
+        }
+    }
+
+    /// The next wider range, if there is one
+    pub const fn wider(self) -> Option<AccelRange> {
+        match self {
+            AccelRange::G2 => Some(AccelRange::G4),
+            AccelRange::G4 => Some(AccelRange::G8),
+            AccelRange::G8 => Some(AccelRange::G16),
+            AccelRange::G16 => None,
+        }
+    }
+
+    /// The next narrower range, if there is one
+    pub const fn narrower(self) -> Option<AccelRange> {
+        match self {
+            AccelRange::G2 => None,
+            AccelRange::G4 => Some(AccelRange::G2),
+            AccelRange::G8 => Some(AccelRange::G4),
+            AccelRange::G16 => Some(AccelRange::G8),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+#[allow(dead_code)]
+pub enum GyroRange {
+    Dps250 = 0b00 << 3,
+    Dps500 = 0b01 << 3,
+    Dps1000 = 0b10 << 3,
+    Dps2000 = 0b11 << 3,
+}
+
+impl GyroRange {
+    /// Sensitivity in LSB/(°/s), from the datasheet
+    pub const fn sensitivity(self) -> f32 {
+        match self {
+            GyroRange::Dps250 => 131.0,
+            GyroRange::Dps500 => 65.5,
+            GyroRange::Dps1000 => 32.8,
+            GyroRange::Dps2000 => 16.4,
+        }
+    }
+}
+
+/// Unscaled IMU readings in ADC counts, as in the data registers
+///
+/// The counts are in the sensor frame, before the axis remap, bias and temperature compensation
+/// the published samples have applied. Scale them with the accelerometer and gyroscope ranges
+/// they were measured with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct RawImuData {
+    /// Acceleration (X, Y, Z)
+    pub accel: [i16; 3],
+    /// Angular velocity (X, Y, Z)
+    pub gyro: [i16; 3],
+    /// Die temperature, zero when the temperature is left out of the FIFO
+    pub temp: i16,
+}
+
+/// IMU readings in physical units
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScaledImuData {
+    /// Acceleration (X, Y, Z) in m/s²
+    pub accel: [f32; 3],
+    /// Angular velocity (X, Y, Z) in rad/s
+    pub gyro: [f32; 3],
+    /// Die temperature in °C
+    pub temperature: f32,
+}
+
+impl RawImuData {
+    /// Split a packet in the data register layout into its counts
+    ///
+    /// # Arguments
+    /// * `packet` - [accel_x_h, accel_x_l, accel_y_h, accel_y_l, accel_z_h, accel_z_l, temp_h,
+    ///   temp_l, gyro_x_h, gyro_x_l, gyro_y_h, gyro_y_l, gyro_z_h, gyro_z_l], as in the data
+    ///   registers and a FIFO packet with all sensors enabled
+    pub fn from_registers(packet: &[u8; PACKET_SIZE]) -> Self {
+        let read = |offset: usize| i16::from_be_bytes([packet[offset], packet[offset + 1]]);
+        Self {
+            accel: [read(0), read(2), read(4)],
+            temp: read(6),
+            gyro: [read(8), read(10), read(12)],
+        }
+    }
+
+    /// Scale the counts to physical units
+    ///
+    /// # Arguments
+    /// * `accel_range` - Accelerometer range the counts were measured with
+    /// * `gyro_range` - Gyroscope range the counts were measured with
+    /// * `temperature_scale` - Temperature sensitivity and offset of the chip, e.g.
+    ///   [`ICM20689_TEMPERATURE_SCALE`]
+    ///
+    /// # Returns
+    /// The readings in m/s², rad/s and °C
+    pub fn scale(
+        &self,
+        accel_range: AccelRange,
+        gyro_range: GyroRange,
+        temperature_scale: (f32, f32),
+    ) -> ScaledImuData {
+        let accel_scale = STANDARD_GRAVITY / accel_range.sensitivity(); // Convert to m/s²
+        let gyro_scale = (core::f32::consts::PI / 180.0) / gyro_range.sensitivity(); // Convert to rad/s
+        let (temperature_sensitivity, temperature_offset) = temperature_scale;
+
+        ScaledImuData {
+            accel: self.accel.map(|count| f32::from(count) * accel_scale),
+            gyro: self.gyro.map(|count| f32::from(count) * gyro_scale),
+            // Temperature scaling (datasheet formula)
+            temperature: f32::from(self.temp) / temperature_sensitivity + temperature_offset,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assert two values agree to within `tolerance`
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    /// A packet with the given counts in the data register layout
+    fn packet(accel: [i16; 3], temp: i16, gyro: [i16; 3]) -> [u8; PACKET_SIZE] {
+        let mut packet = [0u8; PACKET_SIZE];
+        for (index, count) in accel.into_iter().chain([temp]).chain(gyro).enumerate() {
+            packet[2 * index..2 * index + 2].copy_from_slice(&count.to_be_bytes());
+        }
+        packet
+    }
+
+    #[test]
+    fn registers_are_big_endian() {
+        let raw = RawImuData::from_registers(&[
+            0x20, 0x00, 0xE0, 0x00, 0x40, 0x00, 0x0D, 0x0B, 0x19, 0x96, 0xFD, 0x71, 0x80, 0x00,
+        ]);
+        assert_eq!(raw.accel, [8192, -8192, 16384]);
+        assert_eq!(raw.temp, 3339);
+        assert_eq!(raw.gyro, [6550, -655, i16::MIN]);
+    }
+
+    #[test]
+    fn accel_scales_to_metres_per_second_squared() {
+        let raw = RawImuData::from_registers(&packet([8192, -8192, 16384], 0, [0; 3]));
+        let scaled = raw.scale(AccelRange::G4, GyroRange::Dps500, ICM20689_TEMPERATURE_SCALE);
+        assert_close(scaled.accel[0], STANDARD_GRAVITY, 1e-5);
+        assert_close(scaled.accel[1], -STANDARD_GRAVITY, 1e-5);
+        assert_close(scaled.accel[2], 2.0 * STANDARD_GRAVITY, 1e-5);
+
+        let raw = RawImuData::from_registers(&packet([2048, -1024, i16::MIN], 0, [0; 3]));
+        let scaled = raw.scale(AccelRange::G16, GyroRange::Dps500, ICM20689_TEMPERATURE_SCALE);
+        assert_close(scaled.accel[0], STANDARD_GRAVITY, 1e-5);
+        assert_close(scaled.accel[1], -0.5 * STANDARD_GRAVITY, 1e-5);
+        assert_close(scaled.accel[2], -16.0 * STANDARD_GRAVITY, 1e-4);
+    }
+
+    #[test]
+    fn gyro_scales_to_radians_per_second() {
+        // 100°/s, -10°/s and 0°/s at ±500°/s
+        let raw = RawImuData::from_registers(&packet([0; 3], 0, [6550, -655, 0]));
+        let scaled = raw.scale(AccelRange::G4, GyroRange::Dps500, ICM20689_TEMPERATURE_SCALE);
+        assert_close(scaled.gyro[0], 1.745_329_3, 1e-5);
+        assert_close(scaled.gyro[1], -0.174_532_93, 1e-6);
+        assert_close(scaled.gyro[2], 0.0, 0.0);
+
+        // 10°/s and -250°/s at ±2000°/s and ±250°/s
+        let raw = RawImuData::from_registers(&packet([0; 3], 0, [164, 0, 0]));
+        let scaled = raw.scale(AccelRange::G4, GyroRange::Dps2000, ICM20689_TEMPERATURE_SCALE);
+        assert_close(scaled.gyro[0], 0.174_532_93, 1e-6);
+        let raw = RawImuData::from_registers(&packet([0; 3], 0, [-32750, 0, 0]));
+        let scaled = raw.scale(AccelRange::G4, GyroRange::Dps250, ICM20689_TEMPERATURE_SCALE);
+        assert_close(scaled.gyro[0], -4.363_323, 1e-3);
+    }
+
+    #[test]
+    fn temperature_follows_the_datasheet_formula() {
+        let scale = |temp, temperature_scale| {
+            RawImuData::from_registers(&packet([0; 3], temp, [0; 3]))
+                .scale(AccelRange::G4, GyroRange::Dps500, temperature_scale)
+                .temperature
+        };
+        assert_close(scale(0, ICM20689_TEMPERATURE_SCALE), 21.0, 1e-6);
+        assert_close(scale(3339, ICM20689_TEMPERATURE_SCALE), 31.0, 1e-2);
+        assert_close(scale(-3339, ICM20689_TEMPERATURE_SCALE), 11.0, 1e-2);
+        assert_close(scale(0, ICM2060X_TEMPERATURE_SCALE), 25.0, 1e-6);
+        assert_close(scale(-3268, ICM2060X_TEMPERATURE_SCALE), 15.0, 1e-4);
+    }
+
+    #[test]
+    fn sensitivities_match_the_full_scale_ranges() {
+        for range in [AccelRange::G2, AccelRange::G4, AccelRange::G8, AccelRange::G16] {
+            assert_close(32768.0 / range.sensitivity(), range.full_scale_g(), 1e-6);
+        }
+        for (range, full_scale_dps) in [
+            (GyroRange::Dps250, 250.0),
+            (GyroRange::Dps500, 500.0),
+            (GyroRange::Dps1000, 1000.0),
+            (GyroRange::Dps2000, 2000.0),
+        ] {
+            // The datasheet rounds the sensitivities, so the ranges come out slightly wider
+            assert_close(32768.0 / range.sensitivity(), full_scale_dps, full_scale_dps * 0.01);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Internal temperature sensor and backup battery voltage
+//!
+//! The STM32H753 routes its internal channels to ADC3 only: VBAT/4 on channel 17, the temperature
+//! sensor on channel 18 and VREFINT on channel 19. The temperature is calibrated with the factory
+//! TS_CAL1 and TS_CAL2 values, taken at 30°C and 110°C with VDDA at 3.3V. VDDA itself is measured
+//! against VREFINT and its factory VREFINT_CAL value on every read, so the results do not depend
+//! on the supply being exactly 3.3V.
+//!
+//! ADC3 can only transfer with the BDMA, which only reaches SRAM4, so conversions are polled.
+//! Each one takes about 25µs with the long sampling time the temperature sensor needs, and the
+//! reads yield to the executor between conversions.
+
+use embassy_stm32::{
+    adc::{Adc, AdcChannel, Resolution, SampleTime, Temperature, VrefInt},
+    pac,
+    peripherals::ADC3,
+    Peri,
+};
+
+/// Address of TS_CAL1, the temperature sensor reading at [`TS_CAL1_TEMP`] (RM0433 section 25.4.32)
+const TS_CAL1_ADDRESS: usize = 0x1FF1_E820;
+/// Address of TS_CAL2, the temperature sensor reading at [`TS_CAL2_TEMP`]
+const TS_CAL2_ADDRESS: usize = 0x1FF1_E840;
+/// Address of VREFINT_CAL, the VREFINT reading at [`CAL_VDDA`]
+const VREFINT_CAL_ADDRESS: usize = 0x1FF1_E860;
+
+/// Temperature in °C at which TS_CAL1 was taken
+const TS_CAL1_TEMP: f32 = 30.0;
+/// Temperature in °C at which TS_CAL2 was taken
+const TS_CAL2_TEMP: f32 = 110.0;
+/// VDDA in volts at which every factory calibration value was taken
+const CAL_VDDA: f32 = 3.3;
+/// Full scale of a 16-bit conversion, the resolution of the calibration values
+const FULL_SCALE: f32 = 65535.0;
+/// VBAT is measured through an internal divider by four
+const VBAT_DIVIDER: f32 = 4.0;
+
+/// Conversions averaged into every reading
+const SAMPLES: u32 = 8;
+
+/// Peripheral collection for the internal ADC channels
+pub struct InternalAdcPeripherals<'d> {
+    pub adc: Peri<'d, ADC3>,
+}
+
+/// Macro to claim peripherals for InternalAdc
+#[macro_export]
+macro_rules! claim_internal_adc {
+    ($peripherals:expr) => {{
+        $crate::peripherals::claims::register($crate::peripherals::claims::Resource::Adc3);
+        $crate::drivers::internal_adc::InternalAdcPeripherals { adc: $peripherals.ADC3 }
+    }};
+}
+
+/// Core temperature and backup battery monitor on ADC3
+pub struct InternalAdc<'d> {
+    adc: Adc<'d, ADC3>,
+    temperature: Temperature,
+    vrefint: VrefInt,
+}
+
+impl<'d> InternalAdc<'d> {
+    /// Power up ADC3 and the temperature sensor and VREFINT
+    ///
+    /// The VBAT divider is only connected while reading it, as it drains the battery otherwise.
+    ///
+    /// # Arguments
+    /// * `peripherals` - InternalAdcPeripherals struct containing ADC3
+    pub fn new(peripherals: InternalAdcPeripherals<'d>) -> Self {
+        let mut adc = Adc::new(peripherals.adc);
+        adc.set_resolution(Resolution::BITS16);
+        // The temperature sensor needs at least 9µs of sampling, the longest time covers it at
+        // every ADC clock
+        adc.set_sample_time(SampleTime::CYCLES810_5);
+        let temperature = adc.enable_temperature();
+        let vrefint = adc.enable_vrefint();
+
+        Self {
+            adc,
+            temperature,
+            vrefint,
+        }
+    }
+
+    /// Read the core temperature
+    ///
+    /// # Returns
+    /// Die temperature in °C
+    pub async fn read_core_temp(&mut self) -> f32 {
+        let vdda = self.read_vdda().await;
+        let raw = average(&mut self.adc, &mut self.temperature).await;
+
+        // Scale the reading to what it would have been at the calibration VDDA
+        let raw = raw * vdda / CAL_VDDA;
+        let cal1 = f32::from(read_calibration(TS_CAL1_ADDRESS));
+        let cal2 = f32::from(read_calibration(TS_CAL2_ADDRESS));
+        TS_CAL1_TEMP + (raw - cal1) * (TS_CAL2_TEMP - TS_CAL1_TEMP) / (cal2 - cal1)
+    }
+
+    /// Read the backup battery voltage
+    ///
+    /// # Returns
+    /// VBAT in volts
+    pub async fn read_vbat(&mut self) -> f32 {
+        let vdda = self.read_vdda().await;
+        let mut vbat = self.adc.enable_vbat();
+        let raw = average(&mut self.adc, &mut vbat).await;
+        pac::ADC3_COMMON.ccr().modify(|w| w.set_vbaten(false));
+        raw / FULL_SCALE * vdda * VBAT_DIVIDER
+    }
+
+    /// Measure the analog supply against VREFINT
+    ///
+    /// # Returns
+    /// VDDA in volts
+    async fn read_vdda(&mut self) -> f32 {
+        let raw = average(&mut self.adc, &mut self.vrefint).await;
+        CAL_VDDA * f32::from(read_calibration(VREFINT_CAL_ADDRESS)) / raw
+    }
+}
+
+/// Average [`SAMPLES`] conversions of a channel, yielding to the executor between them
+async fn average(adc: &mut Adc<'_, ADC3>, channel: &mut impl AdcChannel<ADC3>) -> f32 {
+    let mut sum = 0u32;
+    for _ in 0..SAMPLES {
+        sum += u32::from(adc.blocking_read(channel));
+        embassy_futures::yield_now().await;
+    }
+    sum as f32 / SAMPLES as f32
+}
+
+/// Read a 16-bit factory calibration value from system memory
+fn read_calibration(address: usize) -> u16 {
+    // SAFETY: The calibration values are read-only halfwords in system memory, always mapped
+    unsafe { core::ptr::read_volatile(address as *const u16) }
+}
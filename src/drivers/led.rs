@@ -0,0 +1,117 @@
+//! Status LED driven with blink patterns
+//!
+//! The status LED on PE3 (active high) shows the system state without a debugger attached. Any
+//! part of the firmware can change the indication by signalling [`LED_PATTERN`], and the LED task
+//! switches over at once, abandoning the pattern it was playing. Patterns are timed with
+//! [`Timer`] so the task sleeps between transitions.
+
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{
+    gpio::{Level, Output, Speed},
+    peripherals::PE3,
+    Peri,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+
+/// Pattern shown from boot until another one is signalled
+pub const DEFAULT_PATTERN: LedPattern = LedPattern::Heartbeat;
+
+/// Peripheral collection for the status LED
+pub struct LedPeripherals<'d> {
+    pub pin: Peri<'d, PE3>,
+}
+
+/// Macro to claim peripherals for StatusLed
+#[macro_export]
+macro_rules! claim_led {
+    ($peripherals:expr) => {{
+        $crate::drivers::led::LedPeripherals { pin: $peripherals.PE3 }
+    }};
+}
+
+/// Indication shown on the status LED
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum LedPattern {
+    /// 1 Hz, evenly on and off
+    SlowBlink,
+    /// 5 Hz, evenly on and off
+    FastBlink,
+    /// Two short pulses followed by a pause, once per second
+    Heartbeat,
+}
+
+impl LedPattern {
+    /// One period of the pattern as (on, duration) steps
+    fn steps(self) -> &'static [(bool, Duration)] {
+        const SLOW: Duration = Duration::from_millis(500);
+        const FAST: Duration = Duration::from_millis(100);
+        const PULSE: Duration = Duration::from_millis(100);
+        const PAUSE: Duration = Duration::from_millis(700);
+
+        match self {
+            LedPattern::SlowBlink => &[(true, SLOW), (false, SLOW)],
+            LedPattern::FastBlink => &[(true, FAST), (false, FAST)],
+            LedPattern::Heartbeat => &[(true, PULSE), (false, PULSE), (true, PULSE), (false, PAUSE)],
+        }
+    }
+}
+
+/// Signal used to change the pattern shown by the LED task
+pub static LED_PATTERN: Signal<CriticalSectionRawMutex, LedPattern> = Signal::new();
+
+/// Active-high status LED
+pub struct StatusLed<'d> {
+    output: Output<'d>,
+}
+
+impl<'d> StatusLed<'d> {
+    /// Create the LED, initially off
+    ///
+    /// # Arguments
+    /// * `peripherals` - LedPeripherals struct containing the LED pin
+    pub fn new(peripherals: LedPeripherals<'d>) -> Self {
+        Self {
+            output: Output::new(peripherals.pin, Level::Low, Speed::Low),
+        }
+    }
+
+    /// Turn the LED on or off
+    pub fn set(&mut self, on: bool) {
+        if on {
+            self.output.set_high();
+        } else {
+            self.output.set_low();
+        }
+    }
+
+    /// Play a pattern forever
+    ///
+    /// Cancelling the returned future leaves the LED at whatever level the pattern had reached.
+    pub async fn play(&mut self, pattern: LedPattern) -> ! {
+        loop {
+            for &(on, duration) in pattern.steps() {
+                self.set(on);
+                Timer::after(duration).await;
+            }
+        }
+    }
+}
+
+/// Status LED task
+///
+/// Shows [`DEFAULT_PATTERN`] until a new pattern is signalled on [`LED_PATTERN`], then restarts
+/// from the beginning of the new pattern.
+#[embassy_executor::task]
+pub async fn task(peripherals: LedPeripherals<'static>) -> ! {
+    let mut led = StatusLed::new(peripherals);
+    let mut pattern = DEFAULT_PATTERN;
+
+    loop {
+        match select(led.play(pattern), LED_PATTERN.wait()).await {
+            Either::First(never) => match never {},
+            Either::Second(next) => pattern = next,
+        }
+    }
+}
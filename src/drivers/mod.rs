@@ -3,5 +3,20 @@
 //! This module contains device drivers for various sensors and actuators
 //! used in the NUSense system.
 
+/// Raw servo bus traffic capture over its own ACM port
+#[cfg(feature = "bus-sniffer")]
+pub mod bus_sniffer;
+/// Debounced user button
+pub mod button;
+/// Half-duplex RS-485 bus for the Dynamixel servos
+pub mod dynamixel_bus;
 /// ICM-20689 IMU driver
 pub mod imu;
+/// Core temperature and VBAT readings from the internal ADC channels
+pub mod internal_adc;
+/// Status LED blink patterns
+pub mod led;
+/// Correlation of servo replies with their requests
+pub mod servo_requests;
+/// Servo commands sent at a scheduled time
+pub mod servo_schedule;
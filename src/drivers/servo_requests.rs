@@ -0,0 +1,265 @@
+//! Correlation of servo replies with the requests that asked for them.
+//!
+//! Pipelined reads, e.g. a bulk read or reads sent to several servos before listening, leave
+//! several replies owed at once. [`ServoRequests`] keeps track of every reply still owed and hands
+//! each status packet received to the request it answers, reporting a [`Completion`] per request,
+//! so a reply that turns up late is never taken for the answer to a later request.
+//!
+//! # Matching rules
+//!
+//! A status packet does not say which instruction it answers, only which servo sent it, so a reply
+//! answers a request when:
+//! - Its ID is the ID of the servo the request addressed
+//! - It holds as many parameters as the instruction returns, e.g. the bytes asked for by a read,
+//!   3 for a ping or none for a write. A reply with an error number is matched whatever its
+//!   parameters, as a servo refusing an instruction returns no data
+//! - It arrives before the request's deadline
+//!
+//! A servo answers in the order it was asked, so a reply that answers several requests goes to the
+//! one sent first. A reply that answers no request is dropped and logged, as late if its servo is
+//! in quarantine (see below) or as unsolicited otherwise.
+//!
+//! # Timeouts
+//!
+//! Each request is given a timeout counted from the end of its instruction, on top of the servos'
+//! Return Delay Time. Once its deadline has passed the request completes with
+//! [`BusError::Timeout`], and its servo is placed in quarantine for [`LATE_REPLY_WINDOW`]:
+//! - Every reply from the servo is dropped as late, as it cannot be told apart from the late answer
+//!   to the request that timed out, so any other request to the servo times out as well
+//! - [`ServoRequests::send`] holds back instructions to the servo until the quarantine has ended
+//!
+//! Deadlines are checked as replies arrive and when listening ends, so [`ServoRequests::collect`]
+//! has reported every request as answered or timed out by the time it returns.
+//!
+//! Every completion is also counted in the servo's
+//! [`ServoStats`](super::dynamixel_bus::ServoStats).
+
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{LinearMap, Vec};
+
+use super::dynamixel_bus::{self, BusError, DynamixelBus, MAX_SCAN_IDS};
+use crate::peripherals::crc::CrcProcessor;
+use crate::protocol::dynamixel::{Instruction, StatusPacket, MAX_BULK_READ_SERVOS};
+
+/// Most replies owed at once, enough for a bulk read of every servo it can address
+pub const MAX_PENDING: usize = MAX_BULK_READ_SERVOS;
+
+/// Time a servo stays in quarantine after one of its requests timed out, long enough for a
+/// servo that was busy to have sent its reply
+pub const LATE_REPLY_WINDOW: Duration = Duration::from_millis(10);
+
+/// A reply an instruction is owed
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct Expected {
+    /// ID of the servo that owes the reply
+    pub id: u8,
+    /// Number of parameters the reply holds
+    pub params: usize,
+    /// Time allowed for the reply once the instruction was sent and the Return Delay Time elapsed
+    pub timeout: Duration,
+}
+
+/// Identifies the instruction a [`Completion`] belongs to, as returned by [`ServoRequests::send`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct RequestTag(pub u32);
+
+/// The outcome of one reply owed
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct Completion {
+    /// The instruction the reply was owed for
+    pub tag: RequestTag,
+    /// Instruction that was sent
+    pub instruction: Instruction,
+    /// ID of the servo that owed the reply
+    pub id: u8,
+    /// The reply, [`BusError::Timeout`] if it did not arrive in time or [`BusError::ServoError`]
+    /// if the servo refused the instruction
+    pub result: Result<StatusPacket, BusError>,
+}
+
+/// Errors from sending an instruction
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum RequestError {
+    /// The replies would not fit with the [`MAX_PENDING`] already owed
+    Full,
+    /// The instruction could not be sent
+    Bus(BusError),
+}
+
+/// A reply still owed
+struct PendingRequest {
+    tag: RequestTag,
+    instruction: Instruction,
+    id: u8,
+    params: usize,
+    deadline: Instant,
+}
+
+/// Replies owed by the servos, in the order their instructions were sent
+pub struct ServoRequests {
+    /// Replies still owed, oldest first
+    pending: Vec<PendingRequest, MAX_PENDING>,
+    /// End of the quarantine of each servo that recently timed out
+    quarantine: LinearMap<u8, Instant, MAX_SCAN_IDS>,
+    /// Tag of the next instruction sent
+    next_tag: u32,
+}
+
+impl ServoRequests {
+    /// Create a tracker with no replies owed
+    pub const fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            quarantine: LinearMap::new(),
+            next_tag: 0,
+        }
+    }
+
+    /// Send an instruction and record the replies it is owed
+    ///
+    /// Waits first for every servo in `expected` to leave quarantine.
+    ///
+    /// # Arguments
+    /// * `bus` - The servo bus
+    /// * `packet` - Encoded instruction packet
+    /// * `instruction` - Instruction in `packet`, reported in its [`Completion`]s
+    /// * `expected` - Replies the instruction is owed, none for a broadcast write
+    ///
+    /// # Returns
+    /// * The tag of the [`Completion`]s of the instruction, which [`Self::collect`] reports
+    /// * [`RequestError::Full`] if too many replies are owed, the instruction is not sent
+    pub async fn send(
+        &mut self,
+        bus: &mut DynamixelBus<'_>,
+        packet: &[u8],
+        instruction: Instruction,
+        expected: &[Expected],
+    ) -> Result<RequestTag, RequestError> {
+        if self.pending.len() + expected.len() > MAX_PENDING {
+            return Err(RequestError::Full);
+        }
+
+        for reply in expected {
+            if let Some(until) = self.quarantine.remove(&reply.id) {
+                Timer::at(until).await;
+            }
+        }
+
+        bus.write_packet(packet).await.map_err(RequestError::Bus)?;
+
+        let sent = Instant::now();
+        let tag = RequestTag(self.next_tag);
+        self.next_tag = self.next_tag.wrapping_add(1);
+        for reply in expected {
+            let _ = self.pending.push(PendingRequest {
+                tag,
+                instruction,
+                id: reply.id,
+                params: reply.params,
+                deadline: sent + bus.return_delay() + reply.timeout,
+            });
+        }
+        Ok(tag)
+    }
+
+    /// Receive the replies owed until every request is answered or has timed out
+    ///
+    /// # Arguments
+    /// * `bus` - The servo bus
+    /// * `crc` - CRC processor used to check the replies
+    /// * `on_completion` - Called with the outcome of every reply owed, as it is known
+    pub async fn collect(
+        &mut self,
+        bus: &mut DynamixelBus<'_>,
+        crc: &mut CrcProcessor<'_>,
+        mut on_completion: impl FnMut(Completion),
+    ) {
+        let Some(deadline) = self.pending.iter().map(|request| request.deadline).max() else {
+            return;
+        };
+
+        bus.collect_statuses(deadline, crc, |status| {
+            let now = Instant::now();
+            self.expire(now, &mut on_completion);
+            self.accept(status, now, &mut on_completion);
+            !self.pending.is_empty()
+        })
+        .await;
+
+        // Listening ended at the last deadline, unless every reply arrived before it
+        self.expire(Instant::now(), &mut on_completion);
+    }
+
+    /// Time out every request whose deadline has passed
+    fn expire(&mut self, now: Instant, on_completion: &mut impl FnMut(Completion)) {
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i].deadline > now {
+                i += 1;
+                continue;
+            }
+
+            let request = self.pending.remove(i);
+            let until = request.deadline + LATE_REPLY_WINDOW;
+            match self.quarantine.get_mut(&request.id) {
+                Some(end) => *end = (*end).max(until),
+                None => {
+                    // A full map only leaves out servos beyond the most a scan reports
+                    let _ = self.quarantine.insert(request.id, until);
+                }
+            }
+            complete(request, Err(BusError::Timeout), on_completion);
+        }
+    }
+
+    /// Hand a status packet to the request it answers, see the matching rules
+    fn accept(&mut self, status: &StatusPacket, now: Instant, on_completion: &mut impl FnMut(Completion)) {
+        if self.quarantine.get(&status.id).is_some_and(|until| *until > now) {
+            defmt::debug!("Dropped a late reply from servo {}", status.id);
+            return;
+        }
+
+        let refused = status.error_number() != 0;
+        let Some(position) = self
+            .pending
+            .iter()
+            .position(|request| request.id == status.id && (refused || request.params == status.params().len()))
+        else {
+            defmt::debug!("Dropped an unsolicited reply from servo {}", status.id);
+            return;
+        };
+
+        let request = self.pending.remove(position);
+        let result = if refused {
+            Err(BusError::ServoError(status.error))
+        } else {
+            Ok(*status)
+        };
+        complete(request, result, on_completion);
+    }
+}
+
+impl Default for ServoRequests {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Report the outcome of a request and count it in its servo's statistics
+fn complete(
+    request: PendingRequest,
+    result: Result<StatusPacket, BusError>,
+    on_completion: &mut impl FnMut(Completion),
+) {
+    dynamixel_bus::record_servo_stats(request.id, &result);
+    on_completion(Completion {
+        tag: request.tag,
+        instruction: request.instruction,
+        id: request.id,
+        result,
+    });
+}
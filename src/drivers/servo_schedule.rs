@@ -0,0 +1,203 @@
+//! Servo commands scheduled for a future time.
+//!
+//! The host, or the trajectory executor, queues encoded broadcast instructions (typically sync
+//! writes of goal positions) together with the time they are to take effect, and [`run_until`]
+//! sends each one on the Dynamixel bus when its time comes. The servo task runs it between its
+//! polls of the servos, see [`crate::apps::servos`], so a command that falls due during a poll is
+//! sent as soon as the poll has finished. Motion stays smooth even when the commands
+//! arrive in bursts, as long as they arrive ahead of time.
+//!
+//! The queue holds [`QUEUE_DEPTH`] commands in time order, commands for the same time keep the
+//! order they were queued in. Times in the past are handled as follows:
+//! - A command up to [`MAX_LATENESS`] late is sent straight away
+//! - A command later than that is refused by [`schedule`], or dropped and counted by
+//!   [`run_until`] if it became that late waiting behind others, as a stale goal would jerk the
+//!   joint back
+//!
+//! Only broadcast instructions are accepted, as nothing waits for a reply.
+//!
+//! [`run_until`] also acts on [`TORQUE_OFF`]: the queue is cleared and the torque switched off.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use super::dynamixel_bus::{DynamixelBus, TORQUE_OFF};
+use crate::peripherals::crc::SharedCrc;
+use crate::protocol::dynamixel::{BROADCAST_ID, MAX_SYNC_WRITE_PARAMS};
+use crate::time_sync;
+
+/// Most commands waiting at once
+pub const QUEUE_DEPTH: usize = 8;
+
+/// Largest command: a full sync write even if a third of its parameters needed byte stuffing,
+/// with the 8 bytes before the parameters and the 2 CRC bytes
+pub const MAX_COMMAND_SIZE: usize = 8 + MAX_SYNC_WRITE_PARAMS + MAX_SYNC_WRITE_PARAMS / 3 + 2;
+
+/// Latest a command is still sent after its time
+pub const MAX_LATENESS: Duration = Duration::from_millis(5);
+
+/// Errors from queueing a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum ScheduleError {
+    /// [`QUEUE_DEPTH`] commands are already waiting
+    Full,
+    /// The packet is longer than [`MAX_COMMAND_SIZE`]
+    TooLarge,
+    /// The packet is not a broadcast instruction
+    NotBroadcast,
+    /// The time is more than [`MAX_LATENESS`] in the past
+    Late,
+    /// A host time was given before the host clock offset is known, see [`crate::time_sync`]
+    NotSynchronized,
+}
+
+/// An encoded instruction waiting for its time
+struct ScheduledCommand {
+    at: Instant,
+    len: usize,
+    packet: [u8; MAX_COMMAND_SIZE],
+}
+
+/// Waiting commands, ordered by time
+static QUEUE: Mutex<CriticalSectionRawMutex, RefCell<Vec<ScheduledCommand, QUEUE_DEPTH>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Signalled when a command was queued, as it may be due before the one [`run_until`] waits for
+static QUEUED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Commands dropped for being too late since boot
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Queue an instruction to be sent at a device time
+///
+/// # Arguments
+/// * `at` - Device time to send the instruction at
+/// * `packet` - Encoded broadcast instruction, e.g. from
+///   [`SyncWrite::finalize`](crate::protocol::dynamixel::SyncWrite::finalize)
+///
+/// # Returns
+/// Success, or the reason the command was refused
+pub fn schedule(at: Instant, packet: &[u8]) -> Result<(), ScheduleError> {
+    if packet.len() > MAX_COMMAND_SIZE {
+        return Err(ScheduleError::TooLarge);
+    }
+    // The ID follows the header in every packet
+    if packet.get(4) != Some(&BROADCAST_ID) {
+        return Err(ScheduleError::NotBroadcast);
+    }
+    if at + MAX_LATENESS < Instant::now() {
+        return Err(ScheduleError::Late);
+    }
+
+    let mut command = ScheduledCommand {
+        at,
+        len: packet.len(),
+        packet: [0; MAX_COMMAND_SIZE],
+    };
+    command.packet[..packet.len()].copy_from_slice(packet);
+
+    QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+        // After every command for the same time, so those keep their order
+        let position = queue.iter().position(|queued| queued.at > at).unwrap_or(queue.len());
+        queue.insert(position, command).map_err(|_| ScheduleError::Full)
+    })?;
+    QUEUED.signal(());
+    Ok(())
+}
+
+/// Queue an instruction to be sent at a host time
+///
+/// # Arguments
+/// * `at_host_us` - Host time in µs to send the instruction at, see [`crate::time_sync`]
+/// * `packet` - Encoded broadcast instruction
+///
+/// # Returns
+/// Success, or the reason the command was refused
+pub fn schedule_host(at_host_us: i64, packet: &[u8]) -> Result<(), ScheduleError> {
+    let at = time_sync::from_host_us(at_host_us).ok_or(ScheduleError::NotSynchronized)?;
+    schedule(at, packet)
+}
+
+/// Drop every waiting command
+///
+/// # Returns
+/// The number of commands dropped
+pub fn clear() -> usize {
+    QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+        let dropped = queue.len();
+        queue.clear();
+        dropped
+    })
+}
+
+/// Time of the next waiting command
+fn next_time() -> Option<Instant> {
+    QUEUE.lock(|queue| queue.borrow().first().map(|command| command.at))
+}
+
+/// Take the next command if it is due
+fn take_due(now: Instant) -> Option<ScheduledCommand> {
+    QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+        queue
+            .first()
+            .is_some_and(|command| command.at <= now)
+            .then(|| queue.remove(0))
+    })
+}
+
+/// Send the scheduled commands on the bus as they fall due, until `until`
+///
+/// # Arguments
+/// * `bus` - The servo bus
+/// * `crc` - CRC processor used to build the torque off instruction
+/// * `until` - Time to return at, once the commands due by then have been sent
+pub async fn run_until(bus: &mut DynamixelBus<'_>, crc: &SharedCrc, until: Instant) {
+    loop {
+        let wake = next_time().map_or(until, |at| at.min(until));
+        // Torque off is polled first, so it goes out ahead of any command already due
+        match select3(TORQUE_OFF.wait(), Timer::at(wake), QUEUED.wait()).await {
+            Either3::First(()) => {
+                let cleared = clear();
+                defmt::warn!("Servo torque off, {} scheduled commands dropped", cleared);
+                if let Err(e) = bus.torque_off(&mut *crc.lock().await).await {
+                    defmt::error!("Servo torque off failed: {:?}", e);
+                }
+                continue;
+            }
+            Either3::Second(()) => {}
+            // A new command may be due before the one waited for
+            Either3::Third(()) => continue,
+        }
+
+        let now = Instant::now();
+        while let Some(command) = take_due(now) {
+            if command.at + MAX_LATENESS < Instant::now() {
+                let dropped = DROPPED.fetch_add(1, Ordering::Relaxed) + 1;
+                defmt::warn!(
+                    "Scheduled servo command {} us late, dropped ({} so far)",
+                    Instant::now().duration_since(command.at).as_micros(),
+                    dropped
+                );
+                continue;
+            }
+            if let Err(e) = bus.write_packet(&command.packet[..command.len]).await {
+                defmt::warn!("Scheduled servo command failed: {:?}", e);
+            }
+        }
+
+        if now >= until {
+            return;
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Liveness monitoring for long-running tasks.
+//!
+//! Each monitored task calls [`checkin`] from its main loop, which records the time it last made
+//! progress. The main task checks [`is_alive`] on every heartbeat and reports tasks that have
+//! been silent for longer than [`STALE_AFTER`], which is the basis for only petting the watchdog
+//! while every task is healthy.
+//!
+//! A task that knows it is about to go quiet for longer, e.g. while averaging a long capture or
+//! waiting for a slow bus scan, declares it with [`long_operation`] instead of checking in from
+//! inside the operation. This stretches its allowance for that one operation only, and a task that
+//! overruns the budget it declared is reported stale like any other, so a hang is still caught.
+//!
+//! Check-in times are kept as milliseconds since boot in an [`AtomicU32`], as the Cortex-M7 has
+//! no 64-bit atomics. The counter wraps after about 49 days, which the wrapping comparison in
+//! [`is_alive`] handles as long as a task is checked at least that often.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_time::{Duration, Instant};
+
+/// Time without a check-in after which a task is reported as stale
+///
+/// Shorter than the watchdog timeout, so a stale task can be caught before the board resets.
+pub const STALE_AFTER: Duration = Duration::from_secs(3);
+
+/// Longest silence [`long_operation`] allows, so a wrong budget cannot hide a hang for long
+pub const MAX_LONG_OPERATION: Duration = Duration::from_secs(60);
+
+/// Check-in time marking a task as idle on purpose, see [`suspend`]
+const SUSPENDED: u32 = u32::MAX;
+
+/// Tasks whose liveness is monitored
+///
+/// Only tasks that wake on their own at a known rate can be monitored. The USB device loop is
+/// left out, as it only wakes for bus events and an idle device looks the same as a stuck one.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum TaskId {
+    /// IMU acquisition, see [`crate::drivers::imu::task`]
+    Imu,
+    /// Servo bus polling, see [`crate::apps::servos::task`]
+    Servo,
+}
+
+impl TaskId {
+    /// Number of monitored tasks
+    pub const COUNT: usize = 2;
+
+    /// Every monitored task
+    pub const ALL: [TaskId; Self::COUNT] = [TaskId::Imu, TaskId::Servo];
+
+    /// Liveness bit of the task for the watchdog, see [`crate::peripherals::watchdog`]
+    pub const fn watchdog_flag(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Time of the last check-in of every task, in milliseconds since boot
+///
+/// Starts at zero, so a task that never checks in is reported once [`STALE_AFTER`] has passed
+/// since boot.
+static LAST_SEEN: [AtomicU32; TaskId::COUNT] = [const { AtomicU32::new(0) }; TaskId::COUNT];
+
+/// Silence in milliseconds allowed on top of [`STALE_AFTER`] until the next check-in, see
+/// [`long_operation`]
+static GRACE_MS: [AtomicU32; TaskId::COUNT] = [const { AtomicU32::new(0) }; TaskId::COUNT];
+
+/// Current time in milliseconds since boot, truncated to 32 bits and never [`SUSPENDED`]
+fn now_ms() -> u32 {
+    (Instant::now().as_millis() as u32).min(SUSPENDED - 1)
+}
+
+/// Record that a task is making progress
+///
+/// Cheap enough to call on every iteration of a task's loop.
+pub fn checkin(task: TaskId) {
+    GRACE_MS[task as usize].store(0, Ordering::Relaxed);
+    LAST_SEEN[task as usize].store(now_ms(), Ordering::Relaxed);
+}
+
+/// Declare a known long operation during which a task cannot check in
+///
+/// The task checks in now and may then stay silent for up to `budget` longer than
+/// [`STALE_AFTER`]. The allowance ends when the returned guard is dropped or the task checks in
+/// again, whichever comes first.
+///
+/// The contract: `budget` is an upper bound on how long the operation takes when it works, not a
+/// way to turn monitoring off. An operation that overruns it is reported stale and, through the
+/// watchdog, resets the board, which is the point. Budgets are capped at [`MAX_LONG_OPERATION`].
+/// The allowance only covers the calling task going quiet; an operation that blocks the executor
+/// stops the heartbeat and still resets the board once the watchdog times out, so long blocking
+/// work has to be split into awaited steps.
+///
+/// # Arguments
+/// * `task` - The task running the operation
+/// * `budget` - Longest the operation takes
+///
+/// # Returns
+/// A guard ending the allowance when dropped
+pub fn long_operation(task: TaskId, budget: Duration) -> LongOperation {
+    if budget > MAX_LONG_OPERATION {
+        defmt::warn!(
+            "{:?} long operation budget of {} ms capped to {} ms",
+            task,
+            budget.as_millis(),
+            MAX_LONG_OPERATION.as_millis()
+        );
+    }
+    checkin(task);
+    let budget = budget.min(MAX_LONG_OPERATION);
+    GRACE_MS[task as usize].store(budget.as_millis() as u32, Ordering::Relaxed);
+    LongOperation { task }
+}
+
+/// Allowance for a long operation, see [`long_operation`]
+#[must_use = "the allowance ends as soon as the guard is dropped"]
+pub struct LongOperation {
+    task: TaskId,
+}
+
+impl Drop for LongOperation {
+    fn drop(&mut self) {
+        checkin(self.task);
+    }
+}
+
+/// Stop monitoring a task that is idle on purpose, e.g. a parked sensor
+///
+/// Monitoring resumes with the task's next [`checkin`].
+pub fn suspend(task: TaskId) {
+    LAST_SEEN[task as usize].store(SUSPENDED, Ordering::Relaxed);
+}
+
+/// Time since a task last checked in, or `None` while it is suspended
+pub fn silent_for(task: TaskId) -> Option<Duration> {
+    let last_seen = LAST_SEEN[task as usize].load(Ordering::Relaxed);
+    (last_seen != SUSPENDED).then(|| Duration::from_millis(u64::from(now_ms().wrapping_sub(last_seen))))
+}
+
+/// Whether a task has checked in within [`STALE_AFTER`], plus the allowance of a running
+/// [`long_operation`], or is suspended
+pub fn is_alive(task: TaskId) -> bool {
+    let grace = Duration::from_millis(u64::from(GRACE_MS[task as usize].load(Ordering::Relaxed)));
+    silent_for(task).is_none_or(|silent| silent <= STALE_AFTER + grace)
+}
@@ -0,0 +1,145 @@
+//! On-device retention of recent log messages.
+//!
+//! defmt-RTT needs a debug probe, so faults in the field usually leave no trace. Messages logged
+//! with [`ring_log!`](crate::ring_log) are also kept in a small RAM ring buffer that the host can
+//! dump over the ACM port with the shell's `log` command. Once the buffer is full the oldest
+//! messages are overwritten, so it always holds the context leading up to the dump.
+//!
+//! Each message is stored as a line prefixed with the time since boot in milliseconds.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+/// Size of the ring buffer in bytes
+pub const CAPACITY: usize = 2048;
+
+/// Log a formatted message into the ring buffer.
+///
+/// Takes the same arguments as [`core::format_args!`]. Messages longer than the buffer only keep
+/// their end.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// ring_log!("IMU restarted after {} failures", failures);
+/// ```
+#[macro_export]
+macro_rules! ring_log {
+    ($($arg:tt)*) => {
+        $crate::log_ring::write(format_args!($($arg)*))
+    };
+}
+
+/// Byte ring holding the most recent log lines
+///
+/// Bytes are addressed by their position in the stream of everything ever logged, so a reader can
+/// tell which of the bytes it has not read yet were overwritten in the meantime.
+struct LogRing {
+    buffer: [u8; CAPACITY],
+    /// Stream position the next byte is written to, it lives at `written % CAPACITY`
+    written: u64,
+    /// Number of valid bytes, at most [`CAPACITY`]
+    len: usize,
+}
+
+impl LogRing {
+    /// Index in the buffer of the byte at a stream position
+    fn index(position: u64) -> usize {
+        (position % CAPACITY as u64) as usize
+    }
+
+    /// Stream position of the oldest byte still in the buffer
+    fn oldest(&self) -> u64 {
+        self.written - self.len as u64
+    }
+
+    /// Stream position of the oldest whole line in the buffer
+    ///
+    /// Once the buffer has wrapped, the line the oldest byte belongs to was partially overwritten
+    /// and is skipped. Without a later line the end of the log is returned.
+    fn first_line(&self) -> u64 {
+        let oldest = self.oldest();
+        if self.len < CAPACITY {
+            return oldest;
+        }
+        (oldest + 1..self.written)
+            .find(|&position| self.buffer[Self::index(position - 1)] == b'\n')
+            .unwrap_or(self.written)
+    }
+}
+
+impl Write for LogRing {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buffer[Self::index(self.written)] = byte;
+            self.written += 1;
+            self.len = core::cmp::min(self.len + 1, CAPACITY);
+        }
+        Ok(())
+    }
+}
+
+static RING: Mutex<CriticalSectionRawMutex, RefCell<LogRing>> = Mutex::new(RefCell::new(LogRing {
+    buffer: [0u8; CAPACITY],
+    written: 0,
+    len: 0,
+}));
+
+/// Append a message to the ring buffer, use [`ring_log!`](crate::ring_log) instead.
+pub fn write(args: fmt::Arguments) {
+    RING.lock(|ring| {
+        let mut ring = ring.borrow_mut();
+        let _ = write!(ring, "[{}] ", Instant::now().as_millis());
+        let _ = ring.write_fmt(args);
+        let _ = ring.write_str("\r\n");
+    });
+}
+
+/// Start reading the buffered log, for streaming it out with [`read`] without copying it whole.
+///
+/// # Returns
+/// The stream positions of the oldest whole line and of the end of the log as it is now
+pub fn range() -> (u64, u64) {
+    RING.lock(|ring| {
+        let ring = ring.borrow();
+        (ring.first_line(), ring.written)
+    })
+}
+
+/// Copy buffered log bytes from `position` up to `end` into `out`.
+///
+/// Messages logged while reading may overwrite bytes that were not read yet, those are skipped up
+/// to the next whole line.
+///
+/// # Arguments
+/// * `position` - Stream position to read from, from [`range`] or the previous read
+/// * `end` - Stream position to stop at, from [`range`]
+/// * `out` - Buffer the bytes are copied into
+///
+/// # Returns
+/// The stream position after the copied bytes and the number of bytes copied, zero once `end` is
+/// reached
+pub fn read(position: u64, end: u64, out: &mut [u8]) -> (u64, usize) {
+    RING.lock(|ring| {
+        let ring = ring.borrow();
+        let position = if position < ring.oldest() {
+            ring.first_line()
+        } else {
+            position
+        };
+        let len = core::cmp::min(end.saturating_sub(position), out.len() as u64) as usize;
+        for (offset, byte) in out[..len].iter_mut().enumerate() {
+            *byte = ring.buffer[LogRing::index(position + offset as u64)];
+        }
+        (position + len as u64, len)
+    })
+}
+
+/// Discard all buffered messages.
+pub fn clear() {
+    RING.lock(|ring| {
+        ring.borrow_mut().len = 0;
+    });
+}
@@ -8,17 +8,42 @@
 
 // Application modules
 mod apps;
+mod battery;
 mod drivers;
+mod liveness;
+mod log_ring;
 mod peripherals;
+mod protocol;
+mod supervisor;
+mod time_sync;
 
 use defmt::info;
 use embassy_executor::Spawner;
-use peripherals::{acm, init_system, usb_system};
+use embassy_sync::channel::Channel;
+use peripherals::{init_system, persistent, system, usb_system};
 
 #[cfg(not(feature = "debug"))]
 use panic_halt as _;
 #[cfg(feature = "debug")]
-use {defmt_rtt as _, panic_probe as _};
+use panic_probe as _;
+// With `log-usb` the USB logger is the defmt global logger instead
+#[cfg(all(feature = "debug", not(feature = "log-usb")))]
+use defmt_rtt as _;
+
+/// System clock speed, lower it for thermally constrained deployments
+const CLOCK_PROFILE: system::ClockProfile = system::ClockProfile::MaxPerformance480;
+/// Supply level below which the clock falls back from Scale0, see [`system::init_system`]
+const PVD_THRESHOLD: system::PvdThreshold = system::PvdThreshold::V2_85;
+
+/// Period of the main heartbeat loop, which pets the watchdog
+const HEARTBEAT_PERIOD: embassy_time::Duration = embassy_time::Duration::from_secs(1);
+/// Heartbeats between uptime records and heartbeat log lines
+const HEARTBEATS_PER_LOG: u32 = 60;
+/// Time without a heartbeat before the watchdog resets the board
+const WATCHDOG_TIMEOUT: embassy_time::Duration = embassy_time::Duration::from_secs(4);
+
+/// Parsed IMU samples published by the IMU task
+static IMU_SAMPLES: drivers::imu::ImuChannel = Channel::new();
 
 /// Main application entry point
 ///
@@ -29,29 +54,169 @@ async fn main(spawner: Spawner) {
     info!("Starting NUSense firmware v{}", env!("CARGO_PKG_VERSION"));
 
     // Initialize STM32 peripherals with optimized clock configuration
-    let peripherals = init_system();
+    let peripherals = init_system(CLOCK_PROFILE, PVD_THRESHOLD);
+    let reset_cause = system::read_reset_cause();
+    info!("Reset cause: {:?}", reset_cause);
+
+    let boot_stats = persistent::init();
+    // Settings stored in flash, e.g. the battery thresholds loaded by the servo task
+    peripherals::flash_config::init(claim_flash_config!(peripherals)).await;
+    info!(
+        "Reset count: {}, cumulative uptime: {} s",
+        boot_stats.reset_count, boot_stats.total_uptime_secs
+    );
+    ring_log!(
+        "Boot v{}, reset count {}, cause {:?}",
+        env!("CARGO_PKG_VERSION"),
+        boot_stats.reset_count,
+        reset_cause
+    );
+
+    // Serial number from the STM32 unique device ID so every board enumerates distinctly
+    let usb_descriptors = usb_system::UsbDescriptorConfig {
+        serial: system::device_serial(),
+        ..Default::default()
+    };
+    let mut usb_system = usb_system::UsbSystem::new(claim_usb!(peripherals), usb_descriptors);
+
+    // USB classes are registered before the device is built, see usb_system for the composition
+    #[cfg(all(any(feature = "debug-acm", feature = "debug-shell"), not(feature = "telemetry")))]
+    let acm_connection = peripherals::acm::AcmConnection::new(usb_system.builder(), claim_acm!(peripherals));
+
+    // Telemetry streams on its own port, after the application's port
+    #[cfg(all(any(feature = "debug-acm", feature = "debug-shell"), feature = "telemetry"))]
+    let [acm_connection, telemetry_acm] =
+        peripherals::acm::AcmConnection::new_multiple(usb_system.builder(), claim_acm!(peripherals, 2));
+    #[cfg(all(not(any(feature = "debug-acm", feature = "debug-shell")), feature = "telemetry"))]
+    let telemetry_acm = peripherals::acm::AcmConnection::new(usb_system.builder(), claim_acm!(peripherals));
+
+    // Servo bus captures stream on their own port, after the telemetry port
+    #[cfg(feature = "bus-sniffer")]
+    let sniffer_acm = peripherals::acm::AcmConnection::new(usb_system.builder(), claim_acm!(peripherals));
 
-    let mut usb_system = usb_system::UsbSystem::new(claim_usb!(peripherals));
-    let acm_connection = acm::AcmConnection::new(usb_system.builder(), claim_acm!(peripherals));
+    // defmt logs go to their own ACM port, registered after the application's port
+    #[cfg(feature = "log-usb")]
+    let usb_logger = peripherals::usb_logger::UsbLogger::new(usb_system.builder(), claim_usb_logger!(peripherals));
+
+    // The CRC unit is shared by every task that builds or checks servo packets
+    let crc = peripherals::crc::share(peripherals::crc::CrcProcessor::new(claim_crc!(peripherals)));
 
     // USB System task manages the usb events
     spawner.spawn(usb_system::task(usb_system)).unwrap();
 
+    #[cfg(feature = "log-usb")]
+    spawner.spawn(peripherals::usb_logger::task(usb_logger)).unwrap();
+
     // Echo application for testing USB CDC ACM communication
-    #[cfg(feature = "debug-acm")]
+    #[cfg(all(feature = "debug-acm", not(feature = "debug-shell")))]
     spawner.spawn(apps::acm_echo::task(acm_connection)).unwrap();
 
+    // Interactive debug shell, takes over the ACM port from the echo app when enabled
+    #[cfg(feature = "debug-shell")]
+    spawner.spawn(apps::shell::task(acm_connection, crc)).unwrap();
+
     #[cfg(feature = "debug-crc")]
-    spawner.spawn(apps::crc_test::task(claim_crc!(peripherals))).unwrap();
+    spawner.spawn(apps::crc_test::task(crc)).unwrap();
+
+    // Servo task owns the Dynamixel bus, sending the scheduled commands and polling the servos
+    spawner
+        .spawn(apps::servos::task(claim_dynamixel_bus!(peripherals), crc))
+        .unwrap();
+
+    // Trajectory executor turns the waypoints sent to it into goal positions for the servo task
+    spawner.spawn(apps::trajectory::task(crc)).unwrap();
+
+    // Bus sniffer streams the servo task's bus traffic to the host
+    #[cfg(feature = "bus-sniffer")]
+    spawner.spawn(drivers::bus_sniffer::task(sniffer_acm)).unwrap();
+
+    // Status LED task shows the system state through blink patterns
+    spawner.spawn(drivers::led::task(claim_led!(peripherals))).unwrap();
+
+    // Holding the user button calibrates the gyroscope
+    spawner
+        .spawn(apps::calibration_button::task(claim_button!(peripherals)))
+        .unwrap();
 
     // IMU task reads from the IMU sensor
     spawner
-        .spawn(drivers::imu::task(claim_imu_spi!(peripherals), claim_imu!(peripherals)))
+        .spawn(drivers::imu::task(
+            claim_imu_spi!(peripherals),
+            claim_imu!(peripherals),
+            &IMU_SAMPLES,
+        ))
         .unwrap();
 
+    // Stream the IMU samples to the host through the telemetry ring
+    #[cfg(feature = "telemetry")]
+    {
+        let (writer, drain) = peripherals::telemetry::split().expect("telemetry ring is only split here");
+        spawner
+            .spawn(peripherals::telemetry::task(drain, telemetry_acm))
+            .unwrap();
+        spawner.spawn(apps::imu_telemetry::task(&IMU_SAMPLES, writer)).unwrap();
+    }
+
+    // Core temperature and VBAT, logged with the heartbeat
+    let mut internal_adc = drivers::internal_adc::InternalAdc::new(claim_internal_adc!(peripherals));
+
+    // From here on the heartbeat loop below must keep petting the watchdog, and only does while
+    // every monitored task is checking in
+    let mut watchdog = peripherals::watchdog::Watchdog::new(claim_watchdog!(peripherals), WATCHDOG_TIMEOUT);
+    for task in liveness::TaskId::ALL {
+        watchdog.require(task.watchdog_flag());
+    }
+
     // Main task can do system-level monitoring
+    let mut beats = 0u32;
+    let mut reported_stale = [false; liveness::TaskId::COUNT];
     loop {
-        embassy_time::Timer::after(embassy_time::Duration::from_secs(60)).await;
-        info!("System heartbeat - all tasks running");
+        embassy_time::Timer::after(HEARTBEAT_PERIOD).await;
+
+        // Report tasks once when they go silent and once when they recover
+        for task in liveness::TaskId::ALL {
+            let stale = !liveness::is_alive(task);
+            if !stale {
+                peripherals::watchdog::report_alive(task.watchdog_flag());
+            }
+            if stale && !reported_stale[task as usize] {
+                defmt::warn!(
+                    "{:?} task has not checked in for {} ms",
+                    task,
+                    liveness::silent_for(task).map_or(0, |silent| silent.as_millis())
+                );
+            } else if !stale && reported_stale[task as usize] {
+                info!("{:?} task is alive again", task);
+            }
+            reported_stale[task as usize] = stale;
+        }
+
+        // A stale task is left out of the liveness bits, so the board resets once the watchdog
+        // times out
+        watchdog.pet();
+
+        // Show the battery state, which the servo task monitors, on the status LED
+        if let Some(event) = battery::BATTERY_EVENTS.try_take() {
+            drivers::led::LED_PATTERN.signal(match event.state {
+                battery::BatteryState::Normal => drivers::led::DEFAULT_PATTERN,
+                battery::BatteryState::Low => drivers::led::LedPattern::SlowBlink,
+                battery::BatteryState::Cutoff | battery::BatteryState::Over => drivers::led::LedPattern::FastBlink,
+            });
+        }
+
+        beats = beats.wrapping_add(1);
+        if beats % HEARTBEATS_PER_LOG == 0 {
+            persistent::record_uptime();
+            if reported_stale.contains(&true) {
+                info!("System heartbeat - some tasks are not checking in");
+            } else {
+                info!("System heartbeat - all tasks running");
+            }
+            info!(
+                "Core temperature: {} °C, VBAT: {} V",
+                internal_adc.read_core_temp().await,
+                internal_adc.read_vbat().await
+            );
+        }
     }
 }
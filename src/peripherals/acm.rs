@@ -2,29 +2,63 @@
 //!
 //! Provides low-latency USB communication using the STM32H753's hardware DMA
 //! for efficient robotics applications.
+//!
+//! [`AcmConnection`] deals in raw USB packets. [`FramedAcm`] builds on it to carry complete
+//! messages of any size, COBS encoded and terminated by a zero byte, independent of how they are
+//! split into USB packets. Messages longer than a frame are sent with
+//! [`FramedAcm::send_message`], which splits them into numbered fragments that
+//! [`FramedAcm::receive_message`] puts back together, see [`fragment`] for the wire format.
+//!
+//! Serial BREAKs are not supported: the embassy class does not advertise SEND_BREAK in its ACM
+//! functional descriptor, so hosts that honour the descriptor, such as Linux, never send one.
+//!
+//! # Multiple ports
+//!
+//! Several ACM interfaces can share one USB device, e.g. one for the host protocol and one for
+//! logs, see [`AcmConnection::new_multiple`]. Each port is a pair of interfaces (communication
+//! and data), and Windows only binds its serial driver to both halves of each pair when they are
+//! grouped by an Interface Association Descriptor. The device therefore declares itself as an
+//! IAD composite device in [`UsbSystem::new`](super::usb_system::UsbSystem::new), and every
+//! port must also be counted in [`ACM_COUNT`](super::usb_system::ACM_COUNT).
 
-use defmt::info;
+use defmt::{info, warn};
+use embassy_futures::select::{select, Either};
 use embassy_stm32::{peripherals::USB_OTG_HS, usb::Driver};
-pub use embassy_usb::class::cdc_acm::State;
-use embassy_usb::{class::cdc_acm::CdcAcmClass, driver::EndpointError, Builder};
-use static_cell::StaticCell;
+use embassy_time::{Duration, Timer};
+pub use embassy_usb::class::cdc_acm::{LineCoding, State};
+use embassy_usb::{
+    class::cdc_acm::{CdcAcmClass, ControlChanged, Receiver, Sender},
+    driver::EndpointError,
+    Builder,
+};
 
 // Import MAX_PACKET_SIZE from USB system
 use super::usb_system::MAX_PACKET_SIZE;
+use crate::protocol::{
+    cobs,
+    fragment::{self, Reassembler, HEADER_SIZE, MAX_FRAGMENT_SIZE},
+};
 
-pub static ACM_STATE: StaticCell<State<'static>> = StaticCell::new();
-
-/// Peripheral collection for ACM interface
-pub struct AcmClaims<'d> {
-    pub acm_state: &'d mut State<'d>,
+/// Peripheral collection for `N` ACM interfaces
+pub struct AcmClaims<'d, const N: usize = 1> {
+    /// One class state per interface
+    pub acm_states: &'d mut [State<'d>; N],
 }
 
 /// Macro to claim peripherals for AcmConnection
+///
+/// `claim_acm!(peripherals)` claims a single port, `claim_acm!(peripherals, N)` claims `N` ports
+/// for [`AcmConnection::new_multiple`].
 #[macro_export]
 macro_rules! claim_acm {
     ($peripherals:expr) => {{
+        $crate::claim_acm!($peripherals, 1)
+    }};
+    ($peripherals:expr, $count:expr) => {{
+        static ACM_STATES: static_cell::StaticCell<[embassy_usb::class::cdc_acm::State<'static>; $count]> =
+            static_cell::StaticCell::new();
         $crate::peripherals::acm::AcmClaims {
-            acm_state: $crate::peripherals::acm::ACM_STATE.init(embassy_usb::class::cdc_acm::State::new()),
+            acm_states: ACM_STATES.init(core::array::from_fn(|_| embassy_usb::class::cdc_acm::State::new())),
         }
     }};
 }
@@ -33,24 +67,42 @@ macro_rules! claim_acm {
 #[derive(Debug, Clone, Copy)]
 pub struct Disconnected;
 
-impl From<EndpointError> for Disconnected {
-    fn from(error: EndpointError) -> Self {
-        match error {
-            EndpointError::BufferOverflow => panic!("USB buffer overflow"),
-            EndpointError::Disabled => Disconnected,
+/// What an [`AcmConnection`] does when a transfer does not fit its buffer
+///
+/// An overflow means the host sent a packet larger than the receive buffer, or the firmware tried
+/// to send more than one packet's worth of data.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum OverflowPolicy {
+    /// Panic, making the bug obvious during development
+    Panic,
+    /// Log a warning and drop the transfer, so a malformed transfer cannot crash the robot
+    LogAndDrop,
+}
+
+impl Default for OverflowPolicy {
+    /// Panic in debug builds and drop the transfer otherwise
+    fn default() -> Self {
+        if cfg!(feature = "debug") {
+            OverflowPolicy::Panic
+        } else {
+            OverflowPolicy::LogAndDrop
         }
     }
 }
 
 /// CDC ACM connection for packet-based USB communication.
 ///
-/// Provides send/receive of individual USB packets up to MAX_PACKET_SIZE bytes.
+/// Provides send/receive of individual USB packets up to MAX_PACKET_SIZE bytes, along with the
+/// line coding and control line state the host has set.
 ///
 /// # Example
 ///
 /// ```rust,ignore
 /// let mut acm = AcmConnection::new(usb_builder, &mut acm_state);
 /// acm.wait_connection().await;
+/// // Enumeration alone does not mean anyone is listening, wait for a terminal to open the port
+/// acm.wait_dtr().await;
 ///
 /// // Send a packet
 /// acm.send_packet(b"Hello").await?;
@@ -60,7 +112,12 @@ impl From<EndpointError> for Disconnected {
 /// let len = acm.receive_packet(&mut buffer).await?;
 /// ```
 pub struct AcmConnection<'d> {
-    class: CdcAcmClass<'d, Driver<'d, USB_OTG_HS>>,
+    sender: Sender<'d, Driver<'d, USB_OTG_HS>>,
+    receiver: Receiver<'d, Driver<'d, USB_OTG_HS>>,
+    /// Signalled by the class whenever the host changes the line coding or control lines
+    control: ControlChanged<'d>,
+    /// Reaction to transfers that do not fit their buffer
+    overflow_policy: OverflowPolicy,
 }
 
 impl<'d> AcmConnection<'d> {
@@ -71,18 +128,84 @@ impl<'d> AcmConnection<'d> {
     /// * `builder` - USB device builder
     /// * `claims` - AcmClaims struct containing ACM state
     pub fn new(builder: &mut Builder<'d, Driver<'d, USB_OTG_HS>>, claims: AcmClaims<'d>) -> Self {
-        info!("CDC ACM connection initialized");
-        Self {
-            class: CdcAcmClass::new(builder, claims.acm_state, MAX_PACKET_SIZE),
-        }
+        let [connection] = Self::new_multiple(builder, claims);
+        connection
+    }
+
+    /// Create `N` independent ACM connections on the same USB device.
+    ///
+    /// The host enumerates the ports in order, so the first connection is the lowest numbered
+    /// port (e.g. `/dev/ttyACM0` when no other ACM device is attached).
+    ///
+    /// # Arguments
+    ///
+    /// * `builder` - USB device builder
+    /// * `claims` - AcmClaims struct containing one ACM state per port
+    pub fn new_multiple<const N: usize>(
+        builder: &mut Builder<'d, Driver<'d, USB_OTG_HS>>,
+        claims: AcmClaims<'d, N>,
+    ) -> [Self; N] {
+        let mut states = claims.acm_states.iter_mut();
+        core::array::from_fn(|index| {
+            let state = states.next().expect("one state per port");
+            let (sender, receiver, control) = CdcAcmClass::new(builder, state, MAX_PACKET_SIZE).split_with_control();
+            info!("CDC ACM connection {} initialized", index);
+            Self {
+                sender,
+                receiver,
+                control,
+                overflow_policy: OverflowPolicy::default(),
+            }
+        })
     }
 
     /// Wait for USB host to connect and open the CDC ACM interface.
     pub async fn wait_connection(&mut self) {
-        self.class.wait_connection().await;
+        self.receiver.wait_connection().await;
         info!("CDC ACM connection established");
     }
 
+    /// Wait for USB host to connect and open the CDC ACM interface, giving up after `timeout`.
+    ///
+    /// Lets an application carry on without a host, e.g. on a standalone robot, and decide for
+    /// itself whether to try again later.
+    ///
+    /// # Returns
+    ///
+    /// * `true` if the host connected in time
+    /// * `false` if the timeout elapsed first
+    pub async fn wait_connection_timeout(&mut self, timeout: Duration) -> bool {
+        match select(self.wait_connection(), Timer::after(timeout)).await {
+            Either::First(()) => true,
+            Either::Second(()) => false,
+        }
+    }
+
+    /// Line coding (baud rate, stop bits, parity and data bits) last set by the host.
+    ///
+    /// The USB link runs at its own speed whatever the host asks for, but a host protocol can use
+    /// the baud rate as a mode switch.
+    pub fn line_coding(&self) -> LineCoding {
+        self.receiver.line_coding()
+    }
+
+    /// Whether the host asserts DTR, which terminals and most serial libraries do on opening the port.
+    pub fn dtr(&self) -> bool {
+        self.receiver.dtr()
+    }
+
+    /// Wait until the host asserts DTR.
+    ///
+    /// Unlike [`wait_connection`](Self::wait_connection), which completes as soon as the device is
+    /// configured, this completes only once a program on the host has opened the port. Returns
+    /// immediately if DTR is already asserted.
+    pub async fn wait_dtr(&mut self) {
+        while !self.dtr() {
+            self.control.control_changed().await;
+        }
+        info!("CDC ACM DTR asserted, {} baud", self.line_coding().data_rate());
+    }
+
     /// Send a USB packet to the host.
     ///
     /// # Arguments
@@ -91,10 +214,13 @@ impl<'d> AcmConnection<'d> {
     ///
     /// # Returns
     ///
-    /// * `Ok(())` if sent successfully
+    /// * `Ok(())` if sent successfully, or dropped by the [`OverflowPolicy`]
     /// * `Err(Disconnected)` if host disconnected
     pub async fn send_packet(&mut self, data: &[u8]) -> Result<(), Disconnected> {
-        self.class.write_packet(data).await.map_err(Into::into)
+        match self.sender.write_packet(data).await {
+            Ok(()) => Ok(()),
+            Err(error) => self.handle_error(error),
+        }
     }
 
     /// Receive a USB packet from the host.
@@ -105,9 +231,284 @@ impl<'d> AcmConnection<'d> {
     ///
     /// # Returns
     ///
-    /// * `Ok(bytes_received)` - Number of bytes received (0 to MAX_PACKET_SIZE)
+    /// * `Ok(bytes_received)` - Number of bytes received (0 to MAX_PACKET_SIZE, 0 if the packet was
+    ///   dropped by the [`OverflowPolicy`])
     /// * `Err(Disconnected)` - If host disconnected
     pub async fn receive_packet(&mut self, buffer: &mut [u8]) -> Result<usize, Disconnected> {
-        self.class.read_packet(buffer).await.map_err(Into::into)
+        match self.receiver.read_packet(buffer).await {
+            Ok(bytes_received) => Ok(bytes_received),
+            Err(error) => self.handle_error(error).map(|()| 0),
+        }
+    }
+
+    /// Turn an endpoint error into a disconnection, or apply the [`OverflowPolicy`].
+    fn handle_error(&self, error: EndpointError) -> Result<(), Disconnected> {
+        match error {
+            EndpointError::Disabled => Err(Disconnected),
+            EndpointError::BufferOverflow => match self.overflow_policy {
+                OverflowPolicy::Panic => panic!("USB buffer overflow"),
+                OverflowPolicy::LogAndDrop => {
+                    warn!("USB buffer overflow, transfer dropped");
+                    Ok(())
+                }
+            },
+        }
+    }
+}
+
+/// Largest encoded frame [`FramedAcm`] can receive, excluding the zero delimiter
+///
+/// COBS adds one byte per 254 bytes of message, so this holds messages of at least 1020 bytes.
+pub const MAX_ENCODED_FRAME_SIZE: usize = 1024;
+
+/// COBS framed message layer over an [`AcmConnection`]
+///
+/// Every message is COBS encoded, which removes all zero bytes from it, and followed by a zero
+/// byte delimiter. This lets a message span any number of USB packets, and several messages
+/// share one, while the receiver always finds where each message starts and ends. A corrupt or
+/// oversized frame is dropped and the receiver picks up again at the next delimiter.
+///
+/// On top of the frames, [`send_message`](Self::send_message) and
+/// [`receive_message`](Self::receive_message) carry messages of up to
+/// [`fragment::MAX_MESSAGE_SIZE`] bytes as one frame per fragment. A fragment lost to a corrupt
+/// frame, or arriving out of order, discards the rest of its message, and a gap in the sequence
+/// numbers of the messages received is logged as lost messages.
+pub struct FramedAcm<'d> {
+    acm: AcmConnection<'d>,
+    /// Encoded bytes of the frame being received, up to its delimiter
+    frame: [u8; MAX_ENCODED_FRAME_SIZE],
+    frame_len: usize,
+    /// The frame being received outgrew [`MAX_ENCODED_FRAME_SIZE`], so drop bytes up to the next delimiter
+    discarding: bool,
+    /// Last USB packet received, with `packet[packet_pos..packet_len]` not yet consumed
+    packet: [u8; MAX_PACKET_SIZE as usize],
+    packet_pos: usize,
+    packet_len: usize,
+    /// Sequence number of the next message sent
+    send_sequence: u16,
+    /// Sequence number of the last message received, `None` until the first
+    received_sequence: Option<u16>,
+}
+
+impl<'d> FramedAcm<'d> {
+    /// Create a framed connection on top of an ACM connection.
+    pub const fn new(acm: AcmConnection<'d>) -> Self {
+        Self {
+            acm,
+            frame: [0; MAX_ENCODED_FRAME_SIZE],
+            frame_len: 0,
+            discarding: false,
+            packet: [0; MAX_PACKET_SIZE as usize],
+            packet_pos: 0,
+            packet_len: 0,
+            send_sequence: 0,
+            received_sequence: None,
+        }
+    }
+
+    /// Send one message as a COBS frame.
+    ///
+    /// The frame is streamed out in full USB packets as it is encoded, so the message can be any
+    /// length. A zero-length packet follows a frame ending exactly on a packet boundary, so the
+    /// host does not wait for more data.
+    ///
+    /// # Arguments
+    ///
+    /// * `msg` - Message to send, which may contain zero bytes
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` if sent successfully
+    /// * `Err(Disconnected)` if host disconnected
+    pub async fn send_frame(&mut self, msg: &[u8]) -> Result<(), Disconnected> {
+        let mut packet = [0u8; MAX_PACKET_SIZE as usize];
+        let mut len = 0;
+
+        for (code, block) in cobs::blocks(msg) {
+            self.push_encoded(&mut packet, &mut len, &[code]).await?;
+            self.push_encoded(&mut packet, &mut len, block).await?;
+        }
+        self.push_encoded(&mut packet, &mut len, &[0]).await?;
+
+        if len > 0 {
+            self.acm.send_packet(&packet[..len]).await
+        } else {
+            self.acm.send_packet(&[]).await
+        }
+    }
+
+    /// Append encoded bytes to the outgoing packet, sending it each time it fills up.
+    async fn push_encoded(&mut self, packet: &mut [u8], len: &mut usize, mut bytes: &[u8]) -> Result<(), Disconnected> {
+        while !bytes.is_empty() {
+            let count = bytes.len().min(packet.len() - *len);
+            packet[*len..*len + count].copy_from_slice(&bytes[..count]);
+            *len += count;
+            bytes = &bytes[count..];
+
+            if *len == packet.len() {
+                self.acm.send_packet(packet).await?;
+                *len = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Receive the next complete message.
+    ///
+    /// Partial frames are kept across USB packets and calls. Frames that fail to decode, are
+    /// longer than [`MAX_ENCODED_FRAME_SIZE`] or do not fit in `buf` are logged and dropped, and
+    /// reception continues with the next frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - Buffer to store the decoded message
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(len)` - Length of the decoded message in `buf`
+    /// * `Err(Disconnected)` - If host disconnected, which also drops any partial frame
+    pub async fn receive_frame(&mut self, buf: &mut [u8]) -> Result<usize, Disconnected> {
+        loop {
+            while self.packet_pos < self.packet_len {
+                let byte = self.packet[self.packet_pos];
+                self.packet_pos += 1;
+
+                if byte != 0 {
+                    if self.discarding {
+                        continue;
+                    }
+                    if self.frame_len == self.frame.len() {
+                        warn!("COBS frame longer than {} bytes, dropped", MAX_ENCODED_FRAME_SIZE);
+                        self.discarding = true;
+                        self.frame_len = 0;
+                        continue;
+                    }
+                    self.frame[self.frame_len] = byte;
+                    self.frame_len += 1;
+                    continue;
+                }
+
+                // Delimiter: the frame is complete, or the dropped one has ended
+                let frame_len = core::mem::take(&mut self.frame_len);
+                if core::mem::take(&mut self.discarding) || frame_len == 0 {
+                    continue;
+                }
+                match cobs::decode(&self.frame[..frame_len], buf) {
+                    Some(len) => return Ok(len),
+                    None => warn!("Corrupt or oversized COBS frame ({} bytes), dropped", frame_len),
+                }
+            }
+
+            match self.acm.receive_packet(&mut self.packet).await {
+                Ok(len) => {
+                    self.packet_pos = 0;
+                    self.packet_len = len;
+                }
+                Err(Disconnected) => {
+                    self.reset_receiver();
+                    return Err(Disconnected);
+                }
+            }
+        }
+    }
+
+    /// Send a message as numbered fragments, one frame each, so it may be longer than a frame.
+    ///
+    /// A message longer than [`fragment::MAX_MESSAGE_SIZE`] is logged and dropped without using
+    /// up a sequence number.
+    ///
+    /// # Arguments
+    ///
+    /// * `msg` - Message to send
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` if sent successfully
+    /// * `Err(Disconnected)` if host disconnected, possibly part way through the message
+    pub async fn send_message(&mut self, msg: &[u8]) -> Result<(), Disconnected> {
+        let Some(fragments) = fragment::fragments(self.send_sequence, msg) else {
+            warn!("Message of {} bytes is too long to send, dropped", msg.len());
+            return Ok(());
+        };
+        self.send_sequence = self.send_sequence.wrapping_add(1);
+
+        let mut frame = [0u8; MAX_FRAGMENT_SIZE];
+        for (header, payload) in fragments {
+            frame[..HEADER_SIZE].copy_from_slice(&header.encode());
+            frame[HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(payload);
+            self.send_frame(&frame[..HEADER_SIZE + payload.len()]).await?;
+        }
+        Ok(())
+    }
+
+    /// Receive the next complete message sent as numbered fragments.
+    ///
+    /// Fragments are put back together in `buf` as they arrive. A malformed fragment, a message
+    /// that does not fit in `buf` and a message missing a fragment are logged and dropped, and so
+    /// are the messages lost in between, going by their sequence numbers.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - Buffer to store the message
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(len)` - Length of the message in `buf`
+    /// * `Err(Disconnected)` - If host disconnected, which also drops any partial message
+    pub async fn receive_message(&mut self, buf: &mut [u8]) -> Result<usize, Disconnected> {
+        let mut frame = [0u8; MAX_FRAGMENT_SIZE];
+        let mut reassembler = Reassembler::new(buf);
+        loop {
+            let len = self.receive_frame(&mut frame).await?;
+
+            // A fragment that ends an incomplete message may still start the next one
+            let pushed = match reassembler.push(&frame[..len]) {
+                Err(fragment::Error::Incomplete { sequence }) => {
+                    warn!("Message {} is missing fragments, discarded", sequence);
+                    self.note_sequence(sequence);
+                    reassembler.push(&frame[..len])
+                }
+                pushed => pushed,
+            };
+
+            match pushed {
+                Ok(Some(message)) => {
+                    self.note_sequence(message.sequence);
+                    return Ok(message.len);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Fragment dropped: {:?}", e);
+                    if let fragment::Error::TooLong { sequence, .. } | fragment::Error::Orphan { sequence, .. } = e {
+                        self.note_sequence(sequence);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Record the sequence number of a message received or discarded, logging any skipped since the last.
+    fn note_sequence(&mut self, sequence: u16) {
+        if let Some(last) = self.received_sequence.filter(|last| *last != sequence) {
+            let lost = sequence.wrapping_sub(last).wrapping_sub(1);
+            if lost > 0 {
+                warn!("{} messages lost before message {}", lost, sequence);
+            }
+        }
+        self.received_sequence = Some(sequence);
+    }
+
+    /// Forget any partially received frame and unconsumed packet data.
+    fn reset_receiver(&mut self) {
+        self.received_sequence = None;
+        self.frame_len = 0;
+        self.discarding = false;
+        self.packet_pos = 0;
+        self.packet_len = 0;
+    }
+
+    /// Get back the underlying packet connection.
+    pub fn into_inner(self) -> AcmConnection<'d> {
+        self.acm
     }
 }
@@ -0,0 +1,46 @@
+//! Runtime bookkeeping for peripheral claims.
+//!
+//! The `claim_*!` macros move peripheral handles out of [`embassy_stm32::Peripherals`], so claiming the
+//! same field twice is normally a compile error. That protection disappears as soon as a handle is
+//! reborrowed or stolen, and shared resources such as DMA channels are easy to hand to two drivers by
+//! accident. Each claim macro therefore also registers the resources it takes here, and a second claim
+//! of the same resource panics at startup with the name of the offending resource.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Resources that are tracked by the claim registry
+///
+/// Each variant maps to one bit in the registry, so there can be at most 32 of them.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone)]
+pub enum Resource {
+    Dma1Ch0,
+    Dma1Ch1,
+    Dma1Ch2,
+    Dma1Ch3,
+    Dma1Ch4,
+    Spi4,
+    Usart2,
+    Exti10,
+    Exti13,
+    UsbOtgHs,
+    Crc,
+    Iwdg1,
+    Adc3,
+    Flash,
+}
+
+/// Bitmask of resources that have been claimed so far
+static CLAIMED: AtomicU32 = AtomicU32::new(0);
+
+/// Register a claim on `resource`
+///
+/// # Panics
+///
+/// Panics if the resource has already been claimed.
+pub fn register(resource: Resource) {
+    let bit = 1u32 << resource as u32;
+    if CLAIMED.fetch_or(bit, Ordering::Relaxed) & bit != 0 {
+        panic!("Peripheral {:?} claimed more than once", resource);
+    }
+}
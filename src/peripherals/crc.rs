@@ -2,26 +2,142 @@
 //!
 //! This module provides hardware CRC calculation using the STM32H753's CRC peripheral
 //! for efficient Dynamixel packet CRC computation.
+//!
+//! Short packets are fed to the CRC unit by the CPU. Longer buffers, such as sync read
+//! responses, can be fed by DMA instead so the executor keeps running other tasks meanwhile.
+//! The CRC unit has no DMA request line, so the transfer runs as a memory-to-memory transfer on
+//! a DMA1 stream that is driven directly through its registers.
+//!
+//! With the `crc-software` feature [`CrcProcessor`] computes every CRC with the lookup table in
+//! [`crc16_dynamixel`] instead, and claims neither the CRC unit nor the DMA stream, for boards
+//! where those are needed by something else.
+//!
+//! The tasks that talk to the servos all need the CRC, so the processor is handed out to them as
+//! a [`SharedCrc`], see [`share`].
 
+#[cfg(feature = "crc-software")]
+use core::marker::PhantomData;
+#[cfg(not(feature = "crc-software"))]
+use core::sync::atomic::{compiler_fence, Ordering};
+#[cfg(not(feature = "crc-software"))]
+use embassy_futures::yield_now;
+#[cfg(not(feature = "crc-software"))]
 use embassy_stm32::{
     crc::{Config, Crc, InputReverseConfig, PolySize},
-    peripherals::CRC,
+    pac::{self, dma::vals},
+    peripherals::{CRC, DMA1_CH4},
     Peri,
 };
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use static_cell::StaticCell;
 
-/// Peripheral collection for CRC
+/// Peripheral collection for CRC, empty with `crc-software`
 pub struct CrcPeripherals<'d> {
+    #[cfg(not(feature = "crc-software"))]
     pub crc: Peri<'d, CRC>,
+    #[cfg(not(feature = "crc-software"))]
+    pub dma: Peri<'d, DMA1_CH4>, // Memory-to-memory DMA feeding the CRC unit
+    #[cfg(feature = "crc-software")]
+    pub _lifetime: PhantomData<&'d ()>,
 }
 
 /// Macro to claim peripherals for CRC
 #[macro_export]
 macro_rules! claim_crc {
     ($peripherals:expr) => {{
-        $crate::peripherals::crc::CrcPeripherals { crc: $peripherals.CRC }
+        #[cfg(not(feature = "crc-software"))]
+        {
+            use $crate::peripherals::claims::{register, Resource};
+            register(Resource::Crc);
+            register(Resource::Dma1Ch4);
+        }
+        $crate::peripherals::crc::CrcPeripherals {
+            #[cfg(not(feature = "crc-software"))]
+            crc: $peripherals.CRC,
+            #[cfg(not(feature = "crc-software"))]
+            dma: $peripherals.DMA1_CH4, // Memory-to-memory DMA feeding the CRC unit
+            #[cfg(feature = "crc-software")]
+            _lifetime: core::marker::PhantomData,
+        }
     }};
 }
 
+/// CRC-16 lookup table for the Dynamixel 2.0 polynomial 0x8005 (CRC-16 IBM/ANSI, unreflected)
+const CRC_TABLE: [u16; 256] = [
+    0x0000, 0x8005, 0x800F, 0x000A, 0x801B, 0x001E, 0x0014, 0x8011, 0x8033, 0x0036, 0x003C, 0x8039, 0x0028, 0x802D,
+    0x8027, 0x0022, 0x8063, 0x0066, 0x006C, 0x8069, 0x0078, 0x807D, 0x8077, 0x0072, 0x0050, 0x8055, 0x805F, 0x005A,
+    0x804B, 0x004E, 0x0044, 0x8041, 0x80C3, 0x00C6, 0x00CC, 0x80C9, 0x00D8, 0x80DD, 0x80D7, 0x00D2, 0x00F0, 0x80F5,
+    0x80FF, 0x00FA, 0x80EB, 0x00EE, 0x00E4, 0x80E1, 0x00A0, 0x80A5, 0x80AF, 0x00AA, 0x80BB, 0x00BE, 0x00B4, 0x80B1,
+    0x8093, 0x0096, 0x009C, 0x8099, 0x0088, 0x808D, 0x8087, 0x0082, 0x8183, 0x0186, 0x018C, 0x8189, 0x0198, 0x819D,
+    0x8197, 0x0192, 0x01B0, 0x81B5, 0x81BF, 0x01BA, 0x81AB, 0x01AE, 0x01A4, 0x81A1, 0x01E0, 0x81E5, 0x81EF, 0x01EA,
+    0x81FB, 0x01FE, 0x01F4, 0x81F1, 0x81D3, 0x01D6, 0x01DC, 0x81D9, 0x01C8, 0x81CD, 0x81C7, 0x01C2, 0x0140, 0x8145,
+    0x814F, 0x014A, 0x815B, 0x015E, 0x0154, 0x8151, 0x8173, 0x0176, 0x017C, 0x8179, 0x0168, 0x816D, 0x8167, 0x0162,
+    0x8123, 0x0126, 0x012C, 0x8129, 0x0138, 0x813D, 0x8137, 0x0132, 0x0110, 0x8115, 0x811F, 0x011A, 0x810B, 0x010E,
+    0x0104, 0x8101, 0x8303, 0x0306, 0x030C, 0x8309, 0x0318, 0x831D, 0x8317, 0x0312, 0x0330, 0x8335, 0x833F, 0x033A,
+    0x832B, 0x032E, 0x0324, 0x8321, 0x0360, 0x8365, 0x836F, 0x036A, 0x837B, 0x037E, 0x0374, 0x8371, 0x8353, 0x0356,
+    0x035C, 0x8359, 0x0348, 0x834D, 0x8347, 0x0342, 0x03C0, 0x83C5, 0x83CF, 0x03CA, 0x83DB, 0x03DE, 0x03D4, 0x83D1,
+    0x83F3, 0x03F6, 0x03FC, 0x83F9, 0x03E8, 0x83ED, 0x83E7, 0x03E2, 0x83A3, 0x03A6, 0x03AC, 0x83A9, 0x03B8, 0x83BD,
+    0x83B7, 0x03B2, 0x0390, 0x8395, 0x839F, 0x039A, 0x838B, 0x038E, 0x0384, 0x8381, 0x0280, 0x8285, 0x828F, 0x028A,
+    0x829B, 0x029E, 0x0294, 0x8291, 0x82B3, 0x02B6, 0x02BC, 0x82B9, 0x02A8, 0x82AD, 0x82A7, 0x02A2, 0x82E3, 0x02E6,
+    0x02EC, 0x82E9, 0x02F8, 0x82FD, 0x82F7, 0x02F2, 0x02D0, 0x82D5, 0x82DF, 0x02DA, 0x82CB, 0x02CE, 0x02C4, 0x82C1,
+    0x8243, 0x0246, 0x024C, 0x8249, 0x0258, 0x825D, 0x8257, 0x0252, 0x0270, 0x8275, 0x827F, 0x027A, 0x826B, 0x026E,
+    0x0264, 0x8261, 0x0220, 0x8225, 0x822F, 0x022A, 0x823B, 0x023E, 0x0234, 0x8231, 0x8213, 0x0216, 0x021C, 0x8219,
+    0x0208, 0x820D, 0x8207, 0x0202,
+];
+
+/// Continue a table-driven CRC-16 over `data`
+///
+/// # Arguments
+/// * `crc` - CRC of the bytes before `data`, 0x0000 to start a new CRC
+/// * `data` - Bytes to add to the CRC
+fn crc16_update(crc: u16, data: &[u8]) -> u16 {
+    data.iter().fold(crc, |crc, &byte| {
+        let i = ((crc >> 8) ^ u16::from(byte)) & 0xFF;
+        (crc << 8) ^ CRC_TABLE[i as usize]
+    })
+}
+
+/// Calculate the Dynamixel 2.0 CRC-16 in software with a lookup table
+///
+/// This is the official Robotis implementation, usable without the CRC peripheral.
+///
+/// # Arguments
+/// * `data` - Packet data buffer (excluding the 2-byte CRC field)
+///
+/// # Returns
+/// 16-bit CRC value in little-endian format (low byte, high byte)
+pub fn crc16_dynamixel(data: &[u8]) -> [u8; 2] {
+    crc16_update(0x0000, data).to_le_bytes()
+}
+
+/// Buffers longer than this many bytes are fed by DMA in [`CrcProcessor::calculate`]
+///
+/// Below this, setting up the transfer costs more than feeding the bytes from the CPU.
+#[cfg(not(feature = "crc-software"))]
+pub const DMA_THRESHOLD: usize = 64;
+
+/// DMA1 stream number of the channel in [`CrcPeripherals::dma`]
+#[cfg(not(feature = "crc-software"))]
+const DMA_STREAM: usize = 4;
+
+/// Most bytes a single DMA transfer can move (NDTR is 16 bits)
+#[cfg(not(feature = "crc-software"))]
+const DMA_MAX_TRANSFER: usize = u16::MAX as usize;
+
+/// Stops the DMA stream if a transfer is abandoned, so it never reads from a buffer that is gone
+#[cfg(not(feature = "crc-software"))]
+struct DmaTransferGuard;
+
+#[cfg(not(feature = "crc-software"))]
+impl Drop for DmaTransferGuard {
+    fn drop(&mut self) {
+        let stream = pac::DMA1.st(DMA_STREAM);
+        stream.cr().modify(|w| w.set_en(false));
+        while stream.cr().read().en() {}
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
 /// Hardware CRC processor for Dynamixel 2.0 protocol packets
 ///
 /// This peripheral uses the STM32H753's hardware CRC peripheral to efficiently
@@ -44,10 +160,21 @@ macro_rules! claim_crc {
 ///
 /// Since the CRC calculation is a synchronous atomic operation that completes
 /// without yielding, no mutex protection is needed when using a single executor.
+/// The DMA path does yield, but holds `&mut self` until the result has been read.
+/// Tasks that each need the processor share it through a [`SharedCrc`].
+///
+/// With the `crc-software` feature the same interface calculates with [`crc16_dynamixel`].
 pub struct CrcProcessor<'d> {
+    #[cfg(not(feature = "crc-software"))]
     crc: Crc<'d>,
+    /// Held to keep the DMA stream used by [`CrcProcessor::calculate_crc_dma`] exclusive
+    #[cfg(not(feature = "crc-software"))]
+    _dma: Peri<'d, DMA1_CH4>,
+    #[cfg(feature = "crc-software")]
+    _lifetime: PhantomData<&'d ()>,
 }
 
+#[cfg(not(feature = "crc-software"))]
 impl<'d> CrcProcessor<'d> {
     /// Create a new hardware CRC processor for Dynamixel 2.0 protocol
     ///
@@ -69,6 +196,7 @@ impl<'d> CrcProcessor<'d> {
 
         Self {
             crc: Crc::new(peripherals.crc, config),
+            _dma: peripherals.dma,
         }
     }
 
@@ -97,6 +225,94 @@ impl<'d> CrcProcessor<'d> {
         // Use Embassy's hardware CRC calculation
         let crc_result_32 = self.crc.feed_bytes(data);
 
+        Self::to_bytes(crc_result_32)
+    }
+
+    /// Calculate CRC-16 for a Dynamixel 2.0 protocol packet, feeding the CRC unit by DMA
+    ///
+    /// The CPU is free for other tasks while the transfer runs; completion is polled each time
+    /// the executor comes back to this task. If the transfer fails, the CRC is recalculated with
+    /// [`calculate_crc`](Self::calculate_crc), so the result is always valid.
+    ///
+    /// `data` must not live in DTCM, which DMA1 cannot reach. Stack and statics are placed in
+    /// AXI RAM by `memory.x`, so this only matters for buffers placed there explicitly.
+    ///
+    /// # Arguments
+    /// * `data` - Packet data buffer (excluding the 2-byte CRC field)
+    ///
+    /// # Returns
+    /// 16-bit CRC value in little-endian format (low byte, high byte)
+    pub async fn calculate_crc_dma(&mut self, data: &[u8]) -> [u8; 2] {
+        self.crc.reset();
+
+        for chunk in data.chunks(DMA_MAX_TRANSFER) {
+            if !Self::feed_dma(chunk).await {
+                defmt::warn!("CRC DMA transfer error, falling back to CPU");
+                return self.calculate_crc(data);
+            }
+        }
+
+        Self::to_bytes(self.crc.read())
+    }
+
+    /// Feed `chunk` into the CRC data register with a memory-to-memory DMA transfer
+    ///
+    /// # Returns
+    /// `true` once the whole chunk has been transferred, `false` on a DMA transfer error
+    async fn feed_dma(chunk: &[u8]) -> bool {
+        let stream = pac::DMA1.st(DMA_STREAM);
+        let (flags, flag) = (DMA_STREAM / 4, DMA_STREAM % 4);
+
+        pac::DMA1.ifcr(flags).write(|w| {
+            w.set_tcif(flag, true);
+            w.set_teif(flag, true);
+            w.set_feif(flag, true);
+        });
+
+        // In memory-to-memory mode the peripheral port is the source and the memory port the
+        // destination, so the buffer goes in PAR and the fixed CRC data register in M0AR
+        stream.par().write_value(chunk.as_ptr() as u32);
+        stream.m0ar().write_value(pac::CRC.dr8().as_ptr() as u32);
+        stream.ndtr().write(|w| w.set_ndt(chunk.len() as u16));
+        // Memory-to-memory transfers require the FIFO
+        stream.fcr().write(|w| {
+            w.set_dmdis(vals::Dmdis::DISABLED);
+            w.set_fth(vals::Fth::HALF);
+        });
+
+        // Make sure the buffer contents are written out before the DMA reads them
+        compiler_fence(Ordering::SeqCst);
+
+        let guard = DmaTransferGuard;
+        stream.cr().write(|w| {
+            w.set_dir(vals::Dir::MEMORY_TO_MEMORY);
+            w.set_pinc(true);
+            w.set_minc(false);
+            w.set_psize(vals::Size::BITS8);
+            w.set_msize(vals::Size::BITS8);
+            w.set_en(true);
+        });
+
+        loop {
+            let status = pac::DMA1.isr(flags).read();
+            if status.teif(flag) {
+                drop(guard);
+                return false;
+            }
+            if status.tcif(flag) {
+                break;
+            }
+            yield_now().await;
+        }
+
+        // The stream disables itself on completion, nothing left for the guard to stop
+        core::mem::forget(guard);
+        compiler_fence(Ordering::SeqCst);
+        true
+    }
+
+    /// Convert the CRC data register value into the little-endian byte pair Dynamixel 2.0 expects
+    fn to_bytes(crc_result_32: u32) -> [u8; 2] {
         // For 16-bit CRC, the result is in the lower 16 bits
         let crc_result = (crc_result_32 & 0xFFFF) as u16;
 
@@ -107,3 +323,64 @@ impl<'d> CrcProcessor<'d> {
         ]
     }
 }
+
+#[cfg(feature = "crc-software")]
+impl<'d> CrcProcessor<'d> {
+    /// Create a CRC processor that calculates in software
+    ///
+    /// # Arguments
+    /// * `peripherals` - Empty CrcPeripherals struct, nothing is claimed with `crc-software`
+    pub fn new(_peripherals: CrcPeripherals<'d>) -> Self {
+        Self { _lifetime: PhantomData }
+    }
+
+    /// Calculate CRC-16 for a Dynamixel 2.0 protocol packet with [`crc16_dynamixel`]
+    ///
+    /// # Arguments
+    /// * `data` - Packet data buffer (excluding the 2-byte CRC field)
+    ///
+    /// # Returns
+    /// 16-bit CRC value in little-endian format (low byte, high byte)
+    pub fn calculate_crc(&mut self, data: &[u8]) -> [u8; 2] {
+        crc16_dynamixel(data)
+    }
+}
+
+impl<'d> CrcProcessor<'d> {
+    /// Calculate CRC-16 for a Dynamixel 2.0 protocol packet, picking the fastest path for its size
+    ///
+    /// Buffers up to `DMA_THRESHOLD` bytes use [`calculate_crc`](Self::calculate_crc), anything
+    /// longer uses `calculate_crc_dma`. With `crc-software` every buffer is calculated in
+    /// software.
+    ///
+    /// # Arguments
+    /// * `data` - Packet data buffer (excluding the 2-byte CRC field)
+    ///
+    /// # Returns
+    /// 16-bit CRC value in little-endian format (low byte, high byte)
+    pub async fn calculate(&mut self, data: &[u8]) -> [u8; 2] {
+        #[cfg(not(feature = "crc-software"))]
+        if data.len() > DMA_THRESHOLD {
+            return self.calculate_crc_dma(data).await;
+        }
+        self.calculate_crc(data)
+    }
+}
+
+/// A [`CrcProcessor`] shared by every task that builds or checks packets
+///
+/// There is a single CRC unit, so each task locks it for one packet or one bus exchange at a time
+/// and never across a wait that does not need it.
+pub type SharedCrc = Mutex<CriticalSectionRawMutex, CrcProcessor<'static>>;
+
+/// Share a CRC processor between tasks
+///
+/// # Arguments
+/// * `processor` - The CRC processor, which can only be shared once
+///
+/// # Returns
+/// The shared processor, to be handed to each task that needs it
+pub fn share(processor: CrcProcessor<'static>) -> &'static SharedCrc {
+    static SHARED: StaticCell<SharedCrc> = StaticCell::new();
+    SHARED.init(Mutex::new(processor))
+}
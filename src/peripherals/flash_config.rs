@@ -0,0 +1,137 @@
+//! Settings stored in a reserved flash sector.
+//!
+//! The last sector of bank 2, `CONFIG` in `memory.x`, is kept out of the firmware image and holds
+//! the settings that must survive losing power, unlike [`super::persistent`] whose backup SRAM
+//! loses them along with VBAT. Currently these are the battery protection thresholds, see
+//! [`crate::battery`].
+//!
+//! Flash can only be written once between erases, and erasing a sector takes about a second, so
+//! each store appends a record of one flash word ([`RECORD_SIZE`] bytes) after the ones before it
+//! and the last valid record is the one in effect. The sector is only erased when it is full.
+//!
+//! The code runs from bank 1, so writing bank 2 does not stall it, but the CPU waits for every
+//! write and erase to finish. An erase therefore holds up every task for about a second, well
+//! within the watchdog timeout, once every [`RECORDS`] stores.
+
+use core::ptr;
+use embassy_stm32::{
+    flash::{self, Blocking, Flash, FLASH_BASE, WRITE_SIZE},
+    peripherals::FLASH,
+    Peri,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+
+use crate::battery::BatteryThresholds;
+
+/// Offset of the settings sector from the start of flash, the `CONFIG` region in `memory.x`
+const CONFIG_OFFSET: u32 = 0x1E_0000;
+/// Size of the settings sector
+const CONFIG_SIZE: u32 = 128 * 1024;
+
+/// Size of a record, one flash word so each record is written on its own
+pub const RECORD_SIZE: usize = WRITE_SIZE;
+/// Records that fit in the sector before it must be erased
+pub const RECORDS: usize = CONFIG_SIZE as usize / RECORD_SIZE;
+
+/// Marker identifying a battery thresholds record ("NUBT")
+const THRESHOLDS_MAGIC: u32 = 0x4E55_4254;
+
+/// Flash controller, set by [`init`]
+static FLASH_CONTROLLER: Mutex<CriticalSectionRawMutex, Option<Flash<'static, Blocking>>> = Mutex::new(None);
+
+/// Peripheral collection for the settings sector
+pub struct FlashConfigClaims<'d> {
+    pub flash: Peri<'d, FLASH>,
+}
+
+/// Macro to claim peripherals for the flash settings
+#[macro_export]
+macro_rules! claim_flash_config {
+    ($peripherals:expr) => {{
+        $crate::peripherals::claims::register($crate::peripherals::claims::Resource::Flash);
+        $crate::peripherals::flash_config::FlashConfigClaims {
+            flash: $peripherals.FLASH,
+        }
+    }};
+}
+
+/// Take the flash controller for storing settings
+///
+/// # Arguments
+/// * `claims` - FlashConfigClaims struct containing the flash controller
+pub async fn init(claims: FlashConfigClaims<'static>) {
+    *FLASH_CONTROLLER.lock().await = Some(Flash::new_blocking(claims.flash));
+}
+
+/// A record slot in the memory-mapped settings sector
+fn read_slot(slot: usize) -> [u8; RECORD_SIZE] {
+    let address = FLASH_BASE + CONFIG_OFFSET as usize + slot * RECORD_SIZE;
+    // SAFETY: The slot lies in the settings sector, which is always mapped and only changed
+    // through FLASH_CONTROLLER
+    unsafe { ptr::read_volatile(address as *const [u8; RECORD_SIZE]) }
+}
+
+/// Whether a record slot is still erased
+fn is_blank(record: &[u8; RECORD_SIZE]) -> bool {
+    record.iter().all(|byte| *byte == 0xFF)
+}
+
+/// Checksum over the magic and the thresholds of a record
+fn checksum(record: &[u8; RECORD_SIZE]) -> u32 {
+    let word = |at: usize| u32::from_le_bytes([record[at], record[at + 1], record[at + 2], record[at + 3]]);
+    !(word(0) ^ word(4) ^ word(8))
+}
+
+/// Battery thresholds stored with [`store_battery_thresholds`]
+///
+/// # Returns
+/// The thresholds of the last valid record, or `None` if none were stored
+pub fn battery_thresholds() -> Option<BatteryThresholds> {
+    let field = |record: &[u8; RECORD_SIZE], at: usize| u16::from_le_bytes([record[at], record[at + 1]]);
+    (0..RECORDS)
+        .map(read_slot)
+        .take_while(|record| !is_blank(record))
+        .filter(|record| {
+            record[..4] == THRESHOLDS_MAGIC.to_le_bytes() && record[12..16] == checksum(record).to_le_bytes()
+        })
+        .last()
+        .map(|record| BatteryThresholds {
+            cutoff_mv: field(&record, 4),
+            warn_mv: field(&record, 6),
+            over_mv: field(&record, 8),
+            hysteresis_mv: field(&record, 10),
+        })
+}
+
+/// Store battery thresholds to be loaded again after a reset or power loss
+///
+/// Erases the sector first if it is full, dropping every record in it.
+///
+/// # Arguments
+/// * `thresholds` - The thresholds to store
+///
+/// # Returns
+/// Success, or the flash error that stopped the record being written
+pub async fn store_battery_thresholds(thresholds: BatteryThresholds) -> Result<(), flash::Error> {
+    let mut record = [0xFF; RECORD_SIZE];
+    record[..4].copy_from_slice(&THRESHOLDS_MAGIC.to_le_bytes());
+    record[4..6].copy_from_slice(&thresholds.cutoff_mv.to_le_bytes());
+    record[6..8].copy_from_slice(&thresholds.warn_mv.to_le_bytes());
+    record[8..10].copy_from_slice(&thresholds.over_mv.to_le_bytes());
+    record[10..12].copy_from_slice(&thresholds.hysteresis_mv.to_le_bytes());
+    let sum = checksum(&record);
+    record[12..16].copy_from_slice(&sum.to_le_bytes());
+
+    let mut controller = FLASH_CONTROLLER.lock().await;
+    let flash = controller.as_mut().expect("flash_config::init runs before any store");
+
+    let slot = match (0..RECORDS).find(|slot| is_blank(&read_slot(*slot))) {
+        Some(slot) => slot,
+        None => {
+            defmt::info!("Settings sector full, erasing it");
+            flash.blocking_erase(CONFIG_OFFSET, CONFIG_OFFSET + CONFIG_SIZE)?;
+            0
+        }
+    };
+    flash.blocking_write(CONFIG_OFFSET + (slot * RECORD_SIZE) as u32, &record)
+}
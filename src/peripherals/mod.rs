@@ -7,14 +7,28 @@
 
 /// CDC ACM (virtual serial port) implementation
 pub mod acm;
+/// Runtime registry that rejects double-claimed peripherals
+pub mod claims;
 /// CRC peripheral for Dynamixel 2.0 protocol
 pub mod crc;
+/// Settings stored in a reserved flash sector
+pub mod flash_config;
+/// Boot statistics persisted in backup SRAM
+pub mod persistent;
 /// SPI peripheral configuration
 pub mod spi;
 /// System initialization and clock configuration
 pub mod system;
+/// Non-blocking telemetry streaming over a CDC ACM port
+#[cfg(feature = "telemetry")]
+pub mod telemetry;
+/// defmt log output over a dedicated CDC ACM port
+#[cfg(feature = "log-usb")]
+pub mod usb_logger;
 /// USB system abstraction
 pub mod usb_system;
+/// Independent watchdog that resets the board if the executor stalls
+pub mod watchdog;
 
 // Re-export commonly used types for convenience
 pub use system::init_system;
@@ -0,0 +1,118 @@
+//! Boot statistics and settings persisted in backup SRAM.
+//!
+//! The backup SRAM keeps its contents across resets (and across power cycles when VBAT is supplied),
+//! so it holds a count of resets and the cumulative uptime. A reset count that keeps rising on a field
+//! unit points at a recurring fault, even when each individual crash is recovered by a reset.
+
+use core::mem::MaybeUninit;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_stm32::pac;
+use embassy_time::Instant;
+
+/// Marker identifying a record written by this firmware ("NUSB")
+const MAGIC: u32 = 0x4E55_5342;
+
+/// Layout of the statistics record in backup SRAM
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Record {
+    magic: u32,
+    reset_count: u32,
+    uptime_secs: u32,
+    checksum: u32,
+}
+
+impl Record {
+    fn new(reset_count: u32, uptime_secs: u32) -> Self {
+        let mut record = Self {
+            magic: MAGIC,
+            reset_count,
+            uptime_secs,
+            checksum: 0,
+        };
+        record.checksum = record.compute_checksum();
+        record
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        !(self.magic ^ self.reset_count ^ self.uptime_secs)
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC && self.checksum == self.compute_checksum()
+    }
+}
+
+/// Statistics record, placed in backup SRAM by the `.backup_sram` section in `memory.x`
+#[link_section = ".backup_sram"]
+static mut RECORD: MaybeUninit<Record> = MaybeUninit::uninit();
+
+/// Cumulative uptime of all previous boots, captured by [`init`]
+static UPTIME_BEFORE_BOOT: AtomicU32 = AtomicU32::new(0);
+/// Number of resets since the record was created, captured by [`init`]
+static RESET_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Statistics accumulated across reboots
+#[derive(Debug, Clone, Copy)]
+pub struct BootStats {
+    /// Number of resets since the record was created (0 on the first boot after power loss)
+    pub reset_count: u32,
+    /// Total time the firmware has been running across all boots, in seconds
+    pub total_uptime_secs: u32,
+}
+
+fn read_record() -> Record {
+    // SAFETY: RECORD is only accessed through these volatile helpers from the single-core executor
+    unsafe { addr_of_mut!(RECORD).cast::<Record>().read_volatile() }
+}
+
+fn write_record(record: Record) {
+    // SAFETY: See `read_record`
+    unsafe { addr_of_mut!(RECORD).cast::<Record>().write_volatile(record) }
+}
+
+/// Enable the backup SRAM and count this boot
+///
+/// Must be called once, right after [`init_system`](super::init_system). If the stored record is
+/// missing or corrupt (e.g. after a power cycle without VBAT) the statistics start again from zero.
+///
+/// # Returns
+///
+/// The statistics including this boot.
+pub fn init() -> BootStats {
+    // The backup SRAM needs its clock enabled and the backup domain unlocked before it can be written
+    pac::RCC.ahb4enr().modify(|w| w.set_bkpramen(true));
+    pac::PWR.cr1().modify(|w| w.set_dbp(true));
+
+    let record = read_record();
+    let (reset_count, uptime_secs) = if record.is_valid() {
+        (record.reset_count.wrapping_add(1), record.uptime_secs)
+    } else {
+        (0, 0)
+    };
+
+    RESET_COUNT.store(reset_count, Ordering::Relaxed);
+    UPTIME_BEFORE_BOOT.store(uptime_secs, Ordering::Relaxed);
+    write_record(Record::new(reset_count, uptime_secs));
+
+    stats()
+}
+
+/// Persist the uptime of the current boot
+///
+/// Call this periodically; any uptime since the last call is lost if the board resets.
+pub fn record_uptime() {
+    let stats = stats();
+    write_record(Record::new(stats.reset_count, stats.total_uptime_secs));
+}
+
+/// Current statistics, including the uptime of this boot
+pub fn stats() -> BootStats {
+    BootStats {
+        reset_count: RESET_COUNT.load(Ordering::Relaxed),
+        total_uptime_secs: UPTIME_BEFORE_BOOT
+            .load(Ordering::Relaxed)
+            .saturating_add(Instant::now().as_secs() as u32),
+    }
+}
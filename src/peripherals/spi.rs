@@ -27,6 +27,10 @@ pub struct SpiClaims<'d> {
 #[macro_export]
 macro_rules! claim_imu_spi {
     ($peripherals:expr) => {{
+        use $crate::peripherals::claims::{register, Resource};
+        register(Resource::Spi4);
+        register(Resource::Dma1Ch0);
+        register(Resource::Dma1Ch1);
         $crate::peripherals::spi::SpiClaims {
             spi4: $peripherals.SPI4,
             cs: $peripherals.PE11,         // CS
@@ -39,6 +43,43 @@ macro_rules! claim_imu_spi {
     }};
 }
 
+/// Slowest SPI clock accepted by [`ImuSpi::set_frequency`]
+pub const MIN_FREQUENCY: Hertz = Hertz(100_000);
+/// Fastest SPI clock accepted by [`ImuSpi::set_frequency`] (ICM-20689 datasheet maximum)
+pub const MAX_FREQUENCY: Hertz = Hertz(8_000_000);
+
+/// Error returned when an SPI clock change is rejected
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum FrequencyError {
+    /// The requested frequency is outside [`MIN_FREQUENCY`]..=[`MAX_FREQUENCY`]
+    OutOfRange,
+    /// The SPI peripheral could not be reconfigured
+    Unsupported,
+}
+
+/// Bus settings for [`ImuSpi`]
+#[derive(Clone, Copy)]
+pub struct ImuSpiConfig {
+    /// SPI clock frequency
+    pub frequency: Hertz,
+    /// SPI clock polarity and phase
+    pub mode: Mode,
+}
+
+impl Default for ImuSpiConfig {
+    /// Mode 3 (CPOL=1, CPHA=1) at [`MAX_FREQUENCY`], as used by the ICM-20689
+    fn default() -> Self {
+        Self {
+            frequency: MAX_FREQUENCY,
+            mode: Mode {
+                polarity: Polarity::IdleHigh,
+                phase: Phase::CaptureOnSecondTransition,
+            },
+        }
+    }
+}
+
 /// SPI configuration for the ICM-20689 IMU
 ///
 /// The ICM-20689 supports SPI mode 0 or 3. We use mode 3 (CPOL=1, CPHA=1) as per CubeMX config.
@@ -49,6 +90,8 @@ pub struct ImuSpi<'d> {
     pub spi: Spi<'d, Async>,
     /// Chip select pin (software controlled)
     pub cs: Output<'d>,
+    /// Configuration currently applied to the SPI peripheral
+    config: SpiConfig,
 }
 
 impl<'d> ImuSpi<'d> {
@@ -60,13 +103,21 @@ impl<'d> ImuSpi<'d> {
     /// # Returns
     /// Configured SPI instance with software chip select control
     pub fn new(claims: SpiClaims<'d>) -> Self {
-        // Configure SPI for ICM-20689 to match CubeMX configuration
+        Self::new_with_config(claims, ImuSpiConfig::default())
+    }
+
+    /// Create a new IMU SPI configuration with custom bus settings
+    ///
+    /// # Arguments
+    /// * `claims` - SpiClaims struct containing all required peripherals
+    /// * `bus_config` - Clock frequency and SPI mode to use
+    ///
+    /// # Returns
+    /// Configured SPI instance with software chip select control
+    pub fn new_with_config(claims: SpiClaims<'d>, bus_config: ImuSpiConfig) -> Self {
         let mut config = SpiConfig::default();
-        config.mode = Mode {
-            polarity: Polarity::IdleHigh,
-            phase: Phase::CaptureOnSecondTransition,
-        };
-        config.frequency = Hertz(8_000_000);
+        config.mode = bus_config.mode;
+        config.frequency = bus_config.frequency;
 
         let cs_pin = Output::new(claims.cs, Level::High, Speed::VeryHigh);
 
@@ -80,7 +131,38 @@ impl<'d> ImuSpi<'d> {
             config,
         );
 
-        Self { spi, cs: cs_pin }
+        Self {
+            spi,
+            cs: cs_pin,
+            config,
+        }
+    }
+
+    /// Current SPI clock frequency
+    pub fn frequency(&self) -> Hertz {
+        self.config.frequency
+    }
+
+    /// Change the SPI clock frequency of the live peripheral
+    ///
+    /// Taking `&mut self` guarantees no transaction is in flight while the peripheral is
+    /// reconfigured. The actual clock is the closest prescaler setting at or below the request.
+    ///
+    /// # Arguments
+    /// * `frequency` - New clock frequency, between [`MIN_FREQUENCY`] and [`MAX_FREQUENCY`]
+    ///
+    /// # Returns
+    /// * Success, or an error leaving the previous frequency in effect
+    pub fn set_frequency(&mut self, frequency: Hertz) -> Result<(), FrequencyError> {
+        if frequency.0 < MIN_FREQUENCY.0 || frequency.0 > MAX_FREQUENCY.0 {
+            return Err(FrequencyError::OutOfRange);
+        }
+
+        let mut config = self.config;
+        config.frequency = frequency;
+        self.spi.set_config(&config).map_err(|_| FrequencyError::Unsupported)?;
+        self.config = config;
+        Ok(())
     }
 
     /// Read a single register from an SPI device
@@ -147,6 +229,38 @@ impl<'d> ImuSpi<'d> {
         result
     }
 
+    /// Write consecutive registers starting at a specific register using DMA
+    ///
+    /// # Arguments
+    /// * `reg` - Register address of the first byte in `data`
+    /// * `data` - Values to write, one per register
+    ///
+    /// # Returns
+    /// * Success or SPI error
+    ///
+    /// This function performs a burst write operation, relying on the device auto-incrementing
+    /// its register pointer:
+    /// 1. Assert chip select (low)
+    /// 2. Send register address with write bit clear
+    /// 3. Write all bytes from data
+    /// 4. Deassert chip select (high)
+    pub async fn write_register_burst(&mut self, reg: u8, data: &[u8]) -> Result<(), embassy_stm32::spi::Error> {
+        const SPI_WRITE_MASK: u8 = 0x7F;
+        let cmd = [reg & SPI_WRITE_MASK];
+
+        self.cs.set_low();
+
+        // Chip select must be released even if the command fails, or the next transaction is corrupted
+        let result = match self.spi.write(&cmd).await {
+            Ok(()) => self.spi.write(data).await,
+            Err(e) => Err(e),
+        };
+
+        self.cs.set_high();
+
+        result
+    }
+
     /// Read data from a specific register using DMA
     ///
     /// # Arguments
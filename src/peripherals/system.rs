@@ -1,21 +1,123 @@
 //! System initialization and clock configuration for STM32H753.
 //!
 //! This module handles the complex clock setup required for high-performance operation
-//! at 480MHz with proper USB support.
+//! at 480MHz with proper USB support. Thermally constrained deployments can trade speed for
+//! power with a slower [`ClockProfile`].
+//!
+//! The clock tree is built from the internal HSI oscillator by default. Boards with the 25MHz
+//! HSE crystal should enable the `hse` feature, which derives the same system clock from the
+//! crystal. Its accuracy carries through to the UART baud rates and the USB clock, so USB no
+//! longer needs to trim HSI48 against the host's SOF packets.
+//!
+//! Scale0 can hang waiting for the overdrive to settle when VDD is marginal, e.g. on weak USB
+//! power. Before any clock is changed the programmable voltage detector (PVD) is armed at a
+//! [`PvdThreshold`], and if VDD is already below it a Scale0 profile falls back to
+//! [`ClockProfile::Balanced400`] instead of attempting 480MHz.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::DWT;
+use defmt::warn;
+#[cfg(feature = "hse")]
+use embassy_stm32::time::Hertz;
+use embassy_stm32::{pac, rcc::*, Config, Peripherals};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use static_cell::StaticCell;
+
+/// CPU core clock in Hz of the active [`ClockProfile`], which drives the DWT cycle counter
+static CPU_FREQUENCY_HZ: AtomicU32 = AtomicU32::new(ClockProfile::MaxPerformance480.cpu_frequency_hz());
+
+/// Frequency of the HSE crystal on the production board
+#[cfg(feature = "hse")]
+const HSE_FREQUENCY_HZ: u32 = 25_000_000;
+/// PLL3 dividers generating the USB clock from the HSE crystal, must match the values in
+/// [`configure_oscillators`]
+#[cfg(feature = "hse")]
+const HSE_PLL3: (u32, u32, u32) = (5, 96, 10); // (DIVM3, DIVN3, DIVQ3)
 
-use embassy_stm32::{rcc::*, Config, Peripherals};
+#[cfg(feature = "hse")]
+const _: () = assert!(
+    HSE_FREQUENCY_HZ / HSE_PLL3.0 * HSE_PLL3.1 / HSE_PLL3.2 == 48_000_000,
+    "PLL3 Q output must be 48MHz for USB"
+);
 
-/// Initialize the STM32H753 system with optimal clock configuration.
+/// CPU cycles to wait for the PVD output to settle after it is enabled, about 20µs on the 64MHz
+/// HSI that runs the core before [`init_system`] (the datasheet gives at most 10µs)
+const PVD_SETTLE_CYCLES: u32 = 1_280;
+
+/// Address of the 96-bit unique device ID (RM0433 section 61.1)
+const UID_ADDRESS: usize = 0x1FF1_E800;
+/// Length of the unique device ID as a hex string (three 32-bit words, 8 digits each)
+const SERIAL_LENGTH: usize = 24;
+
+/// Backing storage for the string returned by [`device_serial`]
+static SERIAL_BUFFER: StaticCell<[u8; SERIAL_LENGTH]> = StaticCell::new();
+/// The formatted serial, once [`device_serial`] has been called
+static SERIAL: Mutex<CriticalSectionRawMutex, Cell<Option<&'static str>>> = Mutex::new(Cell::new(None));
+
+/// System clock speed and the core voltage that supports it
 ///
-/// Configures the system for high-performance operation:
-/// - **480 MHz** system clock (maximum for STM32H753) using PLL1 from HSI
+/// The AHB and APB buses always run at half and a quarter of the system clock, which keeps them
+/// within their limits at every voltage scale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum ClockProfile {
+    /// 480 MHz at Scale0, the maximum for the STM32H753
+    #[default]
+    MaxPerformance480,
+    /// 400 MHz at Scale1, the fastest clock without the Scale0 overdrive
+    Balanced400,
+}
+
+impl ClockProfile {
+    /// System (CPU) clock frequency in Hz
+    pub const fn cpu_frequency_hz(self) -> u32 {
+        match self {
+            ClockProfile::MaxPerformance480 => 480_000_000,
+            ClockProfile::Balanced400 => 400_000_000,
+        }
+    }
+
+    /// Lowest core voltage that supports the system clock
+    const fn voltage_scale(self) -> VoltageScale {
+        match self {
+            ClockProfile::MaxPerformance480 => VoltageScale::Scale0,
+            ClockProfile::Balanced400 => VoltageScale::Scale1,
+        }
+    }
+}
+
+/// VDD level below which the programmable voltage detector reports a low supply
+///
+/// The value of each variant is its PLS field in PWR_CR1. Only the level in use is listed, the
+/// field also selects 1.95 V to 2.7 V in 150 mV steps (0 to 5).
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum PvdThreshold {
+    /// 2.85 V
+    V2_85 = 6,
+}
+
+/// Initialize the STM32H753 system with the clock configuration of `profile`.
+///
+/// With the default [`ClockProfile::MaxPerformance480`] the system is configured for
+/// high-performance operation:
+/// - **480 MHz** system clock (maximum for STM32H753) using PLL1 from HSI, or from HSE with
+///   the `hse` feature
 /// - **240 MHz** AHB clock (CPU and high-speed peripherals)
 /// - **120 MHz** APB clocks (peripheral buses)
-/// - **48 MHz** HSI48 clock for USB (synchronized from USB SOF)
+/// - **48 MHz** USB clock, from HSI48 synchronized from USB SOF, or from PLL3 Q with `hse`
 /// - **Scale0** voltage scaling for maximum performance
 ///
-/// The clock configuration matches STM32CubeMX recommendations for maximum
-/// performance while maintaining USB compatibility.
+/// [`ClockProfile::Balanced400`] lowers the system clock and voltage scale, and the buses with
+/// them. The clock configuration matches STM32CubeMX recommendations for maximum
+/// performance while maintaining USB compatibility. The DWT cycle counter is
+/// also started so code can be timed with [`cycle_count`].
+///
+/// The PVD is armed at `pvd` first and left running, so [`supply_low`] can be polled later. If VDD
+/// is already below it, a profile that needs Scale0 is replaced by
+/// [`ClockProfile::Balanced400`] and a warning is logged.
 ///
 /// # Returns
 ///
@@ -25,42 +127,235 @@ use embassy_stm32::{rcc::*, Config, Peripherals};
 ///
 /// This function will panic if the clock configuration fails, which typically
 /// indicates hardware issues or invalid clock settings.
-pub fn init_system() -> Peripherals {
+pub fn init_system(profile: ClockProfile, pvd: PvdThreshold) -> Peripherals {
+    configure_pvd(pvd);
+    let requested = profile;
+    let profile = if supply_low() && matches!(profile.voltage_scale(), VoltageScale::Scale0) {
+        ClockProfile::Balanced400
+    } else {
+        profile
+    };
+
     let mut config = Config::default();
 
-    // Enable high-speed internal oscillator (16 MHz)
+    // Enable high-speed internal oscillator (64 MHz)
     config.rcc.hsi = Some(HSIPrescaler::DIV1);
 
     // Enable low-power internal oscillator for backup
     config.rcc.csi = true;
 
-    // Enable HSI48 for USB with automatic synchronization from USB SOF packets
-    config.rcc.hsi48 = Some(Hsi48Config { sync_from_usb: true });
-
-    // Configure PLL1 for maximum system performance (480 MHz)
-    // PLL1 = HSI(16MHz) / DIVM1(4) * DIVN1(60) / DIVP1(2) = 480MHz
-    config.rcc.pll1 = Some(Pll {
-        source: PllSource::HSI,   // Use internal 16MHz oscillator
-        prediv: PllPreDiv::DIV4,  // DIVM1=4 → 4MHz PLL input
-        mul: PllMul::MUL60,       // DIVN1=60 → 240MHz VCO
-        divp: Some(PllDiv::DIV2), // DIVP1=2 → 480MHz output
-        divq: None,               // Q output not used
-        divr: None,               // R output not used
-    });
+    // PLL1 and the USB clock
+    configure_oscillators(&mut config, profile);
 
-    // System clock configuration
-    config.rcc.sys = Sysclk::PLL1_P; // 480 MHz system clock
+    // System clock configuration (bus clocks given for 480 MHz)
+    config.rcc.sys = Sysclk::PLL1_P; // System clock from the profile
     config.rcc.ahb_pre = AHBPrescaler::DIV2; // 240 MHz AHB clock
     config.rcc.apb1_pre = APBPrescaler::DIV2; // 120 MHz APB1 clock
     config.rcc.apb2_pre = APBPrescaler::DIV2; // 120 MHz APB2 clock
     config.rcc.apb3_pre = APBPrescaler::DIV2; // 120 MHz APB3 clock
     config.rcc.apb4_pre = APBPrescaler::DIV2; // 120 MHz APB4 clock
 
-    // Maximum voltage scaling for 480MHz operation
-    config.rcc.voltage_scale = VoltageScale::Scale0;
+    // ADC kernel clock from per_ck, which defaults to HSI (64 MHz)
+    config.rcc.mux.adcsel = mux::Adcsel::PER;
+
+    // Scale0 for 480MHz operation, Scale1 is enough for the slower profiles
+    config.rcc.voltage_scale = profile.voltage_scale();
+
+    let peripherals = embassy_stm32::init(config);
+    CPU_FREQUENCY_HZ.store(profile.cpu_frequency_hz(), Ordering::Relaxed);
+    enable_cycle_counter();
+
+    // Logged only now, as the defmt timestamps need the time driver
+    if profile != requested {
+        warn!(
+            "VDD below {:?}, running at {:?} instead of {:?}",
+            pvd, profile, requested
+        );
+    }
+    peripherals
+}
+
+/// Enable the programmable voltage detector and wait for its output to settle.
+fn configure_pvd(threshold: PvdThreshold) {
+    pac::PWR.cr1().modify(|w| {
+        w.set_pls(threshold as u8);
+        w.set_pvde(true);
+    });
+    cortex_m::asm::delay(PVD_SETTLE_CYCLES);
+}
+
+/// Whether VDD is below the [`PvdThreshold`] given to [`init_system`].
+pub fn supply_low() -> bool {
+    pac::PWR.csr1().read().pvdo()
+}
+
+/// Configure PLL1 and the USB clock from the internal oscillators.
+#[cfg(not(feature = "hse"))]
+fn configure_oscillators(config: &mut Config, profile: ClockProfile) {
+    // Enable HSI48 for USB with automatic synchronization from USB SOF packets
+    config.rcc.hsi48 = Some(Hsi48Config { sync_from_usb: true });
+
+    // PLL1 = HSI(64MHz) / DIVM1(4) * DIVN1 / DIVP1(2)
+    let mul = match profile {
+        ClockProfile::MaxPerformance480 => PllMul::MUL60, // 960MHz VCO → 480MHz
+        ClockProfile::Balanced400 => PllMul::MUL50,       // 800MHz VCO → 400MHz
+    };
+    config.rcc.pll1 = Some(Pll {
+        source: PllSource::HSI,   // Use internal 64MHz oscillator
+        prediv: PllPreDiv::DIV4,  // DIVM1=4 → 16MHz PLL input
+        mul,                      // DIVN1 from the profile
+        divp: Some(PllDiv::DIV2), // DIVP1=2 → system clock
+        divq: None,               // Q output not used
+        divr: None,               // R output not used
+    });
 
     // Use HSI48 for USB (provides accurate 48MHz for USB timing)
     config.rcc.mux.usbsel = mux::Usbsel::HSI48;
+}
+
+/// Configure PLL1 and the USB clock from the HSE crystal.
+#[cfg(feature = "hse")]
+fn configure_oscillators(config: &mut Config, profile: ClockProfile) {
+    // External 25MHz crystal
+    config.rcc.hse = Some(Hse {
+        freq: Hertz(HSE_FREQUENCY_HZ),
+        mode: HseMode::Oscillator,
+    });
+
+    // PLL1 = HSE(25MHz) / DIVM1(5) * DIVN1 / DIVP1(2)
+    let mul = match profile {
+        ClockProfile::MaxPerformance480 => PllMul::MUL192, // 960MHz VCO → 480MHz
+        ClockProfile::Balanced400 => PllMul::MUL160,       // 800MHz VCO → 400MHz
+    };
+    config.rcc.pll1 = Some(Pll {
+        source: PllSource::HSE,   // Use external 25MHz crystal
+        prediv: PllPreDiv::DIV5,  // DIVM1=5 → 5MHz PLL input
+        mul,                      // DIVN1 from the profile
+        divp: Some(PllDiv::DIV2), // DIVP1=2 → system clock
+        divq: None,               // Q output not used
+        divr: None,               // R output not used
+    });
+
+    // 48MHz is not a divisor of every PLL1 VCO frequency, so USB gets its own PLL
+    // PLL3 = HSE(25MHz) / DIVM3(5) * DIVN3(96) / DIVQ3(10) = 48MHz
+    config.rcc.pll3 = Some(Pll {
+        source: PllSource::HSE,    // Use external 25MHz crystal
+        prediv: PllPreDiv::DIV5,   // DIVM3=5 → 5MHz PLL input
+        mul: PllMul::MUL96,        // DIVN3=96 → 480MHz VCO
+        divp: None,                // P output not used
+        divq: Some(PllDiv::DIV10), // DIVQ3=10 → 48MHz USB clock
+        divr: None,                // R output not used
+    });
+
+    // USB from the crystal, so no SOF synchronization is needed
+    config.rcc.mux.usbsel = mux::Usbsel::PLL3_Q;
+}
+
+/// Start the DWT cycle counter.
+fn enable_cycle_counter() {
+    // SAFETY: Only the trace enable and cycle counter bits are touched, nothing else in the
+    // firmware uses the DCB or DWT
+    let mut core = unsafe { cortex_m::Peripherals::steal() };
+    core.DCB.enable_trace();
+    // The Cortex-M7 DWT ignores writes until its lock access register is unlocked
+    DWT::unlock();
+    core.DWT.enable_cycle_counter();
+}
+
+/// Current value of the free-running CPU cycle counter.
+///
+/// The counter wraps every ~9 s at 480 MHz (~11 s at 400 MHz), so compare values with `wrapping_sub`.
+pub fn cycle_count() -> u32 {
+    DWT::cycle_count()
+}
+
+/// CPU core clock in Hz, set by [`init_system`] from its [`ClockProfile`]
+pub fn cpu_frequency_hz() -> u32 {
+    CPU_FREQUENCY_HZ.load(Ordering::Relaxed)
+}
+
+/// Convert a number of CPU cycles into microseconds.
+pub fn cycles_to_micros(cycles: u32) -> u32 {
+    cycles / (cpu_frequency_hz() / 1_000_000)
+}
+
+/// Why the microcontroller last came out of reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum ResetCause {
+    /// Power was applied (power-on reset)
+    PowerOn,
+    /// The NRST pin was pulled low externally, e.g. by the reset button or a debugger
+    Pin,
+    /// The firmware requested a reset (`SCB::sys_reset`)
+    Software,
+    /// The independent watchdog expired, see [`watchdog`](super::watchdog)
+    IndependentWatchdog,
+    /// The window watchdog expired
+    WindowWatchdog,
+    /// A low-power mode was entered illegally
+    LowPower,
+    /// The supply dropped below the brown-out threshold without a full power loss
+    BrownOut,
+    /// No reset flag was set, e.g. the flags were already cleared this boot
+    Unknown,
+}
+
+/// Read and clear the reset flags in RCC_RSR.
+///
+/// Must be called right after [`init_system`], before anything else clears the flags. Clearing
+/// them means the next boot reports only its own cause.
+///
+/// Resets also set the flags of the resets they trigger internally: every reset drives the NRST
+/// pin, so PINRSTF is set for all of them, and a power-on reset also sets BORRSTF. The most
+/// specific flag is therefore reported.
+pub fn read_reset_cause() -> ResetCause {
+    let rsr = pac::RCC.rsr().read();
+
+    let cause = if rsr.lpwrrstf() {
+        ResetCause::LowPower
+    } else if rsr.iwdg1rstf() {
+        ResetCause::IndependentWatchdog
+    } else if rsr.wwdg1rstf() {
+        ResetCause::WindowWatchdog
+    } else if rsr.sftrstf() {
+        ResetCause::Software
+    } else if rsr.porrstf() {
+        ResetCause::PowerOn
+    } else if rsr.borrstf() {
+        ResetCause::BrownOut
+    } else if rsr.pinrstf() {
+        ResetCause::Pin
+    } else {
+        ResetCause::Unknown
+    };
+
+    pac::RCC.rsr().modify(|w| w.set_rmvf(true));
+    cause
+}
+
+/// Serial number unique to this chip, from its 96-bit unique device ID.
+///
+/// The three ID words are formatted as 24 upper-case hex digits, lowest address first. The string
+/// is formatted on the first call and the same one returned from then on.
+pub fn device_serial() -> &'static str {
+    SERIAL.lock(|serial| {
+        if let Some(serial) = serial.get() {
+            return serial;
+        }
+
+        let buffer = SERIAL_BUFFER.init([0; SERIAL_LENGTH]);
+        for (word_index, digits) in buffer.chunks_exact_mut(8).enumerate() {
+            // SAFETY: The unique ID is three read-only words in system memory, always mapped
+            let word = unsafe { core::ptr::read_volatile((UID_ADDRESS as *const u32).add(word_index)) };
+            for (digit_index, digit) in digits.iter_mut().enumerate() {
+                let nibble = (word >> (28 - 4 * digit_index)) & 0xF;
+                *digit = b"0123456789ABCDEF"[nibble as usize];
+            }
+        }
 
-    embassy_stm32::init(config)
+        let formatted: &'static str = core::str::from_utf8(buffer).expect("hex digits are ASCII");
+        serial.set(Some(formatted));
+        formatted
+    })
 }
@@ -0,0 +1,189 @@
+//! Streaming telemetry over a CDC ACM port.
+//!
+//! Sending every sample with [`AcmConnection::send_packet`] stalls the producer whenever the host
+//! is slow to read. [`TelemetryWriter`] decouples the two: the producer pushes records into a
+//! lock-free single-producer single-consumer ring buffer without ever waiting, and [`task`]
+//! drains the ring into the port as fast as the host accepts packets.
+//!
+//! A record that does not fit in the ring is dropped whole, so the stream never contains a
+//! partial record, and the number of dropped records is logged when the port reconnects. Bytes
+//! only leave the ring once the host has accepted the packet carrying them, so a disconnect does
+//! not lose what was in flight either. Records are sent as a plain byte stream, so they should
+//! carry their own framing.
+//!
+//! With the `telemetry` feature the IMU samples and servo statistics are streamed this way on
+//! their own ACM port, see [`crate::apps::imu_telemetry`].
+
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+
+use super::acm::AcmConnection;
+use super::usb_system::MAX_PACKET_SIZE;
+
+/// Size of the telemetry ring buffer in bytes, about 0.2s of 40-byte records at 1000Hz
+pub const CAPACITY: usize = 8192;
+
+/// Bytes waiting to be sent, shared by the [`TelemetryWriter`] and the [`TelemetryDrain`]
+struct TelemetryRing {
+    buffer: UnsafeCell<[u8; CAPACITY]>,
+    /// Total bytes ever pushed, only advanced by the writer
+    write: AtomicUsize,
+    /// Total bytes ever sent, only advanced by the drain once the host accepted them
+    read: AtomicUsize,
+    /// Records dropped since the count was last taken
+    dropped: AtomicU32,
+    /// Whether the writer and drain have been handed out
+    split: AtomicBool,
+    /// Signalled when a record has been pushed
+    pending: Signal<CriticalSectionRawMutex, ()>,
+}
+
+// SAFETY: The buffer is only written by the single `TelemetryWriter` in the free part of the ring
+// and only read by the single `TelemetryDrain` in the filled part, and each publishes its side
+// with release ordering before the other may touch those bytes
+unsafe impl Sync for TelemetryRing {}
+
+static RING: TelemetryRing = TelemetryRing {
+    buffer: UnsafeCell::new([0; CAPACITY]),
+    write: AtomicUsize::new(0),
+    read: AtomicUsize::new(0),
+    dropped: AtomicU32::new(0),
+    split: AtomicBool::new(false),
+    pending: Signal::new(),
+};
+
+/// Take the two ends of the telemetry ring
+///
+/// # Returns
+/// The writer for the producer and the drain for [`task`], or `None` if they were already taken
+pub fn split() -> Option<(TelemetryWriter, TelemetryDrain)> {
+    if RING.split.swap(true, Ordering::AcqRel) {
+        return None;
+    }
+    Some((TelemetryWriter { ring: &RING }, TelemetryDrain { ring: &RING }))
+}
+
+/// Producer end of the telemetry ring
+pub struct TelemetryWriter {
+    ring: &'static TelemetryRing,
+}
+
+impl TelemetryWriter {
+    /// Queue a record to be sent, without waiting
+    ///
+    /// # Arguments
+    /// * `data` - The record, kept together in the stream
+    ///
+    /// # Returns
+    /// `true` if the record was queued, `false` if the ring is too full and it was dropped
+    pub fn push(&mut self, data: &[u8]) -> bool {
+        let write = self.ring.write.load(Ordering::Relaxed);
+        let read = self.ring.read.load(Ordering::Acquire);
+        if CAPACITY - write.wrapping_sub(read) < data.len() {
+            self.ring.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        // The record may wrap around the end of the buffer
+        let start = write % CAPACITY;
+        let first = data.len().min(CAPACITY - start);
+        let buffer = self.ring.buffer.get().cast::<u8>();
+        // SAFETY: Both copies stay within the buffer and only cover free bytes, which the drain
+        // does not read until `write` is advanced below
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), buffer.add(start), first);
+            ptr::copy_nonoverlapping(data[first..].as_ptr(), buffer, data.len() - first);
+        }
+
+        self.ring.write.store(write.wrapping_add(data.len()), Ordering::Release);
+        self.ring.pending.signal(());
+        true
+    }
+}
+
+/// Consumer end of the telemetry ring, used by [`task`]
+pub struct TelemetryDrain {
+    ring: &'static TelemetryRing,
+}
+
+impl TelemetryDrain {
+    /// Copy queued bytes into `out`, oldest first, leaving them queued until [`Self::consume`]
+    ///
+    /// # Returns
+    /// The number of bytes copied
+    fn peek(&self, out: &mut [u8]) -> usize {
+        let read = self.ring.read.load(Ordering::Relaxed);
+        let write = self.ring.write.load(Ordering::Acquire);
+        let count = write.wrapping_sub(read).min(out.len());
+
+        let start = read % CAPACITY;
+        let first = count.min(CAPACITY - start);
+        let buffer = self.ring.buffer.get().cast::<u8>();
+        // SAFETY: Both copies stay within the buffer and only cover bytes the writer published
+        // before advancing `write`, which it does not touch again until `read` is advanced in
+        // `consume`
+        unsafe {
+            ptr::copy_nonoverlapping(buffer.add(start), out.as_mut_ptr(), first);
+            ptr::copy_nonoverlapping(buffer, out[first..].as_mut_ptr(), count - first);
+        }
+        count
+    }
+
+    /// Release the oldest `count` bytes, returned by [`Self::peek`], to the writer
+    fn consume(&mut self, count: usize) {
+        let read = self.ring.read.load(Ordering::Relaxed);
+        self.ring.read.store(read.wrapping_add(count), Ordering::Release);
+    }
+
+    /// Whether every queued byte has been sent
+    fn is_empty(&self) -> bool {
+        self.ring.read.load(Ordering::Relaxed) == self.ring.write.load(Ordering::Acquire)
+    }
+}
+
+/// Embassy task sending queued telemetry to the host.
+///
+/// Records pushed while no host is connected stay queued until the ring fills up.
+///
+/// # Parameters
+/// - `drain`: Consumer end of the ring, from [`split`]
+/// - `acm`: The ACM port the telemetry is streamed over
+#[embassy_executor::task]
+pub async fn task(mut drain: TelemetryDrain, mut acm: AcmConnection<'static>) -> ! {
+    let mut packet = [0u8; MAX_PACKET_SIZE as usize];
+
+    loop {
+        acm.wait_connection().await;
+
+        let dropped = drain.ring.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            defmt::warn!(
+                "{} telemetry records dropped while the port was busy or closed",
+                dropped
+            );
+        }
+
+        loop {
+            let len = drain.peek(&mut packet);
+            if len == 0 {
+                drain.ring.pending.wait().await;
+                continue;
+            }
+
+            // Keep the bytes queued until the host has taken them, so they are sent again after
+            // a reconnect rather than lost
+            if acm.send_packet(&packet[..len]).await.is_err() {
+                break;
+            }
+            drain.consume(len);
+
+            // A full packet leaves the host waiting for the rest of the transfer, so end it with a
+            // zero-length packet when nothing else is queued
+            if len == packet.len() && drain.is_empty() && acm.send_packet(&[]).await.is_err() {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! defmt log output over a dedicated CDC ACM port.
+//!
+//! defmt-RTT needs a debug probe, so field units built with the `log-usb` feature send their
+//! defmt frames to the host over USB instead. The logger takes the place of defmt-RTT as the
+//! defmt global logger and registers its own ACM port, so logs never interleave with the data
+//! on the application's port. Capture them on the host with e.g.
+//! `defmt-print -e target/thumbv7em-none-eabi/release/nusense-rs serial --path /dev/ttyACM1`.
+//!
+//! Encoded frames go into a RAM ring buffer that [`task`] drains to the host, so logging never
+//! waits for USB. A frame that does not fit in the buffer, e.g. while no host is connected, is
+//! dropped whole so the stream stays decodable, and the number of dropped frames is logged once
+//! the host reconnects.
+//!
+//! `log-usb` replaces the echo application's port, so it cannot be combined with `debug-acm`.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_stm32::{peripherals::USB_OTG_HS, usb::Driver};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_usb::{
+    class::cdc_acm::{CdcAcmClass, Sender, State},
+    Builder,
+};
+
+use super::usb_system::MAX_PACKET_SIZE;
+
+#[cfg(feature = "debug-acm")]
+compile_error!("Features `log-usb` and `debug-acm` both need the ACM port, enable only one of them");
+
+/// Size of the log ring buffer in bytes, must be a power of two
+pub const CAPACITY: usize = 4096;
+
+const _: () = assert!(CAPACITY.is_power_of_two(), "Log buffer capacity must be a power of two");
+
+/// Peripheral collection for UsbLogger
+pub struct UsbLoggerClaims<'d> {
+    pub acm_state: &'d mut State<'d>,
+}
+
+/// Macro to claim peripherals for UsbLogger
+#[macro_export]
+macro_rules! claim_usb_logger {
+    ($peripherals:expr) => {{
+        static LOGGER_STATE: static_cell::StaticCell<embassy_usb::class::cdc_acm::State<'static>> =
+            static_cell::StaticCell::new();
+        $crate::peripherals::usb_logger::UsbLoggerClaims {
+            acm_state: LOGGER_STATE.init(embassy_usb::class::cdc_acm::State::new()),
+        }
+    }};
+}
+
+/// Encoded defmt frames waiting to be sent
+struct LogRing {
+    buffer: [u8; CAPACITY],
+    /// Total bytes ever written, the next byte goes to `write % CAPACITY`
+    write: usize,
+    /// Total bytes ever sent
+    read: usize,
+    /// Value of `write` when the frame being logged started
+    frame_start: usize,
+    /// The frame being logged did not fit and is dropped when it ends
+    frame_overflow: bool,
+    /// Frames dropped since the count was last taken
+    dropped: u32,
+}
+
+impl LogRing {
+    fn push(&mut self, byte: u8) {
+        if self.len() == CAPACITY {
+            self.frame_overflow = true;
+            return;
+        }
+        self.buffer[self.write % CAPACITY] = byte;
+        self.write = self.write.wrapping_add(1);
+    }
+
+    /// Number of bytes waiting to be sent
+    fn len(&self) -> usize {
+        self.write.wrapping_sub(self.read)
+    }
+
+    /// Copy up to `out.len()` buffered bytes into `out`, oldest first
+    fn pop(&mut self, out: &mut [u8]) -> usize {
+        let count = self.len().min(out.len());
+        for byte in &mut out[..count] {
+            *byte = self.buffer[self.read % CAPACITY];
+            self.read = self.read.wrapping_add(1);
+        }
+        count
+    }
+}
+
+/// Encoder and buffer shared by the global logger and [`task`]
+struct LogState {
+    encoder: defmt::Encoder,
+    ring: LogRing,
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<LogState>> = Mutex::new(RefCell::new(LogState {
+    encoder: defmt::Encoder::new(),
+    ring: LogRing {
+        buffer: [0u8; CAPACITY],
+        write: 0,
+        read: 0,
+        frame_start: 0,
+        frame_overflow: false,
+        dropped: 0,
+    },
+}));
+
+/// Signalled when a frame has been buffered
+static LOG_PENDING: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Whether a frame is being logged
+static TAKEN: AtomicBool = AtomicBool::new(false);
+/// Whether interrupts were enabled before the current frame disabled them
+static INTERRUPTS_WERE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[defmt::global_logger]
+struct Logger;
+
+// SAFETY: Interrupts are disabled from `acquire` to `release`, so frames from different
+// priorities cannot interleave, and a nested `acquire` panics
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let primask = cortex_m::register::primask::read();
+        cortex_m::interrupt::disable();
+
+        if TAKEN.load(Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+        TAKEN.store(true, Ordering::Relaxed);
+        INTERRUPTS_WERE_ENABLED.store(primask.is_active(), Ordering::Relaxed);
+
+        STATE.lock(|state| {
+            let state = &mut *state.borrow_mut();
+            state.ring.frame_start = state.ring.write;
+            state.ring.frame_overflow = false;
+            state
+                .encoder
+                .start_frame(|bytes| bytes.iter().for_each(|&b| state.ring.push(b)));
+        });
+    }
+
+    unsafe fn flush() {
+        // Frames are sent by the logger task, which cannot run while interrupts are disabled
+    }
+
+    unsafe fn release() {
+        STATE.lock(|state| {
+            let state = &mut *state.borrow_mut();
+            state
+                .encoder
+                .end_frame(|bytes| bytes.iter().for_each(|&b| state.ring.push(b)));
+
+            // Roll back a partial frame so the host never sees a truncated one
+            if state.ring.frame_overflow {
+                state.ring.write = state.ring.frame_start;
+                state.ring.dropped = state.ring.dropped.saturating_add(1);
+            }
+        });
+        LOG_PENDING.signal(());
+
+        TAKEN.store(false, Ordering::Relaxed);
+        if INTERRUPTS_WERE_ENABLED.load(Ordering::Relaxed) {
+            // SAFETY: Interrupts were enabled when `acquire` disabled them
+            unsafe { cortex_m::interrupt::enable() };
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        STATE.lock(|state| {
+            let state = &mut *state.borrow_mut();
+            state
+                .encoder
+                .write(bytes, |bytes| bytes.iter().for_each(|&b| state.ring.push(b)));
+        });
+    }
+}
+
+/// Dedicated ACM port carrying the defmt log stream
+pub struct UsbLogger<'d> {
+    sender: Sender<'d, Driver<'d, USB_OTG_HS>>,
+}
+
+impl<'d> UsbLogger<'d> {
+    /// Register the log port with the USB device.
+    ///
+    /// The port is counted in [`ACM_COUNT`](super::usb_system::ACM_COUNT). Register it after the
+    /// application's port so that one keeps the lower port number.
+    ///
+    /// # Arguments
+    ///
+    /// * `builder` - USB device builder
+    /// * `claims` - UsbLoggerClaims struct containing the ACM state
+    pub fn new(builder: &mut Builder<'d, Driver<'d, USB_OTG_HS>>, claims: UsbLoggerClaims<'d>) -> Self {
+        // Nothing is read from the host, the OUT endpoint is only there because the class needs it
+        let (sender, _receiver) = CdcAcmClass::new(builder, claims.acm_state, MAX_PACKET_SIZE).split();
+        Self { sender }
+    }
+}
+
+/// Move buffered bytes into `out`, returning how many were copied
+fn take_buffered(out: &mut [u8]) -> usize {
+    STATE.lock(|state| state.borrow_mut().ring.pop(out))
+}
+
+/// Whether every buffered byte has been sent
+fn is_idle() -> bool {
+    STATE.lock(|state| state.borrow().ring.len() == 0)
+}
+
+/// Number of frames dropped since the last call
+fn take_dropped() -> u32 {
+    STATE.lock(|state| core::mem::take(&mut state.borrow_mut().ring.dropped))
+}
+
+/// Embassy task sending buffered log frames to the host.
+///
+/// Frames logged while no host is connected stay in the buffer until it fills up, so the boot
+/// log is usually still there when the host opens the port.
+#[embassy_executor::task]
+pub async fn task(mut logger: UsbLogger<'static>) -> ! {
+    let mut packet = [0u8; MAX_PACKET_SIZE as usize];
+
+    loop {
+        logger.sender.wait_connection().await;
+
+        let dropped = take_dropped();
+        if dropped > 0 {
+            defmt::warn!("{} log frames dropped while the log port was busy or closed", dropped);
+        }
+
+        loop {
+            let len = take_buffered(&mut packet);
+            if len == 0 {
+                LOG_PENDING.wait().await;
+                continue;
+            }
+
+            if logger.sender.write_packet(&packet[..len]).await.is_err() {
+                break;
+            }
+
+            // A full packet leaves the host waiting for the rest of the transfer, so end it with a
+            // zero-length packet when nothing else is queued
+            if len == packet.len() && is_idle() && logger.sender.write_packet(&[]).await.is_err() {
+                break;
+            }
+        }
+    }
+}
@@ -1,32 +1,100 @@
 //! USB system abstraction for STM32H753 with ULPI PHY.
 //!
 //! Provides USB device initialization and management for the NUSense platform.
+//!
+//! # Bus speed
+//!
+//! The OTG_HS peripheral is used in one of two configurations, selected with exactly one of the
+//! `usb-hs` and `usb-fs` Cargo features:
+//! - `usb-hs` (default): high speed through the external ULPI PHY, 512-byte bulk packets
+//! - `usb-fs`: full speed through the embedded FS PHY on PB14/PB15, 64-byte bulk packets, for
+//!   boards without the ULPI PHY
+//!
+//! [`MAX_PACKET_SIZE`] follows the selected speed, and the endpoint buffers and class packet
+//! sizes are derived from it.
+//!
+//! # Device composition
+//!
+//! Which USB classes make up the composite device is selected with Cargo features, so a board
+//! can build a minimal device without editing `main`. Each class contributes to the endpoint and
+//! interface totals below, which are checked against the OTG_HS and embassy-usb limits at
+//! compile time. A new class must add its usage to those totals.
+//!
+//! # String descriptors
+//!
+//! Besides the manufacturer, product and serial strings, [`UsbDescriptorConfig::interface_names`]
+//! adds strings at consecutive string indexes, allocated right after the builder's own, that
+//! classes building their own interfaces can name them with. The CDC ACM class names its
+//! interfaces itself. The strings are written in [`UsbDescriptorConfig::language_id`].
+//! embassy-usb answers string descriptor 0, the list of supported languages, with US English
+//! only and has no configuration string, so hosts keep asking for [`LANGID_EN_US`] and the extra
+//! strings are served for that language as well as the configured one.
+//!
+//! # Compliance testing
+//!
+//! With the `usb-compliance` feature the high-speed electrical test modes of USB 2.0 (section
+//! 7.1.20) can be entered to qualify the ULPI PHY signal integrity of a new board layout:
+//!
+//! 1. Build and flash with `--features usb-compliance` (this also enables the debug shell and
+//!    requires `usb-hs`)
+//! 2. Connect the board to the host and open the shell on the ACM port
+//! 3. Attach the test fixture and oscilloscope to the D+/D- lines
+//! 4. Run `usb test <j|k|se0|packet>` in the shell; the device drops off the bus and drives the
+//!    selected pattern
+//! 5. Power cycle the board to leave the test mode
 
+use core::cell::Cell;
 use defmt::info;
+#[cfg(feature = "usb-hs")]
+use embassy_stm32::peripherals::{PA3, PA5, PB0, PB1, PB10, PB11, PB12, PB13, PB5, PC0, PC2, PC3};
+#[cfg(feature = "usb-fs")]
+use embassy_stm32::peripherals::{PB14, PB15};
 use embassy_stm32::{
     bind_interrupts, peripherals as stm32_peripherals,
-    peripherals::{PA3, PA5, PB0, PB1, PB10, PB11, PB12, PB13, PB5, PC0, PC2, PC3, USB_OTG_HS},
+    peripherals::USB_OTG_HS,
     usb::{self, Driver, InterruptHandler},
     Peri,
 };
-use embassy_usb::{Builder, UsbDevice};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_usb::{types::StringIndex, Builder, Handler, UsbDevice};
 use static_cell::ConstStaticCell;
 
+#[cfg(all(feature = "usb-hs", feature = "usb-fs"))]
+compile_error!("Features `usb-hs` and `usb-fs` are mutually exclusive (`usb-compliance` implies `usb-hs`)");
+#[cfg(not(any(feature = "usb-hs", feature = "usb-fs")))]
+compile_error!("Select the USB bus speed with either the `usb-hs` or the `usb-fs` feature");
+
 /// Peripheral collection for USB system interface
 pub struct UsbClaims<'d> {
     pub usb_otg_hs: Peri<'d, USB_OTG_HS>,
+    #[cfg(feature = "usb-hs")]
     pub ulpi_clk: Peri<'d, PA5>, // USB_OTG_HS_ULPI_CK
+    #[cfg(feature = "usb-hs")]
     pub ulpi_dir: Peri<'d, PC2>, // USB_OTG_HS_ULPI_DIR
+    #[cfg(feature = "usb-hs")]
     pub ulpi_nxt: Peri<'d, PC3>, // USB_OTG_HS_ULPI_NXT
+    #[cfg(feature = "usb-hs")]
     pub ulpi_stp: Peri<'d, PC0>, // USB_OTG_HS_ULPI_STP
-    pub ulpi_d0: Peri<'d, PA3>,  // USB_OTG_HS_ULPI_D0
-    pub ulpi_d1: Peri<'d, PB0>,  // USB_OTG_HS_ULPI_D1
-    pub ulpi_d2: Peri<'d, PB1>,  // USB_OTG_HS_ULPI_D2
+    #[cfg(feature = "usb-hs")]
+    pub ulpi_d0: Peri<'d, PA3>, // USB_OTG_HS_ULPI_D0
+    #[cfg(feature = "usb-hs")]
+    pub ulpi_d1: Peri<'d, PB0>, // USB_OTG_HS_ULPI_D1
+    #[cfg(feature = "usb-hs")]
+    pub ulpi_d2: Peri<'d, PB1>, // USB_OTG_HS_ULPI_D2
+    #[cfg(feature = "usb-hs")]
     pub ulpi_d3: Peri<'d, PB10>, // USB_OTG_HS_ULPI_D3
+    #[cfg(feature = "usb-hs")]
     pub ulpi_d4: Peri<'d, PB11>, // USB_OTG_HS_ULPI_D4
+    #[cfg(feature = "usb-hs")]
     pub ulpi_d5: Peri<'d, PB12>, // USB_OTG_HS_ULPI_D5
+    #[cfg(feature = "usb-hs")]
     pub ulpi_d6: Peri<'d, PB13>, // USB_OTG_HS_ULPI_D6
-    pub ulpi_d7: Peri<'d, PB5>,  // USB_OTG_HS_ULPI_D7
+    #[cfg(feature = "usb-hs")]
+    pub ulpi_d7: Peri<'d, PB5>, // USB_OTG_HS_ULPI_D7
+    #[cfg(feature = "usb-fs")]
+    pub fs_dp: Peri<'d, PB15>, // USB_OTG_HS_DP
+    #[cfg(feature = "usb-fs")]
+    pub fs_dm: Peri<'d, PB14>, // USB_OTG_HS_DM
     pub usb_buffers: &'d mut UsbBuffers,
 }
 
@@ -34,29 +102,158 @@ pub struct UsbClaims<'d> {
 #[macro_export]
 macro_rules! claim_usb {
     ($peripherals:expr) => {{
+        $crate::peripherals::claims::register($crate::peripherals::claims::Resource::UsbOtgHs);
         $crate::peripherals::usb_system::UsbClaims {
             usb_otg_hs: $peripherals.USB_OTG_HS,
+            #[cfg(feature = "usb-hs")]
             ulpi_clk: $peripherals.PA5, // USB_OTG_HS_ULPI_CK
+            #[cfg(feature = "usb-hs")]
             ulpi_dir: $peripherals.PC2, // USB_OTG_HS_ULPI_DIR
+            #[cfg(feature = "usb-hs")]
             ulpi_nxt: $peripherals.PC3, // USB_OTG_HS_ULPI_NXT
+            #[cfg(feature = "usb-hs")]
             ulpi_stp: $peripherals.PC0, // USB_OTG_HS_ULPI_STP
-            ulpi_d0: $peripherals.PA3,  // USB_OTG_HS_ULPI_D0
-            ulpi_d1: $peripherals.PB0,  // USB_OTG_HS_ULPI_D1
-            ulpi_d2: $peripherals.PB1,  // USB_OTG_HS_ULPI_D2
+            #[cfg(feature = "usb-hs")]
+            ulpi_d0: $peripherals.PA3, // USB_OTG_HS_ULPI_D0
+            #[cfg(feature = "usb-hs")]
+            ulpi_d1: $peripherals.PB0, // USB_OTG_HS_ULPI_D1
+            #[cfg(feature = "usb-hs")]
+            ulpi_d2: $peripherals.PB1, // USB_OTG_HS_ULPI_D2
+            #[cfg(feature = "usb-hs")]
             ulpi_d3: $peripherals.PB10, // USB_OTG_HS_ULPI_D3
+            #[cfg(feature = "usb-hs")]
             ulpi_d4: $peripherals.PB11, // USB_OTG_HS_ULPI_D4
+            #[cfg(feature = "usb-hs")]
             ulpi_d5: $peripherals.PB12, // USB_OTG_HS_ULPI_D5
+            #[cfg(feature = "usb-hs")]
             ulpi_d6: $peripherals.PB13, // USB_OTG_HS_ULPI_D6
-            ulpi_d7: $peripherals.PB5,  // USB_OTG_HS_ULPI_D7
+            #[cfg(feature = "usb-hs")]
+            ulpi_d7: $peripherals.PB5, // USB_OTG_HS_ULPI_D7
+            #[cfg(feature = "usb-fs")]
+            fs_dp: $peripherals.PB15, // USB_OTG_HS_DP
+            #[cfg(feature = "usb-fs")]
+            fs_dm: $peripherals.PB14, // USB_OTG_HS_DM
             usb_buffers: $crate::peripherals::usb_system::USB_BUFFERS.take(),
         }
     }};
 }
 
+/// Default USB vendor ID reported by the device
+pub const USB_VID: u16 = 0xc0de;
+/// Default USB product ID reported by the device
+pub const USB_PID: u16 = 0xcafe;
+
+/// USB language ID of US English, the only language embassy-usb advertises
+pub const LANGID_EN_US: u16 = 0x0409;
+
+/// Identity and power the device reports in its USB descriptors
+///
+/// Board variants can report their own product ID and strings, and udev rules can match any of
+/// them. The strings must outlive the USB device, so a serial number built at runtime, such as
+/// [`device_serial`](super::system::device_serial), has to be `'static`.
+///
+/// A self-powered board senses VBUS to notice the host coming and going, while a bus-powered
+/// board is only running while VBUS is present and may not have VBUS wired to the sense pin, so
+/// [`UsbSystem::new`] only enables VBUS detection for self-powered boards.
+#[derive(Debug, Clone, Copy)]
+pub struct UsbDescriptorConfig {
+    /// Vendor ID
+    pub vid: u16,
+    /// Product ID
+    pub pid: u16,
+    /// Manufacturer string
+    pub manufacturer: &'static str,
+    /// Product string
+    pub product: &'static str,
+    /// Serial number string
+    pub serial: &'static str,
+    /// USB language ID the strings are written in, see the module documentation
+    pub language_id: u16,
+    /// Extra strings for naming interfaces, see the module documentation
+    pub interface_names: &'static [&'static str],
+    /// Whether the board has its own power supply, reported in the configuration descriptor
+    pub self_powered: bool,
+    /// Most current drawn from VBUS in mA, at most 500
+    pub max_power_ma: u16,
+}
+
+impl UsbDescriptorConfig {
+    /// Descriptors the firmware has always reported
+    pub const DEFAULT: Self = Self {
+        vid: USB_VID,
+        pid: USB_PID,
+        manufacturer: "NUbots",
+        product: "NUSense",
+        serial: "12345678",
+        language_id: LANGID_EN_US,
+        interface_names: &[],
+        self_powered: true,
+        max_power_ma: 100,
+    };
+}
+
+impl Default for UsbDescriptorConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Descriptors the running device was built with, see [`descriptor_config`]
+static DESCRIPTOR_CONFIG: Mutex<CriticalSectionRawMutex, Cell<UsbDescriptorConfig>> =
+    Mutex::new(Cell::new(UsbDescriptorConfig::DEFAULT));
+
+/// Descriptors the device reports to the host
+pub fn descriptor_config() -> UsbDescriptorConfig {
+    DESCRIPTOR_CONFIG.lock(Cell::get)
+}
+
+/// Bidirectional endpoints provided by the OTG_HS peripheral, excluding control endpoint 0
+const OTG_HS_ENDPOINTS: usize = 8;
+
+/// Interfaces embassy-usb can register (default `max-interface-count-4` feature)
+const MAX_INTERFACES: usize = 4;
+
+/// Number of CDC ACM ports on the device, only added when an application uses the port
+///
+/// Must match the number of ports claimed with `claim_acm!`, see
+/// [`AcmConnection::new_multiple`](super::acm::AcmConnection::new_multiple), including the
+/// telemetry port of the `telemetry` feature and the sniffer port of the `bus-sniffer` feature,
+/// plus the log port of the `log-usb` feature.
+pub const ACM_COUNT: usize = cfg!(any(feature = "debug-acm", feature = "debug-shell")) as usize
+    + cfg!(feature = "telemetry") as usize
+    + cfg!(feature = "bus-sniffer") as usize
+    + cfg!(feature = "log-usb") as usize;
+
+/// IN endpoints used by all classes (CDC ACM: notification + bulk data)
+const IN_ENDPOINTS: usize = ACM_COUNT * 2;
+/// OUT endpoints used by all classes (CDC ACM: bulk data)
+const OUT_ENDPOINTS: usize = ACM_COUNT;
+/// Interfaces used by all classes (CDC ACM: communication + data)
+const INTERFACES: usize = ACM_COUNT * 2;
+
+const _: () = assert!(
+    IN_ENDPOINTS <= OTG_HS_ENDPOINTS,
+    "Selected USB classes need more IN endpoints than OTG_HS provides"
+);
+const _: () = assert!(
+    OUT_ENDPOINTS <= OTG_HS_ENDPOINTS,
+    "Selected USB classes need more OUT endpoints than OTG_HS provides"
+);
+const _: () = assert!(
+    INTERFACES <= MAX_INTERFACES,
+    "Selected USB classes need more interfaces than embassy-usb is configured for"
+);
+
 /// Maximum USB packet size for high-speed USB (ULPI PHY).
 /// This influences buffer sizing throughout the USB system.
+#[cfg(feature = "usb-hs")]
 pub const MAX_PACKET_SIZE: u16 = 512;
 
+/// Maximum USB packet size for full-speed USB (embedded FS PHY).
+/// This influences buffer sizing throughout the USB system.
+#[cfg(feature = "usb-fs")]
+pub const MAX_PACKET_SIZE: u16 = 64;
+
 // Bind USB interrupts for the OTG_HS peripheral
 bind_interrupts!(
     /// USB interrupt handlers
@@ -65,6 +262,62 @@ bind_interrupts!(
     }
 );
 
+/// USB 2.0 high-speed electrical test modes (TCTL field of OTG_HS DCTL)
+#[cfg(feature = "usb-compliance")]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum UsbTestMode {
+    /// Drive a continuous high-speed J state
+    TestJ = 1,
+    /// Drive a continuous high-speed K state
+    TestK = 2,
+    /// Respond to every IN token with NAK while receiving with SE0 idle
+    TestSe0Nak = 3,
+    /// Repeatedly transmit the standard test packet for eye diagram measurements
+    TestPacket = 4,
+}
+
+/// Put the OTG_HS port into a high-speed electrical test mode.
+///
+/// Normal USB operation stops once the test mode is entered and only a reset or power cycle
+/// returns the port to normal operation. See the module documentation for the test workflow.
+#[cfg(feature = "usb-compliance")]
+pub fn enter_test_mode(mode: UsbTestMode) {
+    info!("Entering USB test mode {:?}", mode);
+    embassy_stm32::pac::USB_OTG_HS.dctl().modify(|w| w.set_tctl(mode as u8));
+}
+
+/// Answers the string descriptor requests for [`UsbDescriptorConfig::interface_names`]
+pub struct ExtraStrings {
+    /// Language the strings are written in
+    language_id: u16,
+    /// String index of the first name, the rest follow on
+    first: u8,
+    /// The strings, in string index order
+    names: &'static [&'static str],
+}
+
+impl ExtraStrings {
+    /// No extra strings, until [`UsbSystem::new`] registers them
+    const fn new() -> Self {
+        Self {
+            language_id: LANGID_EN_US,
+            first: 0,
+            names: &[],
+        }
+    }
+}
+
+impl Handler for ExtraStrings {
+    fn get_string(&mut self, index: StringIndex, lang_id: u16) -> Option<&str> {
+        if lang_id != self.language_id && lang_id != LANGID_EN_US {
+            return None;
+        }
+        let offset = u8::from(index).checked_sub(self.first)?;
+        self.names.get(usize::from(offset)).copied()
+    }
+}
+
 /// USB buffers for device operation.
 #[repr(C, align(32))]
 pub struct UsbBuffers {
@@ -76,6 +329,8 @@ pub struct UsbBuffers {
     pub bos_descriptor: [u8; 256],
     /// USB control transfer buffer
     pub control_buf: [u8; 64],
+    /// Extra string descriptors, registered with the builder as a handler
+    pub extra_strings: ExtraStrings,
 }
 pub static USB_BUFFERS: ConstStaticCell<UsbBuffers> = ConstStaticCell::new(UsbBuffers::new());
 
@@ -87,6 +342,7 @@ impl UsbBuffers {
             config_descriptor: [0u8; 256],
             bos_descriptor: [0u8; 256],
             control_buf: [0u8; 64],
+            extra_strings: ExtraStrings::new(),
         }
     }
 }
@@ -112,19 +368,38 @@ impl<'d> UsbSystem<'d> {
     ///
     /// # Arguments
     /// * `claims` - UsbClaims struct containing all required peripherals and buffers
-    pub fn new(claims: UsbClaims<'d>) -> Self {
-        info!("Initializing USB system...");
+    /// * `descriptors` - Vendor/product IDs, strings and power the device reports
+    pub fn new(claims: UsbClaims<'d>, descriptors: UsbDescriptorConfig) -> Self {
+        info!(
+            "Initializing USB system as {:04x}:{:04x}, serial {}, {} powered, {} mA...",
+            descriptors.vid,
+            descriptors.pid,
+            descriptors.serial,
+            if descriptors.self_powered { "self" } else { "bus" },
+            descriptors.max_power_ma
+        );
+        DESCRIPTOR_CONFIG.lock(|config| config.set(descriptors));
 
         // Configure USB device descriptor
-        let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
-        config.manufacturer = Some("NUbots");
-        config.product = Some("NUSense");
-        config.serial_number = Some("12345678");
+        let mut config = embassy_usb::Config::new(descriptors.vid, descriptors.pid);
+        config.manufacturer = Some(descriptors.manufacturer);
+        config.product = Some(descriptors.product);
+        config.serial_number = Some(descriptors.serial);
+        config.self_powered = descriptors.self_powered;
+        config.max_power = descriptors.max_power_ma;
+
+        // Group each class's interfaces with an Interface Association Descriptor, without which
+        // Windows binds only the first interface of a multi-interface class such as CDC ACM
+        config.device_class = 0xEF; // Miscellaneous
+        config.device_sub_class = 0x02; // Common class
+        config.device_protocol = 0x01; // Interface Association Descriptor
+        config.composite_with_iads = true;
 
-        // Create USB driver with ULPI PHY
         let mut usb_config = usb::Config::default();
-        usb_config.vbus_detection = true;
+        usb_config.vbus_detection = descriptors.self_powered;
 
+        // Create USB driver with ULPI PHY
+        #[cfg(feature = "usb-hs")]
         let driver = Driver::new_hs_ulpi(
             claims.usb_otg_hs,
             UsbInterrupts,
@@ -144,8 +419,19 @@ impl<'d> UsbSystem<'d> {
             usb_config,
         );
 
+        // Create USB driver with the embedded full-speed PHY
+        #[cfg(feature = "usb-fs")]
+        let driver = Driver::new_fs(
+            claims.usb_otg_hs,
+            UsbInterrupts,
+            claims.fs_dp,
+            claims.fs_dm,
+            &mut claims.usb_buffers.ep_out_buffer,
+            usb_config,
+        );
+
         // Create the USB builder with all required buffers
-        let builder = Builder::new(
+        let mut builder = Builder::new(
             driver,
             config,
             &mut claims.usb_buffers.config_descriptor,
@@ -154,6 +440,24 @@ impl<'d> UsbSystem<'d> {
             &mut claims.usb_buffers.control_buf,
         );
 
+        // Allocate consecutive string indexes for the interface names, answered by the handler
+        if descriptors.language_id != LANGID_EN_US {
+            defmt::warn!(
+                "USB strings are in language {:04x}, but embassy-usb only advertises US English",
+                descriptors.language_id
+            );
+        }
+        let names = descriptors.interface_names;
+        let first_interface_name = (!names.is_empty()).then(|| builder.string());
+        for _ in 1..names.len() {
+            builder.string();
+        }
+        let extra_strings = &mut claims.usb_buffers.extra_strings;
+        extra_strings.language_id = descriptors.language_id;
+        extra_strings.first = first_interface_name.map_or(0, u8::from);
+        extra_strings.names = names;
+        builder.handler(extra_strings);
+
         info!("USB system initialized successfully");
 
         Self {
@@ -187,9 +491,10 @@ impl<'d> UsbSystem<'d> {
             }
         }
 
-        // Run the USB device task
+        // Run the USB device task. It is not monitored by crate::liveness, as the device loop only
+        // wakes for bus events, so an idle device cannot be told apart from a stuck one.
         let device = self.usb_device.as_mut().expect("Failed to build USB device");
-        device.run().await;
+        device.run().await
     }
 }
 
@@ -0,0 +1,103 @@
+//! Independent watchdog (IWDG) for recovering from a stalled executor.
+//!
+//! The IWDG runs from the LSI oscillator, independent of the system clocks, and resets the
+//! microcontroller unless it is refreshed within its timeout. Once started it cannot be stopped
+//! or slowed down again until the next reset.
+//!
+//! The main heartbeat loop pets the watchdog every iteration. All tasks share one executor, so a
+//! task that blocks stalls the heartbeat too and the board resets. Tasks that can hang without
+//! blocking the executor (e.g. waiting forever on a peripheral) are made critical: their bit is
+//! set with [`report_alive`] at least once per heartbeat, and [`Watchdog::pet`] only refreshes
+//! the watchdog while every required bit has been set since the last pet. The heartbeat loop sets
+//! the bit of every task [`crate::liveness`] considers alive, so a task that stops checking in
+//! resets the board.
+//!
+//! Known long operations, e.g. a gyroscope calibration, are declared with
+//! [`crate::liveness::long_operation`] rather than by petting the watchdog from inside them. The
+//! heartbeat keeps petting while the operation stays within the budget it declared, and stops once
+//! it overruns.
+//!
+//! A watchdog reset sets the IWDG1RSTF flag in RCC_RSR, so
+//! [`read_reset_cause`](super::system::read_reset_cause) reports it at the next boot.
+//!
+//! The IWDG keeps counting while the core is halted by a debugger, so a breakpoint held longer
+//! than the timeout resets the board.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use defmt::warn;
+use embassy_stm32::{peripherals::IWDG1, wdg::IndependentWatchdog, Peri};
+use embassy_time::Duration;
+
+/// Liveness bits set by critical tasks since the last pet, see [`report_alive`]
+static ALIVE: AtomicU32 = AtomicU32::new(0);
+
+/// Peripheral collection for the watchdog
+pub struct WatchdogClaims<'d> {
+    pub iwdg: Peri<'d, IWDG1>,
+}
+
+/// Macro to claim peripherals for Watchdog
+#[macro_export]
+macro_rules! claim_watchdog {
+    ($peripherals:expr) => {{
+        $crate::peripherals::claims::register($crate::peripherals::claims::Resource::Iwdg1);
+        $crate::peripherals::watchdog::WatchdogClaims {
+            iwdg: $peripherals.IWDG1,
+        }
+    }};
+}
+
+/// Record that a critical task is still making progress
+///
+/// # Arguments
+/// * `flag` - The task's liveness bit, as passed to [`Watchdog::require`]
+pub fn report_alive(flag: u32) {
+    ALIVE.fetch_or(flag, Ordering::Relaxed);
+}
+
+/// Running independent watchdog
+pub struct Watchdog<'d> {
+    iwdg: IndependentWatchdog<'d, IWDG1>,
+    /// Liveness bits that must all be reported before each pet
+    required: u32,
+}
+
+impl<'d> Watchdog<'d> {
+    /// Start the independent watchdog
+    ///
+    /// # Arguments
+    /// * `claims` - WatchdogClaims struct containing the IWDG peripheral
+    /// * `timeout` - Time without a pet before the board resets (at most ~32 s)
+    ///
+    /// # Returns
+    /// The running watchdog, which must be pet from now on
+    pub fn new(claims: WatchdogClaims<'d>, timeout: Duration) -> Self {
+        let mut iwdg = IndependentWatchdog::new(claims.iwdg, timeout.as_micros() as u32);
+        iwdg.unleash();
+        Self { iwdg, required: 0 }
+    }
+
+    /// Make the watchdog wait for a critical task before each refresh
+    ///
+    /// # Arguments
+    /// * `flag` - Liveness bit the task sets with [`report_alive`]
+    pub fn require(&mut self, flag: u32) {
+        self.required |= flag;
+    }
+
+    /// Refresh the watchdog if every required task has reported since the last pet
+    ///
+    /// # Returns
+    /// `true` if the watchdog was refreshed, `false` if a required task has gone quiet
+    pub fn pet(&mut self) -> bool {
+        let alive = ALIVE.swap(0, Ordering::Relaxed);
+        let missing = self.required & !alive;
+        if missing != 0 {
+            warn!("Watchdog not refreshed, tasks {:#010b} have not reported", missing);
+            return false;
+        }
+
+        self.iwdg.pet();
+        true
+    }
+}
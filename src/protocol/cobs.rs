@@ -0,0 +1,134 @@
+//! Consistent Overhead Byte Stuffing, which removes every zero byte from a message so a zero byte
+//! can mark where it ends
+//!
+//! The message is split at its zero bytes, and each part is sent as blocks of up to
+//! [`BLOCK_SIZE`] bytes, each preceded by a code byte of its length plus one. A block shorter than
+//! [`BLOCK_SIZE`] stands for the zero byte after it, except for the last block of the message.
+//! This module only uses `core`, so its tests run on the host without the rest of the firmware:
+//!
+//! ```text
+//! rustc --edition 2021 --test src/protocol/cobs.rs -o target/cobs && target/cobs
+//! ```
+
+/// Largest run of non-zero bytes a single code byte can describe
+pub const BLOCK_SIZE: usize = 254;
+
+/// The encoded blocks of a message, each as its code byte and its bytes
+///
+/// The encoded message is every code byte followed by its block, in order. The zero delimiter is
+/// not included.
+///
+/// # Arguments
+/// * `msg` - Message to encode, which may contain zero bytes
+pub fn blocks(msg: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    // Each zero-separated segment becomes full blocks of 254 bytes (code 0xFF, no implied zero)
+    // followed by a final shorter block whose code implies the zero that ended it
+    msg.split(|&byte| byte == 0).flat_map(|segment| {
+        let full = segment.chunks_exact(BLOCK_SIZE);
+        let remainder = full.remainder();
+        full.map(|block| (BLOCK_SIZE as u8 + 1, block))
+            .chain(core::iter::once((remainder.len() as u8 + 1, remainder)))
+    })
+}
+
+/// Decode a COBS encoded frame without its delimiter
+///
+/// # Arguments
+/// * `frame` - Encoded bytes up to the delimiter
+/// * `out` - Buffer to store the decoded message
+///
+/// # Returns
+/// The decoded length, or `None` if the frame is malformed or does not fit in `out`
+pub fn decode(frame: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut read = 0;
+    let mut written = 0;
+
+    while read < frame.len() {
+        let code = frame[read] as usize;
+        read += 1;
+
+        // A zero code byte cannot occur in a frame, and a block cannot run past its end
+        let block = frame.get(read..read + code.checked_sub(1)?)?;
+        out.get_mut(written..written + block.len())?.copy_from_slice(block);
+        read += block.len();
+        written += block.len();
+
+        // Every block except the last and full 254-byte blocks stands for a zero byte after it
+        if code <= BLOCK_SIZE && read < frame.len() {
+            *out.get_mut(written)? = 0;
+            written += 1;
+        }
+    }
+
+    Some(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a message into `out`, returning the encoded length
+    fn encode(msg: &[u8], out: &mut [u8]) -> usize {
+        let mut len = 0;
+        for (code, block) in blocks(msg) {
+            out[len] = code;
+            out[len + 1..len + 1 + block.len()].copy_from_slice(block);
+            len += 1 + block.len();
+        }
+        len
+    }
+
+    /// Encode then decode a message and check it comes back unchanged without any zero in between
+    fn round_trip(msg: &[u8]) {
+        let mut encoded = [0u8; 1100];
+        let len = encode(msg, &mut encoded);
+        assert!(!encoded[..len].contains(&0), "encoded frame holds a zero byte");
+
+        let mut decoded = [0u8; 1100];
+        assert_eq!(decode(&encoded[..len], &mut decoded), Some(msg.len()));
+        assert_eq!(&decoded[..msg.len()], msg);
+    }
+
+    #[test]
+    fn encodes_known_vectors() {
+        let mut out = [0u8; 16];
+        let len = encode(&[], &mut out);
+        assert_eq!(&out[..len], &[0x01]);
+        let len = encode(&[0x00], &mut out);
+        assert_eq!(&out[..len], &[0x01, 0x01]);
+        let len = encode(&[0x11, 0x22, 0x00, 0x33], &mut out);
+        assert_eq!(&out[..len], &[0x03, 0x11, 0x22, 0x02, 0x33]);
+        let len = encode(&[0x11, 0x00, 0x00], &mut out);
+        assert_eq!(&out[..len], &[0x02, 0x11, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn round_trips_zeros_and_long_runs() {
+        round_trip(&[]);
+        round_trip(&[0; 5]);
+        round_trip(&[1, 2, 0, 3, 0]);
+
+        let mut long = [0u8; 1020];
+        for (i, byte) in long.iter_mut().enumerate() {
+            *byte = (i % 255) as u8 + 1;
+        }
+        // Runs of exactly, just under and just over a full block
+        round_trip(&long[..BLOCK_SIZE]);
+        round_trip(&long[..BLOCK_SIZE - 1]);
+        round_trip(&long[..BLOCK_SIZE + 1]);
+        round_trip(&long);
+        long[BLOCK_SIZE] = 0;
+        round_trip(&long);
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let mut out = [0u8; 16];
+        // Zero code byte
+        assert_eq!(decode(&[0x00], &mut out), None);
+        // Block running past the end of the frame
+        assert_eq!(decode(&[0x05, 0x11, 0x22], &mut out), None);
+        // Message longer than the output buffer
+        assert_eq!(decode(&[0x04, 0x11, 0x22, 0x33], &mut out[..2]), None);
+    }
+}
@@ -0,0 +1,419 @@
+//! Dynamixel 2.0 packet building and parsing.
+//!
+//! Every packet has the layout
+//!
+//! ```text
+//! | 0xFF 0xFF 0xFD 0x00 | ID | LEN_L LEN_H | INSTRUCTION | PARAMS... | CRC_L CRC_H |
+//! ```
+//!
+//! where the length counts the instruction, the (stuffed) parameters and the CRC, and the CRC
+//! covers everything from the header up to the last parameter. To keep the header unique, any
+//! `0xFF 0xFF 0xFD` sequence in the instruction and parameters is followed by an extra `0xFD`
+//! (byte stuffing), which the receiver removes again.
+
+use crate::peripherals::crc::CrcProcessor;
+
+/// Header that starts every packet
+pub const HEADER: [u8; 4] = [0xFF, 0xFF, 0xFD, 0x00];
+
+/// ID addressing every servo on the bus
+pub const BROADCAST_ID: u8 = 0xFE;
+
+/// Highest ID a servo can be given, the IDs above it are reserved
+pub const MAX_ID: u8 = 0xFC;
+
+/// Size of the status packet a servo replies to a ping with, holding its model number and
+/// firmware version
+pub const PING_STATUS_SIZE: usize = 14;
+
+/// Most parameters a [`StatusPacket`] can hold after de-stuffing
+pub const MAX_STATUS_PARAMS: usize = 128;
+
+/// Most parameter bytes a [`SyncWrite`] can hold before byte stuffing
+///
+/// Enough for the goal position (4 bytes) and profile velocity (4 bytes) of 20 servos.
+pub const MAX_SYNC_WRITE_PARAMS: usize = 4 + 20 * (1 + 8);
+
+/// Most servos a [`BulkRead`] can address
+pub const MAX_BULK_READ_SERVOS: usize = 20;
+
+/// Bytes before the parameters: header, ID, length and instruction
+const PREFIX_SIZE: usize = 8;
+
+/// Bytes of the CRC at the end of a packet
+const CRC_SIZE: usize = 2;
+
+/// Instruction byte of a status packet
+const STATUS_INSTRUCTION: u8 = 0x55;
+
+/// Instructions that can be sent to a servo
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum Instruction {
+    /// Check that a servo is present, it replies with its model number and firmware version
+    Ping = 0x01,
+    /// Write to the control table (params: address, data)
+    Write = 0x03,
+    /// Write the same control table range on several servos (params: address, length, then
+    /// ID and data for each servo)
+    SyncWrite = 0x83,
+    /// Read a different control table range from each of several servos (params: ID, address
+    /// and length for each servo)
+    BulkRead = 0x92,
+}
+
+/// Errors from building an instruction packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum BuildError {
+    /// The output buffer cannot hold the packet
+    BufferTooSmall,
+    /// A sync write entry does not hold exactly the data length of the sync write
+    DataLengthMismatch,
+    /// Another servo would take the sync write parameters past [`MAX_SYNC_WRITE_PARAMS`], or the
+    /// bulk read past [`MAX_BULK_READ_SERVOS`]
+    TooManyServos,
+    /// The servo is already part of the bulk read
+    DuplicateId,
+}
+
+/// Errors from parsing a status packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum ParseError {
+    /// Fewer bytes than the packet header or its length field require
+    Incomplete,
+    /// The buffer does not start with [`HEADER`]
+    InvalidHeader,
+    /// The packet is not a status packet
+    NotStatus,
+    /// The length field is too small for a status packet
+    InvalidLength,
+    /// The CRC does not match the packet contents
+    CrcMismatch,
+    /// The packet carries more than [`MAX_STATUS_PARAMS`] parameters
+    TooManyParams,
+}
+
+/// Error byte bit set while the servo has a hardware fault, see the Hardware Error Status register
+pub const HARDWARE_ALERT: u8 = 0x80;
+
+/// A decoded status packet returned by a servo
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct StatusPacket {
+    /// ID of the servo that replied
+    pub id: u8,
+    /// Error byte, bit 7 is the hardware alert and bits 0-6 the error number
+    pub error: u8,
+    /// Parameter storage, only the first `param_count` bytes are valid
+    params: [u8; MAX_STATUS_PARAMS],
+    /// Number of valid parameter bytes
+    param_count: usize,
+}
+
+impl StatusPacket {
+    /// Error number of the instruction, zero if it succeeded
+    pub fn error_number(&self) -> u8 {
+        self.error & !HARDWARE_ALERT
+    }
+
+    /// Whether the servo has a hardware fault, which it reports alongside every reply until the
+    /// fault is cleared and does not stop the instruction from succeeding
+    pub fn hardware_alert(&self) -> bool {
+        self.error & HARDWARE_ALERT != 0
+    }
+
+    /// Parameters of the status packet, with byte stuffing removed
+    pub fn params(&self) -> &[u8] {
+        &self.params[..self.param_count]
+    }
+}
+
+/// Build an instruction packet into `out`.
+///
+/// # Arguments
+/// * `id` - ID of the servo to address, or [`BROADCAST_ID`]
+/// * `instruction` - Instruction to send
+/// * `params` - Instruction parameters, stuffed as needed
+/// * `crc` - CRC processor used to append the packet CRC
+/// * `out` - Buffer the packet is written to
+///
+/// # Returns
+/// The number of bytes of `out` making up the packet
+pub fn build_instruction(
+    id: u8,
+    instruction: Instruction,
+    params: &[u8],
+    crc: &mut CrcProcessor,
+    out: &mut [u8],
+) -> Result<usize, BuildError> {
+    if out.len() < PREFIX_SIZE + params.len() + CRC_SIZE {
+        return Err(BuildError::BufferTooSmall);
+    }
+
+    out[..HEADER.len()].copy_from_slice(&HEADER);
+    out[4] = id;
+    out[7] = instruction as u8;
+
+    // Stuff the parameters, the instruction byte can never start the header pattern
+    let mut len = PREFIX_SIZE;
+    for &byte in params {
+        if len >= out.len() - CRC_SIZE {
+            return Err(BuildError::BufferTooSmall);
+        }
+        out[len] = byte;
+        len += 1;
+
+        if len >= PREFIX_SIZE + 3 && out[len - 3..len] == HEADER[..3] {
+            if len >= out.len() - CRC_SIZE {
+                return Err(BuildError::BufferTooSmall);
+            }
+            out[len] = 0xFD;
+            len += 1;
+        }
+    }
+
+    // Length covers the instruction, the stuffed parameters and the CRC
+    let length = (len - PREFIX_SIZE + 1 + CRC_SIZE) as u16;
+    out[5..7].copy_from_slice(&length.to_le_bytes());
+
+    let packet_crc = crc.calculate_crc(&out[..len]);
+    out[len..len + CRC_SIZE].copy_from_slice(&packet_crc);
+    Ok(len + CRC_SIZE)
+}
+
+/// Builder for a [`Instruction::SyncWrite`] packet
+///
+/// Every servo gets `length` bytes written from `address` in its control table, in a single
+/// broadcast packet that none of them replies to.
+pub struct SyncWrite {
+    /// Address, length and the entries added so far
+    params: [u8; MAX_SYNC_WRITE_PARAMS],
+    /// Number of valid bytes in `params`
+    param_count: usize,
+    /// Data bytes every entry must hold
+    length: u16,
+}
+
+impl SyncWrite {
+    /// Start a sync write without any servos
+    ///
+    /// # Arguments
+    /// * `address` - Control table address the data is written to
+    /// * `length` - Number of data bytes written to each servo
+    pub fn new(address: u16, length: u16) -> Self {
+        let mut params = [0u8; MAX_SYNC_WRITE_PARAMS];
+        params[..2].copy_from_slice(&address.to_le_bytes());
+        params[2..4].copy_from_slice(&length.to_le_bytes());
+        Self {
+            params,
+            param_count: 4,
+            length,
+        }
+    }
+
+    /// Add the data for one servo
+    ///
+    /// # Arguments
+    /// * `id` - ID of the servo
+    /// * `data` - Bytes written to the servo, exactly the length given to [`SyncWrite::new`]
+    pub fn add_servo(&mut self, id: u8, data: &[u8]) -> Result<(), BuildError> {
+        if data.len() != usize::from(self.length) {
+            return Err(BuildError::DataLengthMismatch);
+        }
+        let end = self.param_count + 1 + data.len();
+        if end > MAX_SYNC_WRITE_PARAMS {
+            return Err(BuildError::TooManyServos);
+        }
+
+        self.params[self.param_count] = id;
+        self.params[self.param_count + 1..end].copy_from_slice(data);
+        self.param_count = end;
+        Ok(())
+    }
+
+    /// Number of servos added so far
+    pub fn servo_count(&self) -> usize {
+        (self.param_count - 4) / (1 + usize::from(self.length))
+    }
+
+    /// Build the broadcast packet into `out`.
+    ///
+    /// # Arguments
+    /// * `out` - Buffer the packet is written to
+    /// * `crc` - CRC processor used to append the packet CRC
+    ///
+    /// # Returns
+    /// The number of bytes of `out` making up the packet
+    pub fn finalize(self, out: &mut [u8], crc: &mut CrcProcessor) -> Result<usize, BuildError> {
+        build_instruction(
+            BROADCAST_ID,
+            Instruction::SyncWrite,
+            &self.params[..self.param_count],
+            crc,
+            out,
+        )
+    }
+}
+
+/// One servo's part of a [`BulkRead`]
+#[derive(Debug, Clone, Copy)]
+struct BulkReadEntry {
+    id: u8,
+    address: u16,
+    length: u16,
+}
+
+/// Builder for a [`Instruction::BulkRead`] packet
+///
+/// Each servo can be read from its own address and length. The servos reply one after the other
+/// in the order they were added.
+#[derive(Debug, Clone, Copy)]
+pub struct BulkRead {
+    /// Servos to read, only the first `count` are valid
+    entries: [BulkReadEntry; MAX_BULK_READ_SERVOS],
+    /// Number of valid entries
+    count: usize,
+}
+
+impl BulkRead {
+    /// Start a bulk read without any servos
+    pub fn new() -> Self {
+        Self {
+            entries: [BulkReadEntry {
+                id: 0,
+                address: 0,
+                length: 0,
+            }; MAX_BULK_READ_SERVOS],
+            count: 0,
+        }
+    }
+
+    /// Add a servo to read from
+    ///
+    /// # Arguments
+    /// * `id` - ID of the servo, each servo can only be read once
+    /// * `address` - Control table address to start reading at
+    /// * `length` - Number of bytes to read, at most [`MAX_STATUS_PARAMS`] to be parsed
+    pub fn add_servo(&mut self, id: u8, address: u16, length: u16) -> Result<(), BuildError> {
+        if self.index_of(id).is_some() {
+            return Err(BuildError::DuplicateId);
+        }
+        if self.count == MAX_BULK_READ_SERVOS {
+            return Err(BuildError::TooManyServos);
+        }
+
+        self.entries[self.count] = BulkReadEntry { id, address, length };
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Build the broadcast packet into `out`.
+    ///
+    /// # Arguments
+    /// * `out` - Buffer the packet is written to
+    /// * `crc` - CRC processor used to append the packet CRC
+    ///
+    /// # Returns
+    /// The number of bytes of `out` making up the packet
+    pub fn finalize(&self, out: &mut [u8], crc: &mut CrcProcessor) -> Result<usize, BuildError> {
+        let mut params = [0u8; MAX_BULK_READ_SERVOS * 5];
+        for (chunk, entry) in params.chunks_exact_mut(5).zip(self.entries()) {
+            chunk[0] = entry.id;
+            chunk[1..3].copy_from_slice(&entry.address.to_le_bytes());
+            chunk[3..5].copy_from_slice(&entry.length.to_le_bytes());
+        }
+        build_instruction(BROADCAST_ID, Instruction::BulkRead, &params[..self.count * 5], crc, out)
+    }
+
+    /// Servos added so far, in request order
+    fn entries(&self) -> &[BulkReadEntry] {
+        &self.entries[..self.count]
+    }
+
+    /// Position of the servo in the request
+    fn index_of(&self, id: u8) -> Option<usize> {
+        self.entries().iter().position(|entry| entry.id == id)
+    }
+}
+
+impl Default for BulkRead {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Total size of the packet at the start of `buf`, from its length field.
+///
+/// # Returns
+/// The size in bytes, or `None` if `buf` is too short to hold the length field
+pub fn packet_length(buf: &[u8]) -> Option<usize> {
+    let length = buf.get(5..7)?;
+    Some(PREFIX_SIZE - 1 + usize::from(u16::from_le_bytes([length[0], length[1]])))
+}
+
+/// Parse a status packet at the start of `buf`.
+///
+/// The header and instruction are validated, the CRC is checked against the bytes as received
+/// and the parameters are de-stuffed. Bytes after the end of the packet are ignored.
+///
+/// # Arguments
+/// * `buf` - Received bytes, starting with the packet header
+/// * `crc` - CRC processor used to verify the packet CRC
+pub fn parse_status(buf: &[u8], crc: &mut CrcProcessor) -> Result<StatusPacket, ParseError> {
+    if buf.len() < PREFIX_SIZE {
+        return Err(ParseError::Incomplete);
+    }
+    if buf[..HEADER.len()] != HEADER {
+        return Err(ParseError::InvalidHeader);
+    }
+    if buf[7] != STATUS_INSTRUCTION {
+        return Err(ParseError::NotStatus);
+    }
+
+    // Length covers the instruction, error byte, parameters and CRC
+    let length = usize::from(u16::from_le_bytes([buf[5], buf[6]]));
+    if length < 1 + 1 + CRC_SIZE {
+        return Err(ParseError::InvalidLength);
+    }
+    let total = PREFIX_SIZE - 1 + length;
+    if buf.len() < total {
+        return Err(ParseError::Incomplete);
+    }
+
+    let crc_start = total - CRC_SIZE;
+    if crc.calculate_crc(&buf[..crc_start]) != buf[crc_start..total] {
+        return Err(ParseError::CrcMismatch);
+    }
+
+    let mut packet = StatusPacket {
+        id: buf[4],
+        error: buf[PREFIX_SIZE],
+        params: [0u8; MAX_STATUS_PARAMS],
+        param_count: 0,
+    };
+
+    // Remove the 0xFD inserted after every 0xFF 0xFF 0xFD. Stuffing starts at the instruction,
+    // so the error byte can begin a pattern that continues into the parameters.
+    let stuffed = &buf[PREFIX_SIZE + 1..crc_start];
+    let mut previous = [buf[7], buf[PREFIX_SIZE]];
+    let mut i = 0;
+    while i < stuffed.len() {
+        if packet.param_count == MAX_STATUS_PARAMS {
+            return Err(ParseError::TooManyParams);
+        }
+        let byte = stuffed[i];
+        packet.params[packet.param_count] = byte;
+        packet.param_count += 1;
+
+        if previous == [0xFF, 0xFF] && byte == 0xFD && stuffed.get(i + 1) == Some(&0xFD) {
+            i += 1;
+        }
+        previous = [previous[1], byte];
+        i += 1;
+    }
+
+    Ok(packet)
+}
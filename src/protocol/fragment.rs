@@ -0,0 +1,357 @@
+//! Messages split into numbered fragments, so the receiver can put them back together and notice
+//! the ones it lost
+//!
+//! A message is sent as one or more fragments of up to [`MAX_FRAGMENT_SIZE`] bytes, each carrying
+//! a [`HEADER_SIZE`] byte header followed by its part of the message. All the fragments of a
+//! message share its sequence number, which goes up by one with every message and wraps from
+//! `u16::MAX` to zero. Every fragment except the last carries [`MAX_PAYLOAD_SIZE`] bytes of the
+//! message.
+//!
+//! # Fragment layout
+//!
+//! Little endian:
+//!
+//! | Offset | Size | Field                                            |
+//! |--------|------|--------------------------------------------------|
+//! | 0      | 2    | Message sequence number (`u16`)                  |
+//! | 2      | 1    | Fragment index, from zero                        |
+//! | 3      | 1    | Fragment count of the message, at least one      |
+//! | 4      | 2    | Length of the whole message (`u16`)              |
+//! | 6      | ..   | Part of the message, [`MAX_PAYLOAD_SIZE`] at most |
+//!
+//! Fragments must arrive in order. A fragment that is not the next one of the message being put
+//! back together ends it: the incomplete message is discarded, and a receiver seeing a sequence
+//! number jump knows whole messages were lost in between.
+//!
+//! This module only uses `core`, so its tests run on the host without the rest of the firmware:
+//!
+//! ```text
+//! rustc --edition 2021 --test src/protocol/fragment.rs -o target/fragment && target/fragment
+//! ```
+
+/// Size of the header at the start of every fragment
+pub const HEADER_SIZE: usize = 6;
+
+/// Largest fragment, header included, which a COBS frame of
+/// [`MAX_ENCODED_FRAME_SIZE`](crate::peripherals::acm::MAX_ENCODED_FRAME_SIZE) bytes always holds
+pub const MAX_FRAGMENT_SIZE: usize = 1020;
+
+/// Most bytes of the message a fragment carries
+pub const MAX_PAYLOAD_SIZE: usize = MAX_FRAGMENT_SIZE - HEADER_SIZE;
+
+/// Largest message, limited by the length field of the header
+pub const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// Header at the start of every fragment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct Header {
+    /// Sequence number of the message
+    pub sequence: u16,
+    /// Position of this fragment in the message, from zero
+    pub index: u8,
+    /// Number of fragments in the message
+    pub count: u8,
+    /// Length of the whole message in bytes
+    pub total_len: u16,
+}
+
+impl Header {
+    /// Encode the header into the bytes at the start of a fragment
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let [s0, s1] = self.sequence.to_le_bytes();
+        let [l0, l1] = self.total_len.to_le_bytes();
+        [s0, s1, self.index, self.count, l0, l1]
+    }
+
+    /// Split a fragment into its header and its part of the message
+    ///
+    /// # Arguments
+    /// * `fragment` - A received fragment
+    ///
+    /// # Returns
+    /// The header and payload, or `None` if the fragment is too short or its header does not
+    /// agree with its payload
+    pub fn parse(fragment: &[u8]) -> Option<(Header, &[u8])> {
+        let (header, payload) = (fragment.get(..HEADER_SIZE)?, &fragment[HEADER_SIZE..]);
+        let header = Header {
+            sequence: u16::from_le_bytes([header[0], header[1]]),
+            index: header[2],
+            count: header[3],
+            total_len: u16::from_le_bytes([header[4], header[5]]),
+        };
+
+        let total_len = usize::from(header.total_len);
+        let offset = usize::from(header.index) * MAX_PAYLOAD_SIZE;
+        let expected = total_len.saturating_sub(offset).min(MAX_PAYLOAD_SIZE);
+        let valid = usize::from(header.count) == fragment_count(total_len)
+            && header.index < header.count
+            && payload.len() == expected;
+        valid.then_some((header, payload))
+    }
+}
+
+/// Number of fragments a message of `len` bytes is split into, an empty message still takes one
+fn fragment_count(len: usize) -> usize {
+    len.div_ceil(MAX_PAYLOAD_SIZE).max(1)
+}
+
+/// The fragments of a message, each as its header and its part of the message
+///
+/// # Arguments
+/// * `sequence` - Sequence number of the message
+/// * `msg` - The message, at most [`MAX_MESSAGE_SIZE`] bytes
+///
+/// # Returns
+/// The fragments in the order they are sent, or `None` if the message is too long
+pub fn fragments(sequence: u16, msg: &[u8]) -> Option<impl Iterator<Item = (Header, &[u8])>> {
+    let total_len = u16::try_from(msg.len()).ok()?;
+    let count = fragment_count(msg.len()) as u8;
+    let payloads = msg.chunks(MAX_PAYLOAD_SIZE).chain(msg.is_empty().then_some(msg));
+    Some(payloads.enumerate().map(move |(index, payload)| {
+        let header = Header {
+            sequence,
+            index: index as u8,
+            count,
+            total_len,
+        };
+        (header, payload)
+    }))
+}
+
+/// Why a fragment was not taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub enum Error {
+    /// The fragment is too short or its header does not agree with its payload
+    Malformed,
+    /// The message is longer than the buffer it is put back together in
+    TooLong { sequence: u16, total_len: u16 },
+    /// The fragment is not the next one of message `sequence`, which is discarded. The fragment
+    /// was not taken either, pushing it again starts a new message if it is a first fragment
+    Incomplete { sequence: u16 },
+    /// The fragment is not the first of its message, but no message is being put back together,
+    /// so its start was lost
+    Orphan { sequence: u16, index: u8 },
+}
+
+/// A message put back together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Message {
+    /// Sequence number of the message
+    pub sequence: u16,
+    /// Length of the message at the start of the buffer
+    pub len: usize,
+}
+
+/// Puts the fragments of a message back together in a buffer
+pub struct Reassembler<'a> {
+    buf: &'a mut [u8],
+    /// Header of the message being put back together and the number of fragments taken so far
+    partial: Option<(Header, u8)>,
+}
+
+impl<'a> Reassembler<'a> {
+    /// Create a reassembler putting messages back together in `buf`
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, partial: None }
+    }
+
+    /// Take the next fragment received
+    ///
+    /// # Arguments
+    /// * `fragment` - A received fragment, header included
+    ///
+    /// # Returns
+    /// * `Ok(Some(message))` - The fragment completed a message, which is at the start of the buffer
+    /// * `Ok(None)` - The fragment was taken and the message needs more
+    /// * `Err(error)` - The fragment was dropped, see [`Error`]
+    pub fn push(&mut self, fragment: &[u8]) -> Result<Option<Message>, Error> {
+        let (header, payload) = Header::parse(fragment).ok_or(Error::Malformed)?;
+
+        let taken = match self.partial {
+            Some((partial, taken)) if partial.sequence == header.sequence && taken == header.index => taken,
+            Some((partial, _)) => {
+                self.partial = None;
+                return Err(Error::Incomplete {
+                    sequence: partial.sequence,
+                });
+            }
+            None if header.index == 0 => {
+                if usize::from(header.total_len) > self.buf.len() {
+                    return Err(Error::TooLong {
+                        sequence: header.sequence,
+                        total_len: header.total_len,
+                    });
+                }
+                0
+            }
+            None => {
+                return Err(Error::Orphan {
+                    sequence: header.sequence,
+                    index: header.index,
+                })
+            }
+        };
+
+        let offset = usize::from(header.index) * MAX_PAYLOAD_SIZE;
+        self.buf[offset..offset + payload.len()].copy_from_slice(payload);
+
+        if taken + 1 == header.count {
+            self.partial = None;
+            Ok(Some(Message {
+                sequence: header.sequence,
+                len: usize::from(header.total_len),
+            }))
+        } else {
+            self.partial = Some((header, taken + 1));
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A test message of `len` bytes that differ from fragment to fragment
+    fn message(len: usize) -> [u8; 3000] {
+        let mut msg = [0u8; 3000];
+        for (i, byte) in msg[..len].iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        msg
+    }
+
+    /// Encode every fragment of a message into `out`, returning how many there are
+    fn encode(sequence: u16, msg: &[u8], out: &mut [[u8; MAX_FRAGMENT_SIZE]; 4], lens: &mut [usize; 4]) -> usize {
+        let mut count = 0;
+        for (header, payload) in fragments(sequence, msg).unwrap() {
+            out[count][..HEADER_SIZE].copy_from_slice(&header.encode());
+            out[count][HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(payload);
+            lens[count] = HEADER_SIZE + payload.len();
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn splits_into_full_fragments_and_a_last_one() {
+        let msg = message(2 * MAX_PAYLOAD_SIZE + 10);
+        let msg = &msg[..2 * MAX_PAYLOAD_SIZE + 10];
+        let mut parts = fragments(7, msg).unwrap();
+        for (index, len) in [(0, MAX_PAYLOAD_SIZE), (1, MAX_PAYLOAD_SIZE), (2, 10)] {
+            let (header, payload) = parts.next().unwrap();
+            assert_eq!(
+                header,
+                Header {
+                    sequence: 7,
+                    index,
+                    count: 3,
+                    total_len: msg.len() as u16
+                }
+            );
+            assert_eq!(payload.len(), len);
+        }
+        assert!(parts.next().is_none());
+
+        // An empty message is still sent, as a single empty fragment
+        let mut parts = fragments(8, &[]).unwrap();
+        assert_eq!(parts.next().unwrap().0.count, 1);
+        assert!(parts.next().is_none());
+
+        assert!(fragments(9, &[0; MAX_MESSAGE_SIZE + 1]).is_none());
+    }
+
+    #[test]
+    fn reassembles_in_order_fragments() {
+        let (mut frags, mut lens) = ([[0; MAX_FRAGMENT_SIZE]; 4], [0; 4]);
+        for len in [0, 1, MAX_PAYLOAD_SIZE, MAX_PAYLOAD_SIZE + 1, 2500] {
+            let msg = message(len);
+            let count = encode(3, &msg[..len], &mut frags, &mut lens);
+
+            let mut buf = [0u8; 3000];
+            let mut reassembler = Reassembler::new(&mut buf);
+            for i in 0..count - 1 {
+                assert_eq!(reassembler.push(&frags[i][..lens[i]]), Ok(None));
+            }
+            let last = count - 1;
+            assert_eq!(
+                reassembler.push(&frags[last][..lens[last]]),
+                Ok(Some(Message { sequence: 3, len }))
+            );
+            assert_eq!(&buf[..len], &msg[..len]);
+        }
+    }
+
+    #[test]
+    fn discards_a_message_missing_a_fragment() {
+        let (mut frags, mut lens) = ([[0; MAX_FRAGMENT_SIZE]; 4], [0; 4]);
+        let msg = message(2500);
+        encode(10, &msg[..2500], &mut frags, &mut lens);
+
+        let mut buf = [0u8; 3000];
+        let mut reassembler = Reassembler::new(&mut buf);
+        assert_eq!(reassembler.push(&frags[0][..lens[0]]), Ok(None));
+        // Fragment 1 is lost
+        assert_eq!(
+            reassembler.push(&frags[2][..lens[2]]),
+            Err(Error::Incomplete { sequence: 10 })
+        );
+        // Once discarded, the rest of the message has no start
+        assert_eq!(
+            reassembler.push(&frags[2][..lens[2]]),
+            Err(Error::Orphan { sequence: 10, index: 2 })
+        );
+    }
+
+    #[test]
+    fn a_new_message_ends_an_incomplete_one() {
+        let (mut frags, mut lens) = ([[0; MAX_FRAGMENT_SIZE]; 4], [0; 4]);
+        let msg = message(2500);
+        encode(10, &msg[..2500], &mut frags, &mut lens);
+        let (mut next, mut next_lens) = ([[0; MAX_FRAGMENT_SIZE]; 4], [0; 4]);
+        encode(11, &msg[..5], &mut next, &mut next_lens);
+
+        let mut buf = [0u8; 3000];
+        let mut reassembler = Reassembler::new(&mut buf);
+        assert_eq!(reassembler.push(&frags[0][..lens[0]]), Ok(None));
+        assert_eq!(
+            reassembler.push(&next[0][..next_lens[0]]),
+            Err(Error::Incomplete { sequence: 10 })
+        );
+        // Pushed again, the first fragment starts the new message
+        assert_eq!(
+            reassembler.push(&next[0][..next_lens[0]]),
+            Ok(Some(Message { sequence: 11, len: 5 }))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_and_oversized_fragments() {
+        let (mut frags, mut lens) = ([[0; MAX_FRAGMENT_SIZE]; 4], [0; 4]);
+        let msg = message(2500);
+        encode(4, &msg[..2500], &mut frags, &mut lens);
+
+        let mut buf = [0u8; 3000];
+        let mut reassembler = Reassembler::new(&mut buf);
+        // Shorter than a header
+        assert_eq!(reassembler.push(&frags[0][..3]), Err(Error::Malformed));
+        // Payload cut short
+        assert_eq!(reassembler.push(&frags[0][..lens[0] - 1]), Err(Error::Malformed));
+        // Fragment count that does not match the length
+        let mut wrong = frags[2];
+        wrong[3] = 4;
+        assert_eq!(reassembler.push(&wrong[..lens[2]]), Err(Error::Malformed));
+
+        let mut small = [0u8; 100];
+        let mut reassembler = Reassembler::new(&mut small);
+        assert_eq!(
+            reassembler.push(&frags[0][..lens[0]]),
+            Err(Error::TooLong {
+                sequence: 4,
+                total_len: 2500
+            })
+        );
+    }
+}
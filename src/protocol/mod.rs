@@ -0,0 +1,11 @@
+//! Communication protocols spoken by the NUSense platform.
+//!
+//! This module contains the encoding and decoding of the wire protocols used to talk to devices
+//! attached to the NUSense board, independent of the peripheral that carries the bytes.
+
+/// COBS framing that keeps zero bytes out of a message
+pub mod cobs;
+/// Dynamixel 2.0 servo protocol
+pub mod dynamixel;
+/// Messages split into numbered fragments that are put back together on arrival
+pub mod fragment;
@@ -0,0 +1,159 @@
+//! Supervised restarts for long-running tasks.
+//!
+//! Long-running tasks (IMU, USB, servo bus) return an error when they hit a fault they cannot
+//! handle themselves. [`run_supervised`] restarts them according to a [`RestartPolicy`] so the
+//! recovery behaviour lives in one place instead of a hand-written loop in every task.
+//!
+//! Transient failures are retried quickly, backing off exponentially while they keep happening.
+//! Failures the task marks as permanent through [`Failure`] (e.g. missing hardware) go straight to
+//! the longest delay and are only logged once, with a periodic reminder while they persist.
+
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant, Timer};
+
+/// Interval between reminders that a task is still failing
+const FAILING_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Classification of a task error for [`run_supervised`]
+pub trait Failure {
+    /// Whether retrying soon is pointless, e.g. because the hardware is missing
+    ///
+    /// Permanent failures are retried at the policy's maximum delay and logged once per streak.
+    fn is_permanent(&self) -> bool {
+        false
+    }
+}
+
+/// How a supervised task is restarted after it fails
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    /// Delay before the first restart after a failure
+    pub initial_delay: Duration,
+    /// Upper bound on the delay between restarts
+    pub max_delay: Duration,
+    /// Factor the delay is multiplied by after each consecutive failure (1 for a fixed delay)
+    pub backoff_factor: u32,
+    /// Consecutive failures tolerated before giving up, or `None` to retry forever
+    pub max_restarts: Option<u32>,
+    /// A run lasting at least this long was healthy, so a failure after it starts a new streak
+    /// at the initial delay
+    pub healthy_after: Duration,
+}
+
+impl RestartPolicy {
+    /// Restart forever, doubling the delay after each consecutive failure up to `max_delay`
+    ///
+    /// # Arguments
+    /// * `initial_delay` - Delay after the first failure of a streak
+    /// * `max_delay` - Longest delay, also used for permanent failures
+    /// * `healthy_after` - Run time after which the backoff resets to `initial_delay`
+    pub const fn exponential(initial_delay: Duration, max_delay: Duration, healthy_after: Duration) -> Self {
+        Self {
+            initial_delay,
+            max_delay,
+            backoff_factor: 2,
+            max_restarts: None,
+            healthy_after,
+        }
+    }
+
+    /// Delay before restart number `failures` (counting from 1)
+    fn delay_after(&self, failures: u32) -> Duration {
+        let mut delay = self.initial_delay;
+        for _ in 1..failures {
+            if delay >= self.max_delay {
+                break;
+            }
+            delay = delay * self.backoff_factor;
+        }
+        delay.min(self.max_delay)
+    }
+}
+
+/// Run `task` under supervision, restarting it according to `policy`
+///
+/// `task` is called again each time it returns. An `Err` counts as a failure and the restart is
+/// delayed per the policy; an `Ok` is treated as a clean exit and restarts immediately, resetting
+/// the failure count. A failure after a run longer than [`RestartPolicy::healthy_after`] also
+/// starts counting from one again. The task is responsible for logging its own errors.
+///
+/// # Arguments
+/// * `name` - Task name used in log messages
+/// * `policy` - Restart delays and limit
+/// * `task` - Factory producing one run of the task
+///
+/// # Returns
+/// The last error once `policy.max_restarts` consecutive failures have been exceeded. With no
+/// restart limit this never returns.
+pub async fn run_supervised<E: Failure>(
+    name: &str,
+    policy: RestartPolicy,
+    mut task: impl AsyncFnMut() -> Result<(), E>,
+) -> E {
+    let mut failures = 0u32;
+    // Start of the current failure streak and when it was last reported
+    let mut failing_since = Instant::now();
+    let mut last_report = failing_since;
+    let mut permanent_reported = false;
+
+    loop {
+        let started = Instant::now();
+        let result = task().await;
+        let now = Instant::now();
+
+        match result {
+            Ok(()) => {
+                info!("{} task returned, restarting", name);
+                failures = 0;
+                permanent_reported = false;
+            }
+            Err(e) => {
+                if now.duration_since(started) >= policy.healthy_after {
+                    failures = 0;
+                    permanent_reported = false;
+                }
+                if failures == 0 {
+                    failing_since = started;
+                    last_report = now;
+                }
+                failures = failures.saturating_add(1);
+
+                if policy.max_restarts.is_some_and(|max| failures > max) {
+                    warn!("{} task failed {} times in a row, giving up", name, failures);
+                    crate::ring_log!("{} task failed {} times in a row, giving up", name, failures);
+                    return e;
+                }
+
+                let delay = if e.is_permanent() {
+                    if !permanent_reported {
+                        warn!(
+                            "{} task failed permanently, retrying every {} ms",
+                            name,
+                            policy.max_delay.as_millis()
+                        );
+                        crate::ring_log!("{} task failed permanently", name);
+                        permanent_reported = true;
+                    }
+                    policy.max_delay
+                } else {
+                    let delay = policy.delay_after(failures);
+                    info!("{} task failed, restarting in {} ms", name, delay.as_millis());
+                    crate::ring_log!("{} task failed, restarting", name);
+                    delay
+                };
+
+                if now.duration_since(last_report) >= FAILING_REPORT_INTERVAL {
+                    warn!(
+                        "{} task has been failing for {} s ({} attempts)",
+                        name,
+                        now.duration_since(failing_since).as_secs(),
+                        failures
+                    );
+                    last_report = now;
+                }
+
+                Timer::after(delay).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Alignment of the device clock with the host clock.
+//!
+//! Device timestamps come from the monotonic embassy clock, which starts at zero on every boot.
+//! To correlate them with host logs and camera frames the host measures the offset between the
+//! two clocks and hands it to the firmware, which then converts device timestamps to host time.
+//! IMU samples carry their host time from then on, stamped as they are published.
+//!
+//! # Measuring the offset
+//!
+//! The exchange runs over the debug shell, ping style:
+//! 1. The host notes its time `t0` and sends `time ping`
+//! 2. The device replies with its own time `t1` in µs
+//! 3. The host notes the time `t2` at which the reply arrived
+//! 4. Assuming a symmetric path, `offset = (t0 + t2) / 2 - t1` and `round_trip = t2 - t0`
+//! 5. The host sends `time offset <offset> <round_trip>`
+//!
+//! Repeating the exchange and keeping the estimate with the shortest round trip gives the best
+//! result, which [`set_offset`] does by ignoring estimates that are less accurate than the current
+//! one unless it has gone stale.
+//!
+//! # Accuracy
+//!
+//! The error is bounded by half the round trip. Over USB high speed the round trip is dominated by
+//! host scheduling and the 125 µs microframe, so expect roughly ±0.5 ms at best and several ms on
+//! a loaded host. The two crystals also drift apart by up to ~50 ppm (about 3 ms per minute), so
+//! the host should repeat the exchange periodically.
+
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+/// Age after which an estimate is replaced even by a less accurate one, as drift dominates
+const STALE_AFTER: Duration = Duration::from_secs(60);
+
+/// A measured offset between the host and device clocks
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "debug", derive(defmt::Format))]
+pub struct ClockOffset {
+    /// Host time minus device time in µs
+    pub offset_us: i64,
+    /// Round trip of the exchange the offset was measured with, the error is at most half of it
+    pub round_trip_us: u64,
+    /// Device time at which the offset was set
+    pub measured_at: Instant,
+}
+
+/// The current best estimate of the host clock offset
+static OFFSET: Mutex<CriticalSectionRawMutex, Cell<Option<ClockOffset>>> = Mutex::new(Cell::new(None));
+
+/// Record an offset measured by the host.
+///
+/// The estimate is kept if it is at least as accurate as the current one, or the current one is
+/// older than [`STALE_AFTER`].
+///
+/// # Returns
+/// `true` if the estimate was accepted
+pub fn set_offset(offset_us: i64, round_trip_us: u64) -> bool {
+    let now = Instant::now();
+    OFFSET.lock(|current| {
+        let accept = match current.get() {
+            None => true,
+            Some(previous) => {
+                round_trip_us <= previous.round_trip_us || now.duration_since(previous.measured_at) > STALE_AFTER
+            }
+        };
+        if accept {
+            current.set(Some(ClockOffset {
+                offset_us,
+                round_trip_us,
+                measured_at: now,
+            }));
+        }
+        accept
+    })
+}
+
+/// The current clock offset, or `None` if the host has not synchronized yet.
+pub fn offset() -> Option<ClockOffset> {
+    OFFSET.lock(|current| current.get())
+}
+
+/// Convert a device timestamp to host time in µs.
+///
+/// # Returns
+/// `None` if the host has not synchronized yet
+pub fn to_host_us(device_time: Instant) -> Option<i64> {
+    offset().map(|offset| device_time.as_micros() as i64 + offset.offset_us)
+}
+
+/// Convert a host time in µs to a device timestamp.
+///
+/// Host times from before the device booted are clamped to boot.
+///
+/// # Returns
+/// `None` if the host has not synchronized yet
+pub fn from_host_us(host_us: i64) -> Option<Instant> {
+    offset().map(|offset| Instant::from_micros(host_us.saturating_sub(offset.offset_us).max(0) as u64))
+}